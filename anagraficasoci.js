@@ -17,6 +17,11 @@ let allTipologieSocio = [];
 let allComuniResidenza = [];
 let isNuovoSocioMode = false;
 let isRicercaMode = false;
+// Token della prenotazione IdSocio del nuovo socio (reserve_idsocio)
+let prenotazioneIdsocio = null;
+// Versione dell'anagrafica aperta: il salvataggio fallisce se un altro utente
+// ha cambiato gli stessi campi nel frattempo
+let versioneAnagrafica = '';
 
 const RICERCA_FILTRO_STORAGE_KEY = 'auser-ricerca-filtro-criteri';
 
@@ -87,7 +92,26 @@ function createNuovoSocioTemplate(idsocio) {
     };
 }
 
+async function prenotaNuovoIdsocio() {
+    const prenotazione = await invoke('reserve_idsocio', { prenotatoDa: null });
+    prenotazioneIdsocio = prenotazione?.token || null;
+    return String(prenotazione?.idsocio || '');
+}
+
+// Nuovo socio annullato: il numero torna disponibile subito invece che alla scadenza
+async function rilasciaPrenotazioneIdsocio() {
+    const token = prenotazioneIdsocio;
+    prenotazioneIdsocio = null;
+    if (!token || !invoke) return;
+    try {
+        await invoke('release_idsocio', { token });
+    } catch (err) {
+        console.warn('Rilascio prenotazione IdSocio:', err);
+    }
+}
+
 async function closeAnagraficaWindow() {
+    await rilasciaPrenotazioneIdsocio();
     if (isTauri()) {
         try {
             const { getCurrent } = await import('@tauri-apps/api/window');
@@ -393,7 +417,7 @@ async function ensureTipologiaInTable(tipologia) {
         refreshActiveTipologiaFields();
     } catch (error) {
         console.error('Errore aggiunta tipologia socio:', error);
-        setSaveStatus(`Tipologia non salvata in elenco: ${error}`, true);
+        setSaveStatus(`Tipologia non salvata in elenco: ${error?.message || error}`, true);
     }
 }
 
@@ -576,6 +600,7 @@ function cancelAnagraficaEdit() {
 }
 
 function populateAnagrafica(data) {
+    versioneAnagrafica = data.versione || '';
     document.getElementById('field-row-id').value = data.id || '';
     document.getElementById('field-idsocio').value = data.idsocio || '';
     document.getElementById('field-nominativo').value = data.nominativo || '';
@@ -647,7 +672,8 @@ function collectAnagraficaPayload() {
         attivo: document.getElementById('field-attivo').checked,
         archivia: document.getElementById('field-archivia').checked,
         disponibilita: formatDisponibilita(),
-        notaaggiuntiva: document.getElementById('field-nota').value.trim()
+        notaaggiuntiva: document.getElementById('field-nota').value.trim(),
+        versione: versioneAnagrafica
     };
 }
 
@@ -955,7 +981,7 @@ async function saveTelefono(e) {
         });
     } catch (error) {
         console.error('Errore salvataggio telefono:', error);
-        setSaveStatus(`Errore telefono: ${error}`, true);
+        setSaveStatus(`Errore telefono: ${error?.message || error}`, true);
     } finally {
         if (btn) btn.disabled = false;
     }
@@ -1011,7 +1037,7 @@ async function saveEmail(e) {
         setSaveStatus('Email salvata');
     } catch (error) {
         console.error('Errore salvataggio email:', error);
-        setSaveStatus(`Errore email: ${error}`, true);
+        setSaveStatus(`Errore email: ${error?.message || error}`, true);
     } finally {
         if (btn) btn.disabled = false;
     }
@@ -1064,7 +1090,7 @@ async function deleteTelefonoAt(index) {
         });
     } catch (error) {
         console.error('Errore eliminazione telefono:', error);
-        setSaveStatus(`Errore eliminazione telefono: ${error}`, true);
+        setSaveStatus(`Errore eliminazione telefono: ${error?.message || error}`, true);
     }
 }
 
@@ -1109,7 +1135,7 @@ async function deleteEmailAt(index) {
         setSaveStatus('Email eliminata');
     } catch (error) {
         console.error('Errore eliminazione email:', error);
-        setSaveStatus(`Errore eliminazione email: ${error}`, true);
+        setSaveStatus(`Errore eliminazione email: ${error?.message || error}`, true);
     }
 }
 
@@ -1287,7 +1313,7 @@ async function loadNuovoSocio() {
 
         let nextId = '1';
         if (invoke) {
-            nextId = await invoke('get_next_idsocio');
+            nextId = await prenotaNuovoIdsocio();
         }
 
         currentIdsocio = nextId;
@@ -1314,7 +1340,7 @@ async function loadNuovoSocio() {
         document.getElementById('field-nominativo')?.focus();
     } catch (error) {
         console.error('Errore preparazione nuovo socio:', error);
-        if (loading) loading.textContent = `Errore: ${error}`;
+        if (loading) loading.textContent = `Errore: ${error?.message || error}`;
         setSaveStatus('Impossibile preparare il nuovo socio', true);
     }
 }
@@ -1367,7 +1393,7 @@ async function loadSocioData() {
         setAnagraficaEditMode(false);
     } catch (error) {
         console.error('Errore caricamento anagrafica:', error);
-        if (loading) loading.textContent = `Errore: ${error}`;
+        if (loading) loading.textContent = `Errore: ${error?.message || error}`;
         setSaveStatus('Impossibile caricare i dati', true);
     }
 }
@@ -1759,8 +1785,13 @@ async function saveAnagrafica() {
             const pendingTesseramenti = [...tesseramentiList];
             const pendingTelefoni = [...telefoniList];
             const pendingEmail = [...emailList];
-            const saved = await invoke('create_socio_anagrafica', { anagrafica: payload });
+            const saved = await invoke('create_socio_anagrafica', {
+                anagrafica: payload,
+                prenotazione: prenotazioneIdsocio
+            });
+            prenotazioneIdsocio = null;
             isNuovoSocioMode = false;
+            versioneAnagrafica = saved.versione || '';
             currentIdsocio = saved.idsocio || payload.idsocio;
             populateAnagrafica(saved);
 
@@ -1829,7 +1860,8 @@ async function saveAnagrafica() {
             anagraficaEditSnapshot = null;
             setAnagraficaEditMode(false);
         } else {
-            await invoke('save_socio_anagrafica', { anagrafica: payload });
+            const saved = await invoke('save_socio_anagrafica', { anagrafica: payload });
+            versioneAnagrafica = saved?.versione || '';
             setSaveStatus('Anagrafica salvata');
             const subtitle = document.getElementById('socio-subtitle');
             if (subtitle) subtitle.textContent = `ID ${payload.idsocio} · ${payload.nominativo}`;
@@ -1839,7 +1871,31 @@ async function saveAnagrafica() {
         }
     } catch (error) {
         console.error('Errore salvataggio anagrafica:', error);
-        setSaveStatus(`Errore salvataggio: ${error}`, true);
+        // Numero socio preso da un'altra postazione: se ne prenota un altro
+        if (isNuovoSocioMode && error?.kind === 'conflict') {
+            try {
+                await rilasciaPrenotazioneIdsocio();
+                const nuovoId = await prenotaNuovoIdsocio();
+                currentIdsocio = nuovoId;
+                document.getElementById('field-idsocio').value = nuovoId;
+                const subtitle = document.getElementById('socio-subtitle');
+                if (subtitle) subtitle.textContent = `Nuovo socio — ID ${nuovoId}`;
+                setSaveStatus(`${error.message} — nuovo numero ${nuovoId}, premi di nuovo Salva`, true);
+                return;
+            } catch (err) {
+                console.warn('Nuova prenotazione IdSocio:', err);
+            }
+        }
+        // Un altro utente ha cambiato gli stessi campi: i dati restano nel modulo
+        if (error?.code === 'concurrent_update') {
+            versioneAnagrafica = error.current_version || '';
+            setSaveStatus(
+                `${error.message}. Premi di nuovo Salva per sovrascrivere, oppure chiudi e riapri la scheda per vedere le modifiche.`,
+                true
+            );
+            return;
+        }
+        setSaveStatus(`Errore salvataggio: ${error?.message || error}`, true);
     } finally {
         if (btn) btn.disabled = false;
     }
@@ -1914,7 +1970,7 @@ async function saveTesseramento(e) {
         setSaveStatus(`Tesseramento ${payload.anno} salvato`);
     } catch (error) {
         console.error('Errore salvataggio tesseramento:', error);
-        setSaveStatus(`Errore: ${error}`, true);
+        setSaveStatus(`Errore: ${error?.message || error}`, true);
     } finally {
         if (btn) btn.disabled = false;
     }
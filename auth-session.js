@@ -6,11 +6,8 @@ export function leggiSessione() {
         const raw = localStorage.getItem(SESSION_KEY);
         if (!raw) return null;
         const data = JSON.parse(raw);
-        if (!data?.user_id || !data?.access_token) return null;
-        if (data.expires_at && Date.now() > Number(data.expires_at)) {
-            localStorage.removeItem(SESSION_KEY);
-            return null;
-        }
+        // I token restano nel backend Rust, che li rinnova: qui solo utente e permessi
+        if (!data?.user_id) return null;
         return data;
     } catch (_) {
         return null;
@@ -62,6 +59,17 @@ export function puoUsareProgramma(sessione = leggiSessione()) {
     return sessione.is_admin === true || sessione.programma === true;
 }
 
+/**
+ * True se l'utente ha la capacità (es. 'incassi.vedi'). Le capacità sono
+ * calcolate dal backend (ruolo + eccezioni); il backend le ricontrolla comunque.
+ * @param {string} codice
+ */
+export function haCapacita(codice, sessione = leggiSessione()) {
+    if (!sessione) return false;
+    if (sessione.is_admin === true) return true;
+    return Array.isArray(sessione.capacita) && sessione.capacita.includes(codice);
+}
+
 export function vaiAlLogin() {
     window.location.href = 'LOGIN.html';
 }
@@ -70,6 +78,47 @@ export function vaiAllaHome() {
     window.location.href = 'index.html';
 }
 
+async function caricaTauri() {
+    try {
+        const [tauriModule, eventModule] = await Promise.all([
+            import('@tauri-apps/api/tauri'),
+            import('@tauri-apps/api/event')
+        ]);
+        return { invoke: tauriModule.invoke, listen: eventModule.listen };
+    } catch (_) {
+        return null;
+    }
+}
+
+let controlloSessioneAvviato = false;
+
+/**
+ * La sessione vera è nel backend Rust: se il refresh token non vale più
+ * (evento 'auth-session-expired') o il backend non ha nessun utente collegato,
+ * si torna al login.
+ */
+async function controllaSessioneBackend() {
+    if (controlloSessioneAvviato) return;
+    controlloSessioneAvviato = true;
+    const tauri = await caricaTauri();
+    if (!tauri) return;
+
+    await tauri.listen('auth-session-expired', () => {
+        cancellaSessione();
+        vaiAlLogin();
+    });
+
+    try {
+        const attiva = await tauri.invoke('get_auth_session');
+        if (!attiva) {
+            cancellaSessione();
+            vaiAlLogin();
+        }
+    } catch (_) {
+        // Config Supabase non leggibile: ci penseranno i singoli comandi a segnalarlo
+    }
+}
+
 /**
  * Se non c'è sessione valida → LOGIN.
  * Ritorna la sessione oppure null (dopo redirect).
@@ -81,58 +130,43 @@ export function richiediSessione() {
         vaiAlLogin();
         return null;
     }
+    controllaSessioneBackend();
     return sessione;
 }
 
 /**
- * Login email/password via Supabase Auth REST API.
+ * Login email/password: la sessione Supabase Auth la tiene il backend Rust,
+ * che da qui in poi esegue le query col JWT dell'utente.
  * @param {Function} invoke - tauri invoke
  */
 export async function loginConEmailPassword(invoke, email, password) {
     if (!invoke) throw new Error('Tauri non disponibile');
 
     await invoke('init_supabase_from_config').catch(() => {});
-    const authCfg = await invoke('get_supabase_auth_config');
-    const url = String(authCfg?.url || '').replace(/\/$/, '');
-    const anonKey = String(authCfg?.anon_key || '').trim();
-    if (!url || !anonKey) {
-        throw new Error('Config Auth incompleta (url / anon_key)');
-    }
-
-    const risposta = await fetch(`${url}/auth/v1/token?grant_type=password`, {
-        method: 'POST',
-        headers: {
-            apikey: anonKey,
-            Authorization: `Bearer ${anonKey}`,
-            'Content-Type': 'application/json'
-        },
-        body: JSON.stringify({ email: email.trim(), password })
-    });
 
-    const body = await risposta.json().catch(() => ({}));
-    if (!risposta.ok) {
-        const msg = body?.error_description || body?.msg || body?.error || `Login fallito (${risposta.status})`;
-        throw new Error(msg);
+    let info;
+    try {
+        info = await invoke('supabase_login', { email: email.trim(), password });
+    } catch (error) {
+        throw new Error(error?.message || String(error) || 'Login fallito');
     }
 
-    const userId = body?.user?.id || body?.user_id;
-    const userEmail = body?.user?.email || email.trim();
-    const accessToken = body?.access_token;
-    const refreshToken = body?.refresh_token || '';
-    const expiresIn = Number(body?.expires_in) || 3600;
-
-    if (!userId || !accessToken) {
+    const userId = info?.user_id;
+    const userEmail = info?.email || email.trim();
+    if (!userId) {
         throw new Error('Risposta login incompleta da Supabase');
     }
 
     const perm = await invoke('get_user_permissions', { userId, user_id: userId });
     if (!perm) {
+        await invoke('supabase_logout').catch(() => {});
         throw new Error('Utente non presente in user_permissions. Contatta l\'amministratore.');
     }
 
     const isAdminFlag = perm.is_admin === true;
     const programmaFlag = perm.programma === true;
     if (!isAdminFlag && !programmaFlag) {
+        await invoke('supabase_logout').catch(() => {});
         throw new Error('Non hai il permesso Programma per usare questa applicazione.');
     }
 
@@ -140,20 +174,23 @@ export async function loginConEmailPassword(invoke, email, password) {
         user_id: userId,
         email: userEmail,
         username: perm.username || userEmail,
-        access_token: accessToken,
-        refresh_token: refreshToken,
-        expires_at: Date.now() + (expiresIn - 60) * 1000,
         is_admin: isAdminFlag,
         programma: programmaFlag,
         calendario: perm.calendario === true,
-        sidebar_menu: perm.sidebar_menu ?? {}
+        sidebar_menu: perm.sidebar_menu ?? {},
+        ruolo: perm.ruolo || '',
+        capacita: Array.isArray(perm.capacita) ? perm.capacita : []
     };
 
     salvaSessione(sessione);
     return sessione;
 }
 
-export function logout() {
+export async function logout() {
+    const tauri = await caricaTauri();
+    if (tauri) {
+        await tauri.invoke('supabase_logout').catch(() => {});
+    }
     cancellaSessione();
     vaiAlLogin();
 }
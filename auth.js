@@ -57,6 +57,8 @@ const REDIRECT_URI = 'http://localhost:1420'; // Azure AD non accetta path nel r
 let tenantId = '';
 let clientId = '';
 let clientSecret = '';
+// Client Secret presente in config.json: resta nel backend e non serve inserirlo
+let clientSecretInConfig = false;
 
 // Carica configurazione salvata
 async function loadConfig() {
@@ -67,12 +69,11 @@ async function loadConfig() {
             try {
                 console.log('Tentativo di caricare config.json...');
                 const fileConfig = await invoke('load_config_file');
-                console.log('Config ricevuta:', fileConfig);
                 
                 if (fileConfig && fileConfig.sharepoint) {
                     tenantId = fileConfig.sharepoint.tenant_id || '';
                     clientId = fileConfig.sharepoint.client_id || '';
-                    clientSecret = fileConfig.sharepoint.client_secret || '';
+                    clientSecretInConfig = !!fileConfig.sharepoint.client_secret_configurato;
                     
                     console.log('Valori estratti - tenantId:', tenantId ? 'presente' : 'vuoto', 'clientId:', clientId ? 'presente' : 'vuoto');
                     
@@ -388,7 +389,7 @@ document.getElementById('oauth-login-btn').addEventListener('click', async () =>
             
         } catch (error) {
             console.error('Errore nella creazione della finestra OAuth:', error);
-            showError('Errore nell\'apertura della finestra di autenticazione: ' + error);
+            showError('Errore nell\'apertura della finestra di autenticazione: ' + (error?.message || error));
             // Fallback: apri nel browser di sistema
             const { open } = await import('@tauri-apps/api/shell');
             await open(authUrl);
@@ -417,7 +418,7 @@ document.getElementById('oauth-login-btn').addEventListener('click', async () =>
                 clientSecret = document.getElementById('client-secret')?.value.trim() || '';
             }
             
-            if (!clientSecret) {
+            if (!clientSecret && !clientSecretInConfig) {
                 showError('Client Secret richiesto. Inseriscilo nella sezione configurazione avanzata.');
                 showCodeInput(); // Fallback a input manuale
                 return;
@@ -428,7 +429,7 @@ document.getElementById('oauth-login-btn').addEventListener('click', async () =>
                     code: code,
                     tenantId: tenantId,
                     clientId: clientId,
-                    clientSecret: clientSecret,
+                    clientSecret: clientSecret || null,
                     sharepointUrl: SHAREPOINT_URL,
                     redirectUri: REDIRECT_URI
                 });
@@ -450,14 +451,14 @@ document.getElementById('oauth-login-btn').addEventListener('click', async () =>
                 }
             } catch (error) {
                 console.error('Errore completamento autenticazione:', error);
-                showError('Errore: ' + error);
+                showError('Errore: ' + (error?.message || error));
                 showCodeInput(); // Fallback a input manuale
             }
         };
         
         // Funzione per gestire errori OAuth
         const handleOAuthError = async (error) => {
-            showError('Errore autenticazione: ' + error);
+            showError('Errore autenticazione: ' + (error?.message || error));
             try {
                 if (window.oauthAuthWindow) {
                     await window.oauthAuthWindow.close();
@@ -471,7 +472,7 @@ document.getElementById('oauth-login-btn').addEventListener('click', async () =>
         
     } catch (error) {
         console.error('Errore OAuth:', error);
-        showError('Errore nell\'avvio autenticazione: ' + error);
+        showError('Errore nell\'avvio autenticazione: ' + (error?.message || error));
     }
 });
 
@@ -548,7 +549,7 @@ async function completeAuthenticationWithCode(code) {
         clientSecret = document.getElementById('client-secret')?.value.trim() || '';
     }
     
-    if (!clientSecret) {
+    if (!clientSecret && !clientSecretInConfig) {
         showError('Client Secret richiesto. Inseriscilo nella sezione configurazione avanzata.');
         return;
     }
@@ -560,7 +561,7 @@ async function completeAuthenticationWithCode(code) {
             code: code,
             tenantId: tenantId,
             clientId: clientId,
-            clientSecret: clientSecret,
+            clientSecret: clientSecret || null,
             sharepointUrl: SHAREPOINT_URL,
             redirectUri: REDIRECT_URI
         });
@@ -583,7 +584,7 @@ async function completeAuthenticationWithCode(code) {
         }
     } catch (error) {
         console.error('Errore completamento autenticazione:', error);
-        showError('Errore nel completamento autenticazione: ' + error);
+        showError('Errore nel completamento autenticazione: ' + (error?.message || error));
     }
 }
 
@@ -686,7 +687,7 @@ window.addEventListener('DOMContentLoaded', async () => {
     console.log('Errore trovato:', error || 'NO');
     
     if (error) {
-        showError('Errore autenticazione: ' + error);
+        showError('Errore autenticazione: ' + (error?.message || error));
         // Rimuovi il parametro error dalla URL
         window.history.replaceState({}, document.title, window.location.pathname);
         return;
@@ -705,7 +706,7 @@ window.addEventListener('DOMContentLoaded', async () => {
             clientSecret = document.getElementById('client-secret')?.value.trim() || '';
         }
         
-        if (!clientSecret) {
+        if (!clientSecret && !clientSecretInConfig) {
             showError('Client Secret richiesto. Inseriscilo nella sezione configurazione avanzata.');
             showCodeInput();
             const codeInput = document.getElementById('auth-code');
@@ -724,7 +725,7 @@ window.addEventListener('DOMContentLoaded', async () => {
                 code: code,
                 tenantId: tenantId,
                 clientId: clientId,
-                clientSecret: clientSecret,
+                clientSecret: clientSecret || null,
                 sharepointUrl: SHAREPOINT_URL,
                 redirectUri: REDIRECT_URI
             });
@@ -785,7 +786,7 @@ window.addEventListener('DOMContentLoaded', async () => {
             }
         } catch (error) {
             console.error('Errore completamento autenticazione:', error);
-            showError('Errore: ' + error);
+            showError('Errore: ' + (error?.message || error));
             showCodeInput();
         }
     }
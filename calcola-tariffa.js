@@ -1,10 +1,14 @@
 /**
  * Modale Calcola tariffa — condiviso tra Nuovo servizio e Modifica servizio.
- * Totale = (costo_km × chilometri) + pedaggio + extra
- * Campi vuoti contano come 0.
+ * Il totale lo calcola il backend (calcola_preventivo, tariffe.rs):
+ * (costo_km × chilometri) + pedaggio + extra + attesa oltre la franchigia.
+ * Campi vuoti contano come 0; costo al km ed extra predefiniti dalla tariffa in
+ * vigore alla data del servizio (le tariffe sono versionate per data).
+ * Le regole dell'associazione (gratuità, sconti per tipologia socio, quote fisse
+ * dei richiedenti, minimi, supplementi carrozzina) le applica il backend sui
+ * dati del servizio e compaiono sotto il totale.
  */
 
-const DEFAULT_COSTO_KM = 0.7;
 const MARKUP_ID = 'ct-dialog-calcola-tariffa';
 
 const MARKUP = `
@@ -28,10 +32,20 @@ const MARKUP = `
                 <label for="ct-chilometri">CHILOMETRI</label>
                 <input type="text" id="ct-chilometri" class="ns-input" inputmode="decimal" autocomplete="off" placeholder="es. 40">
             </div>
+            <div class="ct-field">
+                <label for="ct-attesa">ATTESA (MINUTI)</label>
+                <input type="text" id="ct-attesa" class="ns-input" inputmode="numeric" autocomplete="off" placeholder="es. 30">
+            </div>
+            <div class="ct-field ct-field-check">
+                <label for="ct-andata-ritorno">
+                    <input type="checkbox" id="ct-andata-ritorno"> ANDATA E RITORNO (KM × 2)
+                </label>
+            </div>
             <div class="ct-field ct-field-totale">
                 <label for="ct-totale">TOTALE</label>
                 <output id="ct-totale" class="ct-totale-valore" for="ct-costo-km ct-pedaggio ct-extra ct-chilometri">0,00 €</output>
             </div>
+            <ul id="ct-regole" class="ct-regole" hidden></ul>
         </div>
         <div class="ns-dialog-actions ct-dialog-actions">
             <button type="button" id="ct-btn-conferma" class="ns-dialog-btn ns-dialog-btn-si">CONFERMA</button>
@@ -42,6 +56,13 @@ const MARKUP = `
 
 let listenersAttivi = false;
 let onConfermaCorrente = null;
+let invokeCorrente = null;
+/** Data del servizio (ISO) con cui scegliere la versione della tariffa */
+let dataPrelievoCorrente = '';
+/** Richiedente, tipo servizio, carrozzina e socio per le regole della tariffa */
+let contestoCorrente = {};
+/** Solo l'ultima richiesta aggiorna il totale mostrato */
+let richiestaLive = 0;
 
 function parseNumeroIt(valore) {
     if (valore === undefined || valore === null) return 0;
@@ -79,31 +100,84 @@ function escapeHtmlCt(str) {
         .replace(/"/g, '&quot;');
 }
 
-function leggiDettaglioCorrente() {
-    const costoKm = parseNumeroIt(document.getElementById('ct-costo-km')?.value);
-    const pedaggio = parseNumeroIt(document.getElementById('ct-pedaggio')?.value);
-    const extra = parseNumeroIt(document.getElementById('ct-extra')?.value);
-    const km = parseNumeroIt(document.getElementById('ct-chilometri')?.value);
-    const costo = costoKm * km;
-    const totale = costo + pedaggio + extra;
+function leggiRichiestaCorrente() {
+    const testo = (id) => String(document.getElementById(id)?.value || '').trim();
+    return {
+        km: testo('ct-chilometri'),
+        costo_km: testo('ct-costo-km'),
+        pedaggio: testo('ct-pedaggio'),
+        extra: testo('ct-extra'),
+        andata_ritorno: Boolean(document.getElementById('ct-andata-ritorno')?.checked),
+        minuti_attesa: parseInt(testo('ct-attesa'), 10) || 0,
+        data_prelievo: dataPrelievoCorrente || null,
+        ...contestoCorrente
+    };
+}
+
+/** Anteprima senza backend (pagina web): stessa formula, senza attesa */
+function preventivoLocale(richiesta) {
+    const costoKm = parseNumeroIt(richiesta.costo_km);
+    const km = parseNumeroIt(richiesta.km) * (richiesta.andata_ritorno ? 2 : 1);
+    const costo = Math.round(costoKm * km * 100) / 100;
+    const totale = costo + parseNumeroIt(richiesta.pedaggio) + parseNumeroIt(richiesta.extra);
+    return { km, costo_km: costoKm, voci: [{ codice: 'km', importo: costo }], totale };
+}
+
+async function calcolaPreventivoCorrente() {
+    const richiesta = leggiRichiestaCorrente();
+    const preventivo = invokeCorrente
+        ? await invokeCorrente('calcola_preventivo', { richiesta })
+        : preventivoLocale(richiesta);
+    return { richiesta, preventivo };
+}
+
+function dettaglioDaPreventivo(richiesta, preventivo) {
+    const voce = (codice) => (preventivo.voci || []).find((v) => v.codice === codice);
+    const km = Number(preventivo.km) || 0;
     return {
-        costo_km: formatNumeroIt(costoKm, 2),
-        pedaggio: formatNumeroIt(pedaggio, 2),
-        extra: formatNumeroIt(extra, 2),
+        costo_km: formatNumeroIt(preventivo.costo_km, 2),
+        pedaggio: formatNumeroIt(parseNumeroIt(richiesta.pedaggio), 2),
+        extra: formatNumeroIt(parseNumeroIt(richiesta.extra), 2),
         chilometri: formatNumeroIt(km, km % 1 === 0 ? 0 : 1),
-        costo: formatNumeroIt(costo, 2),
-        totale: formatEuroIt(totale),
-        totale_numero: totale
+        attesa: String(richiesta.minuti_attesa || ''),
+        andata_ritorno: richiesta.andata_ritorno,
+        costo: formatNumeroIt(voce('km')?.importo || 0, 2),
+        totale: formatEuroIt(preventivo.totale),
+        regole: (preventivo.regole || []).map((r) => r.descrizione),
+        totale_numero: Number(preventivo.totale) || 0
     };
 }
 
-function calcolaTotaleCorrente() {
-    return leggiDettaglioCorrente().totale_numero;
+/** Regole applicate, con l'effetto sul totale */
+function mostraRegole(voci, regole) {
+    const lista = document.getElementById('ct-regole');
+    if (!lista) return;
+    const applicate = (voci || []).filter((v) => String(v.codice).startsWith('regola:'));
+    const soloNota = (regole || []).filter((r) => !applicate.some((v) => v.codice === `regola:${r.codice}`));
+    lista.innerHTML = [
+        ...applicate.map((v) => `<li>${escapeHtmlCt(v.descrizione)} <strong>${formatEuroIt(v.importo)}</strong></li>`),
+        ...soloNota.map((r) => `<li>${escapeHtmlCt(r.descrizione)}</li>`)
+    ].join('');
+    lista.hidden = lista.children.length === 0;
 }
 
-function aggiornaTotaleLive() {
+async function aggiornaTotaleLive() {
     const out = document.getElementById('ct-totale');
-    if (out) out.textContent = formatEuroIt(calcolaTotaleCorrente());
+    if (!out) return;
+    const numero = ++richiestaLive;
+    try {
+        const { preventivo } = await calcolaPreventivoCorrente();
+        if (numero === richiestaLive) {
+            out.textContent = formatEuroIt(preventivo.totale);
+            mostraRegole(preventivo.voci, preventivo.regole);
+        }
+    } catch (err) {
+        if (numero === richiestaLive) {
+            out.textContent = '—';
+            mostraRegole([], []);
+        }
+        console.warn('Calcola tariffa:', err);
+    }
 }
 
 function chiudiModale() {
@@ -120,15 +194,22 @@ function setupListenersUnaVolta() {
     if (!overlay) return;
     listenersAttivi = true;
 
-    ['ct-costo-km', 'ct-pedaggio', 'ct-extra', 'ct-chilometri'].forEach((id) => {
+    ['ct-costo-km', 'ct-pedaggio', 'ct-extra', 'ct-chilometri', 'ct-attesa', 'ct-andata-ritorno'].forEach((id) => {
         document.getElementById(id)?.addEventListener('input', aggiornaTotaleLive);
         document.getElementById(id)?.addEventListener('change', aggiornaTotaleLive);
     });
 
-    document.getElementById('ct-btn-conferma')?.addEventListener('click', (e) => {
+    document.getElementById('ct-btn-conferma')?.addEventListener('click', async (e) => {
         e.preventDefault();
         e.stopPropagation();
-        const dettaglio = leggiDettaglioCorrente();
+        let dettaglio;
+        try {
+            const { richiesta, preventivo } = await calcolaPreventivoCorrente();
+            dettaglio = dettaglioDaPreventivo(richiesta, preventivo);
+        } catch (err) {
+            window.alert('Impossibile calcolare la tariffa: ' + (err?.message || err));
+            return;
+        }
         const cb = onConfermaCorrente;
         chiudiModale();
         if (typeof cb === 'function') {
@@ -167,6 +248,8 @@ export function normalizzaPayloadTariffa(payload) {
         pedaggio: payload.pedaggio ?? '',
         extra: payload.extra ?? '',
         chilometri: payload.chilometri ?? payload.km ?? '',
+        attesa: payload.attesa ?? '',
+        andata_ritorno: Boolean(payload.andata_ritorno),
         costo: payload.costo ?? '',
         totale: payload.totale ?? ''
     };
@@ -177,10 +260,11 @@ export function htmlContenutoRiepilogoTariffa(payload) {
     if (!t) return '';
     const campi = [
         { label: '€/KM', value: t.costo_km },
-        { label: 'KM', value: t.chilometri },
+        { label: 'KM', value: t.andata_ritorno ? `${t.chilometri} (A/R)` : t.chilometri },
         { label: 'COSTO', value: t.costo },
         { label: 'PEDAGGIO', value: t.pedaggio },
         { label: 'EXTRA', value: t.extra },
+        { label: 'ATTESA (MIN)', value: t.attesa },
         { label: 'TOTALE', value: t.totale }
     ];
     return `
@@ -283,29 +367,20 @@ export function leggiTariffaDalDom(hiddenId = 'ns-tariffa-calcolata') {
     }
 }
 
-async function caricaValoriDaImpostazioni(getInvoke, isTauri) {
-    const defaults = { costoKm: DEFAULT_COSTO_KM, extra: 0 };
+function invokeDaOpzioni(getInvoke, isTauri) {
+    if (!isTauri?.() || typeof getInvoke !== 'function') return null;
+    return getInvoke() || null;
+}
+
+async function caricaValoriDaImpostazioni(inv) {
+    const defaults = { costoKm: null, extra: 0 };
     try {
-        if (!isTauri?.() || !getInvoke) return defaults;
-        const inv = typeof getInvoke === 'function' ? getInvoke() : null;
         if (!inv) return defaults;
         await inv('init_supabase_from_config').catch(() => {});
-        const rows = await inv('get_all_impostazioni');
-        if (!Array.isArray(rows)) return defaults;
-
-        const trova = (...nomi) => {
-            const set = new Set(nomi.map((n) => String(n).trim().toLowerCase()));
-            const row = rows.find((r) => set.has(String(r?.impostazione || '').trim().toLowerCase()));
-            if (!row) return null;
-            return parseNumeroIt(row.valore);
-        };
-
-        const costoKmRaw = trova('CostoAlKm', 'Costo al km', 'CostoKm');
-        const extraRaw = trova('Extra', 'ExtraTariffa', 'CostoExtra', 'TariffaExtra');
-
+        const parametri = await inv('get_parametri_tariffa', { data: dataPrelievoCorrente || null });
         return {
-            costoKm: costoKmRaw != null && costoKmRaw > 0 ? costoKmRaw : DEFAULT_COSTO_KM,
-            extra: extraRaw != null ? extraRaw : 0
+            costoKm: Number(parametri?.costo_al_km) || null,
+            extra: Number(parametri?.extra) || 0
         };
     } catch (err) {
         console.warn('Calcola tariffa: impostazioni non disponibili', err);
@@ -317,6 +392,8 @@ async function caricaValoriDaImpostazioni(getInvoke, isTauri) {
  * Apre il modale Calcola tariffa.
  * @param {object} options
  * @param {(totale: number, dettaglio: object) => void} options.onConferma
+ * @param {string} [options.dataPrelievo] data del servizio (vuota = oggi)
+ * @param {{idsocio?: string, richiedente?: string, tipo_servizio?: string, carrozzina?: string}} [options.contesto]
  */
 export async function apriCalcolaTariffa(options = {}) {
     ensureCalcolaTariffaMarkup();
@@ -324,8 +401,11 @@ export async function apriCalcolaTariffa(options = {}) {
     if (!overlay) return;
 
     onConfermaCorrente = typeof options.onConferma === 'function' ? options.onConferma : null;
+    invokeCorrente = invokeDaOpzioni(options.getInvoke, options.isTauri);
+    dataPrelievoCorrente = String(options.dataPrelievo || '').trim();
+    contestoCorrente = options.contesto || {};
 
-    const daImpostazioni = await caricaValoriDaImpostazioni(options.getInvoke, options.isTauri);
+    const daImpostazioni = await caricaValoriDaImpostazioni(invokeCorrente);
 
     let costoKm = options.costoKmIniziale;
     if (costoKm === undefined || costoKm === null || String(costoKm).trim() === '') {
@@ -346,9 +426,12 @@ export async function apriCalcolaTariffa(options = {}) {
         if (el) el.value = v;
     };
 
-    setVal('ct-costo-km', formatNumeroIt(costoKm, 2));
+    setVal('ct-costo-km', costoKm == null ? '' : formatNumeroIt(costoKm, 2));
     setVal('ct-pedaggio', '');
     setVal('ct-extra', formatNumeroIt(extra, 2));
+    setVal('ct-attesa', '');
+    const ar = document.getElementById('ct-andata-ritorno');
+    if (ar) ar.checked = false;
     const kmInit = options.chilometriIniziali;
     setVal(
         'ct-chilometri',
@@ -169,10 +169,7 @@ async function fetchServiziAnno(anno) {
     }
     try {
         await invoke('init_supabase_from_config').catch(() => {});
-        const servizi = await invoke('get_all_servizi_completi', {
-            anno,
-            tuttiAnni: false
-        });
+        const servizi = await invoke('get_all_servizi_completi', { anno });
         serviziPerAnnoCache[anno] = Array.isArray(servizi) ? servizi : [];
         return serviziPerAnnoCache[anno];
     } catch (error) {
@@ -639,9 +636,35 @@ function setupEventListeners() {
     });
 }
 
+// Servizio creato/modificato/eliminato altrove: si ricaricano gli anni visibili
+let timerAggiornamentoTempoReale = null;
+
+async function setupAggiornamentiTempoReale() {
+    if (!isTauri()) return;
+    try {
+        const { listen } = await import('@tauri-apps/api/event');
+        await listen('db-change', (event) => {
+            const tabella = event.payload?.tabella;
+            if (tabella !== 'servizi' && tabella !== 'automezzi') return;
+            clearTimeout(timerAggiornamentoTempoReale);
+            timerAggiornamentoTempoReale = setTimeout(async () => {
+                if (tabella === 'automezzi') {
+                    await caricaAutomezzi();
+                }
+                Object.keys(serviziPerAnnoCache).forEach(anno => delete serviziPerAnnoCache[anno]);
+                serviziById.clear();
+                await aggiornaEventiCalendario();
+            }, 400);
+        });
+    } catch (err) {
+        console.warn('Listener aggiornamenti in tempo reale:', err);
+    }
+}
+
 document.addEventListener('DOMContentLoaded', async () => {
     await initTauri();
     setupEventListeners();
     initCalendario();
     await caricaAutomezzi();
+    await setupAggiornamentiTempoReale();
 });
@@ -6,7 +6,9 @@ import {
     costruisciFormServizio,
     setupFormServizioListeners,
     raccogliPayloadServizio,
-    aggiornaEtichetteAudit
+    aggiornaEtichetteAudit,
+    isModificaConcorrente,
+    messaggioModificaConcorrente
 } from './modifica-servizio.js';
 
 let getInvokeFn = () => null;
@@ -146,7 +148,8 @@ export async function apriModalCompleta(servizioId) {
         idPrefix: 'comp',
         chiusuraPrima: true,
         mostraArchivia: false,
-        pagamentoSecondo: true
+        pagamentoSecondo: true,
+        orariViaggio: true
     });
     setupFormServizioListeners('comp');
     modal.style.display = 'flex';
@@ -165,6 +168,7 @@ async function salvaCompletaServizio() {
     payload.archivia = ['true', 'si', 'sì', '1', 'yes'].includes(String(archiviaOrig || '').trim().toLowerCase())
         ? 'SI'
         : 'NO';
+    payload.versione = servizioInCompletamento?.versione || null;
 
     const btnSalva = document.getElementById('btn-salva-completa');
     if (btnSalva) btnSalva.disabled = true;
@@ -173,16 +177,43 @@ async function salvaCompletaServizio() {
         const invoke = getInvokeFn();
         if (isTauriEnv() && invoke) {
             await invoke('init_supabase_from_config').catch(() => {});
-            await invoke('update_servizio_completo', { payload });
+            const valore = (id) => document.getElementById(id)?.value.trim() || '';
+            // Km, tempo, importo e stato li calcola e li valida il backend
+            const esito = await invoke('completa_servizio', {
+                payload: {
+                    id: payload.id,
+                    ora_partenza: valore('comp-ora-partenza'),
+                    ora_rientro: valore('comp-ora-rientro'),
+                    km_uscita: payload.km_uscita,
+                    km_rientro: payload.km_rientro,
+                    operatore: payload.operatore,
+                    operatore_2: payload.operatore_2,
+                    note_fine_servizio: payload.note_fine_servizio,
+                    stato_servizio: payload.stato_servizio,
+                    incassato: String(payload.stato_incasso || '').toUpperCase() === 'INCASSATO',
+                    servizio: payload,
+                    versione: payload.versione
+                }
+            });
             const aggiornato = await invoke('get_servizio_completo', { servizioId: payload.id });
             await onSaveSuccess(aggiornato, payload);
             chiudiModalCompleta();
+            if (esito?.tariffa_su) {
+                window.alert(`La tariffa del viaggio è sul servizio ${esito.tariffa_su} (prima tappa): questo servizio non ha importo.`);
+            } else if (esito?.importo_non_calcolato) {
+                window.alert(`${esito.importo_non_calcolato}. Inserisci l'importo dalla modifica del servizio.`);
+            }
         } else {
             await onSaveSuccess({ ...servizioInCompletamento, ...payload, id: String(payload.id) }, payload);
             chiudiModalCompleta();
         }
     } catch (error) {
         console.error('Errore salvataggio completa servizio:', error);
+        if (isModificaConcorrente(error) && servizioInCompletamento) {
+            servizioInCompletamento.versione = error.current_version || '';
+            window.alert(messaggioModificaConcorrente(error));
+            return;
+        }
         mostraErroreCompleta('Errore nel salvataggio: ' + (error.message || error));
     } finally {
         if (btnSalva) btnSalva.disabled = false;
@@ -0,0 +1,42 @@
+-- Registro modifiche (Audit_supa) — esegui nel SQL Editor di Supabase (una volta sola).
+-- L'app aggiunge una riga per ogni creazione, modifica o eliminazione di servizi,
+-- soci, automezzi, tratte e impostazioni. Le righe non si modificano né si
+-- cancellano: ci sono solo policy di lettura e inserimento.
+-- Richiede public.utente_app_desktop() (supabase-policy-app-desktop.sql).
+
+CREATE TABLE IF NOT EXISTS public."Audit_supa" (
+  -- Generata dall'app: il rinvio di una voce fatta offline non la duplica
+  chiave text PRIMARY KEY,
+  quando timestamptz NOT NULL DEFAULT now(),
+  utente_id text,
+  utente text,
+  -- servizi, tesserati, automezzi, tratte, impostazioni
+  entita text NOT NULL,
+  record_id text NOT NULL,
+  -- insert / update / delete
+  azione text NOT NULL,
+  -- [{"campo": "Motivazione", "prima": "...", "dopo": "..."}]
+  modifiche jsonb NOT NULL DEFAULT '[]'::jsonb
+);
+
+CREATE INDEX IF NOT EXISTS audit_supa_record
+  ON public."Audit_supa" (entita, record_id, quando DESC);
+
+ALTER TABLE public."Audit_supa" ENABLE ROW LEVEL SECURITY;
+
+DROP POLICY IF EXISTS "audit_lettura" ON public."Audit_supa";
+CREATE POLICY "audit_lettura"
+ON public."Audit_supa"
+FOR SELECT
+TO authenticated
+USING (public.utente_app_desktop());
+
+-- Si inserisce solo a proprio nome
+DROP POLICY IF EXISTS "audit_inserimento" ON public."Audit_supa";
+CREATE POLICY "audit_inserimento"
+ON public."Audit_supa"
+FOR INSERT
+TO authenticated
+WITH CHECK (public.utente_app_desktop() AND utente_id = auth.uid()::text);
+
+REVOKE UPDATE, DELETE ON public."Audit_supa" FROM authenticated, anon;
@@ -0,0 +1,33 @@
+-- Cestino dei servizi — esegui nel SQL Editor di Supabase (una volta sola).
+-- delete_servizio copia qui la riga (con chi, quando e perché) prima di
+-- eliminarla da Servizi_supa; dal cestino si ripristina. L'eliminazione
+-- definitiva la fa solo un amministratore, dopo i giorni di conservazione
+-- (impostazione GiorniCestino, default 30).
+-- Richiede public.utente_app_desktop() (supabase-policy-app-desktop.sql).
+
+CREATE TABLE IF NOT EXISTS public."CestinoServizi_supa" (
+  idservizio bigint PRIMARY KEY,
+  -- Riga completa di Servizi_supa com'era al momento dell'eliminazione
+  riga jsonb NOT NULL,
+  eliminato_da text,
+  eliminato_da_id text,
+  eliminato_il timestamptz NOT NULL DEFAULT now(),
+  motivo text NOT NULL
+);
+
+ALTER TABLE public."CestinoServizi_supa" ENABLE ROW LEVEL SECURITY;
+
+DROP POLICY IF EXISTS "operatori_app_desktop" ON public."CestinoServizi_supa";
+CREATE POLICY "operatori_app_desktop"
+ON public."CestinoServizi_supa"
+FOR ALL
+TO authenticated
+USING (public.utente_app_desktop())
+WITH CHECK (public.utente_app_desktop());
+
+-- Giorni di conservazione nel cestino
+INSERT INTO public."Impostazioni_supa" ("Impostazione", "Valore")
+SELECT 'GiorniCestino', '30'
+WHERE NOT EXISTS (
+  SELECT 1 FROM public."Impostazioni_supa" WHERE "Impostazione" = 'GiorniCestino'
+);
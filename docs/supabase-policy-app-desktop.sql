@@ -0,0 +1,65 @@
+-- App desktop: policy RLS per gli utenti collegati — esegui nel SQL Editor di Supabase
+-- L'app ora esegue le query col JWT dell'utente (login Supabase Auth), non più con
+-- la secret_key. In config.json servono publishable_key (o anon_key) per tutte le
+-- richieste; secret_key resta facoltativa e serve solo alla pagina Gestione utenti
+-- (elenco di tutti i permessi, creazione utenti).
+--
+-- Regola: legge e scrive chi ha "Programma" o is_admin in user_permissions.
+-- Le policy del calendario web (supabase-calendario-web.sql) restano valide.
+
+-- 1) Funzione di controllo (SECURITY DEFINER: legge user_permissions senza RLS)
+CREATE OR REPLACE FUNCTION public.utente_app_desktop()
+RETURNS boolean
+LANGUAGE sql
+STABLE
+SECURITY DEFINER
+SET search_path = public
+AS $$
+  SELECT EXISTS (
+    SELECT 1 FROM public.user_permissions up
+    WHERE up.user_id = auth.uid()
+    AND (up.is_admin = true OR up."Programma" = true)
+  );
+$$;
+
+-- 2) Una policy "operatori_app_desktop" per ogni tabella usata dall'app
+DO $$
+DECLARE
+  tabella text;
+BEGIN
+  FOREACH tabella IN ARRAY ARRAY[
+    'Servizi_supa',
+    'Automezzi_Supa',
+    'tesserati_supa',
+    'Tesseramenti_supa',
+    'DotazioniMezzi_supa',
+    'Impostazioni_supa',
+    'Motivazioni_trasporto_supa',
+    'Motorizzazioni_supa',
+    'Richiedenti_supa',
+    'StatoDelServizio_supa',
+    'Telefoni_supa',
+    'Email_supa',
+    'TipoPagamenti_supa',
+    'TipoSocio_supa',
+    'Tratte_supa'
+  ]
+  LOOP
+    EXECUTE format('ALTER TABLE public.%I ENABLE ROW LEVEL SECURITY', tabella);
+    EXECUTE format('DROP POLICY IF EXISTS "operatori_app_desktop" ON public.%I', tabella);
+    EXECUTE format(
+      'CREATE POLICY "operatori_app_desktop" ON public.%I FOR ALL TO authenticated '
+      'USING (public.utente_app_desktop()) WITH CHECK (public.utente_app_desktop())',
+      tabella
+    );
+  END LOOP;
+END $$;
+
+-- 3) Permessi: ogni utente legge la propria riga (già in supabase-calendario-web.sql);
+-- l'elenco completo e le modifiche passano dalla secret_key (solo admin).
+DROP POLICY IF EXISTS "utente_legge_propri_permessi" ON public.user_permissions;
+CREATE POLICY "utente_legge_propri_permessi"
+ON public.user_permissions
+FOR SELECT
+TO authenticated
+USING (user_id = auth.uid());
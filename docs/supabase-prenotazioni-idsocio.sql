@@ -0,0 +1,23 @@
+-- Esegui questo blocco nel SQL Editor di Supabase (una volta sola).
+-- Prenotazioni dei nuovi IdSocio: quando una postazione apre "Nuovo socio" il
+-- numero viene riservato per 30 minuti. La chiave primaria impedisce che due
+-- postazioni ottengano lo stesso numero. Senza questa tabella l'app propone
+-- max+1 senza prenotazione.
+
+CREATE TABLE IF NOT EXISTS public."IdSocio_prenotazioni_supa" (
+  "IdSocio" bigint PRIMARY KEY,
+  token text NOT NULL UNIQUE,
+  prenotato_da text,
+  scade_il timestamptz NOT NULL
+);
+
+ALTER TABLE public."IdSocio_prenotazioni_supa" ENABLE ROW LEVEL SECURITY;
+
+DROP POLICY IF EXISTS "utenti_gestiscono_prenotazioni_idsocio" ON public."IdSocio_prenotazioni_supa";
+
+CREATE POLICY "utenti_gestiscono_prenotazioni_idsocio"
+ON public."IdSocio_prenotazioni_supa"
+FOR ALL
+TO authenticated
+USING (true)
+WITH CHECK (true);
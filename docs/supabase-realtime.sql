@@ -0,0 +1,12 @@
+-- Esegui questo blocco nel SQL Editor di Supabase (una volta sola).
+-- Pubblica su Realtime le tabelle seguite dall'app desktop: le modifiche fatte
+-- da una postazione arrivano subito a Calendario, Elenco Servizi e home delle altre.
+-- Se i nomi tabella in config.json sono diversi, adattali qui.
+
+ALTER PUBLICATION supabase_realtime ADD TABLE public."Servizi_supa";
+ALTER PUBLICATION supabase_realtime ADD TABLE public."tesserati_supa";
+ALTER PUBLICATION supabase_realtime ADD TABLE public."Automezzi_Supa";
+
+-- Facoltativo: con REPLICA IDENTITY FULL le eliminazioni riportano la riga intera
+-- (senza, solo la chiave primaria, che all'app basta).
+-- ALTER TABLE public."Servizi_supa" REPLICA IDENTITY FULL;
@@ -0,0 +1,42 @@
+-- Regole della tariffa — esegui nel SQL Editor di Supabase (una volta sola).
+-- Il backend (regole_tariffa.rs) le applica al preventivo base (km, pedaggio,
+-- extra, attesa) e mostra ogni regola applicata fra le voci.
+--   tipo: quota_fissa | supplemento | sconto | minimo | gratuito | rimborso
+--   valore: euro (quota fissa, supplemento, minimo) o percentuale (sconto, rimborso)
+--   filtri tipologia_socio / richiedente / tipo_servizio / carrozzina:
+--     vuoto = qualsiasi, '*' = valorizzato, più valori separati da virgola
+-- Richiede public.utente_app_desktop() (supabase-policy-app-desktop.sql).
+
+CREATE TABLE IF NOT EXISTS public."RegoleTariffa_supa" (
+  codice text PRIMARY KEY,
+  descrizione text NOT NULL,
+  tipo text NOT NULL CHECK (tipo IN ('quota_fissa', 'supplemento', 'sconto', 'minimo', 'gratuito', 'rimborso')),
+  valore numeric NOT NULL DEFAULT 0 CHECK (valore >= 0),
+  tipologia_socio text NOT NULL DEFAULT '',
+  richiedente text NOT NULL DEFAULT '',
+  tipo_servizio text NOT NULL DEFAULT '',
+  carrozzina text NOT NULL DEFAULT '',
+  priorita integer NOT NULL DEFAULT 0,
+  attiva boolean NOT NULL DEFAULT true
+);
+
+ALTER TABLE public."RegoleTariffa_supa" ENABLE ROW LEVEL SECURITY;
+
+DROP POLICY IF EXISTS "operatori_app_desktop" ON public."RegoleTariffa_supa";
+CREATE POLICY "operatori_app_desktop"
+ON public."RegoleTariffa_supa"
+FOR ALL
+TO authenticated
+USING (public.utente_app_desktop())
+WITH CHECK (public.utente_app_desktop());
+
+-- Le due condizioni già in uso (come nel riepilogo chilometri); supplemento
+-- carrozzina e minimo sono esempi da attivare dopo averne scelto l'importo
+INSERT INTO public."RegoleTariffa_supa"
+  (codice, descrizione, tipo, valore, richiedente, carrozzina, attiva)
+VALUES
+  ('auser_gratis', 'Viaggio gratuito AUSER', 'gratuito', 0, 'AUSER GRATIS', '', true),
+  ('auser_rimborso', 'Viaggio rimborsato', 'rimborso', 100, 'AUSER RIMBORSO', '', true),
+  ('supplemento_carrozzina', 'Supplemento carrozzina', 'supplemento', 0, '', 'AUSER', false),
+  ('minimo_viaggio', 'Importo minimo', 'minimo', 0, '', '', false)
+ON CONFLICT (codice) DO NOTHING;
@@ -0,0 +1,38 @@
+-- Ruoli e capacità — esegui nel SQL Editor di Supabase (una volta sola).
+-- L'app ha già i ruoli predefiniti (operatore, centralinista, segreteria,
+-- tesoriere, amministratore). Questa tabella serve per crearne di nuovi o per
+-- ridefinire un predefinito con lo stesso codice; senza tabella valgono solo i
+-- predefiniti.
+
+CREATE TABLE IF NOT EXISTS public."Ruoli_supa" (
+  codice text PRIMARY KEY,
+  nome text NOT NULL,
+  -- Array di codici capacità, es. ["servizi.leggi", "incassi.vedi"]
+  capacita jsonb NOT NULL DEFAULT '[]'::jsonb
+);
+
+ALTER TABLE public."Ruoli_supa" ENABLE ROW LEVEL SECURITY;
+
+-- Lettura per tutti gli utenti collegati (servono a calcolare i propri permessi);
+-- le modifiche passano dalla secret_key (pagina Gestione utenti).
+DROP POLICY IF EXISTS "utenti_leggono_ruoli" ON public."Ruoli_supa";
+CREATE POLICY "utenti_leggono_ruoli"
+ON public."Ruoli_supa"
+FOR SELECT
+TO authenticated
+USING (true);
+
+-- Ruolo assegnato ed eccezioni per utente ({"incassi.vedi": true})
+ALTER TABLE public.user_permissions ADD COLUMN IF NOT EXISTS "Ruolo" text;
+ALTER TABLE public.user_permissions ADD COLUMN IF NOT EXISTS "CapacitaExtra" text;
+
+-- Esempio: tesoriere che vede anche l'elenco operatori ma non modifica i soci
+--
+-- UPDATE public.user_permissions
+-- SET "Ruolo" = 'tesoriere',
+--     "CapacitaExtra" = '{"soci.modifica": false}'
+-- WHERE username = 'mario.rossi';
+
+-- Operatore collegato all'utente: con la capacità "servizi.solo_propri" (ruolo
+-- operatore) vede solo i servizi in cui compare come operatore o secondo operatore
+ALTER TABLE public.user_permissions ADD COLUMN IF NOT EXISTS "IdSocio" text;
@@ -0,0 +1,34 @@
+-- Serie di servizi ricorrenti — esegui nel SQL Editor di Supabase (una volta sola).
+-- crea_serie_servizi copia un servizio modello per ogni data della regola
+-- (settimanale o ogni N giorni, festività saltate) e scrive lo stesso IdSerie
+-- sul modello e sulle copie. Qui resta la regola usata e l'eventuale
+-- annullamento della serie.
+-- Richiede public.utente_app_desktop() (supabase-policy-app-desktop.sql).
+
+ALTER TABLE public."Servizi_supa"
+  ADD COLUMN IF NOT EXISTS "IdSerie" text;
+
+CREATE INDEX IF NOT EXISTS "Servizi_supa_IdSerie_idx"
+  ON public."Servizi_supa" ("IdSerie")
+  WHERE "IdSerie" IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS public."SerieServizi_supa" (
+  id_serie text PRIMARY KEY,
+  -- {"tipo": "settimanale", "giorni": [1, 3, 5], "dal": "...", "fino_al": "...", ...}
+  regola jsonb,
+  servizio_modello bigint,
+  creato_da text,
+  creato_il timestamptz NOT NULL DEFAULT now(),
+  annullata_il timestamptz,
+  motivo_annullamento text
+);
+
+ALTER TABLE public."SerieServizi_supa" ENABLE ROW LEVEL SECURITY;
+
+DROP POLICY IF EXISTS "operatori_app_desktop" ON public."SerieServizi_supa";
+CREATE POLICY "operatori_app_desktop"
+ON public."SerieServizi_supa"
+FOR ALL
+TO authenticated
+USING (public.utente_app_desktop())
+WITH CHECK (public.utente_app_desktop());
@@ -0,0 +1,44 @@
+-- Esegui questo blocco nel SQL Editor di Supabase (una volta sola).
+-- Sequenza per i nuovi idservizio di Servizi_supa: due postazioni che salvano
+-- nello stesso istante ricevono ID diversi. Senza questa funzione l'app usa
+-- max+1 e, se l'ID è già stato preso, riprova con il successivo.
+-- Gli ID dei servizi nel cestino (CestinoServizi_supa) restano riservati,
+-- altrimenti un ripristino troverebbe l'idservizio già occupato.
+-- Eseguire dopo supabase-cestino-servizi.sql.
+
+CREATE SEQUENCE IF NOT EXISTS public.servizi_idservizio_seq;
+
+SELECT setval(
+  'public.servizi_idservizio_seq',
+  GREATEST(
+    (SELECT COALESCE(MAX(idservizio), 0) FROM public."Servizi_supa"),
+    (SELECT COALESCE(MAX(idservizio), 0) FROM public."CestinoServizi_supa"),
+    1
+  )
+);
+
+CREATE OR REPLACE FUNCTION public.next_servizio_id()
+RETURNS bigint
+LANGUAGE plpgsql
+SECURITY DEFINER
+SET search_path = public
+AS $$
+DECLARE
+  nuovo bigint;
+  massimo bigint;
+BEGIN
+  nuovo := nextval('public.servizi_idservizio_seq');
+  SELECT GREATEST(
+    (SELECT COALESCE(MAX(idservizio), 0) FROM public."Servizi_supa"),
+    (SELECT COALESCE(MAX(idservizio), 0) FROM public."CestinoServizi_supa")
+  ) INTO massimo;
+  -- Servizi inseriti a mano con ID più alti: la sequenza si riallinea
+  IF nuovo <= massimo THEN
+    PERFORM setval('public.servizi_idservizio_seq', massimo);
+    nuovo := nextval('public.servizi_idservizio_seq');
+  END IF;
+  RETURN nuovo;
+END;
+$$;
+
+GRANT EXECUTE ON FUNCTION public.next_servizio_id() TO authenticated, service_role;
@@ -0,0 +1,24 @@
+-- Ciclo di vita del servizio — esegui nel SQL Editor di Supabase (una volta sola).
+-- richiesto → confermato → assegnato → in corso → completato, oppure annullato /
+-- non presentato. I passaggi e i campi obbligatori li controlla l'app
+-- (stato_servizio.rs); qui si aggiungono gli stati al menu e la colonna del
+-- motivo, obbligatorio per ANNULLATO. I valori storici (DA ESEGUIRE, ESEGUITO)
+-- restano validi.
+
+ALTER TABLE public."Servizi_supa"
+  ADD COLUMN IF NOT EXISTS "MotivoAnnullamento" text;
+
+INSERT INTO public."StatoDelServizio_supa" ("StatoServizio")
+SELECT s.stato
+FROM (VALUES
+  ('RICHIESTO'),
+  ('CONFERMATO'),
+  ('ASSEGNATO'),
+  ('IN CORSO'),
+  ('COMPLETATO'),
+  ('ANNULLATO'),
+  ('NON PRESENTATO')
+) AS s(stato)
+WHERE NOT EXISTS (
+  SELECT 1 FROM public."StatoDelServizio_supa" t WHERE t."StatoServizio" = s.stato
+);
@@ -0,0 +1,45 @@
+-- Storico delle tariffe — esegui nel SQL Editor di Supabase (una volta sola).
+-- Ogni riga è una versione valida da valido_dal fino al giorno prima della
+-- versione successiva; un servizio si prezza con quella in vigore alla sua data
+-- di prelievo (tariffe.rs). Cambiare CostoAlKm & co. nelle impostazioni aggiunge
+-- una versione da oggi invece di riscrivere Tratta_TariffaKm su tutte le tratte.
+-- Richiede public.utente_app_desktop() (supabase-policy-app-desktop.sql).
+
+CREATE TABLE IF NOT EXISTS public."Tariffe_supa" (
+  valido_dal date PRIMARY KEY,
+  costo_al_km numeric NOT NULL CHECK (costo_al_km > 0),
+  extra numeric NOT NULL DEFAULT 0,
+  costo_attesa_ora numeric NOT NULL DEFAULT 0,
+  attesa_gratuita_minuti integer NOT NULL DEFAULT 0,
+  creato_da text,
+  creato_il timestamptz NOT NULL DEFAULT now()
+);
+
+ALTER TABLE public."Tariffe_supa" ENABLE ROW LEVEL SECURITY;
+
+DROP POLICY IF EXISTS "operatori_app_desktop" ON public."Tariffe_supa";
+CREATE POLICY "operatori_app_desktop"
+ON public."Tariffe_supa"
+FOR ALL
+TO authenticated
+USING (public.utente_app_desktop())
+WITH CHECK (public.utente_app_desktop());
+
+-- Prima versione: la tariffa attuale delle impostazioni, valida per tutto lo storico
+INSERT INTO public."Tariffe_supa" (valido_dal, costo_al_km, creato_da)
+SELECT DATE '2000-01-01',
+       COALESCE(
+         (SELECT NULLIF(replace(trim("Valore"), ',', '.'), '')::numeric
+            FROM public."Impostazioni_supa"
+           WHERE "Impostazione" = 'CostoAlKm'
+           LIMIT 1),
+         0.70
+       ),
+       'migrazione'
+WHERE NOT EXISTS (SELECT 1 FROM public."Tariffe_supa");
+
+-- Tariffa propria di una tratta (facoltativa). Tratta_TariffaKm conteneva la
+-- copia del CostoAlKm globale e non si usa più per il prezzo: una tratta con un
+-- costo al km diverso da quello in vigore lo scrive qui (NULL = tariffa in vigore).
+ALTER TABLE public."Tratte_supa"
+  ADD COLUMN IF NOT EXISTS "Tratta_TariffaKmSpeciale" numeric CHECK ("Tratta_TariffaKmSpeciale" > 0);
@@ -0,0 +1,13 @@
+-- Viaggi collegati (andata e ritorno, più tappe) — esegui nel SQL Editor di
+-- Supabase (una volta sola). I servizi dello stesso viaggio hanno lo stesso
+-- IdViaggio e Tappa = 1, 2, … per data e ora. crea_ritorno_servizio crea il
+-- ritorno con partenza e destinazione invertite; la tariffa del viaggio si
+-- calcola una volta sola, sulla prima tappa, alla chiusura.
+
+ALTER TABLE public."Servizi_supa"
+  ADD COLUMN IF NOT EXISTS "IdViaggio" text,
+  ADD COLUMN IF NOT EXISTS "Tappa" integer;
+
+CREATE INDEX IF NOT EXISTS "Servizi_supa_IdViaggio_idx"
+  ON public."Servizi_supa" ("IdViaggio")
+  WHERE "IdViaggio" IS NOT NULL;
@@ -241,7 +241,7 @@ async function ensureDotazioneInTable(dotazione, block = null) {
         } catch (error) {
             console.error('Errore aggiunta dotazione in Supabase:', error);
             if (block) {
-                setBlockStatus(block, `Dotazione non salvata in elenco: ${error}`, true);
+                setBlockStatus(block, `Dotazione non salvata in elenco: ${error?.message || error}`, true);
             }
         }
     } else {
@@ -523,6 +523,9 @@ async function saveMezzoBlock(block) {
         setBlockStatus(block, 'N. mezzo obbligatorio', true);
         return;
     }
+    // Versione caricata: il salvataggio fallisce se un altro utente ha cambiato gli stessi campi
+    const mezzoCaricato = allMezzi.find(m => String(m.id) === String(payload.id));
+    payload.versione = mezzoCaricato?.versione || '';
 
     const btnSalva = block.querySelector('.btn-mezzo-salva');
     if (btnSalva) btnSalva.disabled = true;
@@ -530,7 +533,8 @@ async function saveMezzoBlock(block) {
     try {
         if (isTauri() && invoke) {
             await invoke('init_supabase_from_config').catch(() => {});
-            await invoke('save_automezzo', { automezzo: payload });
+            const salvato = await invoke('save_automezzo', { automezzo: payload });
+            payload.versione = salvato?.versione || '';
         }
 
         updateMezzoInCache(payload);
@@ -543,7 +547,17 @@ async function saveMezzoBlock(block) {
         setBlockStatus(block, 'Salvato');
     } catch (error) {
         console.error('Errore salvataggio mezzo:', error);
-        setBlockStatus(block, `Errore: ${error}`, true);
+        if (error?.code === 'concurrent_update') {
+            // I dati restano nel blocco: un secondo Salva sovrascrive
+            updateMezzoInCache({ id: payload.id, versione: error.current_version || '' });
+            setBlockStatus(
+                block,
+                `${error.message}. Premi di nuovo Salva per sovrascrivere, oppure Annulla e ricarica la pagina per vedere le modifiche.`,
+                true
+            );
+            return;
+        }
+        setBlockStatus(block, `Errore: ${error?.message || error}`, true);
     } finally {
         if (btnSalva) btnSalva.disabled = false;
     }
@@ -652,7 +666,7 @@ async function saveNuovoMezzoModal() {
         refreshMezziListView();
     } catch (error) {
         console.error('Errore creazione mezzo:', error);
-        setModalStatus(`Errore: ${error}`, true);
+        setModalStatus(`Errore: ${error?.message || error}`, true);
     } finally {
         if (btnSalva) btnSalva.disabled = false;
     }
@@ -871,11 +885,33 @@ function hideSearch() {
     }
 }
 
+// Mezzo modificato da un'altra finestra o postazione: si ricarica la lista,
+// ma non mentre l'utente sta modificando un mezzo
+async function setupAggiornamentiTempoReale() {
+    if (!isTauri()) return;
+    try {
+        const { listen } = await import('@tauri-apps/api/event');
+        await listen('db-change', async (event) => {
+            if (event.payload?.tabella !== 'automezzi' || editingMezzoId != null) return;
+            try {
+                const mezzi = await invoke('get_all_automezzi');
+                allMezzi = Array.isArray(mezzi) ? sortByNumeroMezzo(mezzi) : [];
+                refreshMezziListView();
+            } catch (err) {
+                console.warn('Aggiornamento mezzi in tempo reale:', err);
+            }
+        });
+    } catch (err) {
+        console.warn('Listener aggiornamenti in tempo reale:', err);
+    }
+}
+
 document.addEventListener('DOMContentLoaded', async () => {
     bindMezzoContainerEvents();
     bindNuovoMezzoModalEvents();
     await initTauri();
     await loadAllMezzi();
+    await setupAggiornamentiTempoReale();
 
     document.getElementById('btn-show-search')?.addEventListener('click', showSearch);
     document.getElementById('btn-hide-search')?.addEventListener('click', hideSearch);
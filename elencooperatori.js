@@ -362,13 +362,14 @@ async function loadAllOperatori() {
     }
     
     try {
-        console.log('Chiamata a get_all_tesserati (Supabase)...');
+        console.log('Chiamata a get_elenco_operatori (Supabase)...');
         try {
             await invoke('init_supabase_from_config');
         } catch (initErr) {
             console.warn('Init Supabase:', initErr);
         }
-        const tesserati = await invoke('get_all_tesserati');
+        // Solo i tesserati operatori, con il permesso "operatori.vedi"
+        const tesserati = await invoke('get_elenco_operatori');
         console.log('Risposta ricevuta:', tesserati);
         console.log(`Tipo: ${typeof tesserati}, È array: ${Array.isArray(tesserati)}`);
         console.log(`Numero tesserati totali: ${tesserati ? tesserati.length : 'null/undefined'}`);
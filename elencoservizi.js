@@ -12,6 +12,7 @@ import {
 } from './completa-servizio.js';
 import { testoNoteFineVisibile, parseTrattaDaNote, htmlContenutoRiepilogoTratta, normalizzaPayloadTratta } from './tratta-riepilogo.js';
 import { parseTariffaDaNote, htmlContenutoRiepilogoTariffa } from './calcola-tariffa.js';
+import { normalizzaStatoServizio } from './stato-servizio.js';
 
 function escapeHtmlElenco(str) {
     if (str === undefined || str === null) return '';
@@ -602,33 +603,117 @@ function getServiziListaBaseForModo(modo) {
     return [...raw];
 }
 
-async function fetchServiziFromBackend(modo) {
+// Revisione della cache backend a cui è allineato serviziAnnoCache[modo]
+const serviziAnnoRevisione = {
+    corrente: null,
+    precedente: null
+};
+
+function annoDelModo(modo) {
+    return modo === 'precedente' ? getAnnoPrecedente() : getAnnoCorrente();
+}
+
+// Tutti gli anni: archivio a pagine (cursore su idservizio), senza cache delta nel backend
+async function caricaServiziTuttiGliAnni() {
+    const lista = [];
+    let dopo = null;
+    do {
+        const pagina = await invoke('get_servizi_completi_pagina', { dopo, limite: 1000 });
+        lista.push(...(Array.isArray(pagina?.servizi) ? pagina.servizi : []));
+        dopo = pagina?.prossimo ?? null;
+    } while (dopo !== null);
+    ordinaServiziComeBackend(lista);
+    return lista;
+}
+
+// Stesso ordine del backend (sort_servizi_completi): data e ora di prelievo decrescenti
+function chiaveOrdinamentoServizio(s) {
+    const [gg, mm, aaaa] = String(s.data_prelievo || '').split('/');
+    const data = aaaa && mm && gg ? `${aaaa}${mm.padStart(2, '0')}${gg.padStart(2, '0')}` : '';
+    const ora = String(s.ora_inizio || '').slice(0, 5).padStart(5, '0');
+    return `${data}${ora}`;
+}
+
+function ordinaServiziComeBackend(lista) {
+    lista.sort((a, b) => {
+        const ka = chiaveOrdinamentoServizio(a);
+        const kb = chiaveOrdinamentoServizio(b);
+        if (!ka || !kb) return (ka ? -1 : 0) + (kb ? 1 : 0);
+        return kb.localeCompare(ka);
+    });
+}
+
+async function getServiziForModo(modo) {
     if (!isTauri() || !invoke) {
-        return serviziOriginali.length ? [...serviziOriginali] : [];
+        if (!serviziAnnoCache[modo]) {
+            serviziAnnoCache[modo] = serviziOriginali.length ? [...serviziOriginali] : [];
+        }
+        return [...serviziAnnoCache[modo]];
     }
 
     if (modo === 'tutti') {
-        return await invoke('get_all_servizi_completi', { anno: null, tuttiAnni: true });
+        serviziAnnoCache.tutti = await caricaServiziTuttiGliAnni();
+        return [...serviziAnnoCache.tutti];
     }
-    if (modo === 'precedente') {
-        return await invoke('get_all_servizi_completi', {
-            anno: getAnnoPrecedente(),
-            tuttiAnni: false
-        });
-    }
-    return await invoke('get_all_servizi_completi', {
-        anno: getAnnoCorrente(),
-        tuttiAnni: false
+
+    // Solo le modifiche dall'ultima lettura: il backend tiene la cache dell'anno
+    const modifiche = await invoke('get_changes_since', {
+        tabella: 'servizi',
+        dal: serviziAnnoCache[modo] ? serviziAnnoRevisione[modo] : null,
+        anno: annoDelModo(modo)
     });
+
+    if (modifiche.completo || !serviziAnnoCache[modo]) {
+        serviziAnnoCache[modo] = Array.isArray(modifiche.modificati) ? modifiche.modificati : [];
+    } else if (modifiche.modificati.length || modifiche.eliminati.length) {
+        const toccati = new Set([
+            ...modifiche.eliminati.map(String),
+            ...modifiche.modificati.map(s => String(s.id))
+        ]);
+        const lista = serviziAnnoCache[modo].filter(s => !toccati.has(String(s.id)));
+        lista.push(...modifiche.modificati);
+        ordinaServiziComeBackend(lista);
+        serviziAnnoCache[modo] = lista;
+    }
+    serviziAnnoRevisione[modo] = modifiche.revisione;
+    return [...serviziAnnoCache[modo]];
 }
 
-async function getServiziForModo(modo) {
-    if (serviziAnnoCache[modo]) {
-        return [...serviziAnnoCache[modo]];
+// Modifiche arrivate da altre finestre o postazioni (evento db-change dal backend):
+// si chiedono solo le differenze e si resta sulla pagina corrente
+let timerAggiornamentoTempoReale = null;
+
+async function aggiornaServiziDaTempoReale() {
+    try {
+        await getServiziForModo(filtroAnnoModo);
+        serviziOriginali = getServiziListaBaseForModo(filtroAnnoModo);
+        const pagina = currentPage;
+        applyAllFilters();
+        currentPage = pagina;
+        renderServiziView(false);
+    } catch (error) {
+        console.warn('Aggiornamento servizi in tempo reale:', error);
+    }
+}
+
+async function setupAggiornamentiTempoReale() {
+    if (!isTauri()) return;
+    try {
+        const { listen } = await import('@tauri-apps/api/event');
+        await listen('db-change', (event) => {
+            const tabella = event.payload?.tabella;
+            if (tabella === 'automezzi') {
+                caricaAutomezzi().then(() => renderServiziView(false));
+                return;
+            }
+            if (tabella !== 'servizi') return;
+            // Più eventi ravvicinati (es. duplicazione) → un solo aggiornamento
+            clearTimeout(timerAggiornamentoTempoReale);
+            timerAggiornamentoTempoReale = setTimeout(aggiornaServiziDaTempoReale, 400);
+        });
+    } catch (err) {
+        console.warn('Listener aggiornamenti in tempo reale:', err);
     }
-    const servizi = await fetchServiziFromBackend(modo);
-    serviziAnnoCache[modo] = Array.isArray(servizi) ? servizi : [];
-    return [...serviziAnnoCache[modo]];
 }
 
 function updateAnnoHeaderUI() {
@@ -1109,10 +1194,6 @@ function haServiziStatoCompletato() {
     );
 }
 
-function normalizzaStatoServizio(stato) {
-    return String(stato || '').trim().toUpperCase();
-}
-
 // Funzione per aggiornare lo stato visivo dei pulsanti filtro stato
 function updateFiltroStatoButtons() {
     const btnDaEseguire = document.getElementById('btn-da-eseguire');
@@ -1529,6 +1610,9 @@ document.addEventListener('DOMContentLoaded', async () => {
 
     // Filtro da URL (apertura da Elenco Soci → SERVIZI)
     await applicaFiltroInizialeDaUrl();
+
+    // Aggiornamenti live da altre finestre / postazioni
+    await setupAggiornamentiTempoReale();
     
     // Inizializza lo stato dei pulsanti filtro stato
     updateFiltroStatoButtons();
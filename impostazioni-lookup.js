@@ -109,7 +109,7 @@ export function createLookupManager(deps) {
             if (loading) loading.hidden = true;
             if (errore) {
                 errore.hidden = false;
-                errore.textContent = `Errore: ${error}`;
+                errore.textContent = `Errore: ${error?.message || error}`;
             }
         }
     }
@@ -189,7 +189,7 @@ export function createLookupManager(deps) {
             await carica(kindAttivo);
         } catch (error) {
             console.error('Errore salvataggio lookup:', error);
-            setStatus(`Errore: ${error}`, true);
+            setStatus(`Errore: ${error?.message || error}`, true);
         } finally {
             if (btn) {
                 btn.disabled = false;
@@ -223,7 +223,7 @@ export function createLookupManager(deps) {
             await carica(kindAttivo);
         } catch (error) {
             console.error('Errore eliminazione lookup:', error);
-            setStatus(`Errore eliminazione: ${error}`, true);
+            setStatus(`Errore eliminazione: ${error?.message || error}`, true);
             chiudiConferma();
         } finally {
             if (btn) {
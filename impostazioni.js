@@ -1,5 +1,5 @@
 // Popup Impostazioni — schede: Impostazioni + tabelle lookup (solo admin)
-import { richiediSessione, isAdmin } from './auth-session.js';
+import { richiediSessione, haCapacita } from './auth-session.js';
 import { createLookupManager } from './impostazioni-lookup.js';
 
 let invoke;
@@ -119,7 +119,7 @@ async function caricaImpostazioni() {
         if (loading) loading.hidden = true;
         if (errore) {
             errore.hidden = false;
-            errore.textContent = `Errore: ${error}`;
+            errore.textContent = `Errore: ${error?.message || error}`;
         }
     }
 }
@@ -145,8 +145,8 @@ function raccogliModifiche() {
 }
 
 async function salvaImpostazioni() {
-    if (!isAdmin()) {
-        setStatus('Solo gli amministratori possono modificare le impostazioni.', true);
+    if (!haCapacita('impostazioni.modifica')) {
+        setStatus('Non hai il permesso di modificare le impostazioni.', true);
         return;
     }
 
@@ -179,7 +179,7 @@ async function salvaImpostazioni() {
         setStatus(`Salvate ${modifiche.length} impostazione/i.`);
     } catch (error) {
         console.error('Errore salvataggio impostazioni:', error);
-        setStatus(`Errore salvataggio: ${error}`, true);
+        setStatus(`Errore salvataggio: ${error?.message || error}`, true);
         await caricaImpostazioni();
     } finally {
         if (btn) {
@@ -269,8 +269,8 @@ async function chiudiFinestra() {
 document.addEventListener('DOMContentLoaded', async () => {
     const sessione = richiediSessione();
     if (!sessione) return;
-    if (!isAdmin(sessione)) {
-        alert('Accesso riservato agli amministratori.');
+    if (!haCapacita('impostazioni.modifica', sessione)) {
+        alert('Accesso riservato a chi può modificare le impostazioni.');
         window.location.href = 'index.html';
         return;
     }
@@ -12,7 +12,7 @@ import {
 } from './completa-servizio.js';
 import {
     richiediSessione,
-    isAdmin,
+    haCapacita,
     logout
 } from './auth-session.js';
 import {
@@ -549,6 +549,32 @@ async function setupTessereDaFareRefreshListener() {
     }
 }
 
+// Liste della home aggiornate quando un servizio o un socio cambia altrove
+let timerAggiornamentoServiziHome = null;
+
+async function setupAggiornamentiTempoReale() {
+    if (!isTauri()) return;
+    try {
+        const { listen } = await import('@tauri-apps/api/event');
+        await listen('db-change', (event) => {
+            const tabella = event.payload?.tabella;
+            if (tabella === 'tesserati') {
+                loadTessereDaFare();
+                return;
+            }
+            if (tabella !== 'servizi') return;
+            clearTimeout(timerAggiornamentoServiziHome);
+            timerAggiornamentoServiziHome = setTimeout(() => {
+                loadServiziGiorno();
+                loadProssimiServizi();
+                loadServiziInseritiOggi();
+            }, 400);
+        });
+    } catch (err) {
+        console.warn('Listener aggiornamenti in tempo reale:', err);
+    }
+}
+
 // Funzioni per i pulsanti
 window.stampaServizio = async function(id) {
     const idNum = parseInt(id, 10);
@@ -715,12 +741,11 @@ document.addEventListener('DOMContentLoaded', async () => {
     await applicaTitoloFinestra('AUSER Asti - Accesso');
     await mostraVersioneInElemento('app-version');
 
-    const admin = isAdmin(sessione);
     const btnImpostazioni = document.getElementById('btn-impostazioni');
     const btnUtenti = document.getElementById('btn-utenti');
-    // Impostazioni e Gestione utenti restano solo admin (header)
-    if (btnImpostazioni) btnImpostazioni.hidden = !admin;
-    if (btnUtenti) btnUtenti.hidden = !admin;
+    // Impostazioni e Gestione utenti (header): in base alle capacità del ruolo
+    if (btnImpostazioni) btnImpostazioni.hidden = !haCapacita('impostazioni.modifica', sessione);
+    if (btnUtenti) btnUtenti.hidden = !haCapacita('utenti.gestisci', sessione);
     // Pulsanti sidebar: in base alle spunte in Gestione utenti (admin = tutti)
     applicaVisibilitaSidebar(sessione);
 
@@ -784,12 +809,12 @@ document.addEventListener('DOMContentLoaded', async () => {
         });
     }
 
-    // Pulsante IMPOSTAZIONI (ingranaggio) — solo is_admin
+    // Pulsante IMPOSTAZIONI (ingranaggio) — capacità impostazioni.modifica
     const impostazioniBtn = document.getElementById('btn-impostazioni');
     if (impostazioniBtn) {
         impostazioniBtn.addEventListener('click', async () => {
-            if (!isAdmin()) {
-                alert('Solo gli amministratori possono aprire Impostazioni.');
+            if (!haCapacita('impostazioni.modifica')) {
+                alert('Non hai il permesso di aprire Impostazioni.');
                 return;
             }
             if (isTauri()) {
@@ -815,12 +840,12 @@ document.addEventListener('DOMContentLoaded', async () => {
         });
     }
 
-    // Pulsante GESTIONE UTENTI (persona) — solo is_admin
+    // Pulsante GESTIONE UTENTI (persona) — capacità utenti.gestisci
     const utentiBtn = document.getElementById('btn-utenti');
     if (utentiBtn) {
         utentiBtn.addEventListener('click', async () => {
-            if (!isAdmin()) {
-                alert('Solo gli amministratori possono gestire gli utenti.');
+            if (!haCapacita('utenti.gestisci')) {
+                alert('Non hai il permesso di gestire gli utenti.');
                 return;
             }
             if (isTauri()) {
@@ -1197,6 +1222,7 @@ document.addEventListener('DOMContentLoaded', async () => {
     updateDateInfo();
     setupTessereDaFareClickHandlers();
     await setupTessereDaFareRefreshListener();
+    await setupAggiornamentiTempoReale();
     
     // Carica i dati
     loadServiziGiorno();
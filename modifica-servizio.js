@@ -50,6 +50,11 @@ const MODALE_MODIFICA_MARKUP = `
             <div class="ns-header-left">
                 <button type="button" class="btn btn-elimina-modifica" id="btn-elimina-modifica">ELIMINA</button>
                 <button type="button" class="btn btn-duplica-modifica" id="btn-duplica-modifica">DUPLICA</button>
+                <button type="button" class="btn btn-duplica-modifica" id="btn-duplica-multipla-modifica">DUPLICA SU PIÙ DATE</button>
+                <button type="button" class="btn btn-duplica-modifica" id="btn-serie-modifica">RIPETI</button>
+                <button type="button" class="btn btn-duplica-modifica" id="btn-ritorno-modifica">RITORNO</button>
+                <button type="button" class="btn btn-elimina-modifica" id="btn-scollega-viaggio-modifica" hidden>SCOLLEGA VIAGGIO</button>
+                <button type="button" class="btn btn-elimina-modifica" id="btn-annulla-serie-modifica" hidden>ANNULLA SERIE</button>
                 <button type="button" class="btn btn-modifica-pagamento" id="btn-modifica-pagamento" hidden>MODIFICA PAGAMENTO</button>
             </div>
             <div class="modal-modifica-title-row" aria-live="polite">
@@ -67,7 +72,8 @@ const MODALE_MODIFICA_MARKUP = `
 </div>
 <div id="mod-dialog-elimina" class="mod-dialog-overlay ns-dialog-overlay" hidden aria-hidden="true">
     <div class="ns-dialog" role="dialog" aria-modal="true" aria-labelledby="mod-dialog-elimina-messaggio">
-        <p id="mod-dialog-elimina-messaggio" class="ns-dialog-messaggio">Vuoi veramente eliminare questo servizio?</p>
+        <p id="mod-dialog-elimina-messaggio" class="ns-dialog-messaggio">Vuoi veramente eliminare questo servizio? Finirà nel cestino.</p>
+        <input type="text" id="mod-dialog-elimina-motivo" class="mod-dialog-motivo" placeholder="Motivo dell'eliminazione" autocomplete="off">
         <div class="ns-dialog-actions">
             <button type="button" id="mod-dialog-elimina-si" class="ns-dialog-btn ns-dialog-btn-si">Si Elimina</button>
             <button type="button" id="mod-dialog-elimina-no" class="ns-dialog-btn ns-dialog-btn-no">No Annulla</button>
@@ -92,6 +98,71 @@ const MODALE_MODIFICA_MARKUP = `
         </div>
     </div>
 </div>
+<div id="mod-dialog-duplica-multipla" class="mod-dialog-overlay ns-dialog-overlay" hidden aria-hidden="true">
+    <div class="ns-dialog mod-dialog-duplica mod-dialog-duplica-multipla" role="dialog" aria-modal="true" aria-labelledby="mod-dialog-duplica-multipla-titolo">
+        <p id="mod-dialog-duplica-multipla-titolo" class="ns-dialog-messaggio mod-dialog-duplica-titolo">Su quali date duplico il servizio?</p>
+        <p class="mod-duplica-multipla-nota">Ora, operatore e mezzo vuoti = come nelle opzioni di duplicazione.</p>
+        <table class="mod-duplica-multipla-tabella">
+            <thead><tr><th>DATA</th><th>ORA</th><th>OPERATORE</th><th>MEZZO</th><th></th></tr></thead>
+            <tbody id="mod-duplica-multipla-righe"></tbody>
+        </table>
+        <button type="button" id="mod-duplica-multipla-aggiungi" class="ns-dialog-btn">+ AGGIUNGI DATA</button>
+        <div class="ns-dialog-actions">
+            <button type="button" id="mod-dialog-duplica-multipla-procedi" class="ns-dialog-btn ns-dialog-btn-si">Avanti</button>
+            <button type="button" id="mod-dialog-duplica-multipla-annulla" class="ns-dialog-btn ns-dialog-btn-no">Annulla</button>
+        </div>
+    </div>
+</div>
+<div id="mod-dialog-conferma" class="mod-dialog-overlay ns-dialog-overlay" hidden aria-hidden="true">
+    <div class="ns-dialog" role="dialog" aria-modal="true" aria-labelledby="mod-dialog-conferma-messaggio">
+        <p id="mod-dialog-conferma-messaggio" class="ns-dialog-messaggio"></p>
+        <div class="ns-dialog-actions">
+            <button type="button" id="mod-dialog-conferma-si" class="ns-dialog-btn ns-dialog-btn-si">Procedi comunque</button>
+            <button type="button" id="mod-dialog-conferma-no" class="ns-dialog-btn ns-dialog-btn-no">Annulla</button>
+        </div>
+    </div>
+</div>
+<div id="mod-dialog-serie" class="mod-dialog-overlay ns-dialog-overlay" hidden aria-hidden="true">
+    <div class="ns-dialog mod-dialog-duplica" role="dialog" aria-modal="true" aria-labelledby="mod-dialog-serie-titolo">
+        <p id="mod-dialog-serie-titolo" class="ns-dialog-messaggio mod-dialog-duplica-titolo">Ripeti il servizio</p>
+        <div class="mod-duplica-opzioni">
+            <label class="mod-duplica-check"><input type="radio" name="mod-serie-frequenza" value="settimanale" checked> <span>Ogni settimana il</span></label>
+            <div class="mod-serie-giorni">
+                ${['LUN', 'MAR', 'MER', 'GIO', 'VEN', 'SAB', 'DOM'].map((g, i) => `<label class="mod-duplica-check"><input type="checkbox" data-giorno="${i + 1}"> <span>${g}</span></label>`).join('')}
+            </div>
+            <label class="mod-duplica-check"><input type="radio" name="mod-serie-frequenza" value="ogni_n_giorni"> <span>Ogni</span> <input type="number" id="mod-serie-intervallo" class="mod-serie-numero" min="1" value="2"> <span>giorni</span></label>
+            <label class="mod-serie-campo">DAL <input type="date" id="mod-serie-dal"></label>
+            <label class="mod-serie-campo">FINO AL <input type="date" id="mod-serie-fino-al"></label>
+            <label class="mod-serie-campo">OPPURE PER <input type="number" id="mod-serie-occorrenze" class="mod-serie-numero" min="1"> VOLTE</label>
+            <label class="mod-duplica-check"><input type="checkbox" id="mod-serie-festivi" checked> <span>Salta le festività</span></label>
+        </div>
+        <div class="ns-dialog-actions">
+            <button type="button" id="mod-dialog-serie-procedi" class="ns-dialog-btn ns-dialog-btn-si">Avanti</button>
+            <button type="button" id="mod-dialog-serie-annulla" class="ns-dialog-btn ns-dialog-btn-no">Annulla</button>
+        </div>
+    </div>
+</div>
+<div id="mod-dialog-ambito-serie" class="mod-dialog-overlay ns-dialog-overlay" hidden aria-hidden="true">
+    <div class="ns-dialog" role="dialog" aria-modal="true" aria-labelledby="mod-dialog-ambito-serie-messaggio">
+        <p id="mod-dialog-ambito-serie-messaggio" class="ns-dialog-messaggio">Questo servizio fa parte di una serie. A quali servizi applico le modifiche?</p>
+        <div class="ns-dialog-actions mod-dialog-ambito-azioni" id="mod-dialog-ambito-serie-azioni">
+            <button type="button" data-ambito="singola" class="ns-dialog-btn ns-dialog-btn-si">Solo questo</button>
+            <button type="button" data-ambito="da_questa_in_poi" class="ns-dialog-btn ns-dialog-btn-si">Questo e i successivi</button>
+            <button type="button" data-ambito="tutta" class="ns-dialog-btn ns-dialog-btn-si">Tutta la serie</button>
+            <button type="button" data-ambito="" class="ns-dialog-btn ns-dialog-btn-no">Annulla</button>
+        </div>
+    </div>
+</div>
+<div id="mod-dialog-annulla-serie" class="mod-dialog-overlay ns-dialog-overlay" hidden aria-hidden="true">
+    <div class="ns-dialog" role="dialog" aria-modal="true" aria-labelledby="mod-dialog-annulla-serie-messaggio">
+        <p id="mod-dialog-annulla-serie-messaggio" class="ns-dialog-messaggio">Annullare la serie? I servizi da oggi in poi passano ad ANNULLATO.</p>
+        <input type="text" id="mod-dialog-annulla-serie-motivo" class="mod-dialog-motivo" placeholder="Motivo dell'annullamento" autocomplete="off">
+        <div class="ns-dialog-actions">
+            <button type="button" id="mod-dialog-annulla-serie-si" class="ns-dialog-btn ns-dialog-btn-si">Si Annulla serie</button>
+            <button type="button" id="mod-dialog-annulla-serie-no" class="ns-dialog-btn ns-dialog-btn-no">No</button>
+        </div>
+    </div>
+</div>
 <div id="mod-dialog-mezzo-occupato" class="mod-dialog-overlay ns-dialog-overlay" hidden aria-hidden="true">
     <div class="ns-dialog ns-dialog-mezzo-occupato" role="dialog" aria-modal="true" aria-labelledby="mod-dialog-mezzo-occupato-titolo">
         <h2 id="mod-dialog-mezzo-occupato-titolo" class="ns-dialog-titolo">Mezzo già in uso</h2>
@@ -689,17 +760,305 @@ function chiediOpzioniDuplicazione() {
     });
 }
 
+/** Motivo dell'eliminazione (obbligatorio), oppure null se annullata */
+function rigaDuplicaMultipla() {
+    const operatori = allOperatori
+        .map((op) => op.nominativo || '')
+        .filter(Boolean)
+        .map((nome) => `<option value="${escapeHtmlModifica(nome)}">${escapeHtmlModifica(nome)}</option>`)
+        .join('');
+    const mezzi = allAutomezzi
+        .map((m) => normalizzaNumero(m.nr_automezzo))
+        .filter(Boolean)
+        .map((nr) => `<option value="${escapeHtmlModifica(nr)}">${escapeHtmlModifica(nr)}</option>`)
+        .join('');
+    return `<tr>
+        <td><input type="date" data-campo="data"></td>
+        <td><input type="time" data-campo="oraInizio"></td>
+        <td><select data-campo="operatore"><option value=""></option>${operatori}</select></td>
+        <td><select data-campo="mezzo"><option value=""></option>${mezzi}</select></td>
+        <td><button type="button" class="ns-dialog-btn ns-dialog-btn-no" data-rimuovi>✕</button></td>
+    </tr>`;
+}
+
+/** Date (con ora/operatore/mezzo propri) dal dialog DUPLICA SU PIÙ DATE (null = annullato) */
+function chiediDateDuplicazione() {
+    return new Promise((resolve) => {
+        const overlay = document.getElementById('mod-dialog-duplica-multipla');
+        const tbody = document.getElementById('mod-duplica-multipla-righe');
+        const btnAggiungi = document.getElementById('mod-duplica-multipla-aggiungi');
+        const btnProcedi = document.getElementById('mod-dialog-duplica-multipla-procedi');
+        const btnAnnulla = document.getElementById('mod-dialog-duplica-multipla-annulla');
+        if (!overlay || !tbody || !btnAggiungi || !btnProcedi || !btnAnnulla) {
+            resolve(null);
+            return;
+        }
+
+        tbody.innerHTML = rigaDuplicaMultipla();
+        overlay.hidden = false;
+        overlay.setAttribute('aria-hidden', 'false');
+
+        const chiudi = (date) => {
+            overlay.hidden = true;
+            overlay.setAttribute('aria-hidden', 'true');
+            btnAggiungi.removeEventListener('click', onAggiungi);
+            btnProcedi.removeEventListener('click', onProcedi);
+            btnAnnulla.removeEventListener('click', onAnnulla);
+            tbody.removeEventListener('click', onRimuovi);
+            resolve(date);
+        };
+
+        const onAggiungi = (event) => {
+            event.preventDefault();
+            tbody.insertAdjacentHTML('beforeend', rigaDuplicaMultipla());
+        };
+
+        const onRimuovi = (event) => {
+            const btn = event.target.closest('[data-rimuovi]');
+            if (!btn || tbody.rows.length <= 1) return;
+            event.preventDefault();
+            btn.closest('tr')?.remove();
+        };
+
+        const onProcedi = (event) => {
+            event.preventDefault();
+            event.stopPropagation();
+            const date = [...tbody.rows]
+                .map((tr) => {
+                    const valore = (campo) => tr.querySelector(`[data-campo="${campo}"]`)?.value.trim() || null;
+                    return {
+                        data: valore('data'),
+                        oraInizio: valore('oraInizio'),
+                        operatore: valore('operatore'),
+                        mezzo: valore('mezzo')
+                    };
+                })
+                .filter((d) => d.data);
+            if (!date.length) {
+                tbody.querySelector('[data-campo="data"]')?.focus();
+                return;
+            }
+            chiudi(date);
+        };
+
+        const onAnnulla = (event) => {
+            event.preventDefault();
+            event.stopPropagation();
+            chiudi(null);
+        };
+
+        btnAggiungi.addEventListener('click', onAggiungi);
+        btnProcedi.addEventListener('click', onProcedi);
+        btnAnnulla.addEventListener('click', onAnnulla);
+        tbody.addEventListener('click', onRimuovi);
+    });
+}
+
+function chiediConfermaModifica(messaggio, { si = 'Procedi comunque', no = 'Annulla' } = {}) {
+    return new Promise((resolve) => {
+        const overlay = document.getElementById('mod-dialog-conferma');
+        const msgEl = document.getElementById('mod-dialog-conferma-messaggio');
+        const btnSi = document.getElementById('mod-dialog-conferma-si');
+        const btnNo = document.getElementById('mod-dialog-conferma-no');
+        if (!overlay || !msgEl || !btnSi || !btnNo) {
+            resolve(window.confirm(messaggio));
+            return;
+        }
+
+        msgEl.textContent = messaggio;
+        btnSi.textContent = si;
+        btnNo.textContent = no;
+        overlay.hidden = false;
+        overlay.setAttribute('aria-hidden', 'false');
+
+        const chiudi = (risposta) => {
+            overlay.hidden = true;
+            overlay.setAttribute('aria-hidden', 'true');
+            btnSi.removeEventListener('click', onSi);
+            btnNo.removeEventListener('click', onNo);
+            resolve(risposta);
+        };
+        const onSi = (event) => {
+            event.preventDefault();
+            event.stopPropagation();
+            chiudi(true);
+        };
+        const onNo = (event) => {
+            event.preventDefault();
+            event.stopPropagation();
+            chiudi(false);
+        };
+
+        btnSi.addEventListener('click', onSi);
+        btnNo.addEventListener('click', onNo);
+    });
+}
+
+function messaggioConflittiDuplicazione(conflitti) {
+    const righe = (conflitti || []).map((c) => {
+        const servizi = (c.servizi || [])
+            .map((s) => `${s.ora || '--:--'} ${s.trasportato || ''}`.trim())
+            .join(', ');
+        return `${c.data}: ${c.risorsa} ${c.valore} già impegnato (${servizi})`;
+    });
+    return `Mezzo o operatore già impegnati:\n${righe.join('\n')}\n\nDuplicare comunque?`;
+}
+
+/** Regola di ricorrenza dal dialog RIPETI (null = annullato) */
+function chiediRegolaSerie() {
+    return new Promise((resolve) => {
+        const overlay = document.getElementById('mod-dialog-serie');
+        const btnProcedi = document.getElementById('mod-dialog-serie-procedi');
+        const btnAnnulla = document.getElementById('mod-dialog-serie-annulla');
+        if (!overlay || !btnProcedi || !btnAnnulla) {
+            resolve(null);
+            return;
+        }
+
+        const dataServizio = valorePerInputData(servizioInModifica?.data_prelievo);
+        const giornoServizio = dataServizio ? ((new Date(`${dataServizio}T12:00:00`).getDay() + 6) % 7) + 1 : 0;
+        overlay.querySelectorAll('[data-giorno]').forEach((el) => {
+            el.checked = Number(el.dataset.giorno) === giornoServizio;
+        });
+        document.getElementById('mod-serie-dal').value = dataServizio;
+        document.getElementById('mod-serie-fino-al').value = '';
+        document.getElementById('mod-serie-occorrenze').value = '';
+        overlay.hidden = false;
+        overlay.setAttribute('aria-hidden', 'false');
+
+        const chiudi = (regola) => {
+            overlay.hidden = true;
+            overlay.setAttribute('aria-hidden', 'true');
+            btnProcedi.removeEventListener('click', onProcedi);
+            btnAnnulla.removeEventListener('click', onAnnulla);
+            resolve(regola);
+        };
+
+        const onProcedi = (event) => {
+            event.preventDefault();
+            event.stopPropagation();
+            const tipo = overlay.querySelector('input[name="mod-serie-frequenza"]:checked')?.value || 'settimanale';
+            const regola = {
+                tipo,
+                dal: document.getElementById('mod-serie-dal').value,
+                fino_al: document.getElementById('mod-serie-fino-al').value || null,
+                occorrenze: parseInt(document.getElementById('mod-serie-occorrenze').value, 10) || null,
+                salta_festivi: Boolean(document.getElementById('mod-serie-festivi')?.checked)
+            };
+            if (tipo === 'settimanale') {
+                regola.giorni = [...overlay.querySelectorAll('[data-giorno]:checked')].map((el) => Number(el.dataset.giorno));
+            } else {
+                regola.intervallo = parseInt(document.getElementById('mod-serie-intervallo').value, 10) || 1;
+            }
+            chiudi(regola);
+        };
+
+        const onAnnulla = (event) => {
+            event.preventDefault();
+            event.stopPropagation();
+            chiudi(null);
+        };
+
+        btnProcedi.addEventListener('click', onProcedi);
+        btnAnnulla.addEventListener('click', onAnnulla);
+    });
+}
+
+/** singola / da_questa_in_poi / tutta ('' = annullato) */
+function chiediAmbitoSerie() {
+    return new Promise((resolve) => {
+        const overlay = document.getElementById('mod-dialog-ambito-serie');
+        const azioni = document.getElementById('mod-dialog-ambito-serie-azioni');
+        if (!overlay || !azioni) {
+            resolve('singola');
+            return;
+        }
+
+        overlay.hidden = false;
+        overlay.setAttribute('aria-hidden', 'false');
+
+        const onClick = (event) => {
+            const btn = event.target.closest('[data-ambito]');
+            if (!btn) return;
+            event.preventDefault();
+            event.stopPropagation();
+            overlay.hidden = true;
+            overlay.setAttribute('aria-hidden', 'true');
+            azioni.removeEventListener('click', onClick);
+            resolve(btn.dataset.ambito);
+        };
+
+        azioni.addEventListener('click', onClick);
+    });
+}
+
+function chiediMotivoAnnullaSerie() {
+    return new Promise((resolve) => {
+        const overlay = document.getElementById('mod-dialog-annulla-serie');
+        const btnSi = document.getElementById('mod-dialog-annulla-serie-si');
+        const btnNo = document.getElementById('mod-dialog-annulla-serie-no');
+        const inputMotivo = document.getElementById('mod-dialog-annulla-serie-motivo');
+
+        if (!overlay || !btnSi || !btnNo || !inputMotivo) {
+            const motivo = window.prompt('Motivo dell\'annullamento della serie:');
+            resolve(motivo && motivo.trim() ? motivo.trim() : null);
+            return;
+        }
+
+        inputMotivo.value = '';
+        overlay.hidden = false;
+        overlay.setAttribute('aria-hidden', 'false');
+
+        const chiudi = (risposta) => {
+            overlay.hidden = true;
+            overlay.setAttribute('aria-hidden', 'true');
+            btnSi.removeEventListener('click', onSi);
+            btnNo.removeEventListener('click', onNo);
+            resolve(risposta);
+        };
+
+        const onSi = (event) => {
+            event.preventDefault();
+            event.stopPropagation();
+            const motivo = inputMotivo.value.trim();
+            if (!motivo) {
+                inputMotivo.focus();
+                return;
+            }
+            chiudi(motivo);
+        };
+
+        const onNo = (event) => {
+            event.preventDefault();
+            event.stopPropagation();
+            chiudi(null);
+        };
+
+        btnSi.addEventListener('click', onSi);
+        btnNo.addEventListener('click', onNo);
+    });
+}
+
+/** Riepilogo dei servizi della serie lasciati com'erano */
+function messaggioSaltatiSerie(saltati) {
+    const righe = (saltati || []).map((s) => `${s.data || s.id}: ${s.motivo}`);
+    return righe.length ? `Non modificati:\n${righe.join('\n')}` : '';
+}
+
 function chiediConfermaEliminaModifica() {
     return new Promise((resolve) => {
         const overlay = document.getElementById('mod-dialog-elimina');
         const btnSi = document.getElementById('mod-dialog-elimina-si');
         const btnNo = document.getElementById('mod-dialog-elimina-no');
+        const inputMotivo = document.getElementById('mod-dialog-elimina-motivo');
 
-        if (!overlay || !btnSi || !btnNo) {
-            resolve(window.confirm('Vuoi veramente eliminare questo servizio?'));
+        if (!overlay || !btnSi || !btnNo || !inputMotivo) {
+            const motivo = window.prompt('Motivo dell\'eliminazione del servizio:');
+            resolve(motivo && motivo.trim() ? motivo.trim() : null);
             return;
         }
 
+        inputMotivo.value = '';
         overlay.hidden = false;
         overlay.setAttribute('aria-hidden', 'false');
 
@@ -714,13 +1073,18 @@ function chiediConfermaEliminaModifica() {
         const onSi = (event) => {
             event.preventDefault();
             event.stopPropagation();
-            chiudi(true);
+            const motivo = inputMotivo.value.trim();
+            if (!motivo) {
+                inputMotivo.focus();
+                return;
+            }
+            chiudi(motivo);
         };
 
         const onNo = (event) => {
             event.preventDefault();
             event.stopPropagation();
-            chiudi(false);
+            chiudi(null);
         };
 
         btnSi.addEventListener('click', onSi);
@@ -865,7 +1229,7 @@ function costruisciFormModifica(servizio) {
 
 export function costruisciFormServizio(
     servizio,
-    { idPrefix = 'mod', chiusuraPrima = false, tipiPagamentoExtra = null, mostraArchivia = true, pagamentoSecondo = false } = {}
+    { idPrefix = 'mod', chiusuraPrima = false, tipiPagamentoExtra = null, mostraArchivia = true, pagamentoSecondo = false, orariViaggio = false } = {}
 ) {
     const p = idPrefix;
     const tipiPagamentoOpts = getTipiPagamentoOptions(servizio.tipo_pagamento, tipiPagamentoExtra);
@@ -908,14 +1272,20 @@ export function costruisciFormServizio(
         const archiviaField = mostraArchivia
             ? creaSelectNs(`${p}-archivia`, 'ARCHIVIA', archiviaSi ? 'SI' : 'NO', ['NO', 'SI'], 'mod-chiusura-archivia')
             : '';
+        // Completa: km e tempo li calcola il backend da orari e contachilometri
+        const orariField = orariViaggio
+            ? `${creaInputNs(`${p}-ora-partenza`, 'ORA PARTENZA', valorePerInputOra(servizio.ora_inizio), 'mod-field-ora-partenza', { type: 'time' })}
+                    ${creaInputNs(`${p}-ora-rientro`, 'ORA RIENTRO', '', 'mod-field-ora-rientro', { type: 'time' })}`
+            : '';
         return `
             <section class="ns-section${chiusuraPrima ? ' ns-section-chiusura-prima' : ''}">
                 <h2 class="ns-section-title">${num}. Dati di chiusura del servizio</h2>
                 <div class="ns-grid mod-chiusura-grid">
+                    ${orariField}
                     ${creaInputNs(`${p}-km-uscita`, 'KM USCITA', servizio.km_uscita || '', 'mod-field-km-uscita', { inputMode: 'decimal' })}
                     ${creaInputNs(`${p}-km-rientro`, 'KM RIENTRO', servizio.km_rientro || '', 'mod-field-km-rientro', { inputMode: 'decimal' })}
-                    ${creaInputNs(`${p}-km`, 'KM', servizio.km || '', 'mod-field-km', { inputMode: 'decimal' })}
-                    ${creaInputNs(`${p}-tempo`, 'TEMPO', valorePerInputOra(servizio.tempo), 'mod-field-tempo', { type: 'time' })}
+                    ${creaInputNs(`${p}-km`, 'KM', servizio.km || '', 'mod-field-km', { inputMode: 'decimal', readonly: orariViaggio })}
+                    ${creaInputNs(`${p}-tempo`, 'TEMPO', valorePerInputOra(servizio.tempo), 'mod-field-tempo', { type: orariViaggio ? 'text' : 'time', readonly: orariViaggio })}
                     ${creaTextareaNs(`${p}-note-fine-servizio`, 'NOTE FINE SERVIZIO', noteFineDisplay, notaClass, noteRows)}
                     ${archiviaField}
                 </div>
@@ -963,6 +1333,7 @@ export function costruisciFormServizio(
                     ${creaSelectNs(`${p}-carrozzina`, 'CARROZZINA', servizio.carrozzina, ['', 'AUSER', 'SOCIO'])}
                     ${creaSelectNs(`${p}-stato-servizio`, 'STATO DEL SERVIZIO', resolveStatoServizioSelezionato(servizio.stato_servizio), getStatiServizioOptions())}
                     ${creaInputNs(`${p}-motivazione`, 'MOTIVAZIONE DEL SERVIZIO', servizio.motivazione, 'span-4')}
+                    ${creaInputNs(`${p}-motivo-annullamento`, 'MOTIVO ANNULLAMENTO', servizio.motivo_annullamento, 'span-4')}
                 </div>
             </section>`;
 
@@ -1159,6 +1530,13 @@ export function setupFormServizioListeners(idPrefix = 'mod') {
             getInvoke: getInvokeFn,
             isTauri: isTauriEnv,
             chilometriIniziali: kmVal,
+            dataPrelievo: document.getElementById(`${idPrefix}-data-prelievo`)?.value || '',
+            contesto: {
+                idsocio: document.getElementById(`${idPrefix}-idsocio`)?.value || '',
+                richiedente: document.getElementById(`${idPrefix}-richiedente`)?.value || '',
+                tipo_servizio: document.getElementById(`${idPrefix}-tipo-servizio`)?.value || '',
+                carrozzina: document.getElementById(`${idPrefix}-carrozzina`)?.value || ''
+            },
             onConferma: (totale, dettaglio) => {
                 avvisaSeTrattaRimossaModifica(rimuoviTrattaDalForm(hiddenTrattaIdBtn));
                 const campo = document.getElementById(`${idPrefix}-pagamento`);
@@ -1368,6 +1746,7 @@ export function raccogliPayloadServizio(idPrefix = 'mod') {
         data_ricevuta: valoreDaInputData(get('data-ricevuta')),
         numero_ricevuta: get('numero-ricevuta'),
         stato_servizio: get('stato-servizio'),
+        motivo_annullamento: get('motivo-annullamento'),
         note_prelievo: document.getElementById(`${idPrefix}-note-prelievo`)?.value || '',
         note_arrivo: document.getElementById(`${idPrefix}-note-arrivo`)?.value || '',
         note_fine_servizio: mergeTariffaInNote(
@@ -1383,6 +1762,16 @@ function raccogliPayloadModifica() {
     return raccogliPayloadServizio('mod');
 }
 
+/** Salvataggio respinto: un altro utente ha cambiato gli stessi campi dopo l'apertura */
+export function isModificaConcorrente(error) {
+    return error?.code === 'concurrent_update';
+}
+
+export function messaggioModificaConcorrente(error) {
+    return `${error.message}.\nI tuoi dati sono ancora nel modulo: premi di nuovo SALVA per sovrascrivere `
+        + 'le modifiche dell\'altro utente, oppure ANNULLA e riapri il servizio per vederle.';
+}
+
 export async function caricaDatiModificaServizio() {
     const invoke = getInvokeFn();
     if (!isTauriEnv() || !invoke) return;
@@ -1434,6 +1823,9 @@ function applicaModalitaSolaLettura(attiva) {
     const modal = document.getElementById('modal-modifica');
     const btnElimina = document.getElementById('btn-elimina-modifica');
     const btnDuplica = document.getElementById('btn-duplica-modifica');
+    const btnDuplicaMultipla = document.getElementById('btn-duplica-multipla-modifica');
+    const btnSerie = document.getElementById('btn-serie-modifica');
+    const btnAnnullaSerie = document.getElementById('btn-annulla-serie-modifica');
     const btnSalva = document.getElementById('btn-salva-modifica');
     const btnAnnulla = document.getElementById('btn-annulla-modifica');
     const btnModPag = document.getElementById('btn-modifica-pagamento');
@@ -1445,6 +1837,14 @@ function applicaModalitaSolaLettura(attiva) {
     }
     if (btnElimina) btnElimina.hidden = solaLetturaAttiva;
     if (btnDuplica) btnDuplica.hidden = solaLetturaAttiva;
+    if (btnDuplicaMultipla) btnDuplicaMultipla.hidden = solaLetturaAttiva;
+    const btnRitorno = document.getElementById('btn-ritorno-modifica');
+    const btnScollegaViaggio = document.getElementById('btn-scollega-viaggio-modifica');
+    if (btnRitorno) btnRitorno.hidden = solaLetturaAttiva;
+    if (btnScollegaViaggio) btnScollegaViaggio.hidden = solaLetturaAttiva || !servizioInModifica?.id_viaggio;
+    const inSerie = Boolean(servizioInModifica?.id_serie);
+    if (btnSerie) btnSerie.hidden = solaLetturaAttiva || inSerie;
+    if (btnAnnullaSerie) btnAnnullaSerie.hidden = solaLetturaAttiva || !inSerie;
     if (btnSalva) btnSalva.hidden = solaLetturaAttiva;
     if (btnModPag) btnModPag.hidden = !solaLetturaAttiva;
     if (btnAnnulla) btnAnnulla.textContent = solaLetturaAttiva ? 'CHIUDI' : 'ANNULLA';
@@ -1561,9 +1961,9 @@ export async function apriModalModifica(servizioId, options = {}) {
 
     servizioInModifica = servizio;
     if (title) {
-        title.textContent = solaLettura
+        title.textContent = (solaLettura
             ? `SERVIZIO ${servizio.id || ''}`
-            : `MODIFICA SERVIZIO ${servizio.id || ''}`;
+            : `MODIFICA SERVIZIO ${servizio.id || ''}`) + (servizio.id_viaggio ? ` · TAPPA ${servizio.tappa || ''} DEL VIAGGIO` : '');
     }
     aggiornaEtichetteAudit('mod', servizio);
     body.innerHTML = costruisciFormModifica(servizio);
@@ -1600,8 +2000,8 @@ async function eliminaServizioModifica() {
         return;
     }
 
-    const conferma = await chiediConfermaEliminaModifica();
-    if (!conferma) return;
+    const motivo = await chiediConfermaEliminaModifica();
+    if (!motivo) return;
 
     const btnElimina = document.getElementById('btn-elimina-modifica');
     if (btnElimina) btnElimina.disabled = true;
@@ -1610,7 +2010,7 @@ async function eliminaServizioModifica() {
         const invoke = getInvokeFn();
         if (isTauriEnv() && invoke) {
             await invoke('init_supabase_from_config').catch(() => {});
-            await invoke('delete_servizio', { servizioId: id });
+            await invoke('delete_servizio', { servizioId: id, motivo });
             await onDeleteSuccess(id);
             chiudiModalModifica();
         } else {
@@ -1665,12 +2065,222 @@ async function duplicaServizioModifica() {
     }
 }
 
+async function duplicaMultiplaModifica() {
+    const id = parseInt(servizioInModifica?.id, 10);
+    if (!id || Number.isNaN(id)) {
+        mostraErroreModifica('ID servizio non valido.');
+        return;
+    }
+    const invoke = getInvokeFn();
+    if (!isTauriEnv() || !invoke) {
+        mostraErroreModifica('Duplicazione disponibile solo con connessione al database.');
+        return;
+    }
+
+    const date = await chiediDateDuplicazione();
+    if (!date) return;
+    const opzioni = await chiediOpzioniDuplicazione();
+    if (!opzioni) return;
+
+    const btn = document.getElementById('btn-duplica-multipla-modifica');
+    if (btn) btn.disabled = true;
+    try {
+        await invoke('init_supabase_from_config').catch(() => {});
+        const payload = { servizioId: id, opzioni, date, creatoDa: formatoAccountSessione() || null };
+        let esito = await invoke('duplicate_servizio_multiplo', { payload });
+        if (esito.conflitti?.length) {
+            if (!(await chiediConfermaModifica(messaggioConflittiDuplicazione(esito.conflitti)))) return;
+            esito = await invoke('duplicate_servizio_multiplo', { payload: { ...payload, ignoraConflitti: true } });
+        }
+
+        if (!esito.completata) {
+            let messaggio = `Nessun servizio creato: errore sulla copia del ${esito.errore}.`;
+            if (esito.non_annullati?.length) {
+                messaggio += `\nCopie da eliminare a mano (ID): ${esito.non_annullati.join(', ')}.`;
+            }
+            await mostraAvvisoModifica(messaggio);
+            return;
+        }
+        for (const copia of esito.creati) {
+            const nuovo = await invoke('get_servizio_completo', { servizioId: copia.id });
+            await onSaveSuccess(nuovo);
+        }
+        await mostraAvvisoModifica(
+            `Servizio duplicato su ${esito.creati.length} date: ${esito.creati.map((c) => c.data).join(', ')}.`
+        );
+        chiudiModalModifica();
+    } catch (error) {
+        console.error('Errore duplicazione multipla:', error);
+        mostraErroreModifica('Errore nella duplicazione: ' + (error.message || error));
+    } finally {
+        if (btn) btn.disabled = false;
+    }
+}
+
+async function creaRitornoModifica() {
+    const id = parseInt(servizioInModifica?.id, 10);
+    if (!id || Number.isNaN(id)) {
+        mostraErroreModifica('ID servizio non valido.');
+        return;
+    }
+    const invoke = getInvokeFn();
+    if (!isTauriEnv() || !invoke) {
+        mostraErroreModifica('Ritorno disponibile solo con connessione al database.');
+        return;
+    }
+
+    const btnRitorno = document.getElementById('btn-ritorno-modifica');
+    if (btnRitorno) btnRitorno.disabled = true;
+    try {
+        await invoke('init_supabase_from_config').catch(() => {});
+        const nuovoId = await invoke('crea_ritorno_servizio', {
+            servizioId: id,
+            dataPrelievo: null,
+            oraInizio: null,
+            creatoDa: formatoAccountSessione() || null
+        });
+        await onSaveSuccess(await invoke('get_servizio_completo', { servizioId: id }));
+        await onSaveSuccess(await invoke('get_servizio_completo', { servizioId: nuovoId }));
+        chiudiModalModifica();
+        await apriModalModifica(nuovoId, {
+            avvisoIniziale:
+                'Ritorno creato con partenza e destinazione invertite. Imposta l\'ora e salva. La tariffa del viaggio si calcola una volta sola, sull\'andata.'
+        });
+    } catch (error) {
+        console.error('Errore creazione ritorno:', error);
+        mostraErroreModifica('Errore nella creazione del ritorno: ' + (error.message || error));
+    } finally {
+        if (btnRitorno) btnRitorno.disabled = false;
+    }
+}
+
+async function scollegaViaggioModifica() {
+    const id = parseInt(servizioInModifica?.id, 10);
+    const invoke = getInvokeFn();
+    if (!id || !servizioInModifica?.id_viaggio || !isTauriEnv() || !invoke) return;
+    if (!(await chiediConfermaModifica('Togliere questo servizio dal viaggio? Le altre tappe restano collegate fra loro.', { si: 'Si Scollega', no: 'No' }))) {
+        return;
+    }
+    try {
+        await invoke('init_supabase_from_config').catch(() => {});
+        await invoke('scollega_servizio_viaggio', { servizioId: id });
+        await onSaveSuccess(await invoke('get_servizio_completo', { servizioId: id }));
+        chiudiModalModifica();
+    } catch (error) {
+        console.error('Errore scollegamento viaggio:', error);
+        mostraErroreModifica('Errore nello scollegamento dal viaggio: ' + (error.message || error));
+    }
+}
+
+/** Dopo il salvataggio di una tappa: propone di spostare o annullare anche le altre */
+async function propagaModificaViaggio(invoke, payload, prima) {
+    if (!prima?.id_viaggio) return '';
+    let esito = null;
+    const annullato = (stato) => String(stato || '').trim().toUpperCase() === 'ANNULLATO';
+    const ora = (valore) => String(valore || '').trim().slice(0, 5);
+    const oraSpostata = ora(payload.ora_inizio) && ora(prima.ora_inizio) &&
+        ora(payload.ora_inizio) !== ora(prima.ora_inizio);
+    if (annullato(payload.stato_servizio) && !annullato(prima.stato_servizio)) {
+        if (await chiediConfermaModifica('Annullare anche le altre tappe del viaggio?', { si: 'Si Annulla', no: 'No' })) {
+            esito = await invoke('annulla_tappe_viaggio', {
+                servizioId: payload.id,
+                motivo: payload.motivo_annullamento || 'Annullato con l\'altra tappa del viaggio',
+                modificatoDa: payload.modificato_da
+            });
+        }
+    } else if (valorePerInputData(payload.data_prelievo) !== valorePerInputData(prima.data_prelievo) || oraSpostata) {
+        if (await chiediConfermaModifica('Spostare anche le altre tappe del viaggio dello stesso intervallo (giorni e orario)?', { si: 'Si Sposta', no: 'No' })) {
+            esito = await invoke('sposta_tappe_viaggio', {
+                servizioId: payload.id,
+                dataPrecedente: prima.data_prelievo,
+                oraPrecedente: prima.ora_inizio || null,
+                modificatoDa: payload.modificato_da
+            });
+        }
+    }
+    return esito ? messaggioSaltatiSerie(esito.saltati) : '';
+}
+
+async function creaSerieModifica() {
+    const id = parseInt(servizioInModifica?.id, 10);
+    if (!id || Number.isNaN(id)) {
+        mostraErroreModifica('ID servizio non valido.');
+        return;
+    }
+    const invoke = getInvokeFn();
+    if (!isTauriEnv() || !invoke) {
+        mostraErroreModifica('Serie disponibili solo con connessione al database.');
+        return;
+    }
+
+    const regola = await chiediRegolaSerie();
+    if (!regola) return;
+    const opzioni = await chiediOpzioniDuplicazione();
+    if (!opzioni) return;
+
+    const btnSerie = document.getElementById('btn-serie-modifica');
+    if (btnSerie) btnSerie.disabled = true;
+    try {
+        await invoke('init_supabase_from_config').catch(() => {});
+        const esito = await invoke('crea_serie_servizi', {
+            payload: { servizioId: id, regola, opzioni, creatoDa: formatoAccountSessione() || null }
+        });
+        const aggiornato = await invoke('get_servizio_completo', { servizioId: id });
+        await onSaveSuccess(aggiornato);
+        const saltate = (esito.saltate || []).map((d) => `${d.data} (${d.motivo})`);
+        await mostraAvvisoModifica(
+            `Serie creata: ${esito.occorrenze.length} servizi.` +
+            (saltate.length ? ` Date saltate: ${saltate.join(', ')}.` : '')
+        );
+        chiudiModalModifica();
+    } catch (error) {
+        console.error('Errore creazione serie:', error);
+        mostraErroreModifica('Errore nella creazione della serie: ' + (error.message || error));
+    } finally {
+        if (btnSerie) btnSerie.disabled = false;
+    }
+}
+
+async function annullaSerieModifica() {
+    const idSerie = servizioInModifica?.id_serie;
+    const invoke = getInvokeFn();
+    if (!idSerie || !isTauriEnv() || !invoke) return;
+
+    const motivo = await chiediMotivoAnnullaSerie();
+    if (!motivo) return;
+
+    try {
+        await invoke('init_supabase_from_config').catch(() => {});
+        const esito = await invoke('annulla_serie_servizi', {
+            idSerie,
+            motivo,
+            modificatoDa: formatoAccountSessione() || null
+        });
+        const aggiornato = await invoke('get_servizio_completo', { servizioId: parseInt(servizioInModifica.id, 10) });
+        await onSaveSuccess(aggiornato);
+        await mostraAvvisoModifica(
+            `${esito.aggiornati.length} servizi della serie annullati. ${messaggioSaltatiSerie(esito.saltati)}`.trim()
+        );
+        chiudiModalModifica();
+    } catch (error) {
+        console.error('Errore annullamento serie:', error);
+        mostraErroreModifica('Errore nell\'annullamento della serie: ' + (error.message || error));
+    }
+}
+
 async function salvaModificaServizio() {
     const payload = raccogliPayloadModifica();
     if (!payload.id || Number.isNaN(payload.id)) {
         mostraErroreModifica('ID servizio non valido.');
         return;
     }
+    let ambito = 'singola';
+    if (servizioInModifica?.id_serie && isTauriEnv()) {
+        ambito = await chiediAmbitoSerie();
+        if (!ambito) return;
+    }
+    // Versione aperta: il salvataggio fallisce se nel frattempo qualcuno ha cambiato gli stessi campi
+    payload.versione = servizioInModifica?.versione || null;
 
     const btnSalva = document.getElementById('btn-salva-modifica');
     if (btnSalva) btnSalva.disabled = true;
@@ -1679,9 +2289,17 @@ async function salvaModificaServizio() {
         const invoke = getInvokeFn();
         if (isTauriEnv() && invoke) {
             await invoke('init_supabase_from_config').catch(() => {});
-            await invoke('update_servizio_completo', { payload });
+            let avvisoSerie = '';
+            if (ambito === 'singola') {
+                await invoke('update_servizio_completo', { payload });
+                avvisoSerie = await propagaModificaViaggio(invoke, payload, servizioInModifica);
+            } else {
+                const esito = await invoke('update_servizio_serie', { payload, ambito });
+                avvisoSerie = messaggioSaltatiSerie(esito.saltati);
+            }
             const aggiornato = await invoke('get_servizio_completo', { servizioId: payload.id });
             await onSaveSuccess(aggiornato, payload);
+            if (avvisoSerie) await mostraAvvisoModifica(avvisoSerie);
             chiudiModalModifica();
         } else {
             const demo = { ...servizioInModifica, ...payload, id: String(payload.id) };
@@ -1690,6 +2308,12 @@ async function salvaModificaServizio() {
         }
     } catch (error) {
         console.error('Errore salvataggio servizio:', error);
+        if (isModificaConcorrente(error) && servizioInModifica) {
+            // Il modulo resta aperto; un secondo SALVA sovrascrive consapevolmente
+            servizioInModifica.versione = error.current_version || '';
+            await mostraAvvisoModifica(messaggioModificaConcorrente(error));
+            return;
+        }
         mostraErroreModifica('Errore nel salvataggio: ' + (error.message || error));
     } finally {
         if (btnSalva) btnSalva.disabled = false;
@@ -1703,6 +2327,11 @@ export function setupModaleModifica() {
     document.getElementById('btn-salva-modifica')?.addEventListener('click', salvaModificaServizio);
     document.getElementById('btn-elimina-modifica')?.addEventListener('click', eliminaServizioModifica);
     document.getElementById('btn-duplica-modifica')?.addEventListener('click', duplicaServizioModifica);
+    document.getElementById('btn-duplica-multipla-modifica')?.addEventListener('click', duplicaMultiplaModifica);
+    document.getElementById('btn-serie-modifica')?.addEventListener('click', creaSerieModifica);
+    document.getElementById('btn-ritorno-modifica')?.addEventListener('click', creaRitornoModifica);
+    document.getElementById('btn-scollega-viaggio-modifica')?.addEventListener('click', scollegaViaggioModifica);
+    document.getElementById('btn-annulla-serie-modifica')?.addEventListener('click', annullaSerieModifica);
     document.getElementById('btn-modifica-pagamento')?.addEventListener('click', abilitaModificaPagamento);
     document.getElementById('mod-dialog-mezzo-occupato-chiudi')
         ?.addEventListener('click', chiudiModaleMezzoOccupatoModifica);
@@ -3,7 +3,7 @@
  * Non-admin: solo nominativo, tipologia fissa NUOVO.
  * Admin: può scegliere la tipologia; opzione aggiuntiva per anagrafica + tesseramento.
  */
-import { isAdmin, leggiSessione } from './auth-session.js';
+import { formatoAccountSessione, isAdmin, leggiSessione } from './auth-session.js';
 
 const TIPOLOGIA_NUOVO = 'NUOVO';
 
@@ -197,6 +197,28 @@ export function setupNuovoSocioTrasportato(deps) {
         }
     }
 
+    // IdSocio prenotato all'apertura: riservato a questa postazione fino a salvataggio/annullo
+    let prenotazioneToken = null;
+
+    async function prenotaIdsocio(invoke) {
+        const prenotazione = await invoke('reserve_idsocio', {
+            prenotatoDa: formatoAccountSessione() || null
+        });
+        prenotazioneToken = prenotazione?.token || null;
+        return String(prenotazione?.idsocio || '');
+    }
+
+    function rilasciaPrenotazione() {
+        const invoke = getInvoke?.();
+        const token = prenotazioneToken;
+        prenotazioneToken = null;
+        if (token && invoke) {
+            invoke('release_idsocio', { token }).catch((err) => {
+                console.warn('Rilascio prenotazione IdSocio:', err);
+            });
+        }
+    }
+
     async function apriModale() {
         resetForm();
         aggiornaUiRuolo();
@@ -206,10 +228,10 @@ export function setupNuovoSocioTrasportato(deps) {
             try {
                 await invoke('init_supabase_from_config').catch(() => {});
                 const [nextId] = await Promise.all([
-                    invoke('get_next_idsocio'),
+                    prenotaIdsocio(invoke),
                     caricaTipologiePerAdmin(invoke)
                 ]);
-                setVal('ns-ns-idsocio', String(nextId || ''));
+                setVal('ns-ns-idsocio', nextId);
             } catch (err) {
                 console.warn('Apertura modale nuovo socio:', err);
                 setVal('ns-ns-idsocio', '');
@@ -223,6 +245,7 @@ export function setupNuovoSocioTrasportato(deps) {
     }
 
     function chiudiModale() {
+        rilasciaPrenotazione();
         modal.hidden = true;
         modal.setAttribute('aria-hidden', 'true');
         resetForm();
@@ -330,7 +353,7 @@ export function setupNuovoSocioTrasportato(deps) {
 
             let idsocio = val('ns-ns-idsocio');
             if (!idsocio) {
-                idsocio = String(await invoke('get_next_idsocio'));
+                idsocio = await prenotaIdsocio(invoke);
                 setVal('ns-ns-idsocio', idsocio);
             }
 
@@ -348,7 +371,12 @@ export function setupNuovoSocioTrasportato(deps) {
                 tipologiasocio: tipologia
             };
 
-            const saved = await invoke('create_socio_anagrafica', { anagrafica });
+            const saved = await invoke('create_socio_anagrafica', {
+                anagrafica,
+                prenotazione: prenotazioneToken
+            });
+            // Confermata dal backend insieme al socio
+            prenotazioneToken = null;
             const idsocioFinale = saved?.idsocio || idsocio;
 
             let savedTess = null;
@@ -371,6 +399,18 @@ export function setupNuovoSocioTrasportato(deps) {
             await onSocioCreato?.(tesserato);
         } catch (error) {
             console.error('Nuovo socio da servizio:', error);
+            // Numero preso da un'altra postazione: se ne prenota un altro e si chiede di risalvare
+            if (error?.kind === 'conflict') {
+                try {
+                    rilasciaPrenotazione();
+                    const nuovoId = await prenotaIdsocio(invoke);
+                    setVal('ns-ns-idsocio', nuovoId);
+                    mostraErrore(`${error.message} — nuovo numero ${nuovoId}, premi di nuovo SALVA`);
+                    return;
+                } catch (err) {
+                    console.warn('Nuova prenotazione IdSocio:', err);
+                }
+            }
             mostraErrore(String(error?.message || error || 'Salvataggio non riuscito'));
         } finally {
             if (btnSalva) {
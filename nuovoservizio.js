@@ -425,8 +425,12 @@ async function salvaNotaAggiuntivaTrasportato() {
     if (isTauri() && invoke) {
         try {
             await invoke('init_supabase_from_config').catch(() => {});
-            await invoke('save_socio_anagrafica', { anagrafica: payload });
-            anagraficaTrasportatoCorrente = { ...anagraficaTrasportatoCorrente, notaaggiuntiva: nuovaNota };
+            const salvato = await invoke('save_socio_anagrafica', { anagrafica: payload });
+            anagraficaTrasportatoCorrente = {
+                ...anagraficaTrasportatoCorrente,
+                notaaggiuntiva: nuovaNota,
+                versione: salvato?.versione || ''
+            };
 
             const idx = allTesserati.findIndex(t => String(t.idsocio || '').trim() === idsocio);
             if (idx >= 0) {
@@ -434,7 +438,9 @@ async function salvaNotaAggiuntivaTrasportato() {
             }
         } catch (error) {
             console.error('Salvataggio note aggiuntive:', error);
-            alert('Errore nel salvataggio delle note aggiuntive sul database.');
+            alert(error?.code === 'concurrent_update'
+                ? `${error.message}. Riseleziona il socio per vedere le modifiche prima di salvare la nota.`
+                : 'Errore nel salvataggio delle note aggiuntive sul database.');
             return;
         }
     } else {
@@ -1146,16 +1152,23 @@ async function salvaNoteMezzo() {
     if (isTauri() && invoke) {
         try {
             await invoke('init_supabase_from_config').catch(() => {});
-            await invoke('save_automezzo', { automezzo: payload });
-            automezzoSelezionatoCorrente = { ...automezzo, note_mezzo: nuovaNota };
+            const salvato = await invoke('save_automezzo', { automezzo: payload });
+            automezzoSelezionatoCorrente = {
+                ...automezzo,
+                note_mezzo: nuovaNota,
+                versione: salvato?.versione || ''
+            };
 
             const idx = allAutomezzi.findIndex(m => m.id === automezzo.id);
             if (idx >= 0) {
                 allAutomezzi[idx].note_mezzo = nuovaNota;
+                allAutomezzi[idx].versione = automezzoSelezionatoCorrente.versione;
             }
         } catch (error) {
             console.error('Salvataggio note mezzo:', error);
-            alert('Errore nel salvataggio delle note mezzo sul database.');
+            alert(error?.code === 'concurrent_update'
+                ? `${error.message}. Riseleziona il mezzo per vedere le modifiche prima di salvare le note.`
+                : 'Errore nel salvataggio delle note mezzo sul database.');
             return;
         }
     } else {
@@ -1506,6 +1519,13 @@ function setupPagamentoQuickButtons() {
         await apriCalcolaTariffa({
             getInvoke: () => invoke,
             isTauri,
+            dataPrelievo: getValore('ns-data-prelievo'),
+            contesto: {
+                idsocio: getValore('ns-idsocio'),
+                richiedente: getValore('ns-richiedente'),
+                tipo_servizio: getValore('ns-tipo-servizio'),
+                carrozzina: getValore('ns-carrozzina')
+            },
             onConferma: (totale, dettaglio) => {
                 avvisaSeTrattaRimossa(rimuoviTrattaDalForm('ns-tratta-fuori-asti'));
                 impostaPagamentoEuro(totale);
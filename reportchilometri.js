@@ -1,4 +1,6 @@
 // Riepilogo Chilometri Percorsi — popup da Elenco Operatori
+import { servizioSvolto } from './stato-servizio.js';
+
 let invoke;
 
 const MESI_IT = [
@@ -216,19 +218,13 @@ async function fetchServiziAnno(anno) {
         return [];
     }
     await invoke('init_supabase_from_config').catch(() => {});
-    const list = await invoke('get_all_servizi_completi', {
-        anno,
-        tuttiAnni: false
-    });
+    const list = await invoke('get_all_servizi_completi', { anno });
     serviziAnnoCache[anno] = Array.isArray(list) ? list : [];
     return serviziAnnoCache[anno];
 }
 
 function servizioEseguito(servizio) {
-    const stato = String(servizio?.stato_servizio || '')
-        .trim()
-        .toUpperCase();
-    return stato === 'ESEGUITO';
+    return servizioSvolto(servizio?.stato_servizio);
 }
 
 function nelPeriodo(servizio) {
@@ -539,7 +535,7 @@ async function caricaERender() {
         if (loading) loading.hidden = true;
         if (errore) {
             errore.hidden = false;
-            errore.textContent = `Errore: ${error}`;
+            errore.textContent = `Errore: ${error?.message || error}`;
         }
     }
 }
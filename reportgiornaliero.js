@@ -74,10 +74,7 @@ async function fetchServiziAnno(anno) {
     }
     try {
         await invoke('init_supabase_from_config').catch(() => {});
-        const servizi = await invoke('get_all_servizi_completi', {
-            anno,
-            tuttiAnni: false
-        });
+        const servizi = await invoke('get_all_servizi_completi', { anno });
         serviziAnnoCache[anno] = Array.isArray(servizi) ? servizi : [];
         return serviziAnnoCache[anno];
     } catch (error) {
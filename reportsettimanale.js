@@ -106,7 +106,7 @@ async function fetchServiziAnno(anno) {
         return [];
     }
     await invoke('init_supabase_from_config').catch(() => {});
-    const servizi = await invoke('get_all_servizi_completi', { anno, tuttiAnni: false });
+    const servizi = await invoke('get_all_servizi_completi', { anno });
     serviziAnnoCache[anno] = Array.isArray(servizi) ? servizi : [];
     return serviziAnnoCache[anno];
 }
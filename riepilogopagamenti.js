@@ -6,6 +6,7 @@ import {
     apriModalModifica
 } from './modifica-servizio.js';
 import { richiediSessione } from './auth-session.js';
+import { servizioSvolto } from './stato-servizio.js';
 import { puoVedereSidebar } from './sidebar-permessi.js';
 import {
     generaPdfRiepilogoPagamenti,
@@ -385,14 +386,16 @@ async function caricaDati() {
 
         await invoke('init_supabase_from_config').catch(() => {});
         await caricaLookupFiltri();
-        const list = await invoke('get_all_servizi_completi', {
-            anno: null,
-            tuttiAnni: true
-        });
-        // Riepilogo pagamenti: solo servizi già eseguiti
-        tuttiServizi = (Array.isArray(list) ? list : []).filter((s) =>
-            normalizza(s.stato_servizio) === 'ESEGUITO'
-        );
+        // Archivio completo a pagine: si tengono solo i servizi già svolti
+        // (COMPLETATO o ESEGUITO) del riepilogo pagamenti
+        tuttiServizi = [];
+        let dopo = null;
+        do {
+            const pagina = await invoke('get_servizi_completi_pagina', { dopo, limite: 1000 });
+            const servizi = Array.isArray(pagina?.servizi) ? pagina.servizi : [];
+            tuttiServizi.push(...servizi.filter((s) => servizioSvolto(s.stato_servizio)));
+            dopo = pagina?.prossimo ?? null;
+        } while (dopo !== null);
         popolaSelectAnni();
         if (loading) loading.hidden = true;
         applicaFiltriERender();
@@ -401,7 +404,7 @@ async function caricaDati() {
         if (loading) loading.hidden = true;
         if (errore) {
             errore.hidden = false;
-            errore.textContent = `Errore: ${error}`;
+            errore.textContent = `Errore: ${error?.message || error}`;
         }
     }
 }
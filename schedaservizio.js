@@ -271,7 +271,7 @@ async function caricaScheda() {
         if (loading) loading.hidden = true;
         if (errore) {
             errore.hidden = false;
-            errore.textContent = `Errore: ${error}`;
+            errore.textContent = `Errore: ${error?.message || error}`;
         }
     }
 }
@@ -66,13 +66,33 @@ export function sidebarMenuToJson(menu) {
     return JSON.stringify(normalizzaSidebarMenu(menu));
 }
 
+/** Capacità che serve per ogni voce, per gli utenti con un ruolo */
+export const CAPACITA_VOCE = {
+    calendario_servizi: 'servizi.leggi',
+    nuovo_servizio: 'servizi.crea',
+    elenco_servizi: 'servizi.leggi',
+    report_giorno: 'servizi.leggi',
+    report_settimanale: 'servizi.leggi',
+    elenco_soci: 'soci.leggi',
+    tratte_fuori_asti: 'tratte.leggi',
+    elenco_mezzi: 'mezzi.leggi',
+    riepilogo_incassi: 'incassi.vedi',
+    elenco_operatori: 'operatori.vedi',
+    riepilogo_pagamenti: 'incassi.vedi'
+};
+
 /**
  * True se l'utente può vedere la voce.
- * Admin: sempre sì. Altrimenti dipende da Programma + flag menu.
+ * Admin: sempre sì. Con un ruolo: la capacità della voce.
+ * Altrimenti dipende da Programma + flag menu.
  */
 export function puoVedereSidebar(key, sessione) {
     if (!sessione) return false;
     if (sessione.is_admin === true) return true;
+    if (sessione.ruolo) {
+        const capacita = Array.isArray(sessione.capacita) ? sessione.capacita : [];
+        return capacita.includes(CAPACITA_VOCE[key]);
+    }
     if (sessione.programma !== true) return false;
     const menu = normalizzaSidebarMenu(sessione.sidebar_menu);
     return menu[key] === true;
@@ -0,0 +1,172 @@
+// Registro delle modifiche (Audit_supa): una riga per ogni creazione, modifica o
+// eliminazione fatta dall'app, con utente, ora e differenze campo per campo.
+// Le righe si aggiungono e basta: CreatoDa/ModificatoDa sul record dicono solo
+// l'ultimo autore, qui resta tutta la storia. Offline la voce passa dalla outbox
+// come le altre scritture (chiave generata qui, il rinvio non la duplica).
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::auth::SessioneAuth;
+use crate::supabase::{codice_casuale, json_to_string};
+
+pub const AZIONE_INSERT: &str = "insert";
+pub const AZIONE_UPDATE: &str = "update";
+pub const AZIONE_DELETE: &str = "delete";
+/// Servizio rimesso in tabella dal cestino
+pub const AZIONE_RIPRISTINO: &str = "restore";
+/// Eliminazione definitiva dal cestino
+pub const AZIONE_PULIZIA: &str = "purge";
+
+/// Colonne di servizio che cambiano a ogni salvataggio: nel diff sarebbero solo rumore
+const CAMPI_IGNORATI: &[&str] = &[
+    "creatoda",
+    "creato_da",
+    "modificatoda",
+    "modificato_da",
+    "created",
+    "created_at",
+    "modificated",
+    "modified",
+    "updated_at",
+    "versione",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CampoModificato {
+    pub campo: String,
+    pub prima: Value,
+    pub dopo: Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VoceAudit {
+    /// Chiave univoca generata dall'app (rende idempotente il rinvio offline)
+    pub chiave: String,
+    /// Momento della modifica (RFC 3339, UTC)
+    pub quando: String,
+    pub utente_id: String,
+    pub utente: String,
+    /// Tabella logica: servizi, tesserati, automezzi, tratte, impostazioni
+    pub entita: String,
+    pub record_id: String,
+    /// insert / update / delete (come gli eventi di ModificaTabella), restore / purge
+    pub azione: String,
+    pub modifiche: Vec<CampoModificato>,
+}
+
+impl VoceAudit {
+    pub fn nuova(
+        entita: &str,
+        record_id: &str,
+        azione: &str,
+        modifiche: Vec<CampoModificato>,
+        sessione: Option<&SessioneAuth>,
+    ) -> Self {
+        Self {
+            chiave: codice_casuale(24),
+            quando: chrono::Utc::now().to_rfc3339(),
+            utente_id: sessione.map(|s| s.user_id.clone()).unwrap_or_default(),
+            utente: sessione.map(|s| s.email.clone()).unwrap_or_default(),
+            entita: entita.to_string(),
+            record_id: record_id.to_string(),
+            azione: azione.to_string(),
+            modifiche,
+        }
+    }
+
+    /// Riga da inserire in Audit_supa
+    pub fn riga(&self) -> serde_json::Map<String, Value> {
+        let mut body = serde_json::Map::new();
+        body.insert("chiave".to_string(), serde_json::json!(self.chiave));
+        body.insert("quando".to_string(), serde_json::json!(self.quando));
+        body.insert("utente_id".to_string(), serde_json::json!(self.utente_id));
+        body.insert("utente".to_string(), serde_json::json!(self.utente));
+        body.insert("entita".to_string(), serde_json::json!(self.entita));
+        body.insert("record_id".to_string(), serde_json::json!(self.record_id));
+        body.insert("azione".to_string(), serde_json::json!(self.azione));
+        body.insert(
+            "modifiche".to_string(),
+            serde_json::to_value(&self.modifiche).unwrap_or(Value::Array(Vec::new())),
+        );
+        body
+    }
+
+    pub fn da_riga(row: &Value) -> Option<Self> {
+        let testo = |k: &str| row.get(k).map(json_to_string).unwrap_or_default();
+        let chiave = testo("chiave");
+        if chiave.is_empty() {
+            return None;
+        }
+        // modifiche è jsonb; accettato anche come testo
+        let modifiche = match row.get("modifiche") {
+            Some(Value::String(s)) => serde_json::from_str(s).unwrap_or_default(),
+            Some(v) => serde_json::from_value(v.clone()).unwrap_or_default(),
+            None => Vec::new(),
+        };
+        Some(Self {
+            chiave,
+            quando: testo("quando"),
+            utente_id: testo("utente_id"),
+            utente: testo("utente"),
+            entita: testo("entita"),
+            record_id: testo("record_id"),
+            azione: testo("azione"),
+            modifiche,
+        })
+    }
+}
+
+/// Stesso valore per il database: null e "" coincidono, 12 e "12" pure
+fn stesso_valore(a: &Value, b: &Value) -> bool {
+    let testo = |v: &Value| match v {
+        Value::Null => String::new(),
+        v => json_to_string(v).trim().to_string(),
+    };
+    testo(a) == testo(b)
+}
+
+fn vuoto(v: &Value) -> bool {
+    stesso_valore(v, &Value::Null)
+}
+
+fn ignorato(campo: &str) -> bool {
+    CAMPI_IGNORATI.contains(&campo.to_lowercase().as_str())
+}
+
+/// Differenze campo per campo. In modifica `dopo` è il body della PATCH (solo i
+/// campi inviati); in creazione manca `prima`, in eliminazione manca `dopo`.
+pub fn differenze(prima: Option<&Value>, dopo: Option<&Value>) -> Vec<CampoModificato> {
+    let campo = |nome: &str, prima: &Value, dopo: &Value| CampoModificato {
+        campo: nome.to_string(),
+        prima: prima.clone(),
+        dopo: dopo.clone(),
+    };
+    let mut out = Vec::new();
+    match (prima.and_then(|v| v.as_object()), dopo.and_then(|v| v.as_object())) {
+        (Some(prima), Some(dopo)) => {
+            for (nome, nuovo) in dopo {
+                let vecchio = prima.get(nome).unwrap_or(&Value::Null);
+                if !ignorato(nome) && !stesso_valore(vecchio, nuovo) {
+                    out.push(campo(nome, vecchio, nuovo));
+                }
+            }
+        }
+        (None, Some(dopo)) => {
+            for (nome, nuovo) in dopo {
+                if !ignorato(nome) && !vuoto(nuovo) {
+                    out.push(campo(nome, &Value::Null, nuovo));
+                }
+            }
+        }
+        (Some(prima), None) => {
+            for (nome, vecchio) in prima {
+                if !ignorato(nome) && !vuoto(vecchio) {
+                    out.push(campo(nome, vecchio, &Value::Null));
+                }
+            }
+        }
+        (None, None) => {}
+    }
+    out.sort_by(|a, b| a.campo.cmp(&b.campo));
+    out
+}
@@ -0,0 +1,150 @@
+// Sessione Supabase Auth dell'utente collegato. Il suo JWT accompagna tutte le
+// richieste REST e Realtime, così le policy RLS con auth.uid() valgono anche per
+// l'app desktop; la chiave di servizio resta alle sole operazioni di
+// amministrazione. La sessione è salvata su disco (si resta collegati al riavvio)
+// e rinnovata con il refresh token prima che l'access token scada.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::error::ApiError;
+
+/// Evento Tauri inviato a tutte le finestre quando la sessione non è più rinnovabile
+pub const EVENTO_SESSIONE_SCADUTA: &str = "auth-session-expired";
+
+/// Anticipo sul rinnovo: un token che scade entro questo tempo viene rinnovato
+pub const ANTICIPO_RINNOVO_SECS: i64 = 120;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessioneAuth {
+    pub user_id: String,
+    pub email: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Scadenza dell'access token (secondi Unix)
+    pub expires_at: i64,
+}
+
+/// Sessione come la vedono le finestre: niente token
+#[derive(Debug, Clone, Serialize)]
+pub struct InfoSessione {
+    pub user_id: String,
+    pub email: String,
+    pub expires_at: i64,
+}
+
+impl SessioneAuth {
+    /// Legge la risposta di /auth/v1/token (grant password o refresh_token)
+    pub fn da_risposta(body: &Value) -> Result<Self, ApiError> {
+        let testo = |v: Option<&Value>| {
+            v.and_then(|v| v.as_str())
+                .map(|s| s.trim().to_string())
+                .unwrap_or_default()
+        };
+        let user = body.get("user");
+        let sessione = Self {
+            user_id: testo(user.and_then(|u| u.get("id"))),
+            email: testo(user.and_then(|u| u.get("email"))),
+            access_token: testo(body.get("access_token")),
+            refresh_token: testo(body.get("refresh_token")),
+            expires_at: body
+                .get("expires_at")
+                .and_then(|v| v.as_i64())
+                .unwrap_or_else(|| {
+                    let durata = body.get("expires_in").and_then(|v| v.as_i64()).unwrap_or(3600);
+                    chrono::Utc::now().timestamp() + durata
+                }),
+        };
+        if sessione.user_id.is_empty() || sessione.access_token.is_empty() {
+            return Err("Risposta login incompleta da Supabase Auth".into());
+        }
+        Ok(sessione)
+    }
+
+    pub fn info(&self) -> InfoSessione {
+        InfoSessione {
+            user_id: self.user_id.clone(),
+            email: self.email.clone(),
+            expires_at: self.expires_at,
+        }
+    }
+
+    pub fn scade_entro(&self, secondi: i64) -> bool {
+        self.expires_at - chrono::Utc::now().timestamp() <= secondi
+    }
+}
+
+/// Sessione corrente condivisa da tutti i client Supabase (il client viene
+/// ricreato a ogni init_supabase_from_config, la sessione no)
+pub struct AuthStore {
+    sessione: RwLock<Option<SessioneAuth>>,
+    path: Option<PathBuf>,
+    /// Un solo rinnovo alla volta: il refresh token vale una volta sola
+    pub rinnovo: tokio::sync::Mutex<()>,
+}
+
+impl Default for AuthStore {
+    /// Sessione solo in memoria (nessun file)
+    fn default() -> Self {
+        Self {
+            sessione: RwLock::new(None),
+            path: None,
+            rinnovo: tokio::sync::Mutex::new(()),
+        }
+    }
+}
+
+impl AuthStore {
+    /// Apre sessione.json nella cartella dati dell'utente (accanto alla copia offline)
+    pub fn open_default() -> Self {
+        let path = tauri::api::path::data_dir()
+            .map(|d| d.join("com.auser.gestione-operativa").join("sessione.json"));
+        let sessione = path
+            .as_ref()
+            .and_then(|p| std::fs::read_to_string(p).ok())
+            .and_then(|testo| serde_json::from_str::<SessioneAuth>(&testo).ok());
+        if let Some(s) = &sessione {
+            println!("🔐 Sessione Supabase ripresa per {}", s.email);
+        }
+        Self {
+            sessione: RwLock::new(sessione),
+            path,
+            rinnovo: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn get(&self) -> Option<SessioneAuth> {
+        self.sessione.read().ok().and_then(|s| s.clone())
+    }
+
+    pub fn access_token(&self) -> Option<String> {
+        self.sessione
+            .read()
+            .ok()
+            .and_then(|s| s.as_ref().map(|s| s.access_token.clone()))
+    }
+
+    /// Sostituisce la sessione (None = logout) e la salva su disco
+    pub fn set(&self, sessione: Option<SessioneAuth>) {
+        if let Some(path) = &self.path {
+            let esito = match &sessione {
+                Some(s) => path
+                    .parent()
+                    .map(std::fs::create_dir_all)
+                    .unwrap_or(Ok(()))
+                    .and_then(|_| {
+                        std::fs::write(path, serde_json::to_string(s).unwrap_or_default())
+                    }),
+                None if path.exists() => std::fs::remove_file(path),
+                None => Ok(()),
+            };
+            if let Err(e) = esito {
+                println!("⚠️ Sessione non salvata su {:?}: {}", path, e);
+            }
+        }
+        if let Ok(mut guard) = self.sessione.write() {
+            *guard = sessione;
+        }
+    }
+}
@@ -0,0 +1,185 @@
+// Chiusura di un servizio (completa_servizio): dagli orari e dai km letti sul
+// contachilometri si ricavano km percorsi e tempo, invece di farli calcolare a
+// mano all'operatore. Le letture si confrontano con il rientro del viaggio
+// precedente dello stesso mezzo; l'importo lo calcola tariffe.rs dalla tratta o
+// dalla tariffa salvate nelle note fine servizio.
+use serde::Serialize;
+
+use crate::error::ApiError;
+
+/// Oltre questa distanza in un solo servizio la lettura è quasi certamente sbagliata
+pub const KM_MASSIMI_SERVIZIO: f64 = 2000.0;
+
+/// Viaggio precedente dello stesso mezzo, per il controllo del contachilometri
+#[derive(Debug, Clone, Serialize)]
+pub struct ViaggioPrecedente {
+    pub idservizio: u32,
+    pub data: String,
+    pub km_rientro: f64,
+}
+
+/// Km come li scrive l'operatore ("12345", "12345,5"); vuoto = non letto
+pub fn parse_km(valore: &str) -> Option<f64> {
+    let valore = valore.trim().replace(',', ".");
+    if valore.is_empty() {
+        return None;
+    }
+    valore.parse::<f64>().ok().filter(|km| km.is_finite() && *km >= 0.0)
+}
+
+/// Km da salvare: interi senza decimali, altrimenti due cifre (come il form)
+pub fn formatta_km(km: f64) -> String {
+    if km.fract() == 0.0 {
+        format!("{}", km as i64)
+    } else {
+        format!("{:.2}", km)
+    }
+}
+
+/// "08:30" / "08:30:00" → minuti dalla mezzanotte
+pub fn parse_ora(valore: &str) -> Option<u32> {
+    let mut parti = valore.trim().split(':');
+    let ore = parti.next()?.trim().parse::<u32>().ok()?;
+    let minuti = parti.next()?.trim().parse::<u32>().ok()?;
+    if ore > 23 || minuti > 59 {
+        return None;
+    }
+    Some(ore * 60 + minuti)
+}
+
+/// Durata fra partenza e rientro in "HH:MM" (colonna Tempo); un rientro prima
+/// della partenza vuol dire dopo mezzanotte
+pub fn durata(partenza: &str, rientro: &str) -> Result<String, ApiError> {
+    let inizio = parse_ora(partenza)
+        .ok_or_else(|| ApiError::from(format!("Ora di partenza non valida: '{}'", partenza.trim())))?;
+    let fine = parse_ora(rientro)
+        .ok_or_else(|| ApiError::from(format!("Ora di rientro non valida: '{}'", rientro.trim())))?;
+    let minuti = if fine >= inizio { fine - inizio } else { fine + 24 * 60 - inizio };
+    Ok(format!("{:02}:{:02}", minuti / 60, minuti % 60))
+}
+
+/// Km percorsi dalle due letture, controllate fra loro e con il rientro del
+/// viaggio precedente dello stesso mezzo
+pub fn km_percorsi(
+    km_uscita: f64,
+    km_rientro: f64,
+    precedente: Option<&ViaggioPrecedente>,
+) -> Result<f64, ApiError> {
+    if km_rientro < km_uscita {
+        return Err(format!(
+            "Km al rientro ({}) minori dei km all'uscita ({})",
+            formatta_km(km_rientro),
+            formatta_km(km_uscita)
+        )
+        .into());
+    }
+    let km = km_rientro - km_uscita;
+    if km > KM_MASSIMI_SERVIZIO {
+        return Err(format!(
+            "{} km in un solo servizio: controlla le letture del contachilometri",
+            formatta_km(km)
+        )
+        .into());
+    }
+    if let Some(p) = precedente {
+        if km_uscita < p.km_rientro {
+            return Err(ApiError::Conflict {
+                code: Some("contachilometri_incoerente".to_string()),
+                message: format!(
+                    "Km all'uscita ({}) minori del rientro del servizio {} del {} ({})",
+                    formatta_km(km_uscita),
+                    p.idservizio,
+                    p.data,
+                    formatta_km(p.km_rientro)
+                ),
+            });
+        }
+    }
+    Ok(km)
+}
+
+/// Stato incasso dopo la chiusura: resta incassato se lo era già
+pub fn stato_incasso(attuale: &str, incassato: bool) -> String {
+    if incassato || attuale.trim().eq_ignore_ascii_case("INCASSATO") {
+        "INCASSATO".to_string()
+    } else {
+        "DA INCASSARE".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn precedente(km_rientro: f64) -> ViaggioPrecedente {
+        ViaggioPrecedente {
+            idservizio: 7,
+            data: "02/03/2026".to_string(),
+            km_rientro,
+        }
+    }
+
+    #[test]
+    fn letture_del_contachilometri() {
+        assert_eq!(parse_km(" 12345,5 "), Some(12345.5));
+        assert_eq!(parse_km(""), None);
+        assert_eq!(parse_km("-3"), None);
+        assert_eq!(parse_km("abc"), None);
+        assert_eq!(formatta_km(42.0), "42");
+        assert_eq!(formatta_km(42.5), "42.50");
+    }
+
+    #[test]
+    fn km_percorsi_controlla_le_letture() {
+        assert_eq!(km_percorsi(1000.0, 1042.0, None).unwrap(), 42.0);
+        assert_eq!(km_percorsi(1000.0, 1000.0, None).unwrap(), 0.0);
+        // Uscita uguale al rientro precedente: il mezzo non si è mosso nel frattempo
+        assert_eq!(
+            km_percorsi(1000.0, 1042.0, Some(&precedente(1000.0))).unwrap(),
+            42.0
+        );
+
+        let errore = km_percorsi(1042.0, 1000.0, None).unwrap_err().to_string();
+        assert!(errore.contains("minori dei km all'uscita"), "{}", errore);
+
+        let errore = km_percorsi(1000.0, 1000.0 + KM_MASSIMI_SERVIZIO + 1.0, None).unwrap_err();
+        assert!(errore.to_string().contains("controlla le letture"));
+
+        match km_percorsi(990.0, 1042.0, Some(&precedente(1000.0))) {
+            Err(ApiError::Conflict { code, message }) => {
+                assert_eq!(code.as_deref(), Some("contachilometri_incoerente"));
+                assert!(message.contains("servizio 7 del 02/03/2026"), "{}", message);
+            }
+            altro => panic!("atteso conflitto contachilometri, ottenuto {:?}", altro),
+        }
+    }
+
+    #[test]
+    fn durata_anche_oltre_mezzanotte() {
+        let casi = [
+            ("08:30", "10:15", "01:45"),
+            ("08:30:00", "08:30", "00:00"),
+            ("23:10", "01:05", "01:55"),
+            ("00:00", "23:59", "23:59"),
+        ];
+        for (partenza, rientro, atteso) in casi {
+            assert_eq!(
+                durata(partenza, rientro).unwrap(),
+                atteso,
+                "{} → {}",
+                partenza,
+                rientro
+            );
+        }
+        assert!(durata("24:00", "01:00").is_err());
+        assert!(durata("08:30", "").is_err());
+        assert!(durata("8.30", "10:00").is_err());
+    }
+
+    #[test]
+    fn incasso_resta_incassato() {
+        assert_eq!(stato_incasso("incassato", false), "INCASSATO");
+        assert_eq!(stato_incasso("", true), "INCASSATO");
+        assert_eq!(stato_incasso("DA INCASSARE", false), "DA INCASSARE");
+    }
+}
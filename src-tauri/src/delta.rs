@@ -0,0 +1,393 @@
+// Sincronizzazione incrementale delle tabelle grandi (servizi, tesserati).
+// Il primo caricamento di una porzione di tabella è completo; dopo si chiedono a
+// Supabase solo le righe con timestamp di modifica/creazione successivo all'ultimo
+// visto e si uniscono alla cache in memoria. Ogni riga cambiata riceve una
+// revisione crescente, così il frontend può chiedere "le modifiche dalla revisione N".
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::error::ApiError;
+use crate::offline;
+use crate::supabase::{json_to_string, Filter, Query, SupabaseClient};
+
+/// Colonne che cambiano a ogni modifica: senza almeno una di queste la tabella
+/// viene ricaricata per intero
+pub(crate) const COLONNE_MODIFICA: &[&str] = &[
+    "modificated",
+    "Modificated",
+    "MODIFICATED",
+    "modified",
+    "Modified",
+    "updated_at",
+    "Updated_At",
+];
+
+/// Colonne di creazione: servono per le righe nuove mai modificate
+const COLONNE_CREAZIONE: &[&str] = &["created", "Created", "CREATED", "created_at", "Created_At"];
+
+/// Margine sul timestamp per orologi non allineati fra le postazioni:
+/// le righe già viste vengono rilette, ma nessuna modifica va persa
+const MARGINE_OROLOGI: i64 = 15 * 60;
+
+/// Le righe eliminate non hanno timestamp: ogni tanto si confronta l'elenco delle chiavi
+const CONTROLLO_ELIMINATE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Default)]
+pub struct DeltaTable {
+    righe: HashMap<String, (u64, Value)>,
+    eliminate: HashMap<String, u64>,
+    /// Porzioni di tabella caricate per intero (Query::new() = tutta la tabella)
+    porzioni: Vec<Query>,
+    colonne_ts: Vec<String>,
+    ultimo_ts: Option<String>,
+    revisione: u64,
+    /// Revisione dell'ultimo ricaricamento completo: prima di questa le modifiche non sono note
+    revisione_base: u64,
+    ultimo_controllo: Option<Instant>,
+}
+
+/// Modifiche di una porzione di tabella dopo una revisione
+pub struct DeltaChanges {
+    pub modificate: Vec<Value>,
+    pub eliminate: Vec<String>,
+    pub revisione: u64,
+    /// true se `modificate` contiene tutte le righe (nessuna revisione di partenza valida)
+    pub completo: bool,
+}
+
+fn parse_ts(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s)
+        .or_else(|_| DateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f%#z"))
+    {
+        return Some(dt.with_timezone(&Utc));
+    }
+    // timestamp senza fuso: trattato come UTC, basta per confrontarli fra loro
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .map(|n| n.and_utc())
+}
+
+impl DeltaTable {
+    fn copre(&self, porzione: &Query) -> bool {
+        let chiave = porzione.to_query_string();
+        self.porzioni
+            .iter()
+            .any(|p| p.to_query_string().is_empty() || p.to_query_string() == chiave)
+    }
+
+    fn row_key(table_type: &str, row: &Value) -> String {
+        let col = offline::key_column(table_type, Some(row));
+        row.get(col).map(json_to_string).unwrap_or_default()
+    }
+
+    /// Ultimo timestamp di modifica/creazione presente nella riga
+    fn ts_riga(&self, row: &Value) -> Option<DateTime<Utc>> {
+        self.colonne_ts
+            .iter()
+            .filter_map(|c| row.get(c.as_str()).map(json_to_string))
+            .filter_map(|v| parse_ts(&v))
+            .max()
+    }
+
+    fn aggiorna_ultimo_ts(&mut self, rows: &[Value]) {
+        let attuale = self.ultimo_ts.as_deref().and_then(parse_ts);
+        let massimo = rows.iter().filter_map(|r| self.ts_riga(r)).max();
+        if let Some(m) = massimo {
+            if attuale.map(|a| m > a).unwrap_or(true) {
+                self.ultimo_ts = Some(m.to_rfc3339());
+            }
+        }
+    }
+
+    fn rileva_colonne_ts(&mut self, rows: &[Value]) {
+        let prima = match rows.first().and_then(|r| r.as_object()) {
+            Some(obj) => obj,
+            None => return,
+        };
+        if !COLONNE_MODIFICA.iter().any(|c| prima.contains_key(*c)) {
+            self.colonne_ts.clear();
+            return;
+        }
+        self.colonne_ts = COLONNE_MODIFICA
+            .iter()
+            .chain(COLONNE_CREAZIONE.iter())
+            .filter(|c| prima.contains_key(**c))
+            .map(|c| c.to_string())
+            .collect();
+    }
+
+    fn upsert(&mut self, key: String, row: Value) -> bool {
+        if key.is_empty() {
+            return false;
+        }
+        if self.righe.get(&key).map(|(_, v)| v == &row).unwrap_or(false) {
+            return false;
+        }
+        self.revisione += 1;
+        self.eliminate.remove(&key);
+        self.righe.insert(key, (self.revisione, row));
+        true
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        if self.righe.remove(key).is_none() {
+            return false;
+        }
+        self.revisione += 1;
+        self.eliminate.insert(key.to_string(), self.revisione);
+        true
+    }
+
+    /// Una riga appena letta/scritta dall'app: aggiornata subito senza attendere il delta
+    pub fn merge_row(&mut self, table_type: &str, row: Value) {
+        let key = Self::row_key(table_type, &row);
+        self.upsert(key, row);
+    }
+
+    /// Sostituisce una porzione con il risultato di una lettura completa
+    fn carica_porzione(&mut self, table_type: &str, porzione: &Query, rows: Vec<Value>) {
+        let presenti: HashSet<String> = rows.iter().map(|r| Self::row_key(table_type, r)).collect();
+        let sparite: Vec<String> = self
+            .righe
+            .iter()
+            .filter(|(k, (_, v))| !presenti.contains(*k) && porzione.matches(v))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in sparite {
+            self.remove(&key);
+        }
+        for row in rows {
+            let key = Self::row_key(table_type, &row);
+            self.upsert(key, row);
+        }
+    }
+
+    /// Righe della porzione modificate dopo `dal`. Le righe uscite dalla porzione
+    /// (es. data di prelievo spostata a un altro anno) risultano eliminate.
+    pub fn changes_since(&self, porzione: &Query, dal: Option<u64>) -> DeltaChanges {
+        let dal = match dal {
+            Some(d) if d >= self.revisione_base && d <= self.revisione => d,
+            _ => {
+                return DeltaChanges {
+                    modificate: self.rows(porzione),
+                    eliminate: Vec::new(),
+                    revisione: self.revisione,
+                    completo: true,
+                }
+            }
+        };
+
+        let mut modificate = Vec::new();
+        let mut eliminate: Vec<String> = self
+            .eliminate
+            .iter()
+            .filter(|(_, rev)| **rev > dal)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for (key, (rev, row)) in &self.righe {
+            if *rev <= dal {
+                continue;
+            }
+            if porzione.matches(row) {
+                modificate.push(row.clone());
+            } else {
+                eliminate.push(key.clone());
+            }
+        }
+        DeltaChanges {
+            modificate,
+            eliminate,
+            revisione: self.revisione,
+            completo: false,
+        }
+    }
+
+    pub fn rows(&self, porzione: &Query) -> Vec<Value> {
+        self.righe
+            .values()
+            .filter(|(_, row)| porzione.matches(row))
+            .map(|(_, row)| row.clone())
+            .collect()
+    }
+
+    /// Cosa chiedere a Supabase per portare in pari la porzione: caricamento
+    /// completo la prima volta (o se la tabella non ha colonne di modifica),
+    /// poi solo le differenze. Si decide con la cache bloccata, si scarica con
+    /// `PianoSync::scarica` senza lock e si applica con `applica_sync`.
+    pub fn piano_sync(&self, table_type: &str, porzione: &Query) -> PianoSync {
+        if !self.copre(porzione) || self.colonne_ts.is_empty() {
+            return PianoSync {
+                porzione: porzione.clone(),
+                completo: true,
+                modifiche_dal: None,
+                colonne_ts: Vec::new(),
+                controllo_eliminate: None,
+            };
+        }
+        let scaduto = self
+            .ultimo_controllo
+            .map(|t| t.elapsed() >= CONTROLLO_ELIMINATE)
+            .unwrap_or(true);
+        PianoSync {
+            porzione: porzione.clone(),
+            completo: false,
+            modifiche_dal: self
+                .ultimo_ts
+                .as_deref()
+                .and_then(parse_ts)
+                .map(|t| (t - chrono::Duration::seconds(MARGINE_OROLOGI)).to_rfc3339()),
+            colonne_ts: self.colonne_ts.clone(),
+            controllo_eliminate: scaduto.then(|| {
+                let colonna = offline::key_column(table_type, self.righe.values().next().map(|(_, r)| r));
+                (self.porzioni.clone(), colonna)
+            }),
+        }
+    }
+
+    /// Applica quanto scaricato. Nel frattempo la cache può essere cambiata
+    /// (altre letture, scritture dell'app): le righe già uguali non contano
+    /// come modifica e una riga riletta più vecchia torna in pari al giro dopo,
+    /// grazie al margine sul timestamp.
+    pub fn applica_sync(&mut self, table_type: &str, piano: &PianoSync, dati: DatiSync) {
+        let porzione = &piano.porzione;
+        if piano.completo {
+            let rows = dati.righe;
+            if self.righe.is_empty() {
+                self.revisione_base = self.revisione;
+                self.rileva_colonne_ts(&rows);
+            }
+            if !dati.da_copia_locale {
+                if self.ultimo_ts.is_none() {
+                    self.aggiorna_ultimo_ts(&rows);
+                }
+                if !self.colonne_ts.is_empty() && !self.copre(porzione) {
+                    self.porzioni.push(porzione.clone());
+                }
+                self.ultimo_controllo = Some(Instant::now());
+            }
+            println!(
+                "✓ Delta [{}]: caricamento completo di '{}' ({} righe{})",
+                table_type,
+                porzione,
+                rows.len(),
+                if self.colonne_ts.is_empty() { ", nessuna colonna di modifica" } else { "" }
+            );
+            self.carica_porzione(table_type, porzione, rows);
+            return;
+        }
+
+        if let Some(dal) = &piano.modifiche_dal {
+            let prima = self.revisione;
+            let ricevute = dati.righe.len();
+            if !dati.da_copia_locale {
+                self.aggiorna_ultimo_ts(&dati.righe);
+            }
+            for row in dati.righe {
+                let key = Self::row_key(table_type, &row);
+                if self.porzioni.iter().any(|p| p.matches(&row)) {
+                    self.upsert(key, row);
+                } else {
+                    self.remove(&key);
+                }
+            }
+            println!(
+                "✓ Delta [{}]: {} righe modificate dal {} ({} cambiate)",
+                table_type,
+                ricevute,
+                dal,
+                self.revisione - prima
+            );
+        }
+
+        if let Some(chiavi) = dati.chiavi {
+            for (porzione, chiavi) in chiavi {
+                let sparite: Vec<String> = self
+                    .righe
+                    .iter()
+                    .filter(|(k, (_, v))| !chiavi.contains(*k) && porzione.matches(v))
+                    .map(|(k, _)| k.clone())
+                    .collect();
+                if !sparite.is_empty() {
+                    println!("✓ Delta [{}]: {} righe eliminate su Supabase", table_type, sparite.len());
+                }
+                for key in sparite {
+                    self.remove(&key);
+                }
+            }
+            self.ultimo_controllo = Some(Instant::now());
+        }
+    }
+}
+
+/// Richieste da fare a Supabase per una sincronizzazione (vedi `DeltaTable::piano_sync`)
+pub struct PianoSync {
+    porzione: Query,
+    completo: bool,
+    /// Timestamp da cui chiedere le righe modificate (None = nessuna richiesta)
+    modifiche_dal: Option<String>,
+    colonne_ts: Vec<String>,
+    /// Porzioni di cui confrontare le chiavi con Supabase, e colonna chiave
+    controllo_eliminate: Option<(Vec<Query>, &'static str)>,
+}
+
+/// Risposte di Supabase per un `PianoSync`
+pub struct DatiSync {
+    righe: Vec<Value>,
+    da_copia_locale: bool,
+    /// Chiavi ancora presenti su Supabase per ogni porzione
+    chiavi: Option<Vec<(Query, HashSet<String>)>>,
+}
+
+impl PianoSync {
+    /// Esegue le richieste del piano, senza toccare la cache
+    pub async fn scarica(&self, client: &SupabaseClient, table_type: &str) -> Result<DatiSync, ApiError> {
+        if self.completo {
+            let righe = client.fetch_table(table_type, &self.porzione).await?;
+            return Ok(DatiSync {
+                righe,
+                da_copia_locale: client.is_offline(),
+                chiavi: None,
+            });
+        }
+
+        let righe = match &self.modifiche_dal {
+            Some(dal) => {
+                let filtri = self.colonne_ts.iter().map(|c| Filter::gte(c, dal)).collect();
+                client.fetch_table(table_type, &Query::new().or(filtri)).await?
+            }
+            None => Vec::new(),
+        };
+        let da_copia_locale = client.is_offline();
+
+        // Le righe eliminate non hanno timestamp: si confronta l'elenco delle chiavi
+        let chiavi = match &self.controllo_eliminate {
+            Some((porzioni, colonna)) if !da_copia_locale => {
+                let mut chiavi = Vec::new();
+                for porzione in porzioni {
+                    let presenti: HashSet<String> = client
+                        .fetch_table(table_type, &porzione.clone().select(&[colonna]))
+                        .await?
+                        .iter()
+                        .filter_map(|r| r.get(*colonna).map(json_to_string))
+                        .collect();
+                    chiavi.push((porzione.clone(), presenti));
+                }
+                Some(chiavi)
+            }
+            _ => None,
+        };
+        Ok(DatiSync {
+            righe,
+            da_copia_locale,
+            chiavi,
+        })
+    }
+}
+
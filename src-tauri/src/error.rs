@@ -0,0 +1,299 @@
+// Errori strutturati dei client Supabase / SharePoint.
+// Verso il frontend viene serializzato come oggetto
+// { kind, message, retryable, status?, code?, hint?, details? }
+// (+ fields, modified_by, current_version per le modifiche concorrenti)
+// così la UI distingue "permesso negato" da "connessione assente"
+// senza cercare parole nel testo.
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    /// Server non raggiungibile: DNS, TLS, connessione rifiutata, timeout
+    Network { message: String, timeout: bool },
+    /// Risposta HTTP non riuscita senza un codice errore riconoscibile
+    Http { status: u16, message: String },
+    /// Errore con codice PostgREST / Postgres (es. 22P02, PGRST204) o Auth, con hint
+    Postgrest {
+        status: u16,
+        code: String,
+        message: String,
+        details: Option<String>,
+        hint: Option<String>,
+    },
+    /// Accesso negato: policy RLS (42501) oppure HTTP 401/403 con token valido
+    PermissionDenied {
+        status: u16,
+        code: Option<String>,
+        message: String,
+    },
+    /// Riga o risorsa inesistente
+    NotFound { message: String },
+    /// Vincolo violato (duplicato, chiave esterna) o modifica concorrente
+    Conflict { code: Option<String>, message: String },
+    /// Riga modificata da un altro utente dopo che era stata aperta (versione diversa)
+    ConcurrentUpdate {
+        message: String,
+        /// Colonne cambiate nel frattempo
+        fields: Vec<String>,
+        /// ModificatoDa della riga attuale, se presente
+        modified_by: Option<String>,
+        /// Versione attuale: ricaricando la riga la modifica si può ripetere
+        current_version: String,
+    },
+    /// Configurazione mancante o non valida (chiave API, nome tabella, client non inizializzato)
+    Config { message: String },
+    /// Errore applicativo o di validazione dei dati
+    Other { message: String },
+}
+
+impl ApiError {
+    pub fn network(context: impl Into<String>, err: reqwest::Error) -> Self {
+        ApiError::Network {
+            message: format!("{}: {}", context.into(), err),
+            timeout: err.is_timeout(),
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::NotFound {
+            message: message.into(),
+        }
+    }
+
+    pub fn config(message: impl Into<String>) -> Self {
+        ApiError::Config {
+            message: message.into(),
+        }
+    }
+
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        ApiError::PermissionDenied {
+            status: 403,
+            code: None,
+            message: message.into(),
+        }
+    }
+
+    /// Classifica una risposta HTTP non riuscita. Riconosce il corpo errore di
+    /// PostgREST (`code`/`message`/`details`/`hint`), di Supabase Auth
+    /// (`error_code`/`msg`) e di Microsoft Graph (`error.code`/`error.message`).
+    pub fn from_response(context: &str, status: u16, body: &str) -> Self {
+        let parsed: Option<Value> = serde_json::from_str(body).ok();
+        let root = parsed
+            .as_ref()
+            .map(|v| v.get("error").filter(|e| e.is_object()).unwrap_or(v));
+
+        let text = |keys: &[&str]| -> Option<String> {
+            let obj = root?;
+            keys.iter()
+                .filter_map(|k| obj.get(*k))
+                .find_map(|v| match v {
+                    Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+                    Value::Number(n) => Some(n.to_string()),
+                    _ => None,
+                })
+        };
+
+        let code = text(&["error_code", "code"]);
+        let hint = text(&["hint"]);
+        let details = text(&["details"]);
+        let detail_msg = text(&["message", "msg", "error_description"])
+            .unwrap_or_else(|| body.trim().to_string());
+        let message = format!("{} (HTTP {}): {}", context, status, detail_msg);
+
+        match (status, code.as_deref()) {
+            // Chiave API errata o assente: il gateway Supabase risponde 401 senza codice
+            (401, None) => ApiError::Config {
+                message: format!(
+                    "{}\n\n🔧 SOLUZIONE — la chiave Supabase nel config NON è valida.\n\
+                    Apri Supabase → il tuo progetto → Settings → API Keys:\n\
+                    • SOLUZIONE A (consigliata): copia la Publishable key (inizia con sb_publishable_)\n\
+                      e incollala in config.json come \"publishable_key\": \"sb_publishable_...\"\n\
+                    • SOLUZIONE B: clicca Regenerate sulla anon key e aggiorna \"anon_key\"\n\
+                    • SOLUZIONE C (app desktop): copia Secret key (sb_secret_...) in \"secret_key\"\n\
+                    Poi riavvia l'app (npm run tauri dev).",
+                    message
+                ),
+            },
+            (_, Some("42501")) | (401, _) | (403, _) => ApiError::PermissionDenied {
+                status,
+                code,
+                message: format!("Permesso negato — {}", message),
+            },
+            // Tabella inesistente: nome errato nella sezione tables di config.json
+            (_, Some("42P01")) | (_, Some("PGRST205")) => ApiError::Config { message },
+            (_, Some("PGRST116")) | (404, _) => ApiError::NotFound { message },
+            (_, Some("23505"))
+            | (_, Some("23503"))
+            | (_, Some("email_exists"))
+            | (_, Some("user_already_exists"))
+            | (409, _)
+            | (412, _) => ApiError::Conflict { code, message },
+            (_, Some(c)) => ApiError::Postgrest {
+                status,
+                code: c.to_string(),
+                message,
+                details,
+                hint,
+            },
+            _ => ApiError::Http { status, message },
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApiError::Network { .. } => "network",
+            ApiError::Http { .. } => "http",
+            ApiError::Postgrest { .. } => "postgrest",
+            ApiError::PermissionDenied { .. } => "permission_denied",
+            ApiError::NotFound { .. } => "not_found",
+            ApiError::Conflict { .. } | ApiError::ConcurrentUpdate { .. } => "conflict",
+            ApiError::Config { .. } => "config",
+            ApiError::Other { .. } => "other",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::Network { message, .. }
+            | ApiError::Http { message, .. }
+            | ApiError::Postgrest { message, .. }
+            | ApiError::PermissionDenied { message, .. }
+            | ApiError::NotFound { message }
+            | ApiError::Conflict { message, .. }
+            | ApiError::ConcurrentUpdate { message, .. }
+            | ApiError::Config { message }
+            | ApiError::Other { message } => message,
+        }
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ApiError::Http { status, .. }
+            | ApiError::Postgrest { status, .. }
+            | ApiError::PermissionDenied { status, .. } => Some(*status),
+            ApiError::NotFound { .. } => Some(404),
+            ApiError::ConcurrentUpdate { .. } => Some(409),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            ApiError::Postgrest { code, .. } => Some(code),
+            ApiError::PermissionDenied { code, .. } | ApiError::Conflict { code, .. } => {
+                code.as_deref()
+            }
+            ApiError::ConcurrentUpdate { .. } => Some("concurrent_update"),
+            _ => None,
+        }
+    }
+
+    /// Vale la pena ripetere la stessa richiesta più tardi?
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::Network { .. } => true,
+            ApiError::Http { status, .. } => matches!(status, 408 | 425 | 429 | 500..=599),
+            // 40001 serialization_failure, 40P01 deadlock, 57014 statement timeout
+            ApiError::Postgrest { status, code, .. } => {
+                *status >= 500 || matches!(code.as_str(), "40001" | "40P01" | "57014")
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Network {
+                message,
+                timeout: true,
+            } => write!(f, "Server non risponde (timeout) — {}", message),
+            ApiError::Network { message, .. } => write!(f, "Connessione assente — {}", message),
+            ApiError::Postgrest {
+                code, message, hint, ..
+            } => {
+                write!(f, "{} [{}]", message, code)?;
+                if let Some(h) = hint {
+                    write!(f, " — suggerimento: {}", h)?;
+                }
+                Ok(())
+            }
+            other => f.write_str(other.message()),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<String> for ApiError {
+    fn from(message: String) -> Self {
+        ApiError::Other { message }
+    }
+}
+
+impl From<&str> for ApiError {
+    fn from(message: &str) -> Self {
+        ApiError::Other {
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ApiErrorPayload<'a> {
+    kind: &'static str,
+    message: String,
+    retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<&'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified_by: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_version: Option<&'a str>,
+}
+
+impl Serialize for ApiError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (hint, details) = match self {
+            ApiError::Postgrest { hint, details, .. } => (hint.as_deref(), details.as_deref()),
+            _ => (None, None),
+        };
+        let (fields, modified_by, current_version) = match self {
+            ApiError::ConcurrentUpdate {
+                fields,
+                modified_by,
+                current_version,
+                ..
+            } => (
+                Some(fields.as_slice()),
+                modified_by.as_deref(),
+                Some(current_version.as_str()),
+            ),
+            _ => (None, None, None),
+        };
+        ApiErrorPayload {
+            kind: self.kind(),
+            message: self.to_string(),
+            retryable: self.is_retryable(),
+            status: self.status(),
+            code: self.code(),
+            hint,
+            details,
+            fields,
+            modified_by,
+            current_version,
+        }
+        .serialize(serializer)
+    }
+}
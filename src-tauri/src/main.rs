@@ -2,20 +2,50 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tokio::sync::Mutex;
 use std::sync::OnceLock;
 use std::fs;
 
+mod audit;
+mod auth;
+mod completamento;
+mod delta;
+mod error;
+mod mascheramento;
+mod offline;
+mod permessi;
+mod realtime;
+mod regole_tariffa;
+mod ricorrenze;
 mod sharepoint;
+mod stato_servizio;
 mod supabase;
+mod tariffe;
+use audit::VoceAudit;
+use completamento::ViaggioPrecedente;
+use auth::{AuthStore, InfoSessione, ANTICIPO_RINNOVO_SECS, EVENTO_SESSIONE_SCADUTA};
+use delta::DeltaTable;
+use error::ApiError;
+use mascheramento::{Mascherabile, Mascheramento};
+use permessi::{Capacita, CapacitaInfo, Ruolo, RUOLO_AMMINISTRATORE, TUTTE_LE_CAPACITA};
+use offline::{OfflineStatus, OfflineStore, OutboxSyncReport};
+use realtime::ModificaTabella;
 use sharepoint::{SharePointClient, SharePointConfig};
+use regole_tariffa::RegolaTariffa;
+use ricorrenze::{AmbitoSerie, DataSaltata, RegolaRicorrenza};
+use stato_servizio::{DatiStato, StatoInfo, StatoServizio};
+use tariffe::{
+    ComponentiTratta, ParametriTariffa, Preventivo, RichiestaPreventivo, StoricoTariffe,
+    VersioneTariffa,
+};
 use supabase::{
-    SupabaseClient, SupabaseConfig, SupabaseTablesConfig, format_date_iso, format_time_iso,
-    get_bool_field, get_field, json_to_string,
+    SupabaseClient, SupabaseConfig, SupabaseHttpConfig, SupabaseTablesConfig, format_date_iso, format_time_iso,
+    get_bool_field, get_field, json_to_string, versione_riga, PrenotazioneIdSocio, Query,
 };
 
 use chrono::{Local, Datelike};
+use tauri::Manager;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct AppConfig {
@@ -39,6 +69,8 @@ struct SupabaseConfigSection {
     #[serde(alias = "table_soci")]
     table_tesserati: Option<String>,
     tables: Option<SupabaseTablesConfigSection>,
+    /// Timeout e ripetizioni automatiche (facoltativo: valori predefiniti se assente)
+    http: Option<SupabaseHttpConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -62,6 +94,27 @@ struct SupabaseTablesConfigSection {
     /// Storico annuale: tabella dedicata (Tesseramenti_supa), più righe per IdSocio
     #[serde(default)]
     tesseramenti: Option<String>,
+    /// Prenotazioni temporanee dei nuovi IdSocio (IdSocio_prenotazioni_supa)
+    #[serde(default)]
+    prenotazioni_idsocio: Option<String>,
+    /// Ruoli personalizzati (Ruoli_supa)
+    #[serde(default)]
+    ruoli: Option<String>,
+    /// Registro delle modifiche (Audit_supa)
+    #[serde(default)]
+    audit: Option<String>,
+    /// Servizi eliminati in attesa di pulizia (CestinoServizi_supa)
+    #[serde(default)]
+    cestino_servizi: Option<String>,
+    /// Versioni della tariffa con data di validità (Tariffe_supa)
+    #[serde(default)]
+    tariffe: Option<String>,
+    /// Regole della tariffa per socio, richiedente e tipo servizio (RegoleTariffa_supa)
+    #[serde(default)]
+    regole_tariffa: Option<String>,
+    /// Serie di servizi ricorrenti (SerieServizi_supa)
+    #[serde(default)]
+    serie_servizi: Option<String>,
     /// Legacy: non esiste tabella operatori — filtrare tesserati.Operatore
     #[serde(alias = "operatori")]
     operatori: Option<String>,
@@ -147,10 +200,69 @@ fn get_sharepoint_client() -> &'static Mutex<Option<SharePointClient>> {
     SHAREPOINT_CLIENT.get_or_init(|| Mutex::new(None))
 }
 
+// Copia locale SQLite + outbox, aperta una volta sola e condivisa dal client Supabase
+static OFFLINE_STORE: OnceLock<Option<std::sync::Arc<OfflineStore>>> = OnceLock::new();
+
+fn get_offline_store() -> Option<std::sync::Arc<OfflineStore>> {
+    OFFLINE_STORE
+        .get_or_init(|| OfflineStore::open_default().map(std::sync::Arc::new))
+        .clone()
+}
+
+// Sessione dell'utente collegato: sopravvive alla ricreazione del client Supabase
+static AUTH_STORE: OnceLock<std::sync::Arc<AuthStore>> = OnceLock::new();
+
+fn get_auth_store() -> std::sync::Arc<AuthStore> {
+    AUTH_STORE
+        .get_or_init(|| std::sync::Arc::new(AuthStore::open_default()))
+        .clone()
+}
+
 fn get_supabase_client() -> &'static Mutex<Option<SupabaseClient>> {
     SUPABASE_CLIENT.get_or_init(|| Mutex::new(None))
 }
 
+// Handle dell'app salvato in setup: serve per emettere eventi a tutte le finestre
+static APP_HANDLE: OnceLock<tauri::AppHandle> = OnceLock::new();
+
+/// Avvisa tutte le finestre aperte che una riga è cambiata (evento `db-change`)
+fn emetti_modifica(modifica: ModificaTabella) {
+    if let Some(app) = APP_HANDLE.get() {
+        if let Err(e) = app.emit_all(realtime::EVENTO_MODIFICA, modifica) {
+            println!("⚠️ Evento {} non inviato: {}", realtime::EVENTO_MODIFICA, e);
+        }
+    }
+}
+
+/// Aggiunge una voce al registro modifiche (Audit_supa). Il salvataggio è già
+/// avvenuto: un errore del registro non lo annulla, la voce resta nella outbox
+/// e viene rinviata (solo senza copia locale l'errore resta nel log).
+async fn registra_audit(
+    client: &SupabaseClient,
+    entita: &str,
+    record_id: &str,
+    azione: &str,
+    prima: Option<&serde_json::Value>,
+    dopo: Option<&serde_json::Value>,
+) {
+    let modifiche = audit::differenze(prima, dopo);
+    if azione == audit::AZIONE_UPDATE && modifiche.is_empty() {
+        return;
+    }
+    let sessione = get_auth_store().get();
+    let voce = VoceAudit::nuova(entita, record_id, azione, modifiche, sessione.as_ref());
+    match client.insert_audit(&voce.riga()).await {
+        Ok(()) => println!(
+            "📝 Audit {} {} {} ({} campi)",
+            azione,
+            entita,
+            record_id,
+            voce.modifiche.len()
+        ),
+        Err(e) => println!("⚠️ Audit {} {} {} non registrato: {}", azione, entita, record_id, e),
+    }
+}
+
 /// Converte una riga Supabase (tabella Tesserati) nel struct Tesserato del frontend
 fn supabase_row_to_tesserato(row: &serde_json::Value) -> Option<Tesserato> {
     let id = row
@@ -477,6 +589,7 @@ fn supabase_row_to_automezzo(row: &serde_json::Value) -> Option<Automezzo> {
         in_servizio: get_field_any(row, &["InServizio", "INSERVIZIO", "In_Servizio"]),
         note_mezzo: get_field_any(row, &["Note_mezzo", "NOTE", "Note_Mezzo"]),
         scadenza_bollo: format_date_iso(&scadenza_bollo_raw),
+        versione: versione_riga(row),
     })
 }
 
@@ -499,46 +612,49 @@ async fn setup_supabase_from_config(config: &AppConfig) {
             return;
         }
 
-        // App desktop Tauri (backend Rust): preferisci secret_key per bypassare RLS
-        // publishable/anon restano soggetti alle policy RLS → spesso 0 righe
-        let api_key = supabase
-            .secret_key
-            .clone()
-            .filter(|k| !k.is_empty() && !k.contains("your-"))
-            .or_else(|| {
-                supabase
-                    .publishable_key
-                    .clone()
-                    .filter(|k| !k.is_empty() && !k.contains("your-"))
-            })
-            .unwrap_or_else(|| supabase.anon_key.clone());
-
-        if api_key.contains("your-anon-key") || api_key.is_empty() {
-            println!("⚠️ Chiave API non configurata in config.json");
-            return;
-        }
+        // Le richieste normali girano col JWT dell'utente collegato (policy RLS):
+        // serve la chiave pubblica. La secret_key resta alle operazioni admin.
+        let configurata = |k: &Option<String>| {
+            k.clone()
+                .filter(|k| !k.is_empty() && !k.contains("your-"))
+        };
+        let service_key = configurata(&supabase.secret_key);
+        let public_key = configurata(&supabase.publishable_key)
+            .or_else(|| configurata(&Some(supabase.anon_key.clone())));
+        let api_key = match (public_key, &service_key) {
+            (Some(k), _) => k,
+            (None, Some(secret)) => {
+                println!("⚠️ Solo secret_key in config.json: aggiungi publishable_key/anon_key per le policy RLS per utente");
+                secret.clone()
+            }
+            (None, None) => {
+                println!("⚠️ Chiave API non configurata in config.json");
+                return;
+            }
+        };
 
-        let key_type = if api_key.starts_with("sb_publishable_") {
-            "publishable"
-        } else if api_key.starts_with("sb_secret_") {
-            "secret"
-        } else if api_key.starts_with("eyJ") {
-            if supabase.secret_key.as_ref().map(|k| k == &api_key).unwrap_or(false) {
-                "service_role-jwt"
+        let key_type = |key: &str| {
+            if key.starts_with("sb_publishable_") {
+                "publishable"
+            } else if key.starts_with("sb_secret_") {
+                "secret"
+            } else if key.starts_with("eyJ") {
+                "jwt"
             } else {
-                "anon-jwt"
+                "custom"
             }
-        } else {
-            "custom"
         };
-        if key_type.starts_with("secret") || key_type == "service_role-jwt" {
-            println!("⚠️ Uso chiave SECRET (accesso completo DB) — solo per app desktop interna");
-        }
         println!(
             "🔑 Supabase API key: tipo={}, prefisso={}...",
-            key_type,
+            key_type(&api_key),
             &api_key.chars().take(16).collect::<String>()
         );
+        if let Some(secret) = &service_key {
+            println!(
+                "🔑 Chiave di servizio (solo operazioni admin): tipo={}",
+                key_type(secret)
+            );
+        }
 
         let cfg = supabase.tables.as_ref();
 
@@ -596,15 +712,42 @@ async fn setup_supabase_from_config(config: &AppConfig) {
             tesseramenti: cfg
                 .and_then(|t| t.tesseramenti.clone())
                 .unwrap_or_else(|| "Tesseramenti_supa".to_string()),
+            prenotazioni_idsocio: cfg
+                .and_then(|t| t.prenotazioni_idsocio.clone())
+                .unwrap_or_else(|| "IdSocio_prenotazioni_supa".to_string()),
+            ruoli: cfg
+                .and_then(|t| t.ruoli.clone())
+                .unwrap_or_else(|| "Ruoli_supa".to_string()),
+            audit: cfg
+                .and_then(|t| t.audit.clone())
+                .unwrap_or_else(|| "Audit_supa".to_string()),
+            cestino_servizi: cfg
+                .and_then(|t| t.cestino_servizi.clone())
+                .unwrap_or_else(|| "CestinoServizi_supa".to_string()),
+            tariffe: cfg
+                .and_then(|t| t.tariffe.clone())
+                .unwrap_or_else(|| "Tariffe_supa".to_string()),
+            regole_tariffa: cfg
+                .and_then(|t| t.regole_tariffa.clone())
+                .unwrap_or_else(|| "RegoleTariffa_supa".to_string()),
+            serie_servizi: cfg
+                .and_then(|t| t.serie_servizi.clone())
+                .unwrap_or_else(|| "SerieServizi_supa".to_string()),
         };
 
         let sb_config = SupabaseConfig {
             url: supabase.url.clone(),
             anon_key: api_key,
+            service_key,
             tables: tables.clone(),
+            http: supabase.http.clone().unwrap_or_default(),
         };
         let mut guard = get_supabase_client().lock().await;
-        *guard = Some(SupabaseClient::new(sb_config));
+        *guard = Some(
+            SupabaseClient::new(sb_config)
+                .with_offline_store(get_offline_store())
+                .with_auth_store(get_auth_store()),
+        );
         println!(
             "✓ Client Supabase inizializzato ({} tabelle configurate)",
             17
@@ -658,7 +801,7 @@ fn config_json_paths() -> Vec<std::path::PathBuf> {
         .collect()
 }
 
-async fn load_app_config_from_file() -> Result<AppConfig, String> {
+async fn load_app_config_from_file() -> Result<AppConfig, ApiError> {
     let mut last_error = None;
 
     for config_path in config_json_paths() {
@@ -666,11 +809,11 @@ async fn load_app_config_from_file() -> Result<AppConfig, String> {
             Ok(contents) => {
                 println!("✓ config.json caricato da: {:?}", config_path);
                 let config: AppConfig = serde_json::from_str(&contents)
-                    .map_err(|e| format!("Errore parsing config.json: {}", e))?;
+                    .map_err(|e| ApiError::config(format!("Errore parsing config.json: {}", e)))?;
                 return Ok(config);
             }
             Err(e) => {
-                last_error = Some(format!("{:?}: {}", config_path, e));
+                last_error = Some(ApiError::config(format!("{:?}: {}", config_path, e)));
             }
         }
     }
@@ -686,126 +829,176 @@ async fn load_app_config_from_file() -> Result<AppConfig, String> {
 }
 
 /// Config compilata dentro l'eseguibile (stesso file usato in sviluppo)
-fn load_embedded_config() -> Result<AppConfig, String> {
+fn load_embedded_config() -> Result<AppConfig, ApiError> {
     const RAW: &str = include_str!("../../config.json");
-    serde_json::from_str(RAW).map_err(|e| format!("Errore parsing config incorporata: {}", e))
+    serde_json::from_str(RAW)
+        .map_err(|e| ApiError::config(format!("Errore parsing config incorporata: {}", e)))
 }
 
-async fn ensure_supabase_client() -> Result<(), String> {
-    {
-        let guard = get_supabase_client().lock().await;
-        if guard.is_some() {
-            return Ok(());
+async fn ensure_supabase_client() -> Result<(), ApiError> {
+    let esistente = get_supabase_client().lock().await.as_ref().cloned();
+    let client = match esistente {
+        Some(c) => c,
+        None => {
+            let config = load_app_config_from_file().await?;
+            setup_supabase_from_config(&config).await;
+            match get_supabase_client().lock().await.as_ref().cloned() {
+                Some(c) => c,
+                None => {
+                    return Err(ApiError::config(
+                        "Supabase non inizializzato. Controlla url e anon_key in config.json (Settings → API in Supabase).",
+                    ))
+                }
+            }
+        }
+    };
+
+    // Token in scadenza: rinnovato prima del comando (fuori dal lock del client)
+    rinnova_sessione_se_serve(&client).await;
+    Ok(())
+}
+
+/// Rinnova la sessione utente se sta per scadere. Se il refresh token non vale
+/// più la sessione è chiusa e le finestre tornano al login.
+async fn rinnova_sessione_se_serve(client: &SupabaseClient) {
+    let in_scadenza = client
+        .sessione()
+        .map(|s| s.scade_entro(ANTICIPO_RINNOVO_SECS))
+        .unwrap_or(false);
+    if !in_scadenza {
+        return;
+    }
+    match client.rinnova_sessione(ANTICIPO_RINNOVO_SECS).await {
+        Ok(_) => {}
+        Err(ApiError::Network { .. }) => {
+            println!("⚠️ Rinnovo sessione rimandato: Supabase non raggiungibile");
+        }
+        Err(e) => {
+            if let Some(app) = APP_HANDLE.get() {
+                let _ = app.emit_all(EVENTO_SESSIONE_SCADUTA, e.message());
+            }
         }
     }
+}
 
-    let config = load_app_config_from_file().await?;
-    setup_supabase_from_config(&config).await;
+/// In background: tiene valido il token anche quando nessuno usa l'app
+async fn auth_refresh_loop() {
+    const INTERVALLO: std::time::Duration = std::time::Duration::from_secs(30);
+    loop {
+        let client = get_supabase_client().lock().await.as_ref().cloned();
+        if let Some(client) = client {
+            rinnova_sessione_se_serve(&client).await;
+        }
+        tokio::time::sleep(INTERVALLO).await;
+    }
+}
 
-    let guard = get_supabase_client().lock().await;
-    if guard.is_none() {
-        return Err(
-            "Supabase non inizializzato. Controlla url e anon_key in config.json (Settings → API in Supabase)."
-                .to_string(),
-        );
+async fn supabase_client_clone() -> Result<SupabaseClient, ApiError> {
+    ensure_supabase_client().await?;
+    get_supabase_client()
+        .lock()
+        .await
+        .as_ref()
+        .cloned()
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))
+}
+
+/// Login Supabase Auth: da qui in poi i comandi girano col JWT dell'utente
+#[tauri::command]
+async fn supabase_login(email: String, password: String) -> Result<InfoSessione, ApiError> {
+    let email = email.trim().to_string();
+    if email.is_empty() || password.is_empty() {
+        return Err("Inserisci email e password".into());
     }
+    let client = supabase_client_clone().await?;
+    let sessione = client.login(&email, &password).await?;
+    dimentica_permessi_sessione().await;
+    Ok(sessione.info())
+}
 
-    Ok(())
+#[tauri::command]
+async fn supabase_logout() -> Result<(), ApiError> {
+    let client = supabase_client_clone().await?;
+    dimentica_permessi_sessione().await;
+    client.logout().await
 }
 
-async fn fetch_servizi_supabase(filter: Option<&str>) -> Result<Vec<serde_json::Value>, String> {
+/// Sessione corrente senza token (None = nessun utente collegato)
+#[tauri::command]
+async fn get_auth_session() -> Result<Option<InfoSessione>, ApiError> {
+    let client = supabase_client_clone().await?;
+    Ok(client.sessione().map(|s| s.info()))
+}
+
+async fn fetch_servizi_supabase(query: Query) -> Result<Vec<serde_json::Value>, ApiError> {
     ensure_supabase_client().await?;
     let guard = get_supabase_client().lock().await;
     let client = guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
     client
-        .fetch_servizi(filter)
+        .fetch_servizi(query)
         .await
-        .map_err(|e| format_supabase_error(&e))
 }
 
-async fn fetch_motivazioni_servizi_supabase() -> Result<Vec<serde_json::Value>, String> {
+async fn fetch_motivazioni_servizi_supabase() -> Result<Vec<serde_json::Value>, ApiError> {
     ensure_supabase_client().await?;
     let guard = get_supabase_client().lock().await;
     let client = guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
     client
         .fetch_servizi_motivazioni()
         .await
-        .map_err(|e| format_supabase_error(&e))
 }
 
-async fn fetch_comuni_prelievo_servizi_supabase() -> Result<Vec<serde_json::Value>, String> {
+async fn fetch_comuni_prelievo_servizi_supabase() -> Result<Vec<serde_json::Value>, ApiError> {
     ensure_supabase_client().await?;
     let guard = get_supabase_client().lock().await;
     let client = guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
     client
         .fetch_servizi_comuni_prelievo()
         .await
-        .map_err(|e| format_supabase_error(&e))
 }
 
-async fn fetch_localita_autocomplete_servizi_supabase() -> Result<Vec<serde_json::Value>, String> {
+async fn fetch_localita_autocomplete_servizi_supabase() -> Result<Vec<serde_json::Value>, ApiError> {
     ensure_supabase_client().await?;
     let guard = get_supabase_client().lock().await;
     let client = guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
     client
         .fetch_servizi_localita_autocomplete()
         .await
-        .map_err(|e| format_supabase_error(&e))
 }
 
-async fn fetch_servizi_idsocio_supabase() -> Result<Vec<serde_json::Value>, String> {
+async fn fetch_servizi_idsocio_supabase() -> Result<Vec<serde_json::Value>, ApiError> {
     ensure_supabase_client().await?;
     let guard = get_supabase_client().lock().await;
     let client = guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
     client
         .fetch_servizi_idsocio()
         .await
-        .map_err(|e| format_supabase_error(&e))
-}
-
-fn format_supabase_error(err: &str) -> String {
-    if err.contains("401") || err.contains("Invalid API key") {
-        return format!(
-            "{}\n\n🔧 SOLUZIONE — la anon key (eyJ...) nel config NON funziona più.\n\
-            Apri Supabase → il tuo progetto → Settings → API Keys:\n\
-            • SOLUZIONE A (consigliata): copia la Publishable key (inizia con sb_publishable_)\n\
-              e incollala in config.json come \"publishable_key\": \"sb_publishable_...\"\n\
-            • SOLUZIONE B: clicca Regenerate sulla anon key e aggiorna \"anon_key\"\n\
-            • SOLUZIONE C (app desktop): copia Secret key (sb_secret_...) in \"secret_key\"\n\
-            Poi riavvia l'app (npm run tauri dev).",
-            err
-        );
-    }
-    err.to_string()
 }
 
 // Test connessione Supabase (utile per verificare la chiave API)
 #[tauri::command]
-async fn test_supabase_connection() -> Result<serde_json::Value, String> {
-    ensure_supabase_client().await?;
-
-    let client_guard = get_supabase_client().lock().await;
-    let client = client_guard
-        .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+async fn test_supabase_connection() -> Result<serde_json::Value, ApiError> {
+    // Il test legge tesserati_supa: serve lo stesso permesso dell'elenco soci
+    richiedi_permesso(Capacita::SociLeggi).await?;
+    let client = supabase_client_clone().await?;
 
-    match client.fetch_tesserati(None, None).await {
+    match client.fetch_tesserati(Query::new()).await {
         Ok(rows) => Ok(serde_json::json!({
             "success": true,
             "message": format!("Connessione OK. Trovati {} record in tesserati_supa.", rows.len()),
             "count": rows.len()
         })),
-        Err(e) => Err(format_supabase_error(&e)),
+        Err(e) => Err(e),
     }
 }
 
@@ -816,7 +1009,7 @@ fn get_oauth_state() -> &'static Mutex<Option<String>> {
 
 // Comando per verificare se l'app è pronta (Supabase configurato — SharePoint non più richiesto)
 #[tauri::command]
-async fn check_authentication() -> Result<bool, String> {
+async fn check_authentication() -> Result<bool, ApiError> {
     match ensure_supabase_client().await {
         Ok(()) => {
             let guard = get_supabase_client().lock().await;
@@ -852,6 +1045,18 @@ struct ServizioMezzoOccupato {
     luogo_destinazione: String,
 }
 
+impl ServizioMezzoOccupato {
+    fn da_riga(row: &serde_json::Value, nominativi: &HashMap<String, String>) -> Self {
+        Self {
+            ora: servizio_ora_prelievo(row),
+            operatore: resolve_operatore_nome(row, nominativi),
+            trasportato: resolve_trasportato_nome(row, nominativi),
+            comune_destinazione: get_field_any(row, &["Destinazione_Comune", "DESTINAZIONE_COMUNE"]),
+            luogo_destinazione: get_field_any(row, &["Destinazione_Indirizzo", "DESTINAZIONE_INDIRIZZO"]),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ServizioCompleto {
     id: String,
@@ -883,6 +1088,9 @@ struct ServizioCompleto {
     data_ricevuta: String,
     numero_ricevuta: String,
     stato_servizio: String,
+    /// Obbligatorio per lo stato ANNULLATO
+    #[serde(default)]
+    motivo_annullamento: String,
     note_prelievo: String,
     note_arrivo: String,
     note_fine_servizio: String,
@@ -897,6 +1105,18 @@ struct ServizioCompleto {
     /// Timestamp ultima modifica (colonne: modificated / updated_at)
     #[serde(default)]
     updated_at: String,
+    /// Versione letta, da rimandare in update_servizio_completo (modifiche concorrenti)
+    #[serde(default)]
+    versione: String,
+    /// Serie ricorrente di cui fa parte (vuoto = servizio singolo)
+    #[serde(default)]
+    id_serie: String,
+    /// Viaggio (andata e ritorno o più tappe) di cui è una tappa (vuoto = nessuno)
+    #[serde(default)]
+    id_viaggio: String,
+    /// Posizione nel viaggio, da 1 (0 = nessun viaggio)
+    #[serde(default)]
+    tappa: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -960,6 +1180,10 @@ struct SocioAnagrafica {
     archivia: bool,
     disponibilita: String,
     notaaggiuntiva: String,
+    /// Versione letta: il salvataggio fallisce se nel frattempo un altro utente
+    /// ha cambiato gli stessi campi
+    #[serde(default)]
+    versione: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -1044,6 +1268,7 @@ fn supabase_row_to_anagrafica(row: &serde_json::Value) -> Option<SocioAnagrafica
         archivia: is_truthy_str(&tesserato.archivia),
         disponibilita: tesserato.disponibilita,
         notaaggiuntiva: tesserato.notaaggiuntiva,
+        versione: versione_riga(row),
     })
 }
 
@@ -1265,6 +1490,8 @@ struct Automezzo {
     in_servizio: String,  // InServizio (boolean → stringa)
     note_mezzo: String,   // Note_mezzo
     scadenza_bollo: String, // Scadenza_Bollo
+    #[serde(default)]
+    versione: String,     // versione letta (modifiche concorrenti)
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -1279,6 +1506,9 @@ struct Tratta {
     pedaggio: String,
     totale: String,
     note_aggiuntive: String,
+    /// Costo al km proprio della tratta (Tratta_TariffaKmSpeciale) invece di quello in vigore
+    #[serde(default)]
+    tariffa_speciale: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1308,124 +1538,129 @@ fn supabase_row_to_tratta(row: &serde_json::Value, costo_al_km: f64) -> Option<T
         return None;
     }
 
-    let km_ar = get_numeric_any(row, &["Tratta_KmAr", "TRATTA_KMAR"]);
-    let pedaggio = get_numeric_any(row, &["Tratta_Pedaggio", "TRATTA_PEDAGGIO"]);
-    let tariffa_eff = if costo_al_km > 0.0 {
-        costo_al_km
-    } else {
-        get_numeric_any(row, &["Tratta_TariffaKm", "TRATTA_TARIFFAKM"])
-    };
-    let costo = km_ar * tariffa_eff;
-    let totale = costo + pedaggio;
+    let importi = ComponentiTratta::da_riga(row, costo_al_km);
 
     Some(Tratta {
         id,
         comune: get_field_any(row, &["Tratta_Comune", "TRATTA_COMUNE"]),
         provincia: get_field_any(row, &["Tratta_Provincia", "TRATTA_PROVINCIA"]),
         localita: get_field_any(row, &["Tratta_Localita", "TRATTA_LOCALITA"]),
-        km: format_tratta_decimal(km_ar, 0, false),
-        costo_km: format_tratta_decimal(tariffa_eff, 2, true),
-        costo: format_tratta_decimal(costo, 1, false),
-        pedaggio: format_tratta_decimal(pedaggio, 2, true),
-        totale: format_tratta_decimal(totale, 2, false),
+        km: format_tratta_decimal(importi.km_ar, 0, false),
+        costo_km: format_tratta_decimal(importi.tariffa_km, 2, true),
+        costo: format_tratta_decimal(importi.costo, 1, false),
+        pedaggio: format_tratta_decimal(importi.pedaggio, 2, true),
+        totale: format_tratta_decimal(importi.totale, 2, false),
         note_aggiuntive: get_field_any(row, &["Tratta_Note", "TRATTA_NOTE"]),
+        tariffa_speciale: tariffe::tariffa_speciale_tratta(row).is_some(),
     })
 }
 
-fn costo_al_km_da_impostazioni(rows: &[serde_json::Value]) -> f64 {
-    for row in rows {
-        let chiave = get_field_any(
-            row,
-            &[
-                "Impostazione",
-                "IMPOSTAZIONE",
-                "Nome",
-                "Chiave",
-                "Impostazione_Nome",
-            ],
-        );
-        if !chiave.eq_ignore_ascii_case("CostoAlKm") {
-            continue;
-        }
-        let valore = get_numeric_any(
-            row,
-            &[
-                "Valore",
-                "VALORE",
-                "Valore_Impostazione",
-                "Impostazione_Valore",
-                "ValoreNumerico",
-                "CostoAlKm",
-            ],
-        );
-        if valore > 0.0 {
-            return valore;
+async fn fetch_storico_tariffe(client: &supabase::SupabaseClient) -> StoricoTariffe {
+    match client.fetch_tariffe(Query::new()).await {
+        Ok(rows) => StoricoTariffe::da_righe(&rows),
+        Err(e) => {
+            println!("⚠️ Tariffe_supa non leggibile, valgono le impostazioni: {}", e);
+            StoricoTariffe::default()
         }
     }
-    0.70
 }
 
-async fn fetch_costo_al_km(client: &supabase::SupabaseClient) -> Result<f64, String> {
-    let filter = "Impostazione=eq.CostoAlKm";
-    let rows = client
-        .fetch_impostazioni(Some(filter))
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
+/// Parametri in vigore alla data (None = oggi) con l'inizio della versione usata;
+/// senza versioni in Tariffe_supa valgono le impostazioni
+async fn fetch_parametri_tariffa(
+    client: &supabase::SupabaseClient,
+    data: Option<chrono::NaiveDate>,
+) -> Result<(ParametriTariffa, Option<chrono::NaiveDate>), ApiError> {
+    let data = data.unwrap_or_else(|| Local::now().date_naive());
+    if let Some(versione) = fetch_storico_tariffe(client).await.in_vigore(data) {
+        return Ok((versione.parametri.clone(), Some(versione.valido_dal)));
+    }
+    let rows = client.fetch_impostazioni(Query::new()).await?;
+    Ok((ParametriTariffa::da_impostazioni(&rows), None))
+}
 
-    if !rows.is_empty() {
-        let v = costo_al_km_da_impostazioni(&rows);
-        if v > 0.0 {
-            return Ok(v);
+/// Regole della tariffa; se la tabella non si legge il preventivo resta quello base
+async fn fetch_regole_tariffa(client: &supabase::SupabaseClient) -> Vec<RegolaTariffa> {
+    match client.fetch_regole_tariffa().await {
+        Ok(rows) => rows.iter().filter_map(RegolaTariffa::da_riga).collect(),
+        Err(e) => {
+            println!("⚠️ RegoleTariffa_supa non leggibile, nessuna regola applicata: {}", e);
+            Vec::new()
         }
     }
+}
 
-    let all = client
-        .fetch_impostazioni(None)
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
-    Ok(costo_al_km_da_impostazioni(&all))
+/// Tipologia del socio (TipologiaSocio) per le regole della tariffa
+async fn tipologia_socio(client: &supabase::SupabaseClient, idsocio: &str) -> Result<String, ApiError> {
+    let rows = client
+        .fetch_tesserati(Query::new().eq("IdSocio", idsocio).limit(1))
+        .await?;
+    Ok(rows
+        .first()
+        .map(|row| get_field(row, "TipologiaSocio"))
+        .unwrap_or_default())
 }
 
-async fn sync_tratte_tariffa_km(
+/// Preventivo con la tariffa in vigore alla data del servizio, la tratta da
+/// Tratte_supa se serve e le regole valide per socio, richiedente e tipo servizio
+async fn preventivo_servizio(
     client: &supabase::SupabaseClient,
-    rows: &[serde_json::Value],
-    costo_al_km: f64,
-) {
-    if costo_al_km <= 0.0 {
-        return;
-    }
-
-    for row in rows {
-        let id = get_field_any(row, &["IdTratta", "ID_TRATTA"])
-            .parse::<u32>()
-            .unwrap_or(0);
-        if id == 0 {
-            continue;
-        }
-
-        let attuale = get_numeric_any(row, &["Tratta_TariffaKm", "TRATTA_TARIFFAKM"]);
-        if (attuale - costo_al_km).abs() < 0.000_1 {
-            continue;
-        }
-
-        let mut body = serde_json::Map::new();
-        insert_patch_field(
-            &mut body,
-            row,
-            &["Tratta_TariffaKm", "TRATTA_TARIFFAKM"],
-            serde_json::json!(costo_al_km),
-        );
-        if body.is_empty() {
-            body.insert("Tratta_TariffaKm".to_string(), serde_json::json!(costo_al_km));
-        }
+    richiesta: &RichiestaPreventivo,
+) -> Result<Preventivo, ApiError> {
+    let data = richiesta
+        .data_prelievo
+        .as_deref()
+        .filter(|d| !d.trim().is_empty())
+        .map(|d| {
+            tariffe::data_servizio(d)
+                .ok_or_else(|| ApiError::from(format!("Data del servizio non valida: '{}'", d.trim())))
+        })
+        .transpose()?;
+    let (parametri, valida_dal) = fetch_parametri_tariffa(client, data).await?;
+    let tratte = if richiesta.cerca_tratta() {
+        client.fetch_tratte(Query::new()).await?
+    } else {
+        Vec::new()
+    };
+    let tratta = tariffe::risolvi_tratta(&tratte, richiesta);
+    let mut preventivo = tariffe::calcola(richiesta, &parametri, tratta)?;
+    preventivo.tariffa_valida_dal = valida_dal;
 
-        if let Err(e) = client.patch_tratta(id, &body).await {
-            println!(
-                "⚠️ Sync tariffa km tratta IdTratta={} fallita: {}",
-                id, e
-            );
-        }
+    let mut contesto = richiesta.contesto();
+    let idsocio = richiesta.idsocio.as_deref().unwrap_or("").trim();
+    if contesto.tipologia_socio.is_empty() && !idsocio.is_empty() {
+        contesto.tipologia_socio = tipologia_socio(client, idsocio).await?;
     }
+    regole_tariffa::applica(&fetch_regole_tariffa(client).await, &contesto, &mut preventivo);
+    for regola in &preventivo.regole {
+        println!("ℹ️ Regola tariffa {} applicata ({} €)", regola.codice, regola.importo);
+    }
+    Ok(preventivo)
+}
+
+/// Salva una nuova versione della tariffa, valida da `versione.valido_dal`
+async fn salva_versione_tariffa(
+    client: &supabase::SupabaseClient,
+    versione: &VersioneTariffa,
+    precedente: Option<&VersioneTariffa>,
+) -> Result<(), ApiError> {
+    versione.parametri.valida()?;
+    let body = versione.riga();
+    client.upsert_tariffa(&body).await?;
+    registra_audit(
+        client,
+        "tariffe",
+        &versione.valido_dal.format("%Y-%m-%d").to_string(),
+        audit::AZIONE_INSERT,
+        precedente.map(|p| serde_json::Value::Object(p.riga())).as_ref(),
+        Some(&serde_json::Value::Object(body)),
+    )
+    .await;
+    println!(
+        "✓ Tariffa valida dal {}: {} €/km",
+        versione.valido_dal, versione.parametri.costo_al_km
+    );
+    Ok(())
 }
 
 fn get_numeric_any(row: &serde_json::Value, names: &[&str]) -> f64 {
@@ -1560,11 +1795,10 @@ fn servizio_data_italiana(row: &serde_json::Value) -> String {
 }
 
 /// Filtro PostgREST: servizi con Prelievo_Data nell'anno indicato
-fn servizi_filter_anno(anno: u32) -> String {
-    format!(
-        "Prelievo_Data=gte.{}-01-01&Prelievo_Data=lte.{}-12-31",
-        anno, anno
-    )
+fn servizi_filter_anno(anno: u32) -> Query {
+    Query::new()
+        .gte("Prelievo_Data", format!("{}-01-01", anno))
+        .lte("Prelievo_Data", format!("{}-12-31", anno))
 }
 
 fn oggi_iso_local() -> String {
@@ -1578,40 +1812,39 @@ fn domani_iso_local() -> String {
 }
 
 /// Solo il giorno corrente (home: servizi del giorno)
-fn servizi_filter_solo_oggi() -> String {
-    format!(
-        "Prelievo_Data=gte.{}&Prelievo_Data=lt.{}",
-        oggi_iso_local(),
-        domani_iso_local()
-    )
+fn servizi_filter_solo_oggi() -> Query {
+    Query::new()
+        .gte("Prelievo_Data", oggi_iso_local())
+        .lt("Prelievo_Data", domani_iso_local())
 }
 
 /// Dalla data odierna in poi (home: niente archivio pregresso)
-fn servizi_filter_da_oggi() -> String {
-    format!("Prelievo_Data=gte.{}", oggi_iso_local())
+fn servizi_filter_da_oggi() -> Query {
+    Query::new().gte("Prelievo_Data", oggi_iso_local())
 }
 
 /// Da domani in poi (home: prossimi servizi)
-fn servizi_filter_da_domani() -> String {
-    format!("Prelievo_Data=gte.{}", domani_iso_local())
+fn servizi_filter_da_domani() -> Query {
+    Query::new().gte("Prelievo_Data", domani_iso_local())
 }
 
 /// Servizi creati oggi (home: inseriti oggi)
-fn servizi_filter_creati_oggi() -> String {
-    format!("created_at=gte.{}", oggi_iso_local())
+fn servizi_filter_creati_oggi() -> Query {
+    Query::new().gte("created_at", oggi_iso_local())
 }
 
 /// Fetch servizi per la home con filtro; se fallisce prova da oggi in poi
-async fn fetch_servizi_home(filter_primario: &str) -> Result<Vec<serde_json::Value>, String> {
-    match fetch_servizi_supabase(Some(filter_primario)).await {
+async fn fetch_servizi_home(filter_primario: Query) -> Result<Vec<serde_json::Value>, ApiError> {
+    let descrizione = filter_primario.to_string();
+    match fetch_servizi_supabase(filter_primario).await {
         Ok(rows) => Ok(rows),
         Err(e) => {
             let fallback = servizi_filter_da_oggi();
             println!(
                 "⚠️ Filtro home '{}' fallito ({}), provo '{}'",
-                filter_primario, e, fallback
+                descrizione, e, fallback
             );
-            fetch_servizi_supabase(Some(&fallback)).await
+            fetch_servizi_supabase(fallback).await
         }
     }
 }
@@ -1645,7 +1878,7 @@ async fn fetch_idsocio_nominativo_map() -> HashMap<String, String> {
     let guard = get_supabase_client().lock().await;
     if let Some(client) = guard.as_ref() {
         if let Ok(rows) = client
-            .fetch_tesserati(None, Some("IdSocio,NominativoSocio"))
+            .fetch_tesserati(Query::new().select(&["IdSocio", "NominativoSocio"]))
             .await
         {
             for row in rows {
@@ -1663,6 +1896,88 @@ async fn fetch_idsocio_nominativo_map() -> HashMap<String, String> {
     map
 }
 
+// Cache delta per tabella (servizi, tesserati): righe in memoria aggiornate
+// solo con le modifiche successive all'ultimo timestamp visto
+static DELTA_CACHE: OnceLock<Mutex<HashMap<String, DeltaTable>>> = OnceLock::new();
+
+fn delta_cache() -> &'static Mutex<HashMap<String, DeltaTable>> {
+    DELTA_CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Porta in pari con Supabase una porzione della cache delta. La cache resta
+/// bloccata solo per decidere cosa scaricare e per applicarlo, non durante
+/// le richieste HTTP.
+async fn sincronizza_cache_delta(
+    client: &SupabaseClient,
+    table_type: &str,
+    porzione: &Query,
+) -> Result<(), ApiError> {
+    let piano = delta_cache()
+        .lock()
+        .await
+        .entry(table_type.to_string())
+        .or_default()
+        .piano_sync(table_type, porzione);
+    let dati = piano.scarica(client, table_type).await?;
+    delta_cache()
+        .lock()
+        .await
+        .entry(table_type.to_string())
+        .or_default()
+        .applica_sync(table_type, &piano, dati);
+    Ok(())
+}
+
+/// Righe di una porzione di tabella dalla cache delta, dopo averla portata in pari con Supabase
+async fn righe_da_cache_delta(
+    client: &SupabaseClient,
+    table_type: &str,
+    porzione: &Query,
+) -> Result<Vec<serde_json::Value>, ApiError> {
+    sincronizza_cache_delta(client, table_type, porzione).await?;
+    let cache = delta_cache().lock().await;
+    Ok(cache.get(table_type).map(|t| t.rows(porzione)).unwrap_or_default())
+}
+
+async fn servizi_da_cache_delta(porzione: &Query) -> Result<Vec<serde_json::Value>, ApiError> {
+    let client = supabase_client_clone().await?;
+    righe_da_cache_delta(&client, "servizi", porzione).await
+}
+
+/// Dopo una scrittura dell'app rilegge la riga, così la cache delta non
+/// dipende dal timestamp di modifica (che non tutte le tabelle aggiornano)
+async fn aggiorna_riga_cache_delta(
+    client: &SupabaseClient,
+    table_type: &str,
+    key_column: &str,
+    key: &str,
+) {
+    if !delta_cache().lock().await.contains_key(table_type) {
+        return;
+    }
+    let riga = match client
+        .fetch_table(table_type, &Query::new().eq(key_column, key).limit(1))
+        .await
+    {
+        Ok(rows) => rows.into_iter().next(),
+        Err(e) => {
+            println!("⚠️ Cache delta [{}] {}={} non aggiornata: {}", table_type, key_column, key, e);
+            return;
+        }
+    };
+    let mut cache = delta_cache().lock().await;
+    let tabella = match cache.get_mut(table_type) {
+        Some(t) => t,
+        None => return,
+    };
+    match riga {
+        Some(row) => tabella.merge_row(table_type, row),
+        None => {
+            tabella.remove(key);
+        }
+    }
+}
+
 fn normalize_idsocio_key(id: &str) -> String {
     let trimmed = id.trim();
     if trimmed.is_empty() {
@@ -1719,6 +2034,78 @@ fn resolve_trasportato_nome(row: &serde_json::Value, nominativi: &HashMap<String
     lookup_nominativo_by_idsocio(nominativi, &id).unwrap_or_default()
 }
 
+/// Utente con la restrizione "solo i propri servizi": l'operatore collegato
+struct VisibilitaPropria {
+    idsocio: String,
+    nominativo: String,
+}
+
+impl VisibilitaPropria {
+    /// Servizio in cui l'utente è operatore (IdOperatore o nome) o secondo operatore
+    fn servizio_proprio(&self, row: &serde_json::Value, nominativi: &HashMap<String, String>) -> bool {
+        let stesso_nome = |nome: &str| {
+            !self.nominativo.is_empty() && nome.trim().eq_ignore_ascii_case(self.nominativo.trim())
+        };
+        let id_op = get_field_any(row, &["IdOperatore", "IDOPERATORE", "Id_Operatore"]);
+        normalize_idsocio_key(&id_op) == self.idsocio
+            || stesso_nome(&resolve_operatore_nome(row, nominativi))
+            || stesso_nome(&get_field_any(row, &["Oper2", "OPER2"]))
+    }
+}
+
+/// None se l'utente vede tutti i servizi. Con la restrizione serve il
+/// collegamento a un operatore, altrimenti non vedrebbe nulla.
+async fn visibilita_propria(
+    perm: &UserPermissionsRecord,
+) -> Result<Option<VisibilitaPropria>, ApiError> {
+    if !permessi::solo_propri(perm) {
+        return Ok(None);
+    }
+    if perm.idsocio.is_empty() {
+        return Err(ApiError::permission_denied(
+            "Utente non collegato a un operatore (IdSocio): chiedi all'amministratore",
+        ));
+    }
+    let nominativi = fetch_idsocio_nominativo_map().await;
+    Ok(Some(VisibilitaPropria {
+        nominativo: lookup_nominativo_by_idsocio(&nominativi, &perm.idsocio).unwrap_or_default(),
+        idsocio: perm.idsocio.clone(),
+    }))
+}
+
+/// Soci trasportati nei servizi dell'operatore: i loro contatti restano visibili
+async fn soci_dei_servizi_propri(
+    vis: &VisibilitaPropria,
+    nominativi: &HashMap<String, String>,
+) -> Result<HashSet<String>, ApiError> {
+    let righe = servizi_da_cache_delta(&Query::new()).await?;
+    Ok(righe
+        .iter()
+        .filter(|row| vis.servizio_proprio(row, nominativi))
+        .map(|row| normalize_idsocio_key(&get_field_any(row, &["IdSocio", "IDSOCIO"])))
+        .filter(|id| !id.is_empty())
+        .collect())
+}
+
+/// Contatti dei soci non serviti dall'operatore: per chi vede solo i propri servizi
+/// restano nome e comune, non telefono, email e indirizzo
+fn oscura_contatti_tesserato(t: &mut Tesserato) {
+    t.telefono.clear();
+    t.residenza_indirizzo.clear();
+    t.residenza_civico.clear();
+    t.residenza_cap.clear();
+}
+
+fn oscura_contatti_anagrafica(completa: &mut SocioAnagraficaCompleta) {
+    let a = &mut completa.anagrafica;
+    a.telefono.clear();
+    a.residenza_indirizzo.clear();
+    a.residenza_civico.clear();
+    a.residenza_cap.clear();
+    completa.telefoni.clear();
+    completa.email.clear();
+}
+
 fn normalize_mezzo_key(s: &str) -> String {
     let t = s.trim();
     if t.is_empty() {
@@ -1922,6 +2309,10 @@ fn supabase_row_to_servizio_completo(
             ],
         ),
         stato_servizio: get_field_any(row, &["StatoServizio", "STATOSERVIZIO"]),
+        motivo_annullamento: get_field_any(
+            row,
+            &["MotivoAnnullamento", "MOTIVOANNULLAMENTO", "motivo_annullamento"],
+        ),
         note_prelievo: get_field_any(row, &["Prelievo_Note", "PRELIEVO_NOTE"]),
         note_arrivo: get_field_any(row, &["Destinazione_Note", "DESTINAZIONE_NOTE"]),
         note_fine_servizio: get_field_any(
@@ -1964,6 +2355,10 @@ fn supabase_row_to_servizio_completo(
                 "Updated_At",
             ],
         ),
+        versione: versione_riga(row),
+        id_serie: get_field_any(row, &["IdSerie", "id_serie"]),
+        id_viaggio: get_field_any(row, &["IdViaggio", "id_viaggio"]),
+        tappa: tappa_riga(row),
     })
 }
 
@@ -2041,12 +2436,16 @@ fn sort_servizi_completi(servizi: &mut [ServizioCompleto]) {
 
 // Comando per ottenere servizi del giorno (Supabase / Servizi_supa)
 #[tauri::command]
-async fn get_servizi_giorno() -> Result<Vec<Servizio>, String> {
+async fn get_servizi_giorno() -> Result<Vec<Servizio>, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let propri = visibilita_propria(&perm).await?;
     println!("=== get_servizi_giorno chiamato (Supabase, solo oggi) ===");
 
-    let filter = servizi_filter_solo_oggi();
-    let rows = fetch_servizi_home(&filter).await?;
+    let mut rows = fetch_servizi_home(servizi_filter_solo_oggi()).await?;
     let nominativi = fetch_idsocio_nominativo_map().await;
+    if let Some(vis) = &propri {
+        rows.retain(|row| vis.servizio_proprio(row, &nominativi));
+    }
     let oggi_iso = oggi_iso_local();
     let oggi_italiano = format!(
         "{:02}/{:02}/{}",
@@ -2084,7 +2483,9 @@ async fn get_servizi_mezzo_nella_data(
     mezzo: String,
     data_prelievo: String,
     escludi_id_servizio: Option<u32>,
-) -> Result<Vec<ServizioMezzoOccupato>, String> {
+) -> Result<Vec<ServizioMezzoOccupato>, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let propri = visibilita_propria(&perm).await?;
     println!(
         "=== get_servizi_mezzo_nella_data mezzo='{}' data='{}' escludi={:?} ===",
         mezzo, data_prelievo, escludi_id_servizio
@@ -2106,20 +2507,18 @@ async fn get_servizi_mezzo_nella_data(
     };
     let giorno_dopo = next_day_iso(data_iso).unwrap_or_else(|| data_iso.to_string());
 
-    let filter = format!(
-        "Prelievo_Data=gte.{}&Prelievo_Data=lt.{}",
-        data_iso, giorno_dopo
-    );
+    let filter = Query::new()
+        .gte("Prelievo_Data", data_iso)
+        .lt("Prelievo_Data", &giorno_dopo);
 
-    let rows = match fetch_servizi_supabase(Some(&filter)).await {
+    let rows = match fetch_servizi_supabase(filter).await {
         Ok(rows) => rows,
         Err(e) => {
             println!(
                 "⚠️ Filtro giorno fallito ({}), provo eq.{}",
                 e, data_iso
             );
-            let filter_eq = format!("Prelievo_Data=eq.{}", data_iso);
-            fetch_servizi_supabase(Some(&filter_eq)).await?
+            fetch_servizi_supabase(Query::new().eq("Prelievo_Data", data_iso)).await?
         }
     };
 
@@ -2142,18 +2541,21 @@ async fn get_servizi_mezzo_nella_data(
             let m = normalize_mezzo_key(&get_field_any(row, &["Mezzo", "MEZZO"]));
             m == mezzo_key
         })
-        .map(|row| ServizioMezzoOccupato {
-            ora: servizio_ora_prelievo(row),
-            operatore: resolve_operatore_nome(row, &nominativi),
-            trasportato: resolve_trasportato_nome(row, &nominativi),
-            comune_destinazione: get_field_any(
-                row,
-                &["Destinazione_Comune", "DESTINAZIONE_COMUNE"],
-            ),
-            luogo_destinazione: get_field_any(
-                row,
-                &["Destinazione_Indirizzo", "DESTINAZIONE_INDIRIZZO"],
-            ),
+        .map(|row| {
+            // Chi vede solo i propri servizi sa che il mezzo è occupato e a
+            // che ora, ma non da chi né per chi
+            if let Some(vis) = &propri {
+                if !vis.servizio_proprio(row, &nominativi) {
+                    return ServizioMezzoOccupato {
+                        ora: servizio_ora_prelievo(row),
+                        operatore: String::new(),
+                        trasportato: String::new(),
+                        comune_destinazione: String::new(),
+                        luogo_destinazione: String::new(),
+                    };
+                }
+            }
+            ServizioMezzoOccupato::da_riga(row, &nominativi)
         })
         .collect();
 
@@ -2170,12 +2572,16 @@ async fn get_servizi_mezzo_nella_data(
 
 // Comando per ottenere prossimi servizi (Supabase / Servizi_supa)
 #[tauri::command]
-async fn get_prossimi_servizi() -> Result<Vec<Servizio>, String> {
+async fn get_prossimi_servizi() -> Result<Vec<Servizio>, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let propri = visibilita_propria(&perm).await?;
     println!("=== get_prossimi_servizi chiamato (Supabase, da domani in poi) ===");
 
-    let filter = servizi_filter_da_domani();
-    let rows = fetch_servizi_home(&filter).await?;
+    let mut rows = fetch_servizi_home(servizi_filter_da_domani()).await?;
     let nominativi = fetch_idsocio_nominativo_map().await;
+    if let Some(vis) = &propri {
+        rows.retain(|row| vis.servizio_proprio(row, &nominativi));
+    }
     let domani = Local::now() + chrono::Duration::days(1);
     let domani_date = chrono::NaiveDate::from_ymd_opt(domani.year(), domani.month(), domani.day())
         .ok_or_else(|| "Errore calcolo data domani".to_string())?;
@@ -2198,11 +2604,12 @@ async fn get_prossimi_servizi() -> Result<Vec<Servizio>, String> {
 
 // Comando per ottenere servizi inseriti oggi (Supabase / Servizi_supa)
 #[tauri::command]
-async fn get_servizi_inseriti_oggi() -> Result<Vec<Servizio>, String> {
+async fn get_servizi_inseriti_oggi() -> Result<Vec<Servizio>, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let propri = visibilita_propria(&perm).await?;
     println!("=== get_servizi_inseriti_oggi chiamato (Supabase, creati oggi) ===");
 
-    let filter = servizi_filter_creati_oggi();
-    let rows = match fetch_servizi_supabase(Some(&filter)).await {
+    let mut rows = match fetch_servizi_supabase(servizi_filter_creati_oggi()).await {
         Ok(rows) => rows,
         Err(e) => {
             // Se created_at non è filtrabile, limita almeno ai prelievi da oggi in poi
@@ -2210,11 +2617,13 @@ async fn get_servizi_inseriti_oggi() -> Result<Vec<Servizio>, String> {
                 "⚠️ Filtro created_at fallito ({}), uso Prelievo_Data da oggi",
                 e
             );
-            let fallback = servizi_filter_da_oggi();
-            fetch_servizi_home(&fallback).await?
+            fetch_servizi_home(servizi_filter_da_oggi()).await?
         }
     };
     let nominativi = fetch_idsocio_nominativo_map().await;
+    if let Some(vis) = &propri {
+        rows.retain(|row| vis.servizio_proprio(row, &nominativi));
+    }
     let oggi_iso = oggi_iso_local();
 
     let servizi: Vec<Servizio> = rows
@@ -2238,7 +2647,8 @@ async fn get_servizi_inseriti_oggi() -> Result<Vec<Servizio>, String> {
 
 // Comando per ottenere tessere da fare (da Supabase, filtro TipologiaSocio)
 #[tauri::command]
-async fn get_tessere_da_fare() -> Result<Vec<Tessera>, String> {
+async fn get_tessere_da_fare() -> Result<Vec<Tessera>, ApiError> {
+    richiedi_permesso(Capacita::SociLeggi).await?;
     println!("=== get_tessere_da_fare chiamato ===");
 
     ensure_supabase_client().await?;
@@ -2246,15 +2656,14 @@ async fn get_tessere_da_fare() -> Result<Vec<Tessera>, String> {
     let client_guard = get_supabase_client().lock().await;
 
     if let Some(client) = client_guard.as_ref() {
-        let filter = "TipologiaSocio=in.(NUOVO,ESTERNO)";
-        let rows = match client.fetch_tesserati(Some(filter), None).await {
+        let filter = Query::new().in_list("TipologiaSocio", ["NUOVO", "ESTERNO"]);
+        let rows = match client.fetch_tesserati(filter).await {
             Ok(rows) => rows,
             Err(e) => {
                 println!("⚠️ Filtro Supabase fallito, recupero tutti i tesserati: {}", e);
                 client
-                    .fetch_tesserati(None, None)
-                    .await
-                    .map_err(|e2| format_supabase_error(&e2))?
+                    .fetch_tesserati(Query::new())
+                    .await?
             }
         };
 
@@ -2298,24 +2707,55 @@ async fn get_tessere_da_fare() -> Result<Vec<Tessera>, String> {
         println!("✓ Trovate {} tessere da fare da Supabase", tessere.len());
         Ok(tessere)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 // Comando per ottenere tutti i tesserati da Supabase (Elenco Soci)
 #[tauri::command]
-async fn get_all_tesserati() -> Result<Vec<Tesserato>, String> {
+async fn get_all_tesserati() -> Result<Vec<Tesserato>, ApiError> {
+    let perm = richiedi_permesso(Capacita::SociLeggi).await?;
+    let mut tesserati = leggi_tutti_tesserati().await?;
+    if let Some(vis) = visibilita_propria(&perm).await? {
+        let nominativi = fetch_idsocio_nominativo_map().await;
+        let soci = soci_dei_servizi_propri(&vis, &nominativi).await?;
+        for t in tesserati.iter_mut() {
+            if !soci.contains(&normalize_idsocio_key(&t.idsocio)) {
+                oscura_contatti_tesserato(t);
+            }
+        }
+    }
+    tesserati.maschera(Mascheramento::per_utente(&perm));
+    Ok(tesserati)
+}
+
+// Elenco Operatori: i tesserati con Operatore = SI, solo con "operatori.vedi"
+#[tauri::command]
+async fn get_elenco_operatori() -> Result<Vec<Tesserato>, ApiError> {
+    let perm = richiedi_permesso(Capacita::OperatoriVedi).await?;
+    let mut operatori: Vec<Tesserato> = leggi_tutti_tesserati()
+        .await?
+        .into_iter()
+        .filter(|t| {
+            let v = t.operatore.trim().to_lowercase();
+            v == "true" || v == "si" || v == "sì" || v == "1" || v == "yes"
+        })
+        .collect();
+    operatori.maschera(Mascheramento::per_utente(&perm));
+    println!("✓ Elenco operatori: {}", operatori.len());
+    Ok(operatori)
+}
+
+async fn leggi_tutti_tesserati() -> Result<Vec<Tesserato>, ApiError> {
     println!("=== get_all_tesserati chiamato (Supabase / tesserati_supa) ===");
 
     ensure_supabase_client().await?;
 
-    let client_guard = get_supabase_client().lock().await;
+    // Copia del client: il lock globale non resta preso durante le richieste
+    let client_guard = get_supabase_client().lock().await.clone();
 
     if let Some(client) = client_guard.as_ref() {
-        let rows = client
-            .fetch_tesserati(None, None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+        let rows = righe_da_cache_delta(client, "tesserati", &Query::new()).await?;
 
         if rows.is_empty() {
             println!("⚠️ Nessun tesserato trovato in Supabase");
@@ -2328,7 +2768,7 @@ async fn get_all_tesserati() -> Result<Vec<Tesserato>, String> {
             .collect();
 
         // Telefono in elenco = telefono principale da Telefoni_supa (con riferimento)
-        match client.fetch_telefoni(None).await {
+        match client.fetch_telefoni(Query::new()).await {
             Ok(tel_rows) => {
                 let principali = build_telefoni_principali_map(&tel_rows);
                 println!(
@@ -2395,13 +2835,32 @@ async fn get_all_tesserati() -> Result<Vec<Tesserato>, String> {
 
         Ok(tesserati)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 // Anagrafica completa di un socio (dati + storico tesseramenti)
 #[tauri::command]
-async fn get_socio_anagrafica(idsocio: String) -> Result<SocioAnagraficaCompleta, String> {
+async fn get_socio_anagrafica(idsocio: String) -> Result<SocioAnagraficaCompleta, ApiError> {
+    let perm = richiedi_permesso(Capacita::SociLeggi).await?;
+    let riservato = match visibilita_propria(&perm).await? {
+        Some(vis) => {
+            let nominativi = fetch_idsocio_nominativo_map().await;
+            !soci_dei_servizi_propri(&vis, &nominativi)
+                .await?
+                .contains(&normalize_idsocio_key(&idsocio))
+        }
+        None => false,
+    };
+    let mut completa = leggi_socio_anagrafica(idsocio).await?;
+    if riservato {
+        oscura_contatti_anagrafica(&mut completa);
+    }
+    completa.maschera(Mascheramento::per_utente(&perm));
+    Ok(completa)
+}
+
+async fn leggi_socio_anagrafica(idsocio: String) -> Result<SocioAnagraficaCompleta, ApiError> {
     println!("=== get_socio_anagrafica IdSocio={} ===", idsocio);
 
     ensure_supabase_client().await?;
@@ -2409,22 +2868,21 @@ async fn get_socio_anagrafica(idsocio: String) -> Result<SocioAnagraficaCompleta
     let client_guard = get_supabase_client().lock().await;
 
     if let Some(client) = client_guard.as_ref() {
-        let filter = format!("IdSocio=eq.{}", idsocio);
+        let filter = Query::new().eq("IdSocio", &idsocio);
         let rows = client
-            .fetch_tesserati(Some(&filter), None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_tesserati(filter)
+            .await?;
 
         let row = rows
             .first()
-            .ok_or_else(|| format!("Socio IdSocio={} non trovato", idsocio))?;
+            .ok_or_else(|| ApiError::not_found(format!("Socio IdSocio={} non trovato", idsocio)))?;
 
         let anagrafica = supabase_row_to_anagrafica(row)
             .ok_or_else(|| format!("Dati anagrafici non validi per IdSocio={}", idsocio))?;
 
-        let contatti_filter = format!("IdSocio=eq.{}", idsocio);
+        let contatti_filter = Query::new().eq("IdSocio", &idsocio);
 
-        let mut telefoni: Vec<SocioTelefono> = match client.fetch_telefoni(Some(&contatti_filter)).await {
+        let mut telefoni: Vec<SocioTelefono> = match client.fetch_telefoni(contatti_filter.clone()).await {
             Ok(rows) => {
                 println!(
                     "✓ Telefoni_supa: {} riga/e per IdSocio={}",
@@ -2439,7 +2897,7 @@ async fn get_socio_anagrafica(idsocio: String) -> Result<SocioAnagraficaCompleta
             }
         };
 
-        let mut email: Vec<SocioEmail> = match client.fetch_email_socio(Some(&contatti_filter)).await {
+        let mut email: Vec<SocioEmail> = match client.fetch_email_socio(contatti_filter).await {
             Ok(rows) => {
                 println!(
                     "✓ Email_supa: {} riga/e per IdSocio={}",
@@ -2494,9 +2952,9 @@ async fn get_socio_anagrafica(idsocio: String) -> Result<SocioAnagraficaCompleta
         }
 
         // Storico: tutte le righe in Tesseramenti_supa collegate per IdSocio
-        let tess_filter = format!("IdSocio=eq.{}", idsocio);
+        let tess_filter = Query::new().eq("IdSocio", &idsocio);
         let mut tesseramenti: Vec<TesseramentoRecord> = match client
-            .fetch_tesseramenti(Some(&tess_filter))
+            .fetch_tesseramenti(tess_filter)
             .await
         {
             Ok(rows) => {
@@ -2544,7 +3002,7 @@ async fn get_socio_anagrafica(idsocio: String) -> Result<SocioAnagraficaCompleta
             email,
         })
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
@@ -2750,90 +3208,180 @@ fn build_socio_anagrafica_body(
     body
 }
 
+/// Quanto resta prenotato un IdSocio per un nuovo socio non ancora salvato
+const DURATA_PRENOTAZIONE_IDSOCIO_MINUTI: i64 = 30;
+
+// Numero indicativo (max+1) senza prenotazione: per un nuovo socio usare reserve_idsocio
 #[tauri::command]
-async fn get_next_idsocio() -> Result<String, String> {
+async fn get_next_idsocio() -> Result<String, ApiError> {
+    richiedi_permesso(Capacita::SociCrea).await?;
     println!("=== get_next_idsocio chiamato (Supabase / tesserati_supa) ===");
 
     ensure_supabase_client().await?;
 
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
-        let rows = client
-            .fetch_tesserati(None, Some("IdSocio"))
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
-
-        let mut max_id: u64 = 0;
-        for row in &rows {
-            if let Ok(n) = get_field(row, "IdSocio").trim().parse::<u64>() {
-                max_id = max_id.max(n);
-            }
-        }
-
-        let next = (max_id + 1).to_string();
+        let next = (client.fetch_max_idsocio().await? + 1).to_string();
         println!("✓ Prossimo IdSocio suggerito: {}", next);
         Ok(next)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
+    }
+}
+
+// Prenota un IdSocio per la maschera nuovo socio: resta riservato a questa
+// postazione finché non viene salvato, rilasciato o scade
+#[tauri::command]
+async fn reserve_idsocio(prenotato_da: Option<String>) -> Result<PrenotazioneIdSocio, ApiError> {
+    richiedi_permesso(Capacita::SociCrea).await?;
+    println!("=== reserve_idsocio chiamato ===");
+
+    ensure_supabase_client().await?;
+
+    let client_guard = get_supabase_client().lock().await;
+    let client = client_guard
+        .as_ref()
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
+    client
+        .reserve_idsocio(
+            chrono::Duration::minutes(DURATA_PRENOTAZIONE_IDSOCIO_MINUTI),
+            prenotato_da.as_deref().unwrap_or(""),
+        )
+        .await
+}
+
+// Rilascia un IdSocio prenotato (inserimento nuovo socio annullato)
+#[tauri::command]
+async fn release_idsocio(token: String) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::SociCrea).await?;
+    ensure_supabase_client().await?;
+
+    let client_guard = get_supabase_client().lock().await;
+    let client = client_guard
+        .as_ref()
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
+    client.release_idsocio(&token).await
+}
+
+/// Un IdSocio prenotato da un'altra postazione (prenotazione non scaduta) non si può usare
+fn verifica_prenotazione_idsocio(
+    idsocio: &str,
+    prenotazione: Option<&serde_json::Value>,
+    token: Option<&str>,
+) -> Result<(), ApiError> {
+    let riga = match prenotazione {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    if token.is_some_and(|t| !t.is_empty() && get_field(riga, "token") == t) {
+        return Ok(());
+    }
+    let scaduta = chrono::DateTime::parse_from_rfc3339(&get_field(riga, "scade_il"))
+        .map(|d| d.with_timezone(&chrono::Utc) < chrono::Utc::now())
+        .unwrap_or(true);
+    if scaduta {
+        return Ok(());
     }
+    let da = get_field(riga, "prenotato_da");
+    Err(ApiError::Conflict {
+        code: None,
+        message: format!(
+            "IdSocio {} è prenotato da un'altra postazione{}: richiedi un nuovo numero",
+            idsocio,
+            if da.is_empty() { String::new() } else { format!(" ({})", da) }
+        ),
+    })
 }
 
 #[tauri::command]
-async fn create_socio_anagrafica(anagrafica: SocioAnagrafica) -> Result<SocioAnagrafica, String> {
+async fn create_socio_anagrafica(
+    anagrafica: SocioAnagrafica,
+    prenotazione: Option<String>,
+) -> Result<SocioAnagrafica, ApiError> {
+    let perm = richiedi_permesso(Capacita::SociCrea).await?;
     println!(
         "=== create_socio_anagrafica IdSocio={} Nominativo='{}' ===",
         anagrafica.idsocio, anagrafica.nominativo
     );
 
     if anagrafica.idsocio.trim().is_empty() {
-        return Err("IdSocio obbligatorio".to_string());
+        return Err("IdSocio obbligatorio".into());
     }
     if anagrafica.nominativo.trim().is_empty() {
-        return Err("Nominativo obbligatorio".to_string());
+        return Err("Nominativo obbligatorio".into());
     }
 
     ensure_supabase_client().await?;
 
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
-        let filter = format!("IdSocio=eq.{}", anagrafica.idsocio);
+        let filter = Query::new().eq("IdSocio", &anagrafica.idsocio);
         let existing = client
-            .fetch_tesserati(Some(&filter), None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_tesserati(filter)
+            .await?;
 
         if !existing.is_empty() {
-            return Err(format!(
-                "Esiste già un socio con IdSocio={}",
-                anagrafica.idsocio
-            ));
+            return Err(ApiError::Conflict {
+                code: None,
+                message: format!("Esiste già un socio con IdSocio={}", anagrafica.idsocio),
+            });
         }
 
+        let prenotato = client
+            .fetch_prenotazione_idsocio(anagrafica.idsocio.trim())
+            .await?;
+        verifica_prenotazione_idsocio(
+            anagrafica.idsocio.trim(),
+            prenotato.as_ref(),
+            prenotazione.as_deref(),
+        )?;
+
         let template = client
-            .fetch_tesserati(None, None)
+            .fetch_tesserati(Query::new())
             .await
             .ok()
             .and_then(|rows| rows.first().cloned());
 
         let body = build_socio_anagrafica_body(&anagrafica, template.as_ref(), true);
         if body.is_empty() {
-            return Err("Nessun campo da inserire per il nuovo socio".to_string());
+            return Err("Nessun campo da inserire per il nuovo socio".into());
         }
 
-        let inserted = client
-            .insert_tesserato(&body)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+        let inserted = match client.insert_tesserato(&body).await {
+            Err(ApiError::Conflict { code: Some(code), .. }) if code == "23505" => {
+                return Err(ApiError::Conflict {
+                    code: Some(code),
+                    message: format!(
+                        "IdSocio {} appena usato da un'altra postazione: richiedi un nuovo numero",
+                        anagrafica.idsocio
+                    ),
+                });
+            }
+            other => other?,
+        };
+        if let Some(cache) = delta_cache().lock().await.get_mut("tesserati") {
+            cache.merge_row("tesserati", inserted.clone());
+        }
+        // Numero confermato: la prenotazione non serve più
+        if let Some(token) = prenotazione.as_deref() {
+            if let Err(e) = client.release_idsocio(token).await {
+                println!("⚠️ Prenotazione IdSocio {} non rilasciata: {}", anagrafica.idsocio, e);
+            }
+        }
 
-        supabase_row_to_anagrafica(&inserted)
-            .ok_or_else(|| "Impossibile convertire il socio appena inserito".to_string())
+        let mut creato = supabase_row_to_anagrafica(&inserted)
+            .ok_or_else(|| ApiError::from("Impossibile convertire il socio appena inserito"))?;
+        creato.maschera(Mascheramento::per_utente(&perm));
+        Ok(creato)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 #[tauri::command]
-async fn save_socio_anagrafica(anagrafica: SocioAnagrafica) -> Result<(), String> {
+async fn save_socio_anagrafica(mut anagrafica: SocioAnagrafica) -> Result<SocioAnagrafica, ApiError> {
+    let perm = richiedi_permesso(Capacita::SociModifica).await?;
+    let mascheramento = Mascheramento::per_utente(&perm);
     println!(
         "=== save_socio_anagrafica IdSocio={} ===",
         anagrafica.idsocio
@@ -2843,33 +3391,54 @@ async fn save_socio_anagrafica(anagrafica: SocioAnagrafica) -> Result<(), String
 
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
-        let filter = format!("IdSocio=eq.{}", anagrafica.idsocio);
+        let filter = Query::new().eq("IdSocio", &anagrafica.idsocio);
         let rows = client
-            .fetch_tesserati(Some(&filter), None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_tesserati(filter)
+            .await?;
 
         let row = rows
             .first()
-            .ok_or_else(|| format!("Socio IdSocio={} non trovato", anagrafica.idsocio))?;
+            .ok_or_else(|| {
+                ApiError::not_found(format!("Socio IdSocio={} non trovato", anagrafica.idsocio))
+            })?;
 
+        // I campi oscurati nella finestra restano quelli del database
+        if let Some(originale) = supabase_row_to_anagrafica(row) {
+            mascheramento::ripristina_anagrafica(&mut anagrafica, &originale, mascheramento);
+        }
         let body = build_socio_anagrafica_body(&anagrafica, Some(row), false);
         if body.is_empty() {
-            return Err("Nessun campo da aggiornare".to_string());
+            return Err("Nessun campo da aggiornare".into());
         }
 
-        client
-            .patch_tesserato(&anagrafica.idsocio, &body)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
-        Ok(())
+        // Salvataggio condizionato alla versione aperta dall'utente
+        let aggiornata = client
+            .patch_versionato("tesserati", &anagrafica.idsocio, Some(&anagrafica.versione), &body)
+            .await?;
+        registra_audit(
+            client,
+            "tesserati",
+            &anagrafica.idsocio,
+            audit::AZIONE_UPDATE,
+            Some(row),
+            Some(&serde_json::Value::Object(body)),
+        )
+        .await;
+        aggiorna_riga_cache_delta(client, "tesserati", "IdSocio", &anagrafica.idsocio).await;
+        let mut salvata = aggiornata
+            .as_ref()
+            .and_then(supabase_row_to_anagrafica)
+            .unwrap_or(anagrafica);
+        salvata.maschera(mascheramento);
+        Ok(salvata)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 #[tauri::command]
-async fn save_tesseramento(tesseramento: TesseramentoRecord) -> Result<TesseramentoRecord, String> {
+async fn save_tesseramento(tesseramento: TesseramentoRecord) -> Result<TesseramentoRecord, ApiError> {
+    richiedi_permesso(Capacita::SociModifica).await?;
     println!(
         "=== save_tesseramento IdSocio={} Anno={} ===",
         tesseramento.idsocio, tesseramento.anno
@@ -2920,8 +3489,7 @@ async fn save_tesseramento(tesseramento: TesseramentoRecord) -> Result<Tesserame
         // 1) Modifica (PATCH per id) oppure nuovo (POST)
         let saved_row = client
             .upsert_tesseramento(&body, row_id)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .await?;
         let mut saved = supabase_row_to_tesseramento(&saved_row);
         // Numero/quota/note restano sul form locale (non esistono su Tesseramenti_supa)
         if saved.numero.is_empty() {
@@ -2935,9 +3503,9 @@ async fn save_tesseramento(tesseramento: TesseramentoRecord) -> Result<Tesserame
         }
 
         // 2) Se è l'anno più recente, aggiorna anche i campi su tesserati (Elenco Soci)
-        let filter = format!("IdSocio=eq.{}", tesseramento.idsocio);
+        let filter = Query::new().eq("IdSocio", &tesseramento.idsocio);
         let current_anno = client
-            .fetch_tesserati(Some(&filter), Some("Tesseramento_Anno"))
+            .fetch_tesserati(filter.select(&["Tesseramento_Anno"]))
             .await
             .ok()
             .and_then(|rows| rows.first().cloned())
@@ -2965,7 +3533,7 @@ async fn save_tesseramento(tesseramento: TesseramentoRecord) -> Result<Tesserame
 
         Ok(saved)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
@@ -2994,9 +3562,9 @@ fn ordine_utilizzo_json(value: &str) -> serde_json::Value {
 async fn sync_telefono_principale_su_tesserati(
     client: &supabase::SupabaseClient,
     idsocio: &str,
-) -> Result<(), String> {
-    let filter = format!("IdSocio=eq.{}", idsocio);
-    let rows = client.fetch_telefoni(Some(&filter)).await.unwrap_or_default();
+) -> Result<(), ApiError> {
+    let filter = Query::new().eq("IdSocio", &idsocio);
+    let rows = client.fetch_telefoni(filter).await.unwrap_or_default();
     let list: Vec<SocioTelefono> = rows.iter().map(supabase_row_to_telefono).collect();
     let num_only = list
         .iter()
@@ -3010,7 +3578,8 @@ async fn sync_telefono_principale_su_tesserati(
 }
 
 #[tauri::command]
-async fn save_socio_telefono(telefono: SocioTelefono) -> Result<SocioTelefono, String> {
+async fn save_socio_telefono(telefono: SocioTelefono) -> Result<SocioTelefono, ApiError> {
+    richiedi_permesso(Capacita::SociModifica).await?;
     println!(
         "=== save_socio_telefono IdSocio={} Telefono={} ===",
         telefono.idsocio, telefono.telefono
@@ -3019,10 +3588,10 @@ async fn save_socio_telefono(telefono: SocioTelefono) -> Result<SocioTelefono, S
     let idsocio = telefono.idsocio.trim().to_string();
     let numero = telefono.telefono.trim().to_string();
     if idsocio.is_empty() {
-        return Err("IdSocio obbligatorio per il telefono".to_string());
+        return Err("IdSocio obbligatorio per il telefono".into());
     }
     if numero.is_empty() {
-        return Err("Il numero di telefono è obbligatorio".to_string());
+        return Err("Il numero di telefono è obbligatorio".into());
     }
 
     ensure_supabase_client().await?;
@@ -3056,7 +3625,7 @@ async fn save_socio_telefono(telefono: SocioTelefono) -> Result<SocioTelefono, S
     let client_guard = get_supabase_client().lock().await;
     let client = client_guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     if telefono.principale {
         if let Err(e) = client.clear_principale_telefoni(&idsocio, row_id).await {
@@ -3066,8 +3635,7 @@ async fn save_socio_telefono(telefono: SocioTelefono) -> Result<SocioTelefono, S
 
     let saved_row = client
         .upsert_socio_telefono(&body, row_id)
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
+        .await?;
     let saved = supabase_row_to_telefono(&saved_row);
 
     if let Err(e) = sync_telefono_principale_su_tesserati(client, &idsocio).await {
@@ -3078,7 +3646,8 @@ async fn save_socio_telefono(telefono: SocioTelefono) -> Result<SocioTelefono, S
 }
 
 #[tauri::command]
-async fn delete_socio_telefono(telefono: SocioTelefono) -> Result<(), String> {
+async fn delete_socio_telefono(telefono: SocioTelefono) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::SociModifica).await?;
     println!(
         "=== delete_socio_telefono IdSocio={} Telefono={} ===",
         telefono.idsocio, telefono.telefono
@@ -3096,12 +3665,11 @@ async fn delete_socio_telefono(telefono: SocioTelefono) -> Result<(), String> {
     let client_guard = get_supabase_client().lock().await;
     let client = client_guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     client
         .delete_socio_telefono(row_id, &idsocio, telefono.telefono.trim())
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
+        .await?;
 
     if !idsocio.is_empty() {
         if let Err(e) = sync_telefono_principale_su_tesserati(client, &idsocio).await {
@@ -3113,7 +3681,8 @@ async fn delete_socio_telefono(telefono: SocioTelefono) -> Result<(), String> {
 }
 
 #[tauri::command]
-async fn save_socio_email(email: SocioEmail) -> Result<SocioEmail, String> {
+async fn save_socio_email(email: SocioEmail) -> Result<SocioEmail, ApiError> {
+    richiedi_permesso(Capacita::SociModifica).await?;
     println!(
         "=== save_socio_email IdSocio={} Email={} ===",
         email.idsocio, email.email
@@ -3122,10 +3691,10 @@ async fn save_socio_email(email: SocioEmail) -> Result<SocioEmail, String> {
     let idsocio = email.idsocio.trim().to_string();
     let indirizzo = email.email.trim().to_string();
     if idsocio.is_empty() {
-        return Err("IdSocio obbligatorio per l'email".to_string());
+        return Err("IdSocio obbligatorio per l'email".into());
     }
     if indirizzo.is_empty() {
-        return Err("L'indirizzo email è obbligatorio".to_string());
+        return Err("L'indirizzo email è obbligatorio".into());
     }
 
     ensure_supabase_client().await?;
@@ -3156,7 +3725,7 @@ async fn save_socio_email(email: SocioEmail) -> Result<SocioEmail, String> {
     let client_guard = get_supabase_client().lock().await;
     let client = client_guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     if email.principale {
         if let Err(e) = client.clear_principale_email(&idsocio, row_id).await {
@@ -3166,14 +3735,14 @@ async fn save_socio_email(email: SocioEmail) -> Result<SocioEmail, String> {
 
     let saved_row = client
         .upsert_socio_email(&body, row_id)
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
+        .await?;
 
     Ok(supabase_row_to_email(&saved_row))
 }
 
 #[tauri::command]
-async fn delete_socio_email(email: SocioEmail) -> Result<(), String> {
+async fn delete_socio_email(email: SocioEmail) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::SociModifica).await?;
     println!(
         "=== delete_socio_email IdSocio={} Email={} ===",
         email.idsocio, email.email
@@ -3190,19 +3759,19 @@ async fn delete_socio_email(email: SocioEmail) -> Result<(), String> {
     let client_guard = get_supabase_client().lock().await;
     let client = client_guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     client
         .delete_socio_email(row_id, email.idsocio.trim(), email.email.trim())
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
+        .await?;
 
     Ok(())
 }
 
 // Comando per ottenere tutti gli automezzi da Supabase
 #[tauri::command]
-async fn get_all_automezzi() -> Result<Vec<Automezzo>, String> {
+async fn get_all_automezzi() -> Result<Vec<Automezzo>, ApiError> {
+    richiedi_permesso(Capacita::MezziLeggi).await?;
     println!("=== get_all_automezzi chiamato (Supabase / Automezzi_Supa) ===");
 
     ensure_supabase_client().await?;
@@ -3211,9 +3780,8 @@ async fn get_all_automezzi() -> Result<Vec<Automezzo>, String> {
 
     if let Some(client) = client_guard.as_ref() {
         let rows = client
-            .fetch_automezzi(None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_automezzi(Query::new())
+            .await?;
 
         if rows.is_empty() {
             println!("⚠️ Nessun automezzo trovato in Supabase");
@@ -3252,7 +3820,7 @@ async fn get_all_automezzi() -> Result<Vec<Automezzo>, String> {
         println!("✓ Convertiti {} automezzi da Supabase", automezzi.len());
         Ok(automezzi)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
@@ -3371,14 +3939,15 @@ fn build_automezzo_body(
 }
 
 #[tauri::command]
-async fn create_automezzo(automezzo: Automezzo) -> Result<Automezzo, String> {
+async fn create_automezzo(automezzo: Automezzo) -> Result<Automezzo, ApiError> {
+    richiedi_permesso(Capacita::MezziCrea).await?;
     println!(
         "=== create_automezzo NR='{}' TARGA='{}' ===",
         automezzo.nr_automezzo, automezzo.targa
     );
 
     if automezzo.nr_automezzo.trim().is_empty() {
-        return Err("N. mezzo obbligatorio".to_string());
+        return Err("N. mezzo obbligatorio".into());
     }
 
     ensure_supabase_client().await?;
@@ -3386,70 +3955,89 @@ async fn create_automezzo(automezzo: Automezzo) -> Result<Automezzo, String> {
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
         let template = client
-            .fetch_automezzi(None)
+            .fetch_automezzi(Query::new())
             .await
             .ok()
             .and_then(|rows| rows.first().cloned());
 
         let body = build_automezzo_body(&automezzo, template.as_ref());
         if body.is_empty() {
-            return Err("Nessun campo da inserire per il nuovo mezzo".to_string());
+            return Err("Nessun campo da inserire per il nuovo mezzo".into());
         }
 
         let inserted = client
             .insert_automezzo(&body)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .await?;
 
         supabase_row_to_automezzo(&inserted)
-            .ok_or_else(|| "Impossibile convertire il mezzo appena inserito".to_string())
+            .ok_or_else(|| "Impossibile convertire il mezzo appena inserito".into())
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 #[tauri::command]
-async fn save_automezzo(automezzo: Automezzo) -> Result<(), String> {
+async fn save_automezzo(automezzo: Automezzo) -> Result<Automezzo, ApiError> {
+    richiedi_permesso(Capacita::MezziModifica).await?;
     println!(
         "=== save_automezzo IdAutomezzo={} NR='{}' ===",
         automezzo.id, automezzo.nr_automezzo
     );
 
     if automezzo.id == 0 {
-        return Err("ID automezzo non valido".to_string());
+        return Err("ID automezzo non valido".into());
     }
 
     ensure_supabase_client().await?;
 
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
-        let filter = format!("IdAutomezzo=eq.{}", automezzo.id);
+        let filter = Query::new().eq("IdAutomezzo", &automezzo.id);
         let rows = client
-            .fetch_automezzi(Some(&filter))
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_automezzi(filter)
+            .await?;
 
         let row = rows
             .first()
-            .ok_or_else(|| format!("Automezzo IdAutomezzo={} non trovato", automezzo.id))?;
+            .ok_or_else(|| {
+                ApiError::not_found(format!("Automezzo IdAutomezzo={} non trovato", automezzo.id))
+            })?;
 
         let body = build_automezzo_body(&automezzo, Some(row));
         if body.is_empty() {
-            return Err("Nessun campo da aggiornare".to_string());
+            return Err("Nessun campo da aggiornare".into());
         }
 
-        client
-            .patch_automezzo(automezzo.id, &body)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
-        Ok(())
+        // Salvataggio condizionato alla versione aperta dall'utente
+        let aggiornato = client
+            .patch_versionato(
+                "automezzi",
+                &automezzo.id.to_string(),
+                Some(&automezzo.versione),
+                &body,
+            )
+            .await?;
+        registra_audit(
+            client,
+            "automezzi",
+            &automezzo.id.to_string(),
+            audit::AZIONE_UPDATE,
+            Some(row),
+            Some(&serde_json::Value::Object(body)),
+        )
+        .await;
+        Ok(aggiornato
+            .as_ref()
+            .and_then(supabase_row_to_automezzo)
+            .unwrap_or(automezzo))
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 #[tauri::command]
-async fn get_all_tratte() -> Result<TratteElenco, String> {
+async fn get_all_tratte(data: Option<String>) -> Result<TratteElenco, ApiError> {
+    richiedi_permesso(Capacita::TratteLeggi).await?;
     println!("=== get_all_tratte chiamato (Supabase / Tratte_supa) ===");
 
     ensure_supabase_client().await?;
@@ -3457,13 +4045,17 @@ async fn get_all_tratte() -> Result<TratteElenco, String> {
     let client_guard = get_supabase_client().lock().await;
 
     if let Some(client) = client_guard.as_ref() {
-        let costo_al_km = fetch_costo_al_km(client).await.unwrap_or(0.70);
-        println!("✓ CostoAlKm da Impostazioni_supa: {}", costo_al_km);
+        // Importi con la tariffa in vigore alla data richiesta (vuota = oggi)
+        let data = data.as_deref().and_then(tariffe::data_servizio);
+        let costo_al_km = fetch_parametri_tariffa(client, data)
+            .await
+            .map(|(p, _)| p.costo_al_km)
+            .unwrap_or(tariffe::COSTO_KM_PREDEFINITO);
+        println!("✓ Costo al km in vigore: {}", costo_al_km);
 
         let rows = client
-            .fetch_tratte(None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_tratte(Query::new())
+            .await?;
 
         if rows.is_empty() {
             println!("⚠️ Nessuna tratta trovata in Supabase");
@@ -3473,8 +4065,6 @@ async fn get_all_tratte() -> Result<TratteElenco, String> {
             });
         }
 
-        sync_tratte_tariffa_km(client, &rows, costo_al_km).await;
-
         let tratte: Vec<Tratta> = rows
             .iter()
             .filter_map(|row| supabase_row_to_tratta(row, costo_al_km))
@@ -3502,47 +4092,57 @@ async fn get_all_tratte() -> Result<TratteElenco, String> {
             tratte,
         })
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 #[tauri::command]
-async fn save_tratta(tratta: Tratta) -> Result<(), String> {
+async fn save_tratta(tratta: Tratta) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::TratteModifica).await?;
     println!(
         "=== save_tratta IdTratta={} Comune='{}' ===",
         tratta.id, tratta.comune
     );
 
     if tratta.id == 0 {
-        return Err("ID tratta non valido".to_string());
+        return Err("ID tratta non valido".into());
     }
 
     ensure_supabase_client().await?;
 
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
-        let filter = format!("IdTratta=eq.{}", tratta.id);
+        let filter = Query::new().eq("IdTratta", &tratta.id);
         let rows = client
-            .fetch_tratte(Some(&filter))
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_tratte(filter)
+            .await?;
 
         let row = rows
             .first()
-            .ok_or_else(|| format!("Tratta IdTratta={} non trovata", tratta.id))?;
+            .ok_or_else(|| {
+                ApiError::not_found(format!("Tratta IdTratta={} non trovata", tratta.id))
+            })?;
 
         let body = build_tratta_body(&tratta, Some(row));
         if body.is_empty() {
-            return Err("Nessun campo da aggiornare".to_string());
+            return Err("Nessun campo da aggiornare".into());
         }
 
         client
             .patch_tratta(tratta.id, &body)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .await?;
+        registra_audit(
+            client,
+            "tratte",
+            &tratta.id.to_string(),
+            audit::AZIONE_UPDATE,
+            Some(row),
+            Some(&serde_json::Value::Object(body)),
+        )
+        .await;
         Ok(())
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
@@ -3645,7 +4245,8 @@ fn tipi_pagamento_da_righe(rows: &[serde_json::Value]) -> Vec<String> {
 }
 
 #[tauri::command]
-async fn get_all_tipi_pagamento() -> Result<Vec<String>, String> {
+async fn get_all_tipi_pagamento() -> Result<Vec<String>, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
     println!("=== get_all_tipi_pagamento chiamato (Supabase / TipoPagamenti_supa) ===");
 
     ensure_supabase_client().await?;
@@ -3653,15 +4254,14 @@ async fn get_all_tipi_pagamento() -> Result<Vec<String>, String> {
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
         let rows = client
-            .fetch_tipi_pagamento(None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_tipi_pagamento(Query::new())
+            .await?;
 
         let tipi = tipi_pagamento_da_righe(&rows);
         println!("✓ Caricati {} tipi pagamento da Supabase", tipi.len());
         Ok(tipi)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
@@ -3722,7 +4322,8 @@ fn valore_column_name_impostazione(row: &serde_json::Value) -> &'static str {
 }
 
 #[tauri::command]
-async fn get_all_impostazioni() -> Result<Vec<ImpostazioneRecord>, String> {
+async fn get_all_impostazioni() -> Result<Vec<ImpostazioneRecord>, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
     println!("=== get_all_impostazioni chiamato (Supabase / Impostazioni_supa) ===");
 
     ensure_supabase_client().await?;
@@ -3730,20 +4331,20 @@ async fn get_all_impostazioni() -> Result<Vec<ImpostazioneRecord>, String> {
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
         let rows = client
-            .fetch_impostazioni(None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_impostazioni(Query::new())
+            .await?;
 
         let list: Vec<ImpostazioneRecord> = rows.iter().map(supabase_row_to_impostazione).collect();
         println!("✓ Caricate {} impostazioni da Supabase", list.len());
         Ok(list)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 #[tauri::command]
-async fn update_impostazione(id: String, valore: String) -> Result<(), String> {
+async fn update_impostazione(id: String, valore: String) -> Result<(), ApiError> {
+    let perm = richiedi_permesso(Capacita::ImpostazioniModifica).await?;
     println!(
         "=== update_impostazione id={} valore_len={} ===",
         id,
@@ -3752,7 +4353,7 @@ async fn update_impostazione(id: String, valore: String) -> Result<(), String> {
 
     let id = id.trim().to_string();
     if id.is_empty() {
-        return Err("Id impostazione mancante".to_string());
+        return Err("Id impostazione mancante".into());
     }
 
     ensure_supabase_client().await?;
@@ -3760,12 +4361,11 @@ async fn update_impostazione(id: String, valore: String) -> Result<(), String> {
     let client_guard = get_supabase_client().lock().await;
     let client = client_guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     let rows = client
-        .fetch_impostazioni(None)
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
+        .fetch_impostazioni(Query::new())
+        .await?;
 
     let row = rows
         .iter()
@@ -3780,7 +4380,38 @@ async fn update_impostazione(id: String, valore: String) -> Result<(), String> {
                 .unwrap_or_default();
             rid == id
         })
-        .ok_or_else(|| format!("Impostazione id={} non trovata", id))?;
+        .ok_or_else(|| ApiError::not_found(format!("Impostazione id={} non trovata", id)))?;
+
+    // Le impostazioni della tariffa aprono una nuova versione da oggi: i servizi
+    // già svolti restano prezzati con la tariffa del loro giorno. La versione si
+    // scrive prima dell'impostazione, così un errore non lascia le due discordi.
+    let nome = get_field_any(row, &["Impostazione", "IMPOSTAZIONE", "Nome", "Chiave", "Impostazione_Nome"]);
+    let da_impostazioni = ParametriTariffa::da_impostazioni(&rows);
+    if da_impostazioni.con_impostazione(&nome, &valore).is_some() {
+        let righe_tariffe = client.fetch_tariffe(Query::new()).await.map_err(|e| {
+            ApiError::config(format!(
+                "Tariffe_supa non disponibile ({}): esegui docs/supabase-tariffe.sql prima di cambiare {}",
+                e, nome
+            ))
+        })?;
+        let storico = StoricoTariffe::da_righe(&righe_tariffe);
+        let oggi = Local::now().date_naive();
+        let precedente = storico.in_vigore(oggi);
+        let attuali = match precedente {
+            Some(v) => v.parametri.clone(),
+            None => da_impostazioni,
+        };
+        if let Some(parametri) = attuali.con_impostazione(&nome, &valore) {
+            let versione = VersioneTariffa {
+                valido_dal: oggi,
+                valido_al: None,
+                parametri,
+                creato_da: perm.username,
+                creato_il: chrono::Utc::now().to_rfc3339(),
+            };
+            salva_versione_tariffa(client, &versione, precedente).await?;
+        }
+    }
 
     let col = valore_column_name_impostazione(row);
     let mut body = serde_json::Map::new();
@@ -3788,8 +4419,16 @@ async fn update_impostazione(id: String, valore: String) -> Result<(), String> {
 
     client
         .patch_impostazione(&id, &body)
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
+        .await?;
+    registra_audit(
+        client,
+        "impostazioni",
+        &id,
+        audit::AZIONE_UPDATE,
+        Some(row),
+        Some(&serde_json::Value::Object(body)),
+    )
+    .await;
 
     println!("✓ Impostazione id={} aggiornata (colonna {})", id, col);
     Ok(())
@@ -3805,6 +4444,18 @@ struct UserPermissionsRecord {
     /// JSON object delle voci sidebar consentite (stringa serializzata o oggetto)
     #[serde(default)]
     sidebar_menu: serde_json::Value,
+    /// Codice del ruolo (vuoto = record precedente ai ruoli: valgono le voci sidebar)
+    #[serde(default)]
+    ruolo: String,
+    /// Eccezioni al ruolo per questo utente: {"incassi.vedi": true, ...}
+    #[serde(default)]
+    capacita_extra: serde_json::Value,
+    /// Capacità effettive (calcolate, non salvate)
+    #[serde(default)]
+    capacita: Vec<String>,
+    /// IdSocio dell'operatore collegato all'utente (servizi "solo propri")
+    #[serde(default)]
+    idsocio: String,
 }
 
 fn parse_sidebar_menu_from_row(row: &serde_json::Value) -> serde_json::Value {
@@ -3847,12 +4498,32 @@ fn supabase_row_to_user_permissions(row: &serde_json::Value) -> UserPermissionsR
         programma: get_bool_from_row(row, &["Programma", "programma", "PROGRAMMA"]),
         calendario: get_bool_from_row(row, &["Calendario", "calendario", "CALENDARIO"]),
         sidebar_menu: parse_sidebar_menu_from_row(row),
+        ruolo: get_field_any(row, &["Ruolo", "ruolo", "RUOLO"]).to_lowercase(),
+        capacita_extra: permessi::normalizza_eccezioni(
+            row.get("CapacitaExtra")
+                .or_else(|| row.get("capacita_extra"))
+                .unwrap_or(&serde_json::Value::Null),
+        ),
+        capacita: Vec::new(),
+        idsocio: normalize_idsocio_key(&get_field_any(row, &["IdSocio", "idsocio", "IDSOCIO"])),
+    }
+}
+
+/// Ruoli predefiniti + Ruoli_supa. Senza la tabella (non ancora creata) valgono
+/// solo i predefiniti.
+async fn carica_ruoli(client: &SupabaseClient) -> Vec<Ruolo> {
+    match client.fetch_ruoli().await {
+        Ok(righe) => permessi::unisci_ruoli(&righe),
+        Err(e) => {
+            println!("⚠️ Ruoli personalizzati non letti ({}): uso i predefiniti", e);
+            permessi::ruoli_predefiniti()
+        }
     }
 }
 
 /// Chiavi pubbliche per login Auth (senza secret)
 #[tauri::command]
-async fn get_supabase_auth_config() -> Result<serde_json::Value, String> {
+async fn get_supabase_auth_config() -> Result<serde_json::Value, ApiError> {
     let config = load_app_config_from_file().await?;
     let supabase = config
         .supabase
@@ -3861,7 +4532,7 @@ async fn get_supabase_auth_config() -> Result<serde_json::Value, String> {
 
     let url = supabase.url.trim().to_string();
     if url.is_empty() {
-        return Err("supabase.url mancante in config.json".to_string());
+        return Err("supabase.url mancante in config.json".into());
     }
 
     let public_key = supabase
@@ -3893,7 +4564,7 @@ async fn get_supabase_auth_config() -> Result<serde_json::Value, String> {
 }
 
 #[tauri::command]
-async fn get_user_permissions(user_id: String) -> Result<Option<UserPermissionsRecord>, String> {
+async fn get_user_permissions(user_id: String) -> Result<Option<UserPermissionsRecord>, ApiError> {
     println!(
         "=== get_user_permissions chiamato per user_id={} ===",
         user_id
@@ -3901,61 +4572,181 @@ async fn get_user_permissions(user_id: String) -> Result<Option<UserPermissionsR
 
     let uid = user_id.trim();
     if uid.is_empty() {
-        return Err("user_id vuoto".to_string());
+        return Err("user_id vuoto".into());
+    }
+    // I permessi di un altro utente li legge solo un amministratore
+    let propri = get_auth_store()
+        .get()
+        .map(|s| s.user_id == uid)
+        .unwrap_or(false);
+    if !propri {
+        richiedi_permesso(Capacita::UtentiGestisci).await?;
     }
 
+    leggi_permessi_utente(uid).await
+}
+
+async fn leggi_permessi_utente(uid: &str) -> Result<Option<UserPermissionsRecord>, ApiError> {
     ensure_supabase_client().await?;
 
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
-        let filter = format!("user_id=eq.{}", uid);
+        let filter = Query::new().eq("user_id", &uid);
         let rows = client
-            .fetch_user_permissions(Some(&filter))
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_user_permissions(filter)
+            .await?;
 
-        let perm = rows.first().map(supabase_row_to_user_permissions);
-        if let Some(ref p) = perm {
+        let mut perm = rows.first().map(supabase_row_to_user_permissions);
+        if let Some(ref mut p) = perm {
+            let ruoli = carica_ruoli(client).await;
+            p.capacita = permessi::capacita_effettive(p, &ruoli);
             println!(
-                "✓ Permessi: username={}, is_admin={}, programma={}",
-                p.username, p.is_admin, p.programma
+                "✓ Permessi: username={}, is_admin={}, ruolo={}, capacità={}",
+                p.username,
+                p.is_admin,
+                if p.ruolo.is_empty() { "-" } else { &p.ruolo },
+                p.capacita.len()
             );
         } else {
             println!("⚠️ Nessun record in user_permissions per questo utente");
         }
         Ok(perm)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
-async fn ensure_caller_is_admin(admin_user_id: &str) -> Result<(), String> {
+async fn ensure_caller_is_admin(admin_user_id: &str) -> Result<UserPermissionsRecord, ApiError> {
     let uid = admin_user_id.trim();
-    if uid.is_empty() {
-        return Err("Sessione admin non valida".to_string());
+    // L'id passato dalla finestra deve essere quello dell'utente collegato
+    let sessione = get_auth_store().get();
+    if uid.is_empty() || sessione.map(|s| s.user_id != uid).unwrap_or(true) {
+        return Err(ApiError::permission_denied("Sessione admin non valida"));
+    }
+    richiedi_permesso(Capacita::UtentiGestisci).await
+}
+
+/// Solo un amministratore concede is_admin o il ruolo amministratore: chi ha
+/// "utenti.gestisci" da un ruolo personalizzato non può promuovere (né sé stesso)
+fn verifica_concessione_admin(
+    chiamante: &UserPermissionsRecord,
+    is_admin: bool,
+    ruolo: Option<&str>,
+) -> Result<(), ApiError> {
+    let ruolo_admin = ruolo
+        .map(|r| r.trim().eq_ignore_ascii_case(RUOLO_AMMINISTRATORE))
+        .unwrap_or(false);
+    if (is_admin || ruolo_admin) && !chiamante.is_admin {
+        return Err(ApiError::permission_denied(
+            "Solo un amministratore può assegnare i permessi di amministratore",
+        ));
+    }
+    Ok(())
+}
+
+/// Gli account amministratore si modificano o eliminano solo da un amministratore
+async fn verifica_utente_modificabile(
+    chiamante: &UserPermissionsRecord,
+    user_id: &str,
+) -> Result<(), ApiError> {
+    if chiamante.is_admin {
+        return Ok(());
     }
-    let perm = get_user_permissions(uid.to_string()).await?;
-    match perm {
-        Some(p) if p.is_admin => Ok(()),
-        _ => Err("Solo gli amministratori possono gestire gli utenti".to_string()),
+    let client = supabase_client_clone().await?;
+    let admin = client
+        .fetch_all_user_permissions_admin()
+        .await?
+        .iter()
+        .map(supabase_row_to_user_permissions)
+        .find(|p| p.user_id == user_id)
+        .map(|p| p.is_admin)
+        .unwrap_or(false);
+    if admin {
+        return Err(ApiError::permission_denied(
+            "Solo un amministratore può modificare un account amministratore",
+        ));
+    }
+    Ok(())
+}
+
+// Permessi dell'utente della sessione, riletti al più ogni minuto
+static PERMESSI_SESSIONE: OnceLock<Mutex<Option<(UserPermissionsRecord, std::time::Instant)>>> =
+    OnceLock::new();
+const DURATA_PERMESSI_SESSIONE: std::time::Duration = std::time::Duration::from_secs(60);
+
+async fn dimentica_permessi_sessione() {
+    *PERMESSI_SESSIONE.get_or_init(|| Mutex::new(None)).lock().await = None;
+}
+
+/// Controllo di autorizzazione all'inizio di ogni comando: serve una sessione
+/// valida e un record user_permissions che conceda `capacita`
+async fn richiedi_permesso(capacita: Capacita) -> Result<UserPermissionsRecord, ApiError> {
+    let sessione = get_auth_store()
+        .get()
+        .ok_or_else(|| ApiError::permission_denied("Accesso richiesto: effettua il login"))?;
+
+    let cache = PERMESSI_SESSIONE.get_or_init(|| Mutex::new(None));
+    let mut guard = cache.lock().await;
+    let valida = guard
+        .as_ref()
+        .filter(|(p, letto)| p.user_id == sessione.user_id && letto.elapsed() < DURATA_PERMESSI_SESSIONE)
+        .map(|(p, _)| p.clone());
+    let perm = match valida {
+        Some(p) => p,
+        None => match leggi_permessi_utente(&sessione.user_id).await {
+            Ok(Some(p)) => {
+                *guard = Some((p.clone(), std::time::Instant::now()));
+                p
+            }
+            Ok(None) => {
+                *guard = None;
+                return Err(ApiError::permission_denied(
+                    "Utente non presente in user_permissions. Contatta l'amministratore.",
+                ));
+            }
+            // Offline: valgono gli ultimi permessi letti per lo stesso utente
+            Err(e @ ApiError::Network { .. }) => match guard.as_ref() {
+                Some((p, _)) if p.user_id == sessione.user_id => p.clone(),
+                _ => return Err(e),
+            },
+            Err(e) => return Err(e),
+        },
+    };
+    drop(guard);
+
+    if !capacita.consentita(&perm) {
+        println!(
+            "⛔ {} senza permesso {}",
+            perm.username,
+            capacita.codice()
+        );
+        return Err(ApiError::permission_denied(format!(
+            "Permesso negato: serve «{}»",
+            capacita.descrizione()
+        )));
     }
+    Ok(perm)
 }
 
 #[tauri::command]
 async fn get_all_user_permissions(
     admin_user_id: String,
-) -> Result<Vec<UserPermissionsRecord>, String> {
+) -> Result<Vec<UserPermissionsRecord>, ApiError> {
     ensure_caller_is_admin(&admin_user_id).await?;
     ensure_supabase_client().await?;
 
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
-        let rows = client
-            .fetch_user_permissions(None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
-        let mut list: Vec<UserPermissionsRecord> =
-            rows.iter().map(supabase_row_to_user_permissions).collect();
+        let rows = client.fetch_all_user_permissions_admin().await?;
+        let ruoli = carica_ruoli(client).await;
+        let mut list: Vec<UserPermissionsRecord> = rows
+            .iter()
+            .map(|row| {
+                let mut p = supabase_row_to_user_permissions(row);
+                p.capacita = permessi::capacita_effettive(&p, &ruoli);
+                p
+            })
+            .collect();
         list.sort_by(|a, b| {
             a.username
                 .to_lowercase()
@@ -3963,7 +4754,7 @@ async fn get_all_user_permissions(
         });
         Ok(list)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
@@ -3979,31 +4770,125 @@ struct UpdateUserPermissionsPayload {
     /// JSON object voci sidebar (opzionale)
     #[serde(default)]
     sidebar_menu: Option<serde_json::Value>,
+    /// Codice ruolo ("" = nessun ruolo, valgono le voci sidebar); None = invariato
+    #[serde(default)]
+    ruolo: Option<String>,
+    /// Eccezioni al ruolo {codice: bool}; None = invariate
+    #[serde(default)]
+    capacita_extra: Option<serde_json::Value>,
+    /// IdSocio dell'operatore collegato ("" = nessuno); None = invariato
+    #[serde(default)]
+    idsocio: Option<String>,
     /// Se valorizzata, aggiorna anche la password Auth
     #[serde(default)]
     nuova_password: Option<String>,
 }
 
+/// Colonne Ruolo / CapacitaExtra da salvare in user_permissions. Il ruolo
+/// amministratore porta con sé is_admin; qualsiasi ruolo abilita Programma.
+/// Restituisce (is_admin, programma) aggiornati.
+fn applica_ruolo(
+    body: &mut serde_json::Map<String, serde_json::Value>,
+    ruoli: &[Ruolo],
+    ruolo: Option<&str>,
+    capacita_extra: Option<&serde_json::Value>,
+    is_admin: bool,
+    programma: bool,
+) -> Result<(bool, bool), ApiError> {
+    let mut flags = (is_admin, programma);
+    if let Some(ruolo) = ruolo.map(|r| r.trim().to_lowercase()) {
+        if ruolo.is_empty() {
+            body.insert("Ruolo".to_string(), serde_json::Value::Null);
+        } else {
+            if !ruoli.iter().any(|r| r.codice == ruolo) {
+                return Err(format!("Ruolo sconosciuto: {}", ruolo).into());
+            }
+            flags.0 = is_admin || ruolo == RUOLO_AMMINISTRATORE;
+            flags.1 = true;
+            body.insert("Ruolo".to_string(), serde_json::json!(ruolo));
+        }
+    }
+    if let Some(extra) = capacita_extra {
+        body.insert(
+            "CapacitaExtra".to_string(),
+            serde_json::json!(permessi::normalizza_eccezioni(extra).to_string()),
+        );
+    }
+    Ok(flags)
+}
+
+/// Colonna IdSocio: collega l'utente a un operatore (servizi propri)
+fn applica_idsocio(body: &mut serde_json::Map<String, serde_json::Value>, idsocio: Option<&str>) {
+    if let Some(idsocio) = idsocio.map(normalize_idsocio_key) {
+        let valore = if idsocio.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::json!(idsocio)
+        };
+        body.insert("IdSocio".to_string(), valore);
+    }
+}
+
 #[tauri::command]
-async fn update_user_permissions(payload: UpdateUserPermissionsPayload) -> Result<(), String> {
-    ensure_caller_is_admin(&payload.admin_user_id).await?;
+async fn update_user_permissions(payload: UpdateUserPermissionsPayload) -> Result<(), ApiError> {
+    let chiamante = ensure_caller_is_admin(&payload.admin_user_id).await?;
+    verifica_concessione_admin(&chiamante, payload.is_admin, payload.ruolo.as_deref())?;
+    dimentica_permessi_sessione().await;
 
     let user_id = payload.user_id.trim();
     if user_id.is_empty() {
-        return Err("user_id mancante".to_string());
+        return Err("user_id mancante".into());
     }
+    verifica_utente_modificabile(&chiamante, user_id).await?;
 
     let username = payload.username.trim().to_string();
     if username.is_empty() {
-        return Err("Username obbligatorio".to_string());
+        return Err("Username obbligatorio".into());
     }
 
     ensure_supabase_client().await?;
 
     let mut body = serde_json::Map::new();
     body.insert("username".to_string(), serde_json::json!(username));
-    body.insert("is_admin".to_string(), serde_json::json!(payload.is_admin));
-    body.insert("Programma".to_string(), serde_json::json!(payload.programma));
+    let ruoli = {
+        let client = supabase_client_clone().await?;
+        carica_ruoli(&client).await
+    };
+    let (is_admin, programma) = applica_ruolo(
+        &mut body,
+        &ruoli,
+        payload.ruolo.as_deref(),
+        payload.capacita_extra.as_ref(),
+        payload.is_admin,
+        payload.programma,
+    )?;
+    applica_idsocio(&mut body, payload.idsocio.as_deref());
+
+    // Chi gestisce gli utenti non può togliersi da solo questa capacità
+    let se_stesso = get_auth_store().get().map(|s| s.user_id == user_id).unwrap_or(false);
+    if se_stesso && payload.ruolo.is_some() {
+        let prova = UserPermissionsRecord {
+            user_id: user_id.to_string(),
+            username: username.clone(),
+            is_admin,
+            programma,
+            calendario: payload.calendario,
+            sidebar_menu: payload.sidebar_menu.clone().unwrap_or_default(),
+            ruolo: payload.ruolo.clone().unwrap_or_default().trim().to_lowercase(),
+            capacita_extra: payload.capacita_extra.clone().unwrap_or_default(),
+            capacita: Vec::new(),
+            idsocio: normalize_idsocio_key(payload.idsocio.as_deref().unwrap_or_default()),
+        };
+        let capacita = permessi::capacita_effettive(&prova, &ruoli);
+        if !is_admin && !capacita.iter().any(|c| c == Capacita::UtentiGestisci.codice()) {
+            return Err(ApiError::permission_denied(
+                "Non puoi togliere a te stesso la gestione utenti",
+            ));
+        }
+    }
+
+    body.insert("is_admin".to_string(), serde_json::json!(is_admin));
+    body.insert("Programma".to_string(), serde_json::json!(programma));
     body.insert("Calendario".to_string(), serde_json::json!(payload.calendario));
     if let Some(ref menu) = payload.sidebar_menu {
         // Salva come testo JSON (colonna text) — compatibile anche con jsonb
@@ -4019,7 +4904,7 @@ async fn update_user_permissions(payload: UpdateUserPermissionsPayload) -> Resul
         let client_guard = get_supabase_client().lock().await;
         let client = client_guard
             .as_ref()
-            .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+            .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
         client.patch_user_permissions(user_id, &body).await?;
 
         let pwd = payload
@@ -4029,7 +4914,7 @@ async fn update_user_permissions(payload: UpdateUserPermissionsPayload) -> Resul
             .filter(|s| !s.is_empty());
         if let Some(p) = pwd {
             if p.len() < 6 {
-                return Err("La password deve avere almeno 6 caratteri".to_string());
+                return Err("La password deve avere almeno 6 caratteri".into());
             }
             client
                 .admin_update_auth_user(user_id, Some(p), Some(&username))
@@ -4052,16 +4937,17 @@ struct DeleteAppUserPayload {
 }
 
 #[tauri::command]
-async fn delete_app_user(payload: DeleteAppUserPayload) -> Result<(), String> {
-    ensure_caller_is_admin(&payload.admin_user_id).await?;
+async fn delete_app_user(payload: DeleteAppUserPayload) -> Result<(), ApiError> {
+    let chiamante = ensure_caller_is_admin(&payload.admin_user_id).await?;
 
     let user_id = payload.user_id.trim();
     if user_id.is_empty() {
-        return Err("user_id mancante".to_string());
+        return Err("user_id mancante".into());
     }
+    verifica_utente_modificabile(&chiamante, user_id).await?;
 
     if user_id == payload.admin_user_id.trim() {
-        return Err("Non puoi eliminare il tuo stesso account mentre sei collegato.".to_string());
+        return Err("Non puoi eliminare il tuo stesso account mentre sei collegato.".into());
     }
 
     ensure_supabase_client().await?;
@@ -4069,7 +4955,7 @@ async fn delete_app_user(payload: DeleteAppUserPayload) -> Result<(), String> {
     let client_guard = get_supabase_client().lock().await;
     let client = client_guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     // Prima i permessi app, poi Auth (così non resta un accesso “orfano”)
     client.delete_user_permissions(user_id).await?;
@@ -4079,7 +4965,7 @@ async fn delete_app_user(payload: DeleteAppUserPayload) -> Result<(), String> {
         return Err(format!(
             "Utente rimosso da user_permissions, ma eliminazione Auth fallita: {}",
             e
-        ));
+        ).into());
     }
 
     println!("✓ Utente {} eliminato (permissions + Auth)", user_id);
@@ -4097,20 +4983,27 @@ struct CreateAppUserPayload {
     calendario: bool,
     #[serde(default)]
     sidebar_menu: Option<serde_json::Value>,
+    #[serde(default)]
+    ruolo: Option<String>,
+    #[serde(default)]
+    capacita_extra: Option<serde_json::Value>,
+    #[serde(default)]
+    idsocio: Option<String>,
 }
 
 #[tauri::command]
-async fn create_app_user(payload: CreateAppUserPayload) -> Result<UserPermissionsRecord, String> {
-    ensure_caller_is_admin(&payload.admin_user_id).await?;
+async fn create_app_user(payload: CreateAppUserPayload) -> Result<UserPermissionsRecord, ApiError> {
+    let chiamante = ensure_caller_is_admin(&payload.admin_user_id).await?;
+    verifica_concessione_admin(&chiamante, payload.is_admin, payload.ruolo.as_deref())?;
 
     let email = payload.email.trim();
     let password = payload.password.trim();
     let username = payload.username.trim();
     if email.is_empty() || password.is_empty() {
-        return Err("Email e password sono obbligatorie".to_string());
+        return Err("Email e password sono obbligatorie".into());
     }
     if password.len() < 6 {
-        return Err("La password deve avere almeno 6 caratteri".to_string());
+        return Err("La password deve avere almeno 6 caratteri".into());
     }
 
     ensure_supabase_client().await?;
@@ -4118,7 +5011,7 @@ async fn create_app_user(payload: CreateAppUserPayload) -> Result<UserPermission
     let client_guard = get_supabase_client().lock().await;
     let client = client_guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     let (user_id, auth_nuovo) = client.admin_ensure_auth_user(email, password).await?;
 
@@ -4138,18 +5031,33 @@ async fn create_app_user(payload: CreateAppUserPayload) -> Result<UserPermission
         sidebar_val.to_string()
     };
 
-    let row = serde_json::json!({
+    let ruoli = carica_ruoli(client).await;
+    let mut campi_ruolo = serde_json::Map::new();
+    let (is_admin, programma) = applica_ruolo(
+        &mut campi_ruolo,
+        &ruoli,
+        payload.ruolo.as_deref(),
+        payload.capacita_extra.as_ref(),
+        payload.is_admin,
+        payload.programma,
+    )?;
+    applica_idsocio(&mut campi_ruolo, payload.idsocio.as_deref());
+
+    let mut row = serde_json::json!({
         "user_id": user_id,
         "username": display_name,
-        "is_admin": payload.is_admin,
-        "Programma": payload.programma,
+        "is_admin": is_admin,
+        "Programma": programma,
         "Calendario": payload.calendario,
         "SidebarMenu": sidebar_text,
-        "can_insert": payload.is_admin || payload.programma,
-        "can_update": payload.is_admin || payload.programma,
-        "can_delete": payload.is_admin,
-        "can_export": payload.is_admin || payload.programma
+        "can_insert": is_admin || programma,
+        "can_update": is_admin || programma,
+        "can_delete": is_admin,
+        "can_export": is_admin || programma
     });
+    if let Some(obj) = row.as_object_mut() {
+        obj.extend(campi_ruolo);
+    }
 
     client
         .upsert_user_permissions(&user_id, &row)
@@ -4171,16 +5079,151 @@ async fn create_app_user(payload: CreateAppUserPayload) -> Result<UserPermission
         email, auth_nuovo, user_id, display_name
     );
 
-    Ok(UserPermissionsRecord {
+    let mut record = UserPermissionsRecord {
         user_id,
         username: display_name,
-        is_admin: payload.is_admin,
-        programma: payload.programma,
+        is_admin,
+        programma,
         calendario: payload.calendario,
         sidebar_menu: sidebar_val,
+        ruolo: payload.ruolo.unwrap_or_default().trim().to_lowercase(),
+        capacita_extra: permessi::normalizza_eccezioni(
+            &payload.capacita_extra.unwrap_or_default(),
+        ),
+        capacita: Vec::new(),
+        idsocio: normalize_idsocio_key(payload.idsocio.as_deref().unwrap_or_default()),
+    };
+    record.capacita = permessi::capacita_effettive(&record, &ruoli);
+    Ok(record)
+}
+
+#[derive(Debug, Serialize)]
+struct CatalogoRuoli {
+    ruoli: Vec<Ruolo>,
+    capacita: Vec<CapacitaInfo>,
+}
+
+/// Ruoli disponibili e catalogo delle capacità (pagina Gestione utenti)
+#[tauri::command]
+async fn get_ruoli(admin_user_id: String) -> Result<CatalogoRuoli, ApiError> {
+    ensure_caller_is_admin(&admin_user_id).await?;
+    let client = supabase_client_clone().await?;
+    Ok(CatalogoRuoli {
+        ruoli: carica_ruoli(&client).await,
+        capacita: TUTTE_LE_CAPACITA
+            .iter()
+            .map(|c| CapacitaInfo {
+                codice: c.codice(),
+                descrizione: c.descrizione(),
+            })
+            .collect(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct SaveRuoloPayload {
+    admin_user_id: String,
+    codice: String,
+    nome: String,
+    #[serde(default)]
+    capacita: Vec<String>,
+}
+
+/// Crea un ruolo o modifica uno esistente (anche un predefinito, che viene
+/// ridefinito in Ruoli_supa)
+#[tauri::command]
+async fn save_ruolo(payload: SaveRuoloPayload) -> Result<Ruolo, ApiError> {
+    ensure_caller_is_admin(&payload.admin_user_id).await?;
+
+    let codice = payload.codice.trim().to_lowercase();
+    let nome = payload.nome.trim().to_string();
+    if codice.is_empty()
+        || !codice
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err("Codice ruolo non valido: solo lettere minuscole, cifre e _".into());
+    }
+    if codice == RUOLO_AMMINISTRATORE {
+        return Err("Il ruolo amministratore ha sempre tutte le capacità".into());
+    }
+    if nome.is_empty() {
+        return Err("Nome ruolo obbligatorio".into());
+    }
+    if let Some(sconosciuta) = payload
+        .capacita
+        .iter()
+        .find(|c| Capacita::da_codice(c).is_none())
+    {
+        return Err(format!("Capacità sconosciuta: {}", sconosciuta).into());
+    }
+    let capacita = permessi::normalizza_capacita(&serde_json::json!(payload.capacita));
+
+    let client = supabase_client_clone().await?;
+    client
+        .upsert_ruolo(&serde_json::json!({
+            "codice": codice,
+            "nome": nome,
+            "capacita": capacita
+        }))
+        .await?;
+    dimentica_permessi_sessione().await;
+    println!("✓ Ruolo {} salvato ({} capacità)", codice, capacita.len());
+
+    let predefinito = permessi::ruoli_predefiniti()
+        .iter()
+        .any(|r| r.codice == codice);
+    Ok(Ruolo {
+        codice,
+        nome,
+        capacita,
+        predefinito,
     })
 }
 
+#[derive(Debug, Deserialize)]
+struct DeleteRuoloPayload {
+    admin_user_id: String,
+    codice: String,
+}
+
+/// Elimina un ruolo personalizzato; per un predefinito ripristina il modello.
+/// Rifiuta se qualche utente ha ancora quel ruolo (e il ruolo sparirebbe).
+#[tauri::command]
+async fn delete_ruolo(payload: DeleteRuoloPayload) -> Result<(), ApiError> {
+    ensure_caller_is_admin(&payload.admin_user_id).await?;
+
+    let codice = payload.codice.trim().to_lowercase();
+    let predefinito = permessi::ruoli_predefiniti()
+        .iter()
+        .any(|r| r.codice == codice);
+    let client = supabase_client_clone().await?;
+    if !predefinito {
+        let assegnati: Vec<String> = client
+            .fetch_all_user_permissions_admin()
+            .await?
+            .iter()
+            .map(supabase_row_to_user_permissions)
+            .filter(|p| p.ruolo == codice)
+            .map(|p| p.username)
+            .collect();
+        if !assegnati.is_empty() {
+            return Err(ApiError::Conflict {
+                code: Some("ruolo_in_uso".to_string()),
+                message: format!(
+                    "Ruolo {} ancora assegnato a: {}",
+                    codice,
+                    assegnati.join(", ")
+                ),
+            });
+        }
+    }
+    client.delete_ruolo(&codice).await?;
+    dimentica_permessi_sessione().await;
+    println!("✓ Ruolo {} eliminato", codice);
+    Ok(())
+}
+
 fn stati_servizio_da_righe(rows: &[serde_json::Value]) -> Vec<String> {
     let mut stati: Vec<String> = Vec::new();
 
@@ -4198,7 +5241,8 @@ fn stati_servizio_da_righe(rows: &[serde_json::Value]) -> Vec<String> {
 }
 
 #[tauri::command]
-async fn get_all_stati_servizio() -> Result<Vec<String>, String> {
+async fn get_all_stati_servizio() -> Result<Vec<String>, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
     println!("=== get_all_stati_servizio chiamato (Supabase / StatoDelServizio_supa) ===");
 
     ensure_supabase_client().await?;
@@ -4206,9 +5250,8 @@ async fn get_all_stati_servizio() -> Result<Vec<String>, String> {
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
         let rows = client
-            .fetch_stati_del_servizio(None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_stati_del_servizio(Query::new())
+            .await?;
 
         let stati = stati_servizio_da_righe(&rows);
 
@@ -4227,7 +5270,7 @@ async fn get_all_stati_servizio() -> Result<Vec<String>, String> {
         println!("✓ Caricati {} stati servizio da Supabase", stati.len());
         Ok(stati)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
@@ -4289,7 +5332,8 @@ struct LocalitaAutocompleteServizi {
 }
 
 #[tauri::command]
-async fn get_all_richiedenti() -> Result<Vec<String>, String> {
+async fn get_all_richiedenti() -> Result<Vec<String>, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
     println!("=== get_all_richiedenti chiamato (Supabase / Richiedenti_supa) ===");
 
     ensure_supabase_client().await?;
@@ -4297,20 +5341,20 @@ async fn get_all_richiedenti() -> Result<Vec<String>, String> {
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
         let rows = client
-            .fetch_richiedenti(None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_richiedenti(Query::new())
+            .await?;
 
         let richiedenti = richiedenti_da_righe(&rows);
         println!("✓ Caricati {} richiedenti da Supabase", richiedenti.len());
         Ok(richiedenti)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 #[tauri::command]
-async fn get_all_tipologie_socio() -> Result<Vec<String>, String> {
+async fn get_all_tipologie_socio() -> Result<Vec<String>, ApiError> {
+    richiedi_permesso(Capacita::SociLeggi).await?;
     println!("=== get_all_tipologie_socio chiamato (Supabase / TipoSocio_supa) ===");
 
     ensure_supabase_client().await?;
@@ -4318,20 +5362,20 @@ async fn get_all_tipologie_socio() -> Result<Vec<String>, String> {
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
         let rows = client
-            .fetch_tipologie_socio(None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_tipologie_socio(Query::new())
+            .await?;
 
         let tipologie = tipologie_socio_da_righe(&rows);
         println!("✓ Caricate {} tipologie socio da Supabase", tipologie.len());
         Ok(tipologie)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 #[tauri::command]
-async fn add_tipologia_socio(tipologia: String) -> Result<(), String> {
+async fn add_tipologia_socio(tipologia: String) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::SociModifica).await?;
     let value = tipologia.trim();
     if value.is_empty() {
         return Ok(());
@@ -4347,9 +5391,8 @@ async fn add_tipologia_socio(tipologia: String) -> Result<(), String> {
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
         let rows = client
-            .fetch_tipologie_socio(None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_tipologie_socio(Query::new())
+            .await?;
 
         let existing = tipologie_socio_da_righe(&rows);
         if dotazione_gia_presente(&existing, value) {
@@ -4359,11 +5402,10 @@ async fn add_tipologia_socio(tipologia: String) -> Result<(), String> {
 
         client
             .insert_tipologia_socio(value)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .await?;
         Ok(())
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
@@ -4383,7 +5425,7 @@ struct LookupItemRecord {
     valore: String,
 }
 
-fn lookup_kind_meta(kind: &str) -> Result<(&'static str, &'static [&'static str], &'static str), String> {
+fn lookup_kind_meta(kind: &str) -> Result<(&'static str, &'static [&'static str], &'static str), ApiError> {
     match kind.trim().to_lowercase().as_str() {
         "richiedenti" => Ok((
             "richiedenti",
@@ -4449,7 +5491,8 @@ fn lookup_kind_meta(kind: &str) -> Result<(&'static str, &'static [&'static str]
         other => Err(format!(
             "Tipo lookup non supportato: '{}'. Usa richiedenti, tipo_socio, tipo_pagamenti, motivazioni_trasporto",
             other
-        )),
+        )
+        .into()),
     }
 }
 
@@ -4515,19 +5558,19 @@ fn lookup_item_from_row(
 async fn fetch_lookup_rows(
     client: &SupabaseClient,
     table_type: &str,
-) -> Result<Vec<serde_json::Value>, String> {
+) -> Result<Vec<serde_json::Value>, ApiError> {
     match table_type {
-        "richiedenti" => client.fetch_richiedenti(None).await,
-        "tipo_socio" => client.fetch_tipologie_socio(None).await,
-        "tipo_pagamenti" => client.fetch_tipi_pagamento(None).await,
-        "motivazioni_trasporto" => client.fetch_motivazioni_trasporto(None).await,
-        other => Err(format!("Tabella lookup sconosciuta: {}", other)),
+        "richiedenti" => client.fetch_richiedenti(Query::new()).await,
+        "tipo_socio" => client.fetch_tipologie_socio(Query::new()).await,
+        "tipo_pagamenti" => client.fetch_tipi_pagamento(Query::new()).await,
+        "motivazioni_trasporto" => client.fetch_motivazioni_trasporto(Query::new()).await,
+        other => Err(ApiError::config(format!("Tabella lookup sconosciuta: {}", other))),
     }
-    .map_err(|e| format_supabase_error(&e))
 }
 
 #[tauri::command]
-async fn get_lookup_items(kind: String) -> Result<Vec<LookupItemRecord>, String> {
+async fn get_lookup_items(kind: String) -> Result<Vec<LookupItemRecord>, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
     let (table_type, candidates, _) = lookup_kind_meta(&kind)?;
     println!(
         "=== get_lookup_items kind='{}' table='{}' ===",
@@ -4539,7 +5582,7 @@ async fn get_lookup_items(kind: String) -> Result<Vec<LookupItemRecord>, String>
     let client_guard = get_supabase_client().lock().await;
     let client = client_guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     let rows = fetch_lookup_rows(client, table_type).await?;
     let mut items: Vec<LookupItemRecord> = rows
@@ -4553,10 +5596,11 @@ async fn get_lookup_items(kind: String) -> Result<Vec<LookupItemRecord>, String>
 }
 
 #[tauri::command]
-async fn add_lookup_item(kind: String, valore: String) -> Result<(), String> {
+async fn add_lookup_item(kind: String, valore: String) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::ImpostazioniModifica).await?;
     let value = valore.trim();
     if value.is_empty() {
-        return Err("Il valore non può essere vuoto".to_string());
+        return Err("Il valore non può essere vuoto".into());
     }
 
     let (table_type, candidates, default_column) = lookup_kind_meta(&kind)?;
@@ -4570,7 +5614,7 @@ async fn add_lookup_item(kind: String, valore: String) -> Result<(), String> {
     let client_guard = get_supabase_client().lock().await;
     let client = client_guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     let rows = fetch_lookup_rows(client, table_type).await?;
     let existing: Vec<String> = rows
@@ -4586,28 +5630,28 @@ async fn add_lookup_item(kind: String, valore: String) -> Result<(), String> {
         .collect();
 
     if dotazione_gia_presente(&existing, value) {
-        return Err(format!("Il valore '{}' esiste già", value));
+        return Err(format!("Il valore '{}' esiste già", value).into());
     }
 
     let column = resolve_lookup_text_column(rows.first(), candidates, default_column);
     client
         .insert_lookup_text(table_type, &column, value)
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
+        .await?;
 
     println!("✓ Inserito lookup {} {}='{}'", table_type, column, value);
     Ok(())
 }
 
 #[tauri::command]
-async fn update_lookup_item(kind: String, id: String, valore: String) -> Result<(), String> {
+async fn update_lookup_item(kind: String, id: String, valore: String) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::ImpostazioniModifica).await?;
     let id = id.trim().to_string();
     let value = valore.trim();
     if id.is_empty() {
-        return Err("Id mancante".to_string());
+        return Err("Id mancante".into());
     }
     if value.is_empty() {
-        return Err("Il valore non può essere vuoto".to_string());
+        return Err("Il valore non può essere vuoto".into());
     }
 
     let (table_type, candidates, default_column) = lookup_kind_meta(&kind)?;
@@ -4621,13 +5665,15 @@ async fn update_lookup_item(kind: String, id: String, valore: String) -> Result<
     let client_guard = get_supabase_client().lock().await;
     let client = client_guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     let rows = fetch_lookup_rows(client, table_type).await?;
     let row = rows
         .iter()
         .find(|r| row_id_any(r) == id)
-        .ok_or_else(|| format!("Riga id={} non trovata in {}", id, table_type))?;
+        .ok_or_else(|| {
+            ApiError::not_found(format!("Riga id={} non trovata in {}", id, table_type))
+        })?;
 
     let column = resolve_lookup_text_column(Some(row), candidates, default_column);
     let mut body = serde_json::Map::new();
@@ -4635,18 +5681,18 @@ async fn update_lookup_item(kind: String, id: String, valore: String) -> Result<
 
     client
         .patch_lookup_by_id(table_type, &id, &body)
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
+        .await?;
 
     println!("✓ Aggiornato lookup {} id={} {}='{}'", table_type, id, column, value);
     Ok(())
 }
 
 #[tauri::command]
-async fn delete_lookup_item(kind: String, id: String) -> Result<(), String> {
+async fn delete_lookup_item(kind: String, id: String) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::ImpostazioniModifica).await?;
     let id = id.trim().to_string();
     if id.is_empty() {
-        return Err("Id mancante".to_string());
+        return Err("Id mancante".into());
     }
 
     let (table_type, _, _) = lookup_kind_meta(&kind)?;
@@ -4657,12 +5703,11 @@ async fn delete_lookup_item(kind: String, id: String) -> Result<(), String> {
     let client_guard = get_supabase_client().lock().await;
     let client = client_guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     client
         .delete_lookup_by_id(table_type, &id)
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
+        .await?;
 
     println!("✓ Eliminato lookup {} id={}", table_type, id);
     Ok(())
@@ -4688,7 +5733,8 @@ fn dotazioni_da_righe(rows: &[serde_json::Value]) -> Vec<String> {
 }
 
 #[tauri::command]
-async fn get_all_dotazioni_mezzi() -> Result<Vec<String>, String> {
+async fn get_all_dotazioni_mezzi() -> Result<Vec<String>, ApiError> {
+    richiedi_permesso(Capacita::MezziLeggi).await?;
     println!("=== get_all_dotazioni_mezzi chiamato (Supabase / DotazioniMezzi_supa) ===");
 
     ensure_supabase_client().await?;
@@ -4696,20 +5742,20 @@ async fn get_all_dotazioni_mezzi() -> Result<Vec<String>, String> {
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
         let rows = client
-            .fetch_dotazioni_mezzi(None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_dotazioni_mezzi(Query::new())
+            .await?;
 
         let dotazioni = dotazioni_da_righe(&rows);
         println!("✓ Caricate {} dotazioni mezzi da Supabase", dotazioni.len());
         Ok(dotazioni)
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 #[tauri::command]
-async fn add_dotazione_mezzo(dotazione: String) -> Result<(), String> {
+async fn add_dotazione_mezzo(dotazione: String) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::MezziModifica).await?;
     let value = dotazione.trim();
     if value.is_empty() {
         return Ok(());
@@ -4725,9 +5771,8 @@ async fn add_dotazione_mezzo(dotazione: String) -> Result<(), String> {
     let client_guard = get_supabase_client().lock().await;
     if let Some(client) = client_guard.as_ref() {
         let rows = client
-            .fetch_dotazioni_mezzi(None)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .fetch_dotazioni_mezzi(Query::new())
+            .await?;
 
         let existing = dotazioni_da_righe(&rows);
         if dotazione_gia_presente(&existing, value) {
@@ -4737,42 +5782,67 @@ async fn add_dotazione_mezzo(dotazione: String) -> Result<(), String> {
 
         client
             .insert_dotazione_mezzo(value)
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .await?;
         Ok(())
     } else {
-        Err("Client Supabase non disponibile".to_string())
+        Err(ApiError::config("Client Supabase non disponibile"))
     }
 }
 
 // Comando per ottenere un servizio completo per ID (Supabase / Servizi_supa)
 #[tauri::command]
-async fn get_servizio_completo(servizio_id: u32) -> Result<ServizioCompleto, String> {
+async fn get_servizio_completo(servizio_id: u32) -> Result<ServizioCompleto, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let propri = visibilita_propria(&perm).await?;
+    let mut servizio = leggi_servizio_completo(servizio_id, propri.as_ref()).await?;
+    servizio.maschera(Mascheramento::per_utente(&perm));
+    Ok(servizio)
+}
+
+/// Servizio senza oscuramento (serve anche alla duplicazione, che copia i dati veri)
+async fn leggi_servizio_completo(
+    servizio_id: u32,
+    propri: Option<&VisibilitaPropria>,
+) -> Result<ServizioCompleto, ApiError> {
     println!("=== get_servizio_completo chiamato per ID: {} (Supabase) ===", servizio_id);
 
-    let filter = format!("idservizio=eq.{}", servizio_id);
+    let filter = Query::new().eq("idservizio", servizio_id);
     let nominativi = fetch_idsocio_nominativo_map().await;
-    let rows = fetch_servizi_supabase(Some(&filter)).await?;
+    let visibile = |row: &serde_json::Value| -> Result<(), ApiError> {
+        match propri {
+            Some(vis) if !vis.servizio_proprio(row, &nominativi) => Err(ApiError::permission_denied(
+                format!("Il servizio {} non è assegnato a te", servizio_id),
+            )),
+            _ => Ok(()),
+        }
+    };
+    let rows = fetch_servizi_supabase(filter).await?;
     if let Some(row) = rows.first() {
         if let Some(sc) = supabase_row_to_servizio_completo(row, &nominativi) {
+            visibile(row)?;
             return Ok(sc);
         }
     }
 
-    let rows = fetch_servizi_supabase(None).await?;
+    let rows = fetch_servizi_supabase(Query::new()).await?;
     for row in &rows {
         if servizio_id_from_row(row) == servizio_id {
             if let Some(sc) = supabase_row_to_servizio_completo(row, &nominativi) {
+                visibile(row)?;
                 return Ok(sc);
             }
         }
     }
 
-    Err(format!("Servizio {} non trovato in Supabase", servizio_id))
+    Err(ApiError::not_found(format!(
+        "Servizio {} non trovato in Supabase",
+        servizio_id
+    )))
 }
 
 #[tauri::command]
-async fn get_motivazioni_servizi() -> Result<Vec<String>, String> {
+async fn get_motivazioni_servizi() -> Result<Vec<String>, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
     println!("=== get_motivazioni_servizi chiamato (Supabase) ===");
     let rows = fetch_motivazioni_servizi_supabase().await?;
     let lista = motivazioni_da_righe(&rows);
@@ -4781,7 +5851,8 @@ async fn get_motivazioni_servizi() -> Result<Vec<String>, String> {
 }
 
 #[tauri::command]
-async fn get_comuni_prelievo_servizi() -> Result<Vec<String>, String> {
+async fn get_comuni_prelievo_servizi() -> Result<Vec<String>, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
     println!("=== get_comuni_prelievo_servizi chiamato (Supabase) ===");
     let rows = fetch_comuni_prelievo_servizi_supabase().await?;
     let lista = comuni_prelievo_da_righe(&rows);
@@ -4790,7 +5861,8 @@ async fn get_comuni_prelievo_servizi() -> Result<Vec<String>, String> {
 }
 
 #[tauri::command]
-async fn get_localita_autocomplete_servizi() -> Result<LocalitaAutocompleteServizi, String> {
+async fn get_localita_autocomplete_servizi() -> Result<LocalitaAutocompleteServizi, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
     println!("=== get_localita_autocomplete_servizi chiamato (Supabase) ===");
     let rows = fetch_localita_autocomplete_servizi_supabase().await?;
     let result = LocalitaAutocompleteServizi {
@@ -4835,7 +5907,8 @@ async fn get_localita_autocomplete_servizi() -> Result<LocalitaAutocompleteServi
 
 /// IdSocio che compaiono in almeno un servizio (per abilitare pulsante SERVIZI in elenco soci).
 #[tauri::command]
-async fn get_idsocio_con_servizi() -> Result<Vec<String>, String> {
+async fn get_idsocio_con_servizi() -> Result<Vec<String>, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
     println!("=== get_idsocio_con_servizi chiamato ===");
     let rows = fetch_servizi_idsocio_supabase().await?;
     let mut set = std::collections::HashSet::new();
@@ -4865,10 +5938,11 @@ struct OperatoriConServiziResult {
 
 /// Nominativi operatore presenti in almeno un servizio (campo OPERATORE principale).
 #[tauri::command]
-async fn get_operatori_con_servizi() -> Result<OperatoriConServiziResult, String> {
+async fn get_operatori_con_servizi() -> Result<OperatoriConServiziResult, ApiError> {
+    richiedi_permesso(Capacita::OperatoriVedi).await?;
     println!("=== get_operatori_con_servizi chiamato ===");
     let nominativi = fetch_idsocio_nominativo_map().await;
-    let rows = fetch_servizi_supabase(None).await?;
+    let rows = fetch_servizi_supabase(Query::new()).await?;
     let mut ids_set = std::collections::HashSet::new();
     let mut nom_set = std::collections::HashSet::new();
 
@@ -4909,26 +5983,20 @@ async fn get_operatori_con_servizi() -> Result<OperatoriConServiziResult, String
     })
 }
 
-// Comando per ottenere tutti i servizi completi (Supabase / Servizi_supa)
-// anno: filtra per anno di Prelievo_Data; tutti_anni=true scarica l'intero archivio
+// Comando per ottenere i servizi completi di un anno (Supabase / Servizi_supa)
+// anno: filtra per anno di Prelievo_Data (default anno corrente). L'archivio
+// completo si legge a pagine con get_servizi_completi_pagina.
 #[tauri::command]
-async fn get_all_servizi_completi(
-    anno: Option<u32>,
-    tutti_anni: Option<bool>,
-) -> Result<Vec<ServizioCompleto>, String> {
+async fn get_all_servizi_completi(anno: Option<u32>) -> Result<Vec<ServizioCompleto>, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let propri = visibilita_propria(&perm).await?;
     let nominativi = fetch_idsocio_nominativo_map().await;
-
-    if tutti_anni.unwrap_or(false) {
-        println!("=== get_all_servizi_completi chiamato (Supabase, TUTTI GLI ANNI) ===");
-        let rows = fetch_servizi_supabase(None).await?;
-        let mut servizi: Vec<ServizioCompleto> = rows
-            .iter()
-            .filter_map(|row| supabase_row_to_servizio_completo(row, &nominativi))
-            .collect();
-        sort_servizi_completi(&mut servizi);
-        println!("✓ Convertiti {} servizi completi da Supabase", servizi.len());
-        return Ok(servizi);
-    }
+    let visibile = |row: &serde_json::Value| {
+        propri
+            .as_ref()
+            .map(|vis| vis.servizio_proprio(row, &nominativi))
+            .unwrap_or(true)
+    };
 
     let year = anno.unwrap_or_else(|| Local::now().date_naive().year() as u32);
     println!(
@@ -4937,19 +6005,20 @@ async fn get_all_servizi_completi(
     );
 
     let filter = servizi_filter_anno(year);
-    let (rows, filtered_at_db) = match fetch_servizi_supabase(Some(&filter)).await {
+    let (rows, filtered_at_db) = match servizi_da_cache_delta(&filter).await {
         Ok(r) => (r, true),
         Err(e) => {
             println!(
                 "⚠️ Filtro anno Supabase fallito ({}), recupero tutti i servizi: {}",
                 filter, e
             );
-            (fetch_servizi_supabase(None).await?, false)
+            (servizi_da_cache_delta(&Query::new()).await?, false)
         }
     };
 
     let mut servizi: Vec<ServizioCompleto> = rows
         .iter()
+        .filter(|row| visibile(row))
         .filter_map(|row| supabase_row_to_servizio_completo(row, &nominativi))
         .collect();
 
@@ -4983,10 +6052,182 @@ async fn get_all_servizi_completi(
         }
     }
 
+    servizi.maschera(Mascheramento::per_utente(&perm));
     println!("✓ Convertiti {} servizi completi da Supabase", servizi.len());
     Ok(servizi)
 }
 
+#[derive(Debug, Serialize)]
+struct PaginaServizi {
+    servizi: Vec<ServizioCompleto>,
+    /// Da ripassare come `dopo` per la pagina successiva; None a fine archivio
+    prossimo: Option<u32>,
+}
+
+// Archivio completo una pagina alla volta (cursore su idservizio), così né
+// l'app né la finestra tengono in memoria tutte le righe grezze. Offline la
+// pagina viene dalla copia locale, come per le altre letture.
+#[tauri::command]
+async fn get_servizi_completi_pagina(
+    dopo: Option<u32>,
+    limite: Option<usize>,
+) -> Result<PaginaServizi, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let propri = visibilita_propria(&perm).await?;
+    let limite = limite.unwrap_or(1000).clamp(1, 1000);
+    println!("=== get_servizi_completi_pagina dopo {:?} (max {}) ===", dopo, limite);
+
+    let nominativi = fetch_idsocio_nominativo_map().await;
+    let mut query = Query::new().order("idservizio", true).limit(limite);
+    if let Some(dopo) = dopo {
+        query = query.gt("idservizio", dopo);
+    }
+    let client = supabase_client_clone().await?;
+    let rows = client.fetch_servizi(query).await?;
+
+    let prossimo = if rows.len() < limite {
+        None
+    } else {
+        rows.last().map(servizio_id_from_row).filter(|id| *id > 0)
+    };
+    let mut servizi: Vec<ServizioCompleto> = rows
+        .iter()
+        .filter(|row| {
+            propri
+                .as_ref()
+                .map(|vis| vis.servizio_proprio(row, &nominativi))
+                .unwrap_or(true)
+        })
+        .filter_map(|row| supabase_row_to_servizio_completo(row, &nominativi))
+        .collect();
+    servizi.maschera(Mascheramento::per_utente(&perm));
+    println!("✓ Pagina servizi: {} righe, prossima dopo {:?}", servizi.len(), prossimo);
+    Ok(PaginaServizi { servizi, prossimo })
+}
+
+#[derive(Debug, Serialize)]
+struct ModificheDal {
+    tabella: String,
+    /// Righe nuove o modificate (ServizioCompleto / Tesserato); tutte se `completo`
+    modificati: Vec<serde_json::Value>,
+    /// Chiavi (idservizio / IdSocio) eliminate o uscite dalla porzione richiesta
+    eliminati: Vec<String>,
+    /// Da ripassare come `dal` alla chiamata successiva
+    revisione: u64,
+    completo: bool,
+}
+
+// Modifiche dalla revisione `dal` (servizi di un anno, oppure tesserati).
+// Senza `dal`, o con una revisione non più valida, restituisce tutte le righe.
+#[tauri::command]
+async fn get_changes_since(
+    tabella: String,
+    dal: Option<u64>,
+    anno: Option<u32>,
+) -> Result<ModificheDal, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let propri = visibilita_propria(&perm).await?;
+    println!("=== get_changes_since {} dal revisione {:?} ===", tabella, dal);
+
+    let nominativi = if tabella == "servizi" || propri.is_some() {
+        fetch_idsocio_nominativo_map().await
+    } else {
+        HashMap::new()
+    };
+    // Contatti visibili solo per i soci dei propri servizi
+    let soci_visibili = match &propri {
+        Some(vis) if tabella == "tesserati" => Some(soci_dei_servizi_propri(vis, &nominativi).await?),
+        _ => None,
+    };
+
+    let client = supabase_client_clone().await?;
+
+    let porzione = match tabella.as_str() {
+        "servizi" => servizi_filter_anno(
+            anno.unwrap_or_else(|| Local::now().date_naive().year() as u32),
+        ),
+        "tesserati" => Query::new(),
+        other => return Err(format!("Tabella non gestita dalla sincronizzazione: {}", other).into()),
+    };
+
+    sincronizza_cache_delta(&client, &tabella, &porzione).await?;
+    let changes = delta_cache()
+        .lock()
+        .await
+        .entry(tabella.clone())
+        .or_default()
+        .changes_since(&porzione, dal);
+
+    let mut eliminati = changes.eliminate;
+    let modificati = if tabella == "servizi" {
+        let mut servizi: Vec<ServizioCompleto> = Vec::new();
+        for row in &changes.modificate {
+            // Righe non (più) proprie: per la finestra equivalgono a eliminate
+            if let Some(vis) = &propri {
+                if !vis.servizio_proprio(row, &nominativi) {
+                    eliminati.push(servizio_id_from_row(row).to_string());
+                    continue;
+                }
+            }
+            if let Some(sc) = supabase_row_to_servizio_completo(row, &nominativi) {
+                servizi.push(sc);
+            }
+        }
+        sort_servizi_completi(&mut servizi);
+        servizi.maschera(Mascheramento::per_utente(&perm));
+        servizi
+            .iter()
+            .filter_map(|s| serde_json::to_value(s).ok())
+            .collect()
+    } else {
+        let mut tesserati: Vec<Tesserato> = changes
+            .modificate
+            .iter()
+            .filter_map(supabase_row_to_tesserato)
+            .collect();
+        // Telefono in elenco = principale da Telefoni_supa, come in get_all_tesserati
+        if !tesserati.is_empty() {
+            let query = if changes.completo {
+                Query::new()
+            } else {
+                Query::new().in_list("IdSocio", tesserati.iter().map(|t| t.idsocio.clone()))
+            };
+            if let Ok(tel_rows) = client.fetch_telefoni(query).await {
+                apply_telefoni_principali(&mut tesserati, &build_telefoni_principali_map(&tel_rows));
+            }
+        }
+        if let Some(soci) = &soci_visibili {
+            for t in tesserati.iter_mut() {
+                if !soci.contains(&normalize_idsocio_key(&t.idsocio)) {
+                    oscura_contatti_tesserato(t);
+                }
+            }
+        }
+        tesserati.maschera(Mascheramento::per_utente(&perm));
+        tesserati
+            .iter()
+            .filter_map(|t| serde_json::to_value(t).ok())
+            .collect()
+    };
+
+    println!(
+        "✓ {}: {} modificati, {} eliminati, revisione {}{}",
+        tabella,
+        changes.modificate.len(),
+        eliminati.len(),
+        changes.revisione,
+        if changes.completo { " (completo)" } else { "" }
+    );
+
+    Ok(ModificheDal {
+        tabella,
+        modificati,
+        eliminati,
+        revisione: changes.revisione,
+        completo: changes.completo,
+    })
+}
+
 // Helper per formattare un valore in formato euro italiano (1.234,56 €)
 fn format_euro_italiano(value_str: &str) -> String {
     if value_str.is_empty() {
@@ -5077,7 +6318,8 @@ fn format_date_sharepoint_rust(date_str: &str) -> String {
 
 // Comando per stampare servizio
 #[tauri::command]
-async fn stampa_servizio(id: u32) -> Result<(), String> {
+async fn stampa_servizio(id: u32) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::Esporta).await?;
     println!("Stampa servizio {}", id);
     // TODO: Implementare logica di stampa
     Ok(())
@@ -5085,23 +6327,17 @@ async fn stampa_servizio(id: u32) -> Result<(), String> {
 
 // Comando per modificare servizio
 #[tauri::command]
-async fn modifica_servizio(id: u32) -> Result<(), String> {
+async fn modifica_servizio(id: u32) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::ServiziModifica).await?;
     println!("Modifica servizio {}", id);
     // TODO: Implementare logica di modifica
     Ok(())
 }
 
-// Comando per completare servizio
-#[tauri::command]
-async fn completa_servizio(id: u32) -> Result<(), String> {
-    println!("Completa servizio {}", id);
-    // TODO: Implementare logica di completamento e aggiornamento SharePoint
-    Ok(())
-}
-
 // Comando per nuova tessera
 #[tauri::command]
-async fn nuova_tessera(id: u32) -> Result<(), String> {
+async fn nuova_tessera(id: u32) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::SociModifica).await?;
     println!("Nuova tessera {}", id);
     // TODO: Implementare logica di creazione tessera
     Ok(())
@@ -5109,7 +6345,8 @@ async fn nuova_tessera(id: u32) -> Result<(), String> {
 
 // Comando per aprire tessera
 #[tauri::command]
-async fn apri_tessera(id: u32) -> Result<(), String> {
+async fn apri_tessera(id: u32) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::SociLeggi).await?;
     println!("Apri tessera {}", id);
     // TODO: Implementare logica di apertura tessera
     Ok(())
@@ -5121,7 +6358,8 @@ async fn authenticate_sharepoint(
     username: String,
     password: String,
     sharepoint_url: String,
-) -> Result<serde_json::Value, String> {
+) -> Result<serde_json::Value, ApiError> {
+    richiedi_permesso(Capacita::ImpostazioniModifica).await?;
     println!("Autenticazione SharePoint per {}", username);
     
     // Per ora usiamo un token di esempio
@@ -5157,7 +6395,8 @@ async fn authenticate_sharepoint(
 
 // Comando per salvare credenziali e configurare SharePoint
 #[tauri::command]
-async fn save_credentials(sharepoint_url: String, token: String) -> Result<(), String> {
+async fn save_credentials(sharepoint_url: String, token: String) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::ImpostazioniModifica).await?;
     println!("Salvataggio credenziali per {}", sharepoint_url);
     
     let config = SharePointConfig {
@@ -5191,7 +6430,8 @@ async fn update_servizio_sharepoint(
     ora_sotto_casa: Option<String>,
     ora_destinazione: Option<String>,
     tipo_servizio: Option<String>,
-) -> Result<(), String> {
+) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::ServiziModifica).await?;
     ensure_supabase_client().await?;
 
     let mut body = serde_json::Map::new();
@@ -5230,15 +6470,14 @@ async fn update_servizio_sharepoint(
     let guard = get_supabase_client().lock().await;
     let client = guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     client
         .patch_servizio(id, &body)
         .await
-        .map_err(|e| format_supabase_error(&e))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 struct UpdateServizioPayload {
     #[serde(default)]
     id: u32,
@@ -5269,6 +6508,8 @@ struct UpdateServizioPayload {
     data_ricevuta: Option<String>,
     numero_ricevuta: Option<String>,
     stato_servizio: Option<String>,
+    #[serde(default)]
+    motivo_annullamento: Option<String>,
     note_prelievo: Option<String>,
     note_arrivo: Option<String>,
     note_fine_servizio: Option<String>,
@@ -5277,6 +6518,8 @@ struct UpdateServizioPayload {
     creato_da: Option<String>,
     /// Account che ha modificato per ultimo il servizio
     modificato_da: Option<String>,
+    /// Versione aperta dall'utente (ServizioCompleto.versione); senza, salvataggio senza controllo
+    versione: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -5418,12 +6661,17 @@ fn put_opt_date_field(
     }
 }
 
-async fn fetch_servizio_row_template(servizio_id: u32) -> Result<serde_json::Value, String> {
-    let filter = format!("idservizio=eq.{}", servizio_id);
-    let rows = fetch_servizi_supabase(Some(&filter)).await?;
+async fn fetch_servizio_row_template(servizio_id: u32) -> Result<serde_json::Value, ApiError> {
+    let filter = Query::new().eq("idservizio", servizio_id);
+    let rows = fetch_servizi_supabase(filter).await?;
     rows.into_iter()
         .next()
-        .ok_or_else(|| format!("Servizio {} non trovato per mappatura colonne", servizio_id))
+        .ok_or_else(|| {
+            ApiError::not_found(format!(
+                "Servizio {} non trovato per mappatura colonne",
+                servizio_id
+            ))
+        })
 }
 
 fn strip_empty_strings_from_body(body: &mut serde_json::Map<String, serde_json::Value>) {
@@ -5471,6 +6719,9 @@ fn prepara_payload_duplicazione(
     payload.km_uscita = Some(String::new());
     payload.km_rientro = Some(String::new());
     payload.note_fine_servizio = Some(String::new());
+    // La copia riparte da eseguire, qualunque fosse lo stato dell'originale
+    payload.stato_servizio = Some("DA ESEGUIRE".to_string());
+    payload.motivo_annullamento = Some(String::new());
 
     if !opzioni.mantieni_ora_partenza {
         payload.ora_inizio = Some(String::new());
@@ -5531,12 +6782,14 @@ fn servizio_completo_to_update_payload(sc: &ServizioCompleto) -> UpdateServizioP
         data_ricevuta: Some(sc.data_ricevuta.clone()),
         numero_ricevuta: Some(sc.numero_ricevuta.clone()),
         stato_servizio: Some(sc.stato_servizio.clone()),
+        motivo_annullamento: Some(sc.motivo_annullamento.clone()),
         note_prelievo: Some(sc.note_prelievo.clone()),
         note_arrivo: Some(sc.note_arrivo.clone()),
         note_fine_servizio: Some(sc.note_fine_servizio.clone()),
         archivia: Some(sc.archivia.clone()),
         creato_da: None,
         modificato_da: None,
+        versione: None,
     }
 }
 
@@ -5822,6 +7075,13 @@ async fn build_servizio_supabase_body(
         "StatoServizio",
         payload.stato_servizio.clone(),
     );
+    put_opt_nullable_string_field(
+        &mut body,
+        template_row,
+        &["MotivoAnnullamento", "MOTIVOANNULLAMENTO", "motivo_annullamento"],
+        "MotivoAnnullamento",
+        payload.motivo_annullamento.clone(),
+    );
     put_opt_string_field(
         &mut body,
         template_row,
@@ -5921,12 +7181,138 @@ async fn build_servizio_supabase_body(
     body
 }
 
+/// Campi che decidono lo stato, come saranno dopo il salvataggio: quelli del
+/// payload prevalgono sulla riga attuale (None = campo non inviato)
+fn dati_stato_servizio(
+    payload: &UpdateServizioPayload,
+    row: Option<&serde_json::Value>,
+) -> DatiStato {
+    let attuale = |candidates: &[&str]| row.map(|r| get_field_any(r, candidates)).unwrap_or_default();
+    let operatore = attuale(&["IdOperatore", "IDOPERATORE", "Id_Operatore"]);
+    DatiStato {
+        operatore: payload.operatore.clone().unwrap_or_else(|| {
+            if operatore.is_empty() {
+                attuale(&["Oper", "OPER", "Operatore"])
+            } else {
+                operatore
+            }
+        }),
+        mezzo: payload.mezzo.clone().unwrap_or_else(|| attuale(&["Mezzo", "MEZZO"])),
+        km: payload.km.clone().unwrap_or_else(|| attuale(&["Km", "KM"])),
+        motivo_annullamento: payload.motivo_annullamento.clone().unwrap_or_else(|| {
+            attuale(&["MotivoAnnullamento", "MOTIVOANNULLAMENTO", "motivo_annullamento"])
+        }),
+    }
+}
+
+/// Valida lo stato richiesto dal payload rispetto a quello attuale della riga
+/// (None per un servizio nuovo). Senza stato nel payload valgono comunque i campi
+/// obbligatori dello stato attuale, che il salvataggio non deve svuotare.
+/// Stato attuale di una riga di Servizi_supa (None = vuoto o fuori elenco)
+fn stato_servizio_riga(row: &serde_json::Value) -> Option<StatoServizio> {
+    let dati = dati_stato_servizio(&UpdateServizioPayload::default(), Some(row));
+    StatoServizio::da_testo(&get_field_any(row, &["StatoServizio", "STATOSERVIZIO"]), &dati)
+}
+
+fn verifica_stato_servizio(
+    payload: &UpdateServizioPayload,
+    row: Option<&serde_json::Value>,
+    nuovo: bool,
+    perm: &UserPermissionsRecord,
+) -> Result<(), ApiError> {
+    let dopo = dati_stato_servizio(payload, row);
+    let riconosciuto = row.and_then(stato_servizio_riga);
+    // Stato vuoto o fuori elenco (dati storici) lasciato com'è: passa il
+    // salvataggio degli altri campi
+    if !nuovo && riconosciuto.is_none() {
+        let attuale = row
+            .map(|r| get_field_any(r, &["StatoServizio", "STATOSERVIZIO"]))
+            .unwrap_or_default();
+        let invariato = match payload.stato_servizio.as_deref().map(str::trim) {
+            None | Some("") => true,
+            Some(testo) => testo.eq_ignore_ascii_case(attuale.trim()),
+        };
+        if invariato {
+            return Ok(());
+        }
+    }
+    // Se invece lo si cambia, si parte da richiesto
+    let prima = if nuovo {
+        None
+    } else {
+        Some(riconosciuto.unwrap_or(StatoServizio::Richiesto))
+    };
+    let richiesto = match payload.stato_servizio.as_deref().map(str::trim) {
+        Some(testo) if !testo.is_empty() => StatoServizio::da_testo(testo, &dopo)
+            .ok_or_else(|| ApiError::from(format!("Stato servizio non valido: {}", testo)))?,
+        _ => match prima {
+            Some(stato) => stato,
+            None => return Ok(()),
+        },
+    };
+    stato_servizio::verifica_transizione(prima, richiesto, &dopo, perm)
+}
+
+// Stati del servizio con passaggi ammessi e campi obbligatori (per le finestre)
+#[tauri::command]
+async fn get_ciclo_stati_servizio() -> Result<Vec<StatoInfo>, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
+    Ok(stato_servizio::TUTTI_GLI_STATI
+        .iter()
+        .map(|s| s.info())
+        .collect())
+}
+
+/// Chi non vede motivazione, note o incassi non li sovrascrive (arriverebbe il segnaposto)
+fn scarta_campi_oscurati(payload: &mut UpdateServizioPayload, perm: &UserPermissionsRecord) {
+    let m = Mascheramento::per_utente(perm);
+    if !m.dati_sanitari {
+        payload.motivazione = None;
+        payload.note_prelievo = None;
+        payload.note_arrivo = None;
+        payload.note_fine_servizio = None;
+    }
+    if !m.incassi {
+        payload.pagamento = None;
+        payload.stato_incasso = None;
+        payload.tipo_pagamento = None;
+        payload.data_bonifico = None;
+        payload.data_ricevuta = None;
+        payload.numero_ricevuta = None;
+    }
+}
+
+/// Chi vede solo i propri servizi modifica solo quelli
+async fn verifica_servizio_proprio(
+    perm: &UserPermissionsRecord,
+    id: u32,
+    row: Option<&serde_json::Value>,
+) -> Result<(), ApiError> {
+    if let Some(vis) = visibilita_propria(perm).await? {
+        let nominativi = fetch_idsocio_nominativo_map().await;
+        let proprio = row
+            .map(|row| vis.servizio_proprio(row, &nominativi))
+            .unwrap_or(false);
+        if !proprio {
+            return Err(ApiError::permission_denied(format!(
+                "Il servizio {} non è assegnato a te",
+                id
+            )));
+        }
+    }
+    Ok(())
+}
+
 // Comando per aggiornare tutti i campi di un servizio (Supabase / Servizi_supa)
 #[tauri::command]
-async fn update_servizio_completo(payload: UpdateServizioPayload) -> Result<(), String> {
+async fn update_servizio_completo(mut payload: UpdateServizioPayload) -> Result<(), ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziModifica).await?;
+    scarta_campi_oscurati(&mut payload, &perm);
     ensure_supabase_client().await?;
 
     let template_row = fetch_servizio_row_template(payload.id).await.ok();
+    verifica_servizio_proprio(&perm, payload.id, template_row.as_ref()).await?;
+    verifica_stato_servizio(&payload, template_row.as_ref(), false, &perm)?;
     let mut body = build_servizio_supabase_body(&payload, template_row.as_ref()).await;
     // In update non si tocca CreatoDa / created: solo chi ha modificato e quando
     strip_servizio_audit_fields(&mut body);
@@ -5942,18 +7328,483 @@ async fn update_servizio_completo(payload: UpdateServizioPayload) -> Result<(),
     let guard = get_supabase_client().lock().await;
     let client = guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
+
+    // Fallisce se un altro utente ha cambiato gli stessi campi dopo l'apertura
+    client
+        .patch_versionato(
+            "servizi",
+            &payload.id.to_string(),
+            payload.versione.as_deref(),
+            &body,
+        )
+        .await?;
+    registra_audit(
+        client,
+        "servizi",
+        &payload.id.to_string(),
+        audit::AZIONE_UPDATE,
+        template_row.as_ref(),
+        Some(&serde_json::Value::Object(body)),
+    )
+    .await;
+    aggiorna_riga_cache_delta(client, "servizi", "idservizio", &payload.id.to_string()).await;
+    emetti_modifica(ModificaTabella::locale("servizi", "update", payload.id));
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletaServizioPayload {
+    id: u32,
+    /// Orari reali del viaggio (HH:MM): servono a calcolare il tempo
+    #[serde(default)]
+    ora_partenza: String,
+    #[serde(default)]
+    ora_rientro: String,
+    #[serde(default)]
+    km_uscita: String,
+    #[serde(default)]
+    km_rientro: String,
+    operatore: Option<String>,
+    operatore_2: Option<String>,
+    note_fine_servizio: Option<String>,
+    /// COMPLETATO (predefinito) o NON PRESENTATO
+    stato_servizio: Option<String>,
+    /// Donazione pagata sul posto: stato incasso INCASSATO
+    #[serde(default)]
+    incassato: bool,
+    /// Altri campi del form di chiusura, salvati nella stessa scrittura
+    servizio: Option<UpdateServizioPayload>,
+    versione: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct EsitoCompletamento {
+    id: u32,
+    stato_servizio: String,
+    km: String,
+    tempo: String,
+    /// Importo dalla tratta o dalla tariffa nelle note (None = donazione lasciata com'era)
+    preventivo: Option<Preventivo>,
+    /// Perché l'importo non è stato calcolato (né nota tariffa né tratta della destinazione)
+    importo_non_calcolato: Option<String>,
+    stato_incasso: String,
+    viaggio_precedente: Option<ViaggioPrecedente>,
+    /// Prima tappa del viaggio, che porta la tariffa di tutto il viaggio (None = questo servizio)
+    tariffa_su: Option<u32>,
+}
+
+/// Ultimo viaggio dello stesso mezzo prima di questo servizio con i km di rientro
+async fn viaggio_precedente_mezzo(
+    client: &supabase::SupabaseClient,
+    row: &serde_json::Value,
+    id: u32,
+    mezzo: &str,
+) -> Result<Option<ViaggioPrecedente>, ApiError> {
+    let data = servizio_data_raw(row);
+    if mezzo.trim().is_empty() || data.len() < 10 {
+        return Ok(None);
+    }
+    let data = &data[..10];
+    let ora = servizio_ora_prelievo(row);
+    let mezzo_col = resolve_column_key(row, &["Mezzo", "MEZZO"]).unwrap_or_else(|| "Mezzo".to_string());
+    let rientro_candidati = [
+        "Km_rientro",
+        "KM_RIENTRO",
+        "km_rientro",
+        "KmRientro",
+        "Km_Arrivo",
+        "KM_ARRIVO",
+        "km_arrivo",
+        "Chiusura_Km_Arrivo",
+    ];
+    let rientro_col =
+        resolve_column_key(row, &rientro_candidati).unwrap_or_else(|| "Km_rientro".to_string());
+    let giorno_dopo = next_day_iso(data).unwrap_or_else(|| data.to_string());
+
+    let rows = client
+        .fetch_servizi(
+            Query::new()
+                .eq(&mezzo_col, mezzo.trim())
+                .lt("Prelievo_Data", &giorno_dopo)
+                .neq("idservizio", id)
+                .not_null(&rientro_col)
+                .order("Prelievo_Data", false)
+                .limit(20),
+        )
+        .await?;
+
+    // Lo stesso giorno conta solo chi è partito prima
+    let mut viaggi: Vec<(String, String, &serde_json::Value)> = rows
+        .iter()
+        .map(|r| {
+            let d = servizio_data_raw(r);
+            (d.get(..10).unwrap_or(&d).to_string(), servizio_ora_prelievo(r), r)
+        })
+        .filter(|(d, o, _)| (d.as_str(), o.as_str()) < (data, ora.as_str()))
+        .collect();
+    viaggi.sort_by(|a, b| (&b.0, &b.1).cmp(&(&a.0, &a.1)));
+    Ok(viaggi.into_iter().find_map(|(d, _, r)| {
+        let km_rientro = get_numeric_any(r, &rientro_candidati);
+        (km_rientro > 0.0).then(|| ViaggioPrecedente {
+            idservizio: servizio_id_from_row(r),
+            data: format_date_sharepoint_rust(&d),
+            km_rientro,
+        })
+    }))
+}
+
+/// Preventivo dalla tratta fuori Asti della destinazione, per i servizi chiusi
+/// senza nota tariffa: tratta della località, o l'unica del comune
+async fn richiesta_da_destinazione(
+    client: &supabase::SupabaseClient,
+    comune: &str,
+    luogo: &str,
+) -> Result<Option<RichiestaPreventivo>, ApiError> {
+    if comune.trim().is_empty() {
+        return Ok(None);
+    }
+    let tratte = client.fetch_tratte(Query::new()).await?;
+    let mut richiesta = RichiestaPreventivo {
+        comune: Some(comune.to_string()),
+        localita: Some(luogo.to_string()),
+        ..RichiestaPreventivo::default()
+    };
+    let tratta = tariffe::risolvi_tratta(&tratte, &richiesta).or_else(|| {
+        richiesta.localita = None;
+        tariffe::risolvi_tratta(&tratte, &richiesta)
+    });
+    Ok(tratta.map(|row| RichiestaPreventivo {
+        id_tratta: Some(tariffe::id_tratta(row)),
+        ..RichiestaPreventivo::default()
+    }))
+}
+
+// Comando per completare un servizio: dagli orari e dalle letture del
+// contachilometri calcola km e tempo, controlla i km con il viaggio precedente
+// del mezzo, prende l'importo dalla tratta e salva tutto in un'unica scrittura
+#[tauri::command]
+async fn completa_servizio(payload: CompletaServizioPayload) -> Result<EsitoCompletamento, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziModifica).await?;
+    let id = payload.id;
+    println!("=== completa_servizio chiamato per ID: {} (Supabase) ===", id);
+    ensure_supabase_client().await?;
+
+    let row = fetch_servizio_row_template(id).await?;
+    verifica_servizio_proprio(&perm, id, Some(&row)).await?;
+    let client = supabase_client_clone().await?;
+
+    let mut servizio = payload.servizio.unwrap_or_default();
+    servizio.id = id;
+    scarta_campi_oscurati(&mut servizio, &perm);
+    if payload.operatore.is_some() {
+        servizio.operatore = payload.operatore;
+    }
+    if payload.operatore_2.is_some() {
+        servizio.operatore_2 = payload.operatore_2;
+    }
+    if payload.note_fine_servizio.is_some() && Mascheramento::per_utente(&perm).dati_sanitari {
+        servizio.note_fine_servizio = payload.note_fine_servizio;
+    }
+    if payload.versione.is_some() {
+        servizio.versione = payload.versione;
+    }
+
+    let dati = DatiStato::default();
+    let stato = match payload.stato_servizio.as_deref().map(|s| StatoServizio::da_testo(s, &dati)) {
+        Some(Some(StatoServizio::NonPresentato)) => StatoServizio::NonPresentato,
+        _ => StatoServizio::Completato,
+    };
+    servizio.stato_servizio = Some(stato.etichetta().to_string());
+
+    // Km e tempo: obbligatori per chiudere come completato
+    let km_uscita = completamento::parse_km(&payload.km_uscita);
+    let km_rientro = completamento::parse_km(&payload.km_rientro);
+    let mut viaggio_precedente = None;
+    let mut km = String::new();
+    match (km_uscita, km_rientro) {
+        (Some(uscita), Some(rientro)) => {
+            let mezzo = servizio
+                .mezzo
+                .clone()
+                .filter(|m| !m.trim().is_empty())
+                .unwrap_or_else(|| get_field_any(&row, &["Mezzo", "MEZZO"]));
+            viaggio_precedente = viaggio_precedente_mezzo(&client, &row, id, &mezzo).await?;
+            let percorsi = completamento::km_percorsi(uscita, rientro, viaggio_precedente.as_ref())?;
+            km = completamento::formatta_km(percorsi);
+            servizio.km_uscita = Some(completamento::formatta_km(uscita));
+            servizio.km_rientro = Some(completamento::formatta_km(rientro));
+            servizio.km = Some(km.clone());
+        }
+        _ if stato == StatoServizio::Completato => {
+            return Err("Per completare il servizio servono i km all'uscita e al rientro".into());
+        }
+        _ => {}
+    }
+    let mut tempo = String::new();
+    if !payload.ora_partenza.trim().is_empty() || !payload.ora_rientro.trim().is_empty() {
+        tempo = completamento::durata(&payload.ora_partenza, &payload.ora_rientro)?;
+        servizio.tempo = Some(tempo.clone());
+    } else if stato == StatoServizio::Completato {
+        return Err("Per completare il servizio servono l'ora di partenza e di rientro".into());
+    }
+
+    // Importo dalla tratta o dalla tariffa calcolata nelle note (solo per un servizio svolto)
+    let note = servizio
+        .note_fine_servizio
+        .clone()
+        .unwrap_or_else(|| {
+            get_field_any(&row, &["NoteFineServizio", "NOTAFINESERVIZIO", "NOTE_FINE_SERVIZIO"])
+        });
+    // Viaggio collegato: la tariffa (km andata e ritorno) si calcola una volta
+    // sola, sulla prima tappa; le altre non hanno importo
+    let id_viaggio = get_field_any(&row, &["IdViaggio", "id_viaggio"]);
+    let tappe = if id_viaggio.is_empty() {
+        Vec::new()
+    } else {
+        righe_viaggio(&client, &id_viaggio).await?
+    };
+    let prima_tappa = tappe.first().filter(|r| servizio_id_from_row(r) != id);
+    let mut preventivo = None;
+    let mut importo_non_calcolato = None;
+    if stato == StatoServizio::Completato && prima_tappa.is_none() {
+        let comune = servizio
+            .comune_destinazione
+            .clone()
+            .unwrap_or_else(|| get_field_any(&row, &["Destinazione_Comune", "DESTINAZIONE_COMUNE"]));
+        let luogo = servizio
+            .luogo_destinazione
+            .clone()
+            .unwrap_or_else(|| get_field_any(&row, &["Destinazione_Indirizzo", "DESTINAZIONE_INDIRIZZO"]));
+        let richiesta = match RichiestaPreventivo::dalle_note(&note) {
+            Some(r) => Some(r),
+            None => richiesta_da_destinazione(&client, &comune, &luogo).await?,
+        };
+        if richiesta.is_none() {
+            let motivo = format!(
+                "Importo non calcolato: nessuna tariffa nelle note e nessuna tratta per {} {}",
+                comune.trim(),
+                luogo.trim()
+            );
+            println!("ℹ️ Servizio {}: {}", id, motivo);
+            importo_non_calcolato = Some(motivo);
+        }
+        if let Some(mut richiesta) = richiesta {
+            richiesta.data_prelievo = Some(servizio_data_raw(&row));
+            if tappe.len() > 1 {
+                richiesta.andata_ritorno = Some(true);
+            }
+            let campo = |valore: &Option<String>, nomi: &[&str]| {
+                Some(valore.clone().unwrap_or_else(|| get_field_any(&row, nomi)))
+            };
+            richiesta.idsocio = campo(&servizio.idsocio, &["IdSocio", "IDSOCIO"]);
+            richiesta.richiedente = campo(&servizio.richiedente, &["Richiedente", "RICHIEDENTE"]);
+            richiesta.carrozzina = campo(&servizio.carrozzina, &["Carrozzina", "CARROZZINA"]);
+            richiesta.tipo_servizio = Some(
+                servizio
+                    .tipo_servizio
+                    .clone()
+                    .unwrap_or_else(|| build_tipo_servizio_row(&row)),
+            );
+            let p = preventivo_servizio(&client, &richiesta).await?;
+            servizio.pagamento = Some(p.totale_testo.clone());
+            preventivo = Some(p);
+        }
+    }
+    // Un viaggio reso gratuito da una regola non resta da incassare
+    let gratuito_in_viaggio = prima_tappa
+        .map(|r| get_field_any(r, &["Incassato", "INCASSATO"]).trim().eq_ignore_ascii_case("GRATIS"))
+        .unwrap_or(false);
+    let stato_incasso = if preventivo.as_ref().map(|p| p.gratuito).unwrap_or(false) || gratuito_in_viaggio {
+        "GRATIS".to_string()
+    } else {
+        completamento::stato_incasso(
+            &get_field_any(&row, &["Incassato", "INCASSATO"]),
+            payload.incassato,
+        )
+    };
+    servizio.stato_incasso = Some(stato_incasso.clone());
+
+    verifica_stato_servizio(&servizio, Some(&row), false, &perm)?;
+
+    let mut body = build_servizio_supabase_body(&servizio, Some(&row)).await;
+    strip_servizio_audit_fields(&mut body);
+    apply_servizio_audit_fields(
+        &mut body,
+        Some(&row),
+        None,
+        servizio.modificato_da.as_deref(),
+        false,
+        true,
+    );
 
+    // Una sola PATCH: o si salva la chiusura intera o niente
     client
-        .patch_servizio(payload.id, &body)
+        .patch_versionato("servizi", &id.to_string(), servizio.versione.as_deref(), &body)
+        .await?;
+    registra_audit(
+        &client,
+        "servizi",
+        &id.to_string(),
+        audit::AZIONE_UPDATE,
+        Some(&row),
+        Some(&serde_json::Value::Object(body)),
+    )
+    .await;
+    aggiorna_riga_cache_delta(&client, "servizi", "idservizio", &id.to_string()).await;
+    emetti_modifica(ModificaTabella::locale("servizi", "update", id));
+    println!("✓ Servizio {} chiuso come {} ({} km, tempo {})", id, stato.etichetta(), km, tempo);
+
+    Ok(EsitoCompletamento {
+        id,
+        stato_servizio: stato.etichetta().to_string(),
+        km,
+        tempo,
+        preventivo,
+        importo_non_calcolato,
+        stato_incasso,
+        viaggio_precedente,
+        tariffa_su: prima_tappa.map(servizio_id_from_row),
+    })
+}
+
+// Preventivo dettagliato di un viaggio (modale Calcola tariffa, nuovo / modifica servizio)
+#[tauri::command]
+async fn calcola_preventivo(richiesta: RichiestaPreventivo) -> Result<Preventivo, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let client = supabase_client_clone().await?;
+    preventivo_servizio(&client, &richiesta).await
+}
+
+// Costo al km, extra e attesa in vigore alla data (valori iniziali del modale)
+#[tauri::command]
+async fn get_parametri_tariffa(data: Option<String>) -> Result<ParametriTariffa, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let client = supabase_client_clone().await?;
+    let data = data.as_deref().and_then(tariffe::data_servizio);
+    Ok(fetch_parametri_tariffa(&client, data).await?.0)
+}
+
+// Tutte le versioni della tariffa con il periodo di validità
+#[tauri::command]
+async fn get_storico_tariffe() -> Result<StoricoTariffe, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let client = supabase_client_clone().await?;
+    let storico = fetch_storico_tariffe(&client).await;
+    println!("✓ {} versioni della tariffa", storico.versioni.len());
+    Ok(storico)
+}
+
+// Regole della tariffa (attive e non), per priorità
+#[tauri::command]
+async fn get_regole_tariffa() -> Result<Vec<RegolaTariffa>, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let client = supabase_client_clone().await?;
+    let regole = fetch_regole_tariffa(&client).await;
+    println!("✓ {} regole della tariffa", regole.len());
+    Ok(regole)
+}
+
+// Crea o modifica una regola della tariffa
+#[tauri::command]
+async fn save_regola_tariffa(regola: RegolaTariffa) -> Result<RegolaTariffa, ApiError> {
+    richiedi_permesso(Capacita::ImpostazioniModifica).await?;
+    let mut regola = regola;
+    regola.codice = regola.codice.trim().to_lowercase();
+    regola.descrizione = regola.descrizione.trim().to_string();
+    regola.valida()?;
+    println!("=== save_regola_tariffa {} ===", regola.codice);
+
+    let client = supabase_client_clone().await?;
+    let prima = fetch_regole_tariffa(&client)
+        .await
+        .into_iter()
+        .find(|r| r.codice == regola.codice);
+    client.upsert_regola_tariffa(&regola.riga()).await?;
+    registra_audit(
+        &client,
+        "regole_tariffa",
+        &regola.codice,
+        if prima.is_some() { audit::AZIONE_UPDATE } else { audit::AZIONE_INSERT },
+        prima.map(|r| r.riga()).as_ref(),
+        Some(&regola.riga()),
+    )
+    .await;
+    println!("✓ Regola tariffa {} salvata", regola.codice);
+    Ok(regola)
+}
+
+#[tauri::command]
+async fn delete_regola_tariffa(codice: String) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::ImpostazioniModifica).await?;
+    let codice = codice.trim().to_lowercase();
+    println!("=== delete_regola_tariffa {} ===", codice);
+
+    let client = supabase_client_clone().await?;
+    let regola = fetch_regole_tariffa(&client)
         .await
-        .map_err(|e| format_supabase_error(&e))
+        .into_iter()
+        .find(|r| r.codice == codice)
+        .ok_or_else(|| ApiError::not_found(format!("Regola tariffa {} non trovata", codice)))?;
+    client.delete_regola_tariffa(&codice).await?;
+    registra_audit(
+        &client,
+        "regole_tariffa",
+        &codice,
+        audit::AZIONE_DELETE,
+        Some(&regola.riga()),
+        None,
+    )
+    .await;
+    println!("✓ Regola tariffa {} eliminata", codice);
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct NuovaVersioneTariffaPayload {
+    /// Vuoto = da oggi
+    valido_dal: Option<String>,
+    #[serde(flatten)]
+    parametri: ParametriTariffa,
+}
+
+// Nuova tariffa da una data. Da oggi in avanti basta impostazioni.modifica;
+// retrodatarla cambia il prezzo di servizi già svolti e lo fa solo un amministratore.
+#[tauri::command]
+async fn crea_versione_tariffa(payload: NuovaVersioneTariffaPayload) -> Result<StoricoTariffe, ApiError> {
+    let perm = richiedi_permesso(Capacita::ImpostazioniModifica).await?;
+    let oggi = Local::now().date_naive();
+    let valido_dal = match payload.valido_dal.as_deref().filter(|d| !d.trim().is_empty()) {
+        Some(d) => tariffe::data_servizio(d)
+            .ok_or_else(|| ApiError::from(format!("Data di inizio non valida: '{}'", d.trim())))?,
+        None => oggi,
+    };
+    println!("=== crea_versione_tariffa dal {} ===", valido_dal);
+    if valido_dal < oggi && !perm.is_admin {
+        return Err(ApiError::permission_denied(
+            "Solo un amministratore può creare una tariffa con validità passata",
+        ));
+    }
+
+    let client = supabase_client_clone().await?;
+    let storico = fetch_storico_tariffe(&client).await;
+    let versione = VersioneTariffa {
+        valido_dal,
+        valido_al: None,
+        parametri: payload.parametri,
+        creato_da: perm.username,
+        creato_il: chrono::Utc::now().to_rfc3339(),
+    };
+    salva_versione_tariffa(&client, &versione, storico.in_vigore(valido_dal)).await?;
+    Ok(fetch_storico_tariffe(&client).await)
 }
 
 // Comando per creare un nuovo servizio (Supabase / Servizi_supa)
 #[tauri::command]
-async fn create_servizio(payload: UpdateServizioPayload) -> Result<u32, String> {
+async fn create_servizio(payload: UpdateServizioPayload) -> Result<u32, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziCrea).await?;
     println!("=== create_servizio chiamato (Supabase) ===");
+    verifica_stato_servizio(&payload, None, true, &perm)?;
 
     ensure_supabase_client().await?;
 
@@ -5962,11 +7813,10 @@ async fn create_servizio(payload: UpdateServizioPayload) -> Result<u32, String>
         let guard = get_supabase_client().lock().await;
         let client = guard
             .as_ref()
-            .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
+            .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
         let max_id = client
             .fetch_max_servizio_id()
-            .await
-            .map_err(|e| format_supabase_error(&e))?;
+            .await?;
         drop(guard);
         if max_id > 0 {
             fetch_servizio_row_template(max_id).await.ok()
@@ -5995,19 +7845,13 @@ async fn create_servizio(payload: UpdateServizioPayload) -> Result<u32, String>
     );
 
     if body.is_empty() {
-        return Err("Nessun dato da salvare per il nuovo servizio".to_string());
+        return Err("Nessun dato da salvare per il nuovo servizio".into());
     }
 
     let guard = get_supabase_client().lock().await;
     let client = guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
-
-    let max_id = client
-        .fetch_max_servizio_id()
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
-    let next_id = max_id.saturating_add(1);
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
 
     let id_column = template_row
         .as_ref()
@@ -6024,49 +7868,281 @@ async fn create_servizio(payload: UpdateServizioPayload) -> Result<u32, String>
             )
         })
         .unwrap_or_else(|| "idservizio".to_string());
-    body.insert(id_column, serde_json::json!(next_id));
-
-    println!(
-        "📋 Creazione servizio: max_id={} → nuovo idservizio={} ({} campi)",
-        max_id,
-        next_id,
-        body.len()
-    );
 
-    let inserted = client
-        .insert_servizio(&body)
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
+    println!("📋 Creazione servizio ({} campi)", body.len());
 
-    let new_id = servizio_id_from_row(&inserted);
-    let new_id = if new_id == 0 { next_id } else { new_id };
+    // ID assegnato dal client Supabase senza collisioni fra postazioni
+    let (new_id, _) = client
+        .insert_servizio_nuovo_id(&body, &id_column)
+        .await?;
     if new_id == 0 {
-        return Err("Servizio creato ma ID non restituito da Supabase".to_string());
+        return Err("Servizio creato ma ID non restituito da Supabase".into());
     }
 
     println!("✓ Nuovo servizio creato con ID {}", new_id);
+    registra_audit(
+        client,
+        "servizi",
+        &new_id.to_string(),
+        audit::AZIONE_INSERT,
+        None,
+        Some(&serde_json::Value::Object(body)),
+    )
+    .await;
+    aggiorna_riga_cache_delta(client, "servizi", "idservizio", &new_id.to_string()).await;
+    emetti_modifica(ModificaTabella::locale("servizi", "insert", new_id));
     Ok(new_id)
 }
 
-// Comando per eliminare un servizio (Supabase / Servizi_supa)
+// Comando per eliminare un servizio (Supabase / Servizi_supa): la riga passa
+// prima nel cestino, con chi, quando e perché, ed è ripristinabile fino alla pulizia
 #[tauri::command]
-async fn delete_servizio(servizio_id: u32) -> Result<(), String> {
+async fn delete_servizio(servizio_id: u32, motivo: Option<String>) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::ServiziElimina).await?;
     println!(
         "=== delete_servizio chiamato per ID: {} (Supabase) ===",
         servizio_id
     );
 
+    let motivo = motivo.unwrap_or_default().trim().to_string();
+    if motivo.is_empty() {
+        return Err("Indica il motivo dell'eliminazione".into());
+    }
+
     ensure_supabase_client().await?;
+    // Riga com'era: va nel cestino e nel registro modifiche (prima del lock sul client)
+    let prima = fetch_servizio_row_template(servizio_id).await?;
 
     let guard = get_supabase_client().lock().await;
     let client = guard
         .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
-
-    client
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
+
+    let sessione = get_auth_store().get();
+    let mut voce = serde_json::Map::new();
+    voce.insert("idservizio".to_string(), serde_json::json!(servizio_id));
+    voce.insert("riga".to_string(), prima.clone());
+    voce.insert(
+        "eliminato_da".to_string(),
+        serde_json::json!(sessione.as_ref().map(|s| s.email.clone()).unwrap_or_default()),
+    );
+    voce.insert(
+        "eliminato_da_id".to_string(),
+        serde_json::json!(sessione.as_ref().map(|s| s.user_id.clone()).unwrap_or_default()),
+    );
+    voce.insert(
+        "eliminato_il".to_string(),
+        serde_json::json!(chrono::Utc::now().to_rfc3339()),
+    );
+    voce.insert("motivo".to_string(), serde_json::json!(motivo));
+    // Senza copia nel cestino il servizio non si elimina
+    client.insert_cestino_servizio(&voce).await?;
+
+    client
         .delete_servizio(servizio_id)
+        .await?;
+    registra_audit(
+        client,
+        "servizi",
+        &servizio_id.to_string(),
+        audit::AZIONE_DELETE,
+        Some(&prima),
+        None,
+    )
+    .await;
+    if let Some(cache) = delta_cache().lock().await.get_mut("servizi") {
+        cache.remove(&servizio_id.to_string());
+    }
+    emetti_modifica(ModificaTabella::locale("servizi", "delete", servizio_id));
+    Ok(())
+}
+
+/// Giorni di permanenza nel cestino prima della pulizia (impostazione GiorniCestino)
+const GIORNI_CESTINO_DEFAULT: i64 = 30;
+
+async fn giorni_conservazione_cestino(client: &supabase::SupabaseClient) -> i64 {
+    let rows = client
+        .fetch_impostazioni(Query::new().eq("Impostazione", "GiorniCestino"))
         .await
-        .map_err(|e| format_supabase_error(&e))
+        .unwrap_or_default();
+    rows.iter()
+        .map(|row| {
+            get_numeric_any(
+                row,
+                &["ValoreImpostazione", "Valore", "VALORE", "Valore_Impostazione", "Impostazione_Valore"],
+            )
+        })
+        .find(|giorni| *giorni > 0.0)
+        .map(|giorni| giorni as i64)
+        .unwrap_or(GIORNI_CESTINO_DEFAULT)
+}
+
+#[derive(Debug, Serialize)]
+struct ServizioNelCestino {
+    idservizio: u32,
+    /// Dati del servizio com'era al momento dell'eliminazione
+    servizio: Option<ServizioCompleto>,
+    eliminato_da: String,
+    eliminato_il: String,
+    motivo: String,
+    /// Da questa data l'amministratore può eliminarlo definitivamente
+    eliminabile_dal: String,
+}
+
+fn scadenza_cestino(eliminato_il: &str, giorni: i64) -> String {
+    chrono::DateTime::parse_from_rfc3339(eliminato_il)
+        .map(|d| (d + chrono::Duration::days(giorni)).to_rfc3339())
+        .unwrap_or_default()
+}
+
+// Servizi nel cestino, dal più recente
+#[tauri::command]
+async fn get_cestino_servizi() -> Result<Vec<ServizioNelCestino>, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziElimina).await?;
+    println!("=== get_cestino_servizi chiamato ===");
+
+    let nominativi = fetch_idsocio_nominativo_map().await;
+    let client = supabase_client_clone().await?;
+    let giorni = giorni_conservazione_cestino(&client).await;
+    let rows = client
+        .fetch_cestino_servizi(Query::new().order("eliminato_il", false))
+        .await?;
+
+    let mascheramento = Mascheramento::per_utente(&perm);
+    let voci: Vec<ServizioNelCestino> = rows
+        .iter()
+        .filter_map(|row| {
+            let idservizio = get_field_any(row, &["idservizio"]).parse::<u32>().ok()?;
+            let eliminato_il = get_field_any(row, &["eliminato_il"]);
+            let mut servizio = row
+                .get("riga")
+                .and_then(|riga| supabase_row_to_servizio_completo(riga, &nominativi));
+            if let Some(sc) = servizio.as_mut() {
+                sc.maschera(mascheramento);
+            }
+            Some(ServizioNelCestino {
+                idservizio,
+                servizio,
+                eliminato_da: get_field_any(row, &["eliminato_da"]),
+                eliminabile_dal: scadenza_cestino(&eliminato_il, giorni),
+                eliminato_il,
+                motivo: get_field_any(row, &["motivo"]),
+            })
+        })
+        .collect();
+    println!("✓ {} servizi nel cestino (conservati {} giorni)", voci.len(), giorni);
+    Ok(voci)
+}
+
+// Rimette in Servizi_supa un servizio del cestino. Restituisce l'idservizio:
+// quello originale, o uno nuovo se nel frattempo è stato riusato.
+#[tauri::command]
+async fn ripristina_servizio(servizio_id: u32) -> Result<u32, ApiError> {
+    richiedi_permesso(Capacita::ServiziElimina).await?;
+    println!("=== ripristina_servizio {} ===", servizio_id);
+
+    ensure_supabase_client().await?;
+    let guard = get_supabase_client().lock().await;
+    let client = guard
+        .as_ref()
+        .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?;
+
+    let filtro = Query::new().eq("idservizio", servizio_id);
+    let voce = client
+        .fetch_cestino_servizi(filtro.clone())
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| ApiError::not_found(format!("Servizio {} non presente nel cestino", servizio_id)))?;
+    let riga = voce
+        .get("riga")
+        .and_then(|r| r.as_object())
+        .cloned()
+        .ok_or_else(|| ApiError::from(format!("Dati del servizio {} mancanti nel cestino", servizio_id)))?;
+
+    let id_column = resolve_column_key(
+        &serde_json::Value::Object(riga.clone()),
+        &["idservizio", "IdServizio", "Id_Servizio", "IDSERVIZIO", "id_servizio"],
+    )
+    .unwrap_or_else(|| "idservizio".to_string());
+    let ripristinato = match client.insert_servizio_con_id(&riga).await {
+        Ok(_) => servizio_id,
+        Err(ApiError::Conflict { code: Some(code), .. }) if code == "23505" => {
+            let (nuovo, _) = client.insert_servizio_nuovo_id(&riga, &id_column).await?;
+            println!("⚠️ idservizio {} già usato: ripristinato come {}", servizio_id, nuovo);
+            nuovo
+        }
+        Err(e) => return Err(e),
+    };
+
+    client.delete_cestino_servizi(&filtro).await?;
+    let mut dopo = riga;
+    dopo.insert(id_column, serde_json::json!(ripristinato));
+    registra_audit(
+        client,
+        "servizi",
+        &ripristinato.to_string(),
+        audit::AZIONE_RIPRISTINO,
+        None,
+        Some(&serde_json::Value::Object(dopo)),
+    )
+    .await;
+    aggiorna_riga_cache_delta(client, "servizi", "idservizio", &ripristinato.to_string()).await;
+    emetti_modifica(ModificaTabella::locale("servizi", "insert", ripristinato));
+    println!("✓ Servizio {} ripristinato come {}", servizio_id, ripristinato);
+    Ok(ripristinato)
+}
+
+// Eliminazione definitiva dal cestino (solo amministratori): solo i servizi che
+// ci sono da più dei giorni di conservazione. Senza servizio_id li pulisce tutti.
+#[tauri::command]
+async fn svuota_cestino_servizi(servizio_id: Option<u32>) -> Result<usize, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziElimina).await?;
+    if !perm.is_admin {
+        return Err(ApiError::permission_denied(
+            "Solo un amministratore può eliminare definitivamente i servizi",
+        ));
+    }
+
+    let client = supabase_client_clone().await?;
+    let giorni = giorni_conservazione_cestino(&client).await;
+    let limite = (chrono::Utc::now() - chrono::Duration::days(giorni)).to_rfc3339();
+    println!(
+        "=== svuota_cestino_servizi {:?} (eliminati prima del {}) ===",
+        servizio_id, limite
+    );
+
+    let mut filtro = Query::new().lt("eliminato_il", &limite);
+    if let Some(id) = servizio_id {
+        filtro = filtro.eq("idservizio", id);
+    }
+    let scaduti = client
+        .fetch_cestino_servizi(filtro.clone().select(&["idservizio"]))
+        .await?;
+    if scaduti.is_empty() {
+        if servizio_id.is_some() {
+            return Err(format!(
+                "Il servizio resta nel cestino almeno {} giorni prima della pulizia",
+                giorni
+            )
+            .into());
+        }
+        return Ok(0);
+    }
+    client.delete_cestino_servizi(&filtro).await?;
+    for row in &scaduti {
+        registra_audit(
+            &client,
+            "servizi",
+            &get_field_any(row, &["idservizio"]),
+            audit::AZIONE_PULIZIA,
+            None,
+            None,
+        )
+        .await;
+    }
+    println!("✓ {} servizi eliminati definitivamente", scaduti.len());
+    Ok(scaduti.len())
 }
 
 // Comando per duplicare un servizio (Supabase / Servizi_supa)
@@ -6075,7 +8151,9 @@ async fn duplicate_servizio(
     servizio_id: u32,
     opzioni: DuplicateServizioOptions,
     creato_da: Option<String>,
-) -> Result<u32, String> {
+) -> Result<u32, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziCrea).await?;
+    let propri = visibilita_propria(&perm).await?;
     println!(
         "=== duplicate_servizio chiamato per ID: {} (Supabase) opzioni: {:?} ===",
         servizio_id, opzioni
@@ -6083,46 +8161,52 @@ async fn duplicate_servizio(
 
     ensure_supabase_client().await?;
 
-    let originale = get_servizio_completo(servizio_id).await?;
+    let originale = leggi_servizio_completo(servizio_id, propri.as_ref()).await?;
     let template_row = fetch_servizio_row_template(servizio_id).await?;
     let mut payload = servizio_completo_to_update_payload(&originale);
     prepara_payload_duplicazione(&mut payload, &opzioni);
+    verifica_stato_servizio(&payload, None, true, &perm)?;
+
+    let client = supabase_client_clone().await?;
+    println!("📋 Duplicazione servizio {}", servizio_id);
+    let new_id = inserisci_copia_servizio(
+        &client,
+        &payload,
+        &template_row,
+        creato_da.as_deref(),
+        &serde_json::Map::new(),
+    )
+    .await?;
+    println!("✓ Servizio {} duplicato come ID {}", servizio_id, new_id);
+    Ok(new_id)
+}
 
-    let mut body = build_servizio_supabase_body(&payload, Some(&template_row)).await;
+/// Inserisce come nuovo servizio una copia già preparata (duplicazione, serie):
+/// nuovo id, solo CreatoDa di chi copia (ModificatoDa resta vuoto), audit, cache
+/// ed evento. `extra` sono colonne aggiunte così come sono (es. IdSerie).
+async fn inserisci_copia_servizio(
+    client: &SupabaseClient,
+    payload: &UpdateServizioPayload,
+    template_row: &serde_json::Value,
+    creato_da: Option<&str>,
+    extra: &serde_json::Map<String, serde_json::Value>,
+) -> Result<u32, ApiError> {
+    let mut body = build_servizio_supabase_body(payload, Some(template_row)).await;
     strip_empty_strings_from_body(&mut body);
     body.remove("idservizio");
     body.remove("IdServizio");
     body.remove("IDSERVIZIO");
     body.remove("Id_Servizio");
     body.remove("id_servizio");
-    // La copia è un nuovo servizio: solo CreatoDa di chi duplica; ModificatoDa resta vuoto
     strip_servizio_audit_fields(&mut body);
-    let account = creato_da.as_deref();
-    apply_servizio_audit_fields(
-        &mut body,
-        Some(&template_row),
-        account,
-        None,
-        true,
-        false,
-    );
+    apply_servizio_audit_fields(&mut body, Some(template_row), creato_da, None, true, false);
     if body.is_empty() {
-        return Err("Nessun dato da copiare per la duplicazione".to_string());
+        return Err("Nessun dato da copiare per la duplicazione".into());
     }
-
-    let guard = get_supabase_client().lock().await;
-    let client = guard
-        .as_ref()
-        .ok_or_else(|| "Client Supabase non disponibile".to_string())?;
-
-    let max_id = client
-        .fetch_max_servizio_id()
-        .await
-        .map_err(|e| format_supabase_error(&e))?;
-    let next_id = max_id.saturating_add(1);
+    body.extend(extra.clone());
 
     let id_column = resolve_column_key(
-        &template_row,
+        template_row,
         &[
             "idservizio",
             "IdServizio",
@@ -6132,42 +8216,1403 @@ async fn duplicate_servizio(
         ],
     )
     .unwrap_or_else(|| "idservizio".to_string());
-    body.insert(id_column, serde_json::json!(next_id));
 
+    let (new_id, _) = client
+        .insert_servizio_nuovo_id(&body, &id_column)
+        .await?;
+    if new_id == 0 {
+        return Err("Servizio duplicato ma ID non restituito da Supabase".into());
+    }
+
+    registra_audit(
+        client,
+        "servizi",
+        &new_id.to_string(),
+        audit::AZIONE_INSERT,
+        None,
+        Some(&serde_json::Value::Object(body)),
+    )
+    .await;
+    aggiorna_riga_cache_delta(client, "servizi", "idservizio", &new_id.to_string()).await;
+    emetti_modifica(ModificaTabella::locale("servizi", "insert", new_id));
+    Ok(new_id)
+}
+
+/// Elimina copie appena inserite (rollback di una serie o di una duplicazione
+/// multipla); restituisce gli id che non è stato possibile eliminare
+async fn elimina_copie_servizio(client: &SupabaseClient, ids: &[u32]) -> Vec<u32> {
+    let mut rimasti = Vec::new();
+    for &id in ids {
+        match client.delete_servizio(id).await {
+            Ok(()) => {
+                registra_audit(client, "servizi", &id.to_string(), audit::AZIONE_DELETE, None, None)
+                    .await;
+                if let Some(cache) = delta_cache().lock().await.get_mut("servizi") {
+                    cache.remove(&id.to_string());
+                }
+                emetti_modifica(ModificaTabella::locale("servizi", "delete", id));
+            }
+            Err(e) => {
+                println!("⚠️ Copia {} non eliminata: {}", id, e);
+                rimasti.push(id);
+            }
+        }
+    }
+    rimasti
+}
+
+/// Una data della duplicazione multipla, con ora, operatore e mezzo propri
+/// (None = quelli della copia secondo le opzioni)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DataDuplicazione {
+    data: String,
+    ora_inizio: Option<String>,
+    operatore: Option<String>,
+    mezzo: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DuplicazioneMultiplaPayload {
+    servizio_id: u32,
+    opzioni: DuplicateServizioOptions,
+    date: Vec<DataDuplicazione>,
+    creato_da: Option<String>,
+    /// Inserisce anche se mezzo o operatore sono già impegnati in quella data
+    #[serde(default)]
+    ignora_conflitti: bool,
+}
+
+/// Mezzo o operatore già impegnato nella data di una copia
+#[derive(Debug, Serialize)]
+struct ConflittoDuplicazione {
+    data: String,
+    /// "mezzo" o "operatore"
+    risorsa: String,
+    valore: String,
+    servizi: Vec<ServizioMezzoOccupato>,
+}
+
+#[derive(Debug, Serialize)]
+struct CopiaCreata {
+    id: u32,
+    data: String,
+}
+
+/// Esito della duplicazione multipla: o tutte le copie o nessuna. Con conflitti
+/// (senza ignora_conflitti) non si inserisce niente; se un inserimento fallisce
+/// le copie già create vengono eliminate e qui si dice quali.
+#[derive(Debug, Default, Serialize)]
+struct EsitoDuplicazioneMultipla {
+    completata: bool,
+    creati: Vec<CopiaCreata>,
+    conflitti: Vec<ConflittoDuplicazione>,
+    /// Data e motivo dell'inserimento fallito
+    errore: Option<String>,
+    /// Copie create e poi eliminate dal rollback
+    annullati: Vec<u32>,
+    /// Copie che il rollback non è riuscito a eliminare (da eliminare a mano)
+    non_annullati: Vec<u32>,
+}
+
+/// Servizi del giorno (non annullati) che usano già il mezzo o l'operatore della copia
+fn conflitti_copia(
+    copia: &UpdateServizioPayload,
+    data: chrono::NaiveDate,
+    id_operatore: Option<&str>,
+    righe_giorno: &[&serde_json::Value],
+    nominativi: &HashMap<String, String>,
+) -> Vec<ConflittoDuplicazione> {
+    let occupato = |row: &serde_json::Value| ServizioMezzoOccupato {
+        ora: servizio_ora_prelievo(row),
+        operatore: resolve_operatore_nome(row, nominativi),
+        trasportato: resolve_trasportato_nome(row, nominativi),
+        comune_destinazione: get_field_any(row, &["Destinazione_Comune", "DESTINAZIONE_COMUNE"]),
+        luogo_destinazione: get_field_any(row, &["Destinazione_Indirizzo", "DESTINAZIONE_INDIRIZZO"]),
+    };
+    let data_it = data.format("%d/%m/%Y").to_string();
+    let mut conflitti = Vec::new();
+
+    let mezzo = copia.mezzo.as_deref().map(normalize_mezzo_key).unwrap_or_default();
+    if !mezzo.is_empty() {
+        let servizi: Vec<ServizioMezzoOccupato> = righe_giorno
+            .iter()
+            .filter(|row| normalize_mezzo_key(&get_field_any(row, &["Mezzo", "MEZZO"])) == mezzo)
+            .map(|row| occupato(row))
+            .collect();
+        if !servizi.is_empty() {
+            conflitti.push(ConflittoDuplicazione {
+                data: data_it.clone(),
+                risorsa: "mezzo".to_string(),
+                valore: mezzo,
+                servizi,
+            });
+        }
+    }
+
+    let operatore = copia.operatore.as_deref().map(str::trim).unwrap_or_default();
+    if !operatore.is_empty() {
+        let id_operatore = id_operatore.map(normalize_idsocio_key).unwrap_or_default();
+        let servizi: Vec<ServizioMezzoOccupato> = righe_giorno
+            .iter()
+            .filter(|row| {
+                let id_op = get_field_any(row, &["IdOperatore", "IDOPERATORE", "Id_Operatore"]);
+                (!id_operatore.is_empty() && normalize_idsocio_key(&id_op) == id_operatore)
+                    || resolve_operatore_nome(row, nominativi).trim().eq_ignore_ascii_case(operatore)
+                    || get_field_any(row, &["Oper2", "OPER2"]).trim().eq_ignore_ascii_case(operatore)
+            })
+            .map(|row| occupato(row))
+            .collect();
+        if !servizi.is_empty() {
+            conflitti.push(ConflittoDuplicazione {
+                data: data_it,
+                risorsa: "operatore".to_string(),
+                valore: operatore.to_string(),
+                servizi,
+            });
+        }
+    }
+    conflitti
+}
+
+// Duplica un servizio su più date in un colpo solo. Prima controlla mezzo e
+// operatore di ogni copia contro i servizi già in quella data; poi inserisce
+// tutte le copie e, se una fallisce, elimina quelle già create.
+#[tauri::command]
+async fn duplicate_servizio_multiplo(
+    payload: DuplicazioneMultiplaPayload,
+) -> Result<EsitoDuplicazioneMultipla, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziCrea).await?;
+    let propri = visibilita_propria(&perm).await?;
     println!(
-        "📋 Duplicazione servizio: max_id={} → nuovo idservizio={} ({} campi)",
-        max_id,
-        next_id,
-        body.len()
+        "=== duplicate_servizio_multiplo servizio {} su {} date (ignora conflitti: {}) ===",
+        payload.servizio_id,
+        payload.date.len(),
+        payload.ignora_conflitti
     );
 
-    let inserted = client
-        .insert_servizio(&body)
+    if payload.date.is_empty() {
+        return Err("Indica almeno una data".into());
+    }
+    if payload.date.len() > ricorrenze::MAX_OCCORRENZE {
+        return Err(format!(
+            "Al massimo {} copie per volta",
+            ricorrenze::MAX_OCCORRENZE
+        )
+        .into());
+    }
+
+    ensure_supabase_client().await?;
+    let originale = leggi_servizio_completo(payload.servizio_id, propri.as_ref()).await?;
+    let template_row = fetch_servizio_row_template(payload.servizio_id).await?;
+
+    let mut copie: Vec<(chrono::NaiveDate, UpdateServizioPayload)> = Vec::new();
+    for voce in &payload.date {
+        let data = tariffe::data_servizio(&voce.data)
+            .ok_or_else(|| ApiError::from(format!("Data non valida: '{}'", voce.data.trim())))?;
+        if copie.iter().any(|(d, _)| *d == data) {
+            return Err(format!("Data ripetuta: {}", data.format("%d/%m/%Y")).into());
+        }
+        let mut copia = servizio_completo_to_update_payload(&originale);
+        prepara_payload_duplicazione(&mut copia, &payload.opzioni);
+        copia.data_prelievo = Some(data.format("%d/%m/%Y").to_string());
+        if voce.ora_inizio.is_some() {
+            copia.ora_inizio = voce.ora_inizio.clone();
+        }
+        if voce.operatore.is_some() {
+            copia.operatore = voce.operatore.clone();
+        }
+        if voce.mezzo.is_some() {
+            copia.mezzo = voce.mezzo.clone();
+        }
+        verifica_stato_servizio(&copia, None, true, &perm)?;
+        copie.push((data, copia));
+    }
+
+    let mut esito = EsitoDuplicazioneMultipla::default();
+    if !payload.ignora_conflitti {
+        let prima = copie.iter().map(|(d, _)| *d).min().unwrap_or_default();
+        let ultima = copie.iter().map(|(d, _)| *d).max().unwrap_or_default();
+        let filtro = Query::new()
+            .gte("Prelievo_Data", prima.format("%Y-%m-%d").to_string())
+            .lt(
+                "Prelievo_Data",
+                (ultima + chrono::Duration::days(1)).format("%Y-%m-%d").to_string(),
+            );
+        let righe: Vec<serde_json::Value> = fetch_servizi_supabase(filtro)
+            .await?
+            .into_iter()
+            .filter(|r| stato_servizio_riga(r) != Some(StatoServizio::Annullato))
+            .collect();
+        let nominativi = fetch_idsocio_nominativo_map().await;
+        let mut id_operatori: HashMap<String, Option<String>> = HashMap::new();
+        for (data, copia) in &copie {
+            let operatore = copia.operatore.clone().unwrap_or_default();
+            if !operatore.trim().is_empty() && !id_operatori.contains_key(&operatore) {
+                let id = resolve_operatore_id_by_nome(&operatore).await;
+                id_operatori.insert(operatore.clone(), id);
+            }
+            let data_iso = data.format("%Y-%m-%d").to_string();
+            let righe_giorno: Vec<&serde_json::Value> = righe
+                .iter()
+                .filter(|r| {
+                    servizio_data_iso(r) == data_iso
+                        || tariffe::data_servizio(&servizio_data_raw(r)) == Some(*data)
+                })
+                .collect();
+            esito.conflitti.extend(conflitti_copia(
+                copia,
+                *data,
+                id_operatori.get(&operatore).cloned().flatten().as_deref(),
+                &righe_giorno,
+                &nominativi,
+            ));
+        }
+        if !esito.conflitti.is_empty() {
+            println!(
+                "⚠️ Duplicazione multipla di {} bloccata: {} conflitti",
+                payload.servizio_id,
+                esito.conflitti.len()
+            );
+            return Ok(esito);
+        }
+    }
+
+    let client = supabase_client_clone().await?;
+    for (data, copia) in &copie {
+        match inserisci_copia_servizio(
+            &client,
+            copia,
+            &template_row,
+            payload.creato_da.as_deref(),
+            &serde_json::Map::new(),
+        )
         .await
-        .map_err(|e| format_supabase_error(&e))?;
+        {
+            Ok(id) => esito.creati.push(CopiaCreata {
+                id,
+                data: data.format("%d/%m/%Y").to_string(),
+            }),
+            Err(e) => {
+                println!(
+                    "❌ Duplicazione multipla di {}: copia del {} non creata, annullo le altre: {}",
+                    payload.servizio_id, data, e
+                );
+                let ids: Vec<u32> = esito.creati.iter().map(|c| c.id).collect();
+                esito.non_annullati = elimina_copie_servizio(&client, &ids).await;
+                esito.annullati = ids
+                    .into_iter()
+                    .filter(|id| !esito.non_annullati.contains(id))
+                    .collect();
+                esito.creati.retain(|c| esito.non_annullati.contains(&c.id));
+                esito.errore = Some(format!("{}: {}", data.format("%d/%m/%Y"), e));
+                return Ok(esito);
+            }
+        }
+    }
 
-    let new_id = servizio_id_from_row(&inserted);
-    let new_id = if new_id == 0 { next_id } else { new_id };
-    if new_id == 0 {
-        return Err("Servizio duplicato ma ID non restituito da Supabase".to_string());
+    println!(
+        "✓ Servizio {} duplicato su {} date",
+        payload.servizio_id,
+        esito.creati.len()
+    );
+    esito.completata = true;
+    Ok(esito)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreaSeriePayload {
+    servizio_id: u32,
+    regola: RegolaRicorrenza,
+    opzioni: DuplicateServizioOptions,
+    creato_da: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OccorrenzaSerie {
+    id: u32,
+    data: String,
+    stato_servizio: String,
+}
+
+impl OccorrenzaSerie {
+    fn da_riga(row: &serde_json::Value) -> Self {
+        Self {
+            id: servizio_id_from_row(row),
+            data: servizio_data_italiana(row),
+            stato_servizio: get_field_any(row, &["StatoServizio", "STATOSERVIZIO"]),
+        }
     }
+}
 
-    println!("✓ Servizio {} duplicato come ID {}", servizio_id, new_id);
-    Ok(new_id)
+#[derive(Debug, Serialize)]
+struct EsitoSerie {
+    id_serie: String,
+    occorrenze: Vec<OccorrenzaSerie>,
+    /// Date della regola non create (festività, date escluse)
+    saltate: Vec<DataSaltata>,
+}
+
+/// Servizio lasciato com'era da una modifica o da un annullamento della serie
+/// (o delle altre tappe del viaggio)
+#[derive(Debug, Serialize)]
+struct OccorrenzaSaltata {
+    id: u32,
+    data: String,
+    motivo: String,
+}
+
+#[derive(Debug, Serialize)]
+struct EsitoModificaSerie {
+    aggiornati: Vec<u32>,
+    saltati: Vec<OccorrenzaSaltata>,
+}
+
+#[derive(Debug, Serialize)]
+struct SerieServizi {
+    id_serie: String,
+    /// Regola con cui è stata generata (None se la riga della serie manca)
+    regola: Option<serde_json::Value>,
+    annullata: bool,
+    occorrenze: Vec<OccorrenzaSerie>,
+}
+
+/// Data ISO (aaaa-mm-gg) di una riga di Servizi_supa, per confronti
+fn servizio_data_iso(row: &serde_json::Value) -> String {
+    let data = servizio_data_raw(row);
+    data.get(..10).unwrap_or(&data).to_string()
+}
+
+async fn righe_serie(client: &SupabaseClient, id_serie: &str) -> Result<Vec<serde_json::Value>, ApiError> {
+    let mut rows = client
+        .fetch_servizi(Query::new().eq("IdSerie", id_serie).order("Prelievo_Data", true))
+        .await?;
+    rows.sort_by_key(|r| (servizio_data_iso(r), servizio_ora_prelievo(r)));
+    Ok(rows)
+}
+
+/// Le modifiche a più servizi insieme le fa chi gestisce tutti i servizi
+async fn richiedi_gestione_serie() -> Result<UserPermissionsRecord, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziModifica).await?;
+    if permessi::solo_propri(&perm) {
+        return Err(ApiError::permission_denied(
+            "Le serie di servizi le gestisce chi vede tutti i servizi",
+        ));
+    }
+    Ok(perm)
+}
+
+/// Elimina le copie di una serie non riuscita e compone l'errore per l'utente
+async fn annulla_serie_parziale(
+    client: &SupabaseClient,
+    creati: &[u32],
+    dove: &str,
+    errore: ApiError,
+) -> ApiError {
+    let rimasti = elimina_copie_servizio(client, creati).await;
+    if rimasti.is_empty() {
+        return format!("Serie non creata: {} ({})", dove, errore).into();
+    }
+    format!(
+        "Serie interrotta: {} ({}); servizi rimasti da eliminare a mano: {:?}",
+        dove, errore, rimasti
+    )
+    .into()
+}
+
+// Crea una serie ricorrente dal servizio modello: una copia per ogni data della
+// regola (festività saltate), tutte con lo stesso IdSerie del modello. Modello
+// e riga della serie si aggiornano dopo le copie; se un passo fallisce, le
+// copie già create vengono eliminate.
+#[tauri::command]
+async fn crea_serie_servizi(payload: CreaSeriePayload) -> Result<EsitoSerie, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziCrea).await?;
+    let propri = visibilita_propria(&perm).await?;
+    println!(
+        "=== crea_serie_servizi dal servizio {} regola {:?} ===",
+        payload.servizio_id, payload.regola
+    );
+
+    let piano = payload.regola.piano()?;
+    let originale = leggi_servizio_completo(payload.servizio_id, propri.as_ref()).await?;
+    let template_row = fetch_servizio_row_template(payload.servizio_id).await?;
+    if !originale.id_serie.is_empty() {
+        return Err(ApiError::Conflict {
+            code: Some("servizio_in_serie".to_string()),
+            message: format!(
+                "Il servizio {} fa già parte della serie {}",
+                payload.servizio_id, originale.id_serie
+            ),
+        });
+    }
+
+    let mut modello = servizio_completo_to_update_payload(&originale);
+    prepara_payload_duplicazione(&mut modello, &payload.opzioni);
+    verifica_stato_servizio(&modello, None, true, &perm)?;
+
+    let client = supabase_client_clone().await?;
+    let id_serie = ricorrenze::nuovo_id_serie();
+    let id_serie_col = resolve_column_key(&template_row, &["IdSerie", "id_serie"])
+        .unwrap_or_else(|| "IdSerie".to_string());
+    let mut extra = serde_json::Map::new();
+    extra.insert(id_serie_col, serde_json::json!(id_serie));
+
+    // La data del modello è già coperta dal modello stesso
+    let data_modello = tariffe::data_servizio(&servizio_data_raw(&template_row));
+    let mut creati: Vec<u32> = Vec::new();
+    for data in piano.date.iter().filter(|d| Some(**d) != data_modello) {
+        let mut copia = servizio_completo_to_update_payload(&originale);
+        prepara_payload_duplicazione(&mut copia, &payload.opzioni);
+        copia.data_prelievo = Some(data.format("%d/%m/%Y").to_string());
+        match inserisci_copia_servizio(
+            &client,
+            &copia,
+            &template_row,
+            payload.creato_da.as_deref(),
+            &extra,
+        )
+        .await
+        {
+            Ok(id) => creati.push(id),
+            Err(e) => {
+                println!("❌ Serie {}: copia del {} non creata, annullo le altre: {}", id_serie, data, e);
+                let dove = format!("errore sul servizio del {}", data.format("%d/%m/%Y"));
+                return Err(annulla_serie_parziale(&client, &creati, &dove, e).await);
+            }
+        }
+    }
+
+    // Il modello entra nella serie solo a copie create: se una copia fallisce
+    // resta un servizio singolo, senza IdSerie né riga in serie_servizi
+    if let Err(e) = client.patch_servizio(payload.servizio_id, &extra).await {
+        println!("❌ Serie {}: modello {} non collegato, annullo le copie: {}", id_serie, payload.servizio_id, e);
+        return Err(annulla_serie_parziale(&client, &creati, "errore sul servizio modello", e).await);
+    }
+    let registrata = client
+        .upsert_serie_servizi(&serde_json::json!({
+            "id_serie": id_serie,
+            "regola": payload.regola,
+            "servizio_modello": payload.servizio_id,
+            "creato_da": perm.username,
+            "creato_il": chrono::Utc::now().to_rfc3339(),
+        }))
+        .await;
+    if let Err(e) = registrata {
+        println!("❌ Serie {}: riga della serie non salvata, annullo le copie: {}", id_serie, e);
+        let mut scollega = serde_json::Map::new();
+        for col in extra.keys() {
+            scollega.insert(col.clone(), serde_json::Value::Null);
+        }
+        if let Err(e2) = client.patch_servizio(payload.servizio_id, &scollega).await {
+            println!("⚠️ Serie {}: IdSerie non tolto dal modello {}: {}", id_serie, payload.servizio_id, e2);
+        }
+        return Err(annulla_serie_parziale(&client, &creati, "errore nel registrare la serie", e).await);
+    }
+    aggiorna_riga_cache_delta(&client, "servizi", "idservizio", &payload.servizio_id.to_string()).await;
+    emetti_modifica(ModificaTabella::locale("servizi", "update", payload.servizio_id));
+
+    println!("✓ Serie {}: {} servizi creati, {} date saltate", id_serie, creati.len(), piano.saltate.len());
+    let occorrenze = righe_serie(&client, &id_serie)
+        .await?
+        .iter()
+        .map(OccorrenzaSerie::da_riga)
+        .collect();
+    Ok(EsitoSerie {
+        id_serie,
+        occorrenze,
+        saltate: piano.saltate,
+    })
+}
+
+// Servizi della serie, in ordine di data, con la regola che li ha generati
+#[tauri::command]
+async fn get_serie_servizi(id_serie: String) -> Result<SerieServizi, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let propri = visibilita_propria(&perm).await?;
+    let id_serie = id_serie.trim().to_string();
+    println!("=== get_serie_servizi {} ===", id_serie);
+
+    let client = supabase_client_clone().await?;
+    let mut rows = righe_serie(&client, &id_serie).await?;
+    if let Some(vis) = propri {
+        let nominativi = fetch_idsocio_nominativo_map().await;
+        rows.retain(|r| vis.servizio_proprio(r, &nominativi));
+    }
+    let serie = client
+        .fetch_serie_servizi(Query::new().eq("id_serie", &id_serie))
+        .await?;
+    let riga = serie.first();
+    Ok(SerieServizi {
+        regola: riga.and_then(|r| r.get("regola")).cloned(),
+        annullata: riga
+            .map(|r| !get_field_any(r, &["annullata_il"]).is_empty())
+            .unwrap_or(false),
+        occorrenze: rows.iter().map(OccorrenzaSerie::da_riga).collect(),
+        id_serie,
+    })
+}
+
+/// Campi comuni alla serie cambiati rispetto a com'era l'occorrenza: data, km,
+/// stato, chiusura e incasso restano della singola occorrenza
+fn modifiche_comuni_serie(
+    nuovo: &UpdateServizioPayload,
+    prima: &UpdateServizioPayload,
+) -> UpdateServizioPayload {
+    let cambiato = |n: &Option<String>, p: &Option<String>| -> Option<String> {
+        let n = n.as_deref()?.trim();
+        (n != p.as_deref().unwrap_or("").trim()).then(|| n.to_string())
+    };
+    UpdateServizioPayload {
+        idsocio: cambiato(&nuovo.idsocio, &prima.idsocio),
+        socio_trasportato: cambiato(&nuovo.socio_trasportato, &prima.socio_trasportato),
+        ora_inizio: cambiato(&nuovo.ora_inizio, &prima.ora_inizio),
+        comune_prelievo: cambiato(&nuovo.comune_prelievo, &prima.comune_prelievo),
+        luogo_prelievo: cambiato(&nuovo.luogo_prelievo, &prima.luogo_prelievo),
+        tipo_servizio: cambiato(&nuovo.tipo_servizio, &prima.tipo_servizio),
+        carrozzina: cambiato(&nuovo.carrozzina, &prima.carrozzina),
+        richiedente: cambiato(&nuovo.richiedente, &prima.richiedente),
+        motivazione: cambiato(&nuovo.motivazione, &prima.motivazione),
+        comune_destinazione: cambiato(&nuovo.comune_destinazione, &prima.comune_destinazione),
+        luogo_destinazione: cambiato(&nuovo.luogo_destinazione, &prima.luogo_destinazione),
+        operatore: cambiato(&nuovo.operatore, &prima.operatore),
+        operatore_2: cambiato(&nuovo.operatore_2, &prima.operatore_2),
+        mezzo: cambiato(&nuovo.mezzo, &prima.mezzo),
+        tipo_pagamento: cambiato(&nuovo.tipo_pagamento, &prima.tipo_pagamento),
+        pagamento: cambiato(&nuovo.pagamento, &prima.pagamento),
+        note_prelievo: cambiato(&nuovo.note_prelievo, &prima.note_prelievo),
+        note_arrivo: cambiato(&nuovo.note_arrivo, &prima.note_arrivo),
+        ..UpdateServizioPayload::default()
+    }
+}
+
+/// Applica `payload` a un altro servizio della serie o del viaggio, con gli
+/// stessi controlli di stato di un salvataggio normale
+async fn aggiorna_servizio_collegato(
+    client: &SupabaseClient,
+    row: &serde_json::Value,
+    payload: &UpdateServizioPayload,
+    modificato_da: Option<&str>,
+    perm: &UserPermissionsRecord,
+) -> Result<u32, ApiError> {
+    let id = servizio_id_from_row(row);
+    verifica_stato_servizio(payload, Some(row), false, perm)?;
+    let mut body = build_servizio_supabase_body(payload, Some(row)).await;
+    strip_servizio_audit_fields(&mut body);
+    apply_servizio_audit_fields(&mut body, Some(row), None, modificato_da, false, true);
+    client.patch_servizio(id, &body).await?;
+    registra_audit(
+        client,
+        "servizi",
+        &id.to_string(),
+        audit::AZIONE_UPDATE,
+        Some(row),
+        Some(&serde_json::Value::Object(body)),
+    )
+    .await;
+    aggiorna_riga_cache_delta(client, "servizi", "idservizio", &id.to_string()).await;
+    emetti_modifica(ModificaTabella::locale("servizi", "update", id));
+    Ok(id)
+}
+
+// Salva un servizio di una serie: solo questo, questo e i successivi o tutta la
+// serie. Alle altre occorrenze passano solo i campi comuni cambiati; quelle già
+// chiuse (completate, annullate, non presentate) restano com'erano.
+#[tauri::command]
+async fn update_servizio_serie(
+    payload: UpdateServizioPayload,
+    ambito: AmbitoSerie,
+) -> Result<EsitoModificaSerie, ApiError> {
+    let id = payload.id;
+    if ambito == AmbitoSerie::Singola {
+        update_servizio_completo(payload).await?;
+        return Ok(EsitoModificaSerie {
+            aggiornati: vec![id],
+            saltati: Vec::new(),
+        });
+    }
+    let perm = richiedi_gestione_serie().await?;
+    println!("=== update_servizio_serie {} ambito {:?} ===", id, ambito);
+
+    let row = fetch_servizio_row_template(id).await?;
+    let id_serie = get_field_any(&row, &["IdSerie", "id_serie"]);
+    if id_serie.is_empty() {
+        return Err(format!("Il servizio {} non fa parte di una serie", id).into());
+    }
+    let nominativi = fetch_idsocio_nominativo_map().await;
+    let prima = supabase_row_to_servizio_completo(&row, &nominativi)
+        .map(|sc| servizio_completo_to_update_payload(&sc))
+        .unwrap_or_default();
+    let modifiche = modifiche_comuni_serie(&payload, &prima);
+    let modificato_da = payload.modificato_da.clone();
+    let dal = servizio_data_iso(&row);
+
+    update_servizio_completo(payload).await?;
+    let mut esito = EsitoModificaSerie {
+        aggiornati: vec![id],
+        saltati: Vec::new(),
+    };
+
+    let client = supabase_client_clone().await?;
+    let body_vuoto = build_servizio_supabase_body(&modifiche, Some(&row)).await.is_empty();
+    if body_vuoto {
+        println!("ℹ️ Nessun campo comune cambiato: aggiornato solo il servizio {}", id);
+        return Ok(esito);
+    }
+    for r in righe_serie(&client, &id_serie).await? {
+        let rid = servizio_id_from_row(&r);
+        if rid == id || (ambito == AmbitoSerie::DaQuestaInPoi && servizio_data_iso(&r) < dal) {
+            continue;
+        }
+        if let Some(stato) = stato_servizio_riga(&r).filter(|s| s.finale()) {
+            esito.saltati.push(OccorrenzaSaltata {
+                id: rid,
+                data: servizio_data_italiana(&r),
+                motivo: format!("servizio {}", stato.etichetta()),
+            });
+            continue;
+        }
+        match aggiorna_servizio_collegato(&client, &r, &modifiche, modificato_da.as_deref(), &perm).await {
+            Ok(rid) => esito.aggiornati.push(rid),
+            Err(e) => esito.saltati.push(OccorrenzaSaltata {
+                id: rid,
+                data: servizio_data_italiana(&r),
+                motivo: e.to_string(),
+            }),
+        }
+    }
+    println!(
+        "✓ Serie {}: {} servizi aggiornati, {} lasciati com'erano",
+        id_serie,
+        esito.aggiornati.len(),
+        esito.saltati.len()
+    );
+    Ok(esito)
+}
+
+// Annulla la serie: le occorrenze da oggi in poi non ancora chiuse passano ad
+// ANNULLATO con il motivo; quelle passate restano come sono
+#[tauri::command]
+async fn annulla_serie_servizi(
+    id_serie: String,
+    motivo: String,
+    modificato_da: Option<String>,
+) -> Result<EsitoModificaSerie, ApiError> {
+    let perm = richiedi_gestione_serie().await?;
+    let id_serie = id_serie.trim().to_string();
+    let motivo = motivo.trim().to_string();
+    if motivo.is_empty() {
+        return Err("Indica il motivo dell'annullamento della serie".into());
+    }
+    println!("=== annulla_serie_servizi {} ===", id_serie);
+
+    let client = supabase_client_clone().await?;
+    let rows = righe_serie(&client, &id_serie).await?;
+    if rows.is_empty() {
+        return Err(ApiError::not_found(format!("Serie {} non trovata", id_serie)));
+    }
+    let oggi = Local::now().format("%Y-%m-%d").to_string();
+    let annullamento = UpdateServizioPayload {
+        stato_servizio: Some(StatoServizio::Annullato.etichetta().to_string()),
+        motivo_annullamento: Some(motivo.clone()),
+        ..UpdateServizioPayload::default()
+    };
+    let mut esito = EsitoModificaSerie {
+        aggiornati: Vec::new(),
+        saltati: Vec::new(),
+    };
+    for r in rows.iter().filter(|r| servizio_data_iso(r) >= oggi) {
+        let rid = servizio_id_from_row(r);
+        if let Some(stato) = stato_servizio_riga(r).filter(|s| s.finale()) {
+            esito.saltati.push(OccorrenzaSaltata {
+                id: rid,
+                data: servizio_data_italiana(r),
+                motivo: format!("servizio {}", stato.etichetta()),
+            });
+            continue;
+        }
+        match aggiorna_servizio_collegato(&client, r, &annullamento, modificato_da.as_deref(), &perm).await {
+            Ok(rid) => esito.aggiornati.push(rid),
+            Err(e) => esito.saltati.push(OccorrenzaSaltata {
+                id: rid,
+                data: servizio_data_italiana(r),
+                motivo: e.to_string(),
+            }),
+        }
+    }
+    if let Err(e) = client
+        .upsert_serie_servizi(&serde_json::json!({
+            "id_serie": id_serie,
+            "annullata_il": chrono::Utc::now().to_rfc3339(),
+            "motivo_annullamento": motivo,
+        }))
+        .await
+    {
+        println!("⚠️ Serie {} non segnata come annullata: {}", id_serie, e);
+    }
+    println!("✓ Serie {}: {} servizi annullati", id_serie, esito.aggiornati.len());
+    Ok(esito)
+}
+
+/// Tappa di un viaggio collegato, per il riepilogo nel modale
+#[derive(Debug, Serialize)]
+struct TappaViaggio {
+    id: u32,
+    tappa: u32,
+    data: String,
+    ora: String,
+    comune_prelievo: String,
+    comune_destinazione: String,
+    stato_servizio: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ViaggioServizi {
+    id_viaggio: String,
+    tappe: Vec<TappaViaggio>,
+}
+
+fn tappa_riga(row: &serde_json::Value) -> u32 {
+    get_field_any(row, &["Tappa", "tappa"])
+        .trim()
+        .trim_end_matches(".0")
+        .parse()
+        .unwrap_or(0)
+}
+
+/// Codice di un viaggio (colonna IdViaggio dei servizi)
+fn nuovo_id_viaggio() -> String {
+    supabase::codice_casuale(12)
+}
+
+/// Tappe del viaggio nell'ordine: numero di tappa, poi data e ora
+async fn righe_viaggio(client: &SupabaseClient, id_viaggio: &str) -> Result<Vec<serde_json::Value>, ApiError> {
+    let mut rows = client
+        .fetch_servizi(Query::new().eq("IdViaggio", id_viaggio))
+        .await?;
+    rows.sort_by_key(|r| (tappa_riga(r), servizio_data_iso(r), servizio_ora_prelievo(r)));
+    Ok(rows)
+}
+
+fn viaggio_da_righe(id_viaggio: &str, rows: &[serde_json::Value]) -> ViaggioServizi {
+    ViaggioServizi {
+        id_viaggio: id_viaggio.to_string(),
+        tappe: rows
+            .iter()
+            .map(|r| TappaViaggio {
+                id: servizio_id_from_row(r),
+                tappa: tappa_riga(r),
+                data: servizio_data_italiana(r),
+                ora: servizio_ora_prelievo(r),
+                comune_prelievo: get_field_any(r, &["Prelievo_Comune", "PRELIEVO_COMUNE"]),
+                comune_destinazione: get_field_any(r, &["Destinazione_Comune", "DESTINAZIONE_COMUNE"]),
+                stato_servizio: get_field_any(r, &["StatoServizio", "STATOSERVIZIO"]),
+            })
+            .collect(),
+    }
+}
+
+/// Scrive IdViaggio e Tappa di un servizio (None = tolto dal viaggio)
+async fn imposta_tappa_viaggio(
+    client: &SupabaseClient,
+    row: &serde_json::Value,
+    viaggio: Option<(&str, u32)>,
+) -> Result<(), ApiError> {
+    let id = servizio_id_from_row(row);
+    let attuale = (get_field_any(row, &["IdViaggio", "id_viaggio"]), tappa_riga(row));
+    let nuovo = viaggio
+        .map(|(v, t)| (v.to_string(), t))
+        .unwrap_or((String::new(), 0));
+    if attuale == nuovo {
+        return Ok(());
+    }
+    let mut body = serde_json::Map::new();
+    body.insert(
+        resolve_column_key(row, &["IdViaggio", "id_viaggio"]).unwrap_or_else(|| "IdViaggio".to_string()),
+        viaggio.map(|(v, _)| serde_json::json!(v)).unwrap_or(serde_json::Value::Null),
+    );
+    body.insert(
+        resolve_column_key(row, &["Tappa", "tappa"]).unwrap_or_else(|| "Tappa".to_string()),
+        viaggio.map(|(_, t)| serde_json::json!(t)).unwrap_or(serde_json::Value::Null),
+    );
+    client.patch_servizio(id, &body).await?;
+    registra_audit(
+        client,
+        "servizi",
+        &id.to_string(),
+        audit::AZIONE_UPDATE,
+        Some(row),
+        Some(&serde_json::Value::Object(body)),
+    )
+    .await;
+    aggiorna_riga_cache_delta(client, "servizi", "idservizio", &id.to_string()).await;
+    emetti_modifica(ModificaTabella::locale("servizi", "update", id));
+    Ok(())
+}
+
+/// Rinumera le tappe per data e ora; un viaggio rimasto con una tappa sola si scioglie
+async fn rinumera_tappe_viaggio(
+    client: &SupabaseClient,
+    id_viaggio: &str,
+    mut rows: Vec<serde_json::Value>,
+) -> Result<Vec<serde_json::Value>, ApiError> {
+    rows.sort_by_key(|r| (servizio_data_iso(r), servizio_ora_prelievo(r), tappa_riga(r)));
+    if rows.len() == 1 {
+        imposta_tappa_viaggio(client, &rows[0], None).await?;
+        return Ok(Vec::new());
+    }
+    for (i, r) in rows.iter().enumerate() {
+        imposta_tappa_viaggio(client, r, Some((id_viaggio, i as u32 + 1))).await?;
+    }
+    righe_viaggio(client, id_viaggio).await
+}
+
+// Crea il ritorno di un servizio: stessa persona, mezzo e operatore, partenza e
+// destinazione invertite. Andata e ritorno finiscono nello stesso viaggio; la
+// donazione resta vuota perché la tariffa si calcola una volta sola sulla prima
+// tappa.
+#[tauri::command]
+async fn crea_ritorno_servizio(
+    servizio_id: u32,
+    data_prelievo: Option<String>,
+    ora_inizio: Option<String>,
+    creato_da: Option<String>,
+) -> Result<u32, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziCrea).await?;
+    let propri = visibilita_propria(&perm).await?;
+    println!("=== crea_ritorno_servizio dal servizio {} ===", servizio_id);
+
+    ensure_supabase_client().await?;
+    let andata = leggi_servizio_completo(servizio_id, propri.as_ref()).await?;
+    let template_row = fetch_servizio_row_template(servizio_id).await?;
+
+    let opzioni = DuplicateServizioOptions {
+        mantieni_ora_partenza: false,
+        mantieni_operatore: true,
+        mantieni_mezzo: true,
+        mantieni_motivazione: true,
+        mantieni_note_partenza: true,
+        mantieni_note_arrivo: true,
+        mantieni_stato_incasso: true,
+        mantieni_tipo_pagamento: true,
+        mantieni_donazione: false,
+    };
+    let mut ritorno = servizio_completo_to_update_payload(&andata);
+    prepara_payload_duplicazione(&mut ritorno, &opzioni);
+    std::mem::swap(&mut ritorno.comune_prelievo, &mut ritorno.comune_destinazione);
+    std::mem::swap(&mut ritorno.luogo_prelievo, &mut ritorno.luogo_destinazione);
+    std::mem::swap(&mut ritorno.note_prelievo, &mut ritorno.note_arrivo);
+    let data = match data_prelievo.as_deref().filter(|d| !d.trim().is_empty()) {
+        Some(d) => tariffe::data_servizio(d)
+            .ok_or_else(|| ApiError::from(format!("Data del ritorno non valida: '{}'", d.trim())))?
+            .format("%d/%m/%Y")
+            .to_string(),
+        None => andata.data_prelievo.clone(),
+    };
+    ritorno.data_prelievo = Some(data);
+    ritorno.ora_inizio = Some(ora_inizio.unwrap_or_default());
+    verifica_stato_servizio(&ritorno, None, true, &perm)?;
+
+    let client = supabase_client_clone().await?;
+    let viaggio_nuovo = andata.id_viaggio.is_empty();
+    let (id_viaggio, tappa) = if viaggio_nuovo {
+        (nuovo_id_viaggio(), 2)
+    } else {
+        let ultima = righe_viaggio(&client, &andata.id_viaggio)
+            .await?
+            .iter()
+            .map(tappa_riga)
+            .max()
+            .unwrap_or(1);
+        (andata.id_viaggio.clone(), ultima + 1)
+    };
+    let mut extra = serde_json::Map::new();
+    extra.insert(
+        resolve_column_key(&template_row, &["IdViaggio", "id_viaggio"]).unwrap_or_else(|| "IdViaggio".to_string()),
+        serde_json::json!(id_viaggio),
+    );
+    extra.insert(
+        resolve_column_key(&template_row, &["Tappa", "tappa"]).unwrap_or_else(|| "Tappa".to_string()),
+        serde_json::json!(tappa),
+    );
+    // Prima il ritorno, poi l'andata nel viaggio: se l'inserimento fallisce
+    // l'andata non resta in un viaggio senza altre tappe
+    let nuovo_id =
+        inserisci_copia_servizio(&client, &ritorno, &template_row, creato_da.as_deref(), &extra).await?;
+    if viaggio_nuovo {
+        if let Err(e) = imposta_tappa_viaggio(&client, &template_row, Some((&id_viaggio, 1))).await {
+            println!("❌ Andata {} non collegata al viaggio {}, annullo il ritorno {}: {}", servizio_id, id_viaggio, nuovo_id, e);
+            if !elimina_copie_servizio(&client, &[nuovo_id]).await.is_empty() {
+                return Err(format!(
+                    "Andata non collegata al ritorno ({}); ritorno {} da eliminare a mano",
+                    e, nuovo_id
+                )
+                .into());
+            }
+            return Err(e);
+        }
+    }
+    println!("✓ Ritorno del servizio {} creato come ID {} (viaggio {})", servizio_id, nuovo_id, id_viaggio);
+    Ok(nuovo_id)
+}
+
+// Collega servizi già inseriti dello stesso socio in un viaggio (andata e
+// ritorno o più tappe), numerati per data e ora. Se uno è già in un viaggio gli
+// altri si aggiungono a quello.
+#[tauri::command]
+async fn collega_servizi_viaggio(servizi_ids: Vec<u32>) -> Result<ViaggioServizi, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziModifica).await?;
+    println!("=== collega_servizi_viaggio {:?} ===", servizi_ids);
+    let ids: HashSet<u32> = servizi_ids.iter().copied().collect();
+    if ids.len() < 2 {
+        return Err("Seleziona almeno due servizi da collegare".into());
+    }
+
+    ensure_supabase_client().await?;
+    let mut rows = Vec::new();
+    for &id in &ids {
+        let row = fetch_servizio_row_template(id).await?;
+        verifica_servizio_proprio(&perm, id, Some(&row)).await?;
+        rows.push(row);
+    }
+    let soci: HashSet<String> = rows
+        .iter()
+        .map(|r| normalize_idsocio_key(&get_field_any(r, &["IdSocio", "IDSOCIO"])))
+        .collect();
+    if soci.len() > 1 {
+        return Err("Si collegano solo servizi dello stesso socio".into());
+    }
+    let viaggi: HashSet<String> = rows
+        .iter()
+        .map(|r| get_field_any(r, &["IdViaggio", "id_viaggio"]))
+        .filter(|v| !v.is_empty())
+        .collect();
+    if viaggi.len() > 1 {
+        return Err(ApiError::Conflict {
+            code: Some("servizi_in_viaggi_diversi".to_string()),
+            message: "I servizi fanno già parte di viaggi diversi: scollegali prima".to_string(),
+        });
+    }
+
+    let client = supabase_client_clone().await?;
+    let id_viaggio = match viaggi.into_iter().next() {
+        Some(v) => {
+            for r in righe_viaggio(&client, &v).await? {
+                if !ids.contains(&servizio_id_from_row(&r)) {
+                    rows.push(r);
+                }
+            }
+            v
+        }
+        None => nuovo_id_viaggio(),
+    };
+    let rows = rinumera_tappe_viaggio(&client, &id_viaggio, rows).await?;
+    println!("✓ Viaggio {}: {} tappe", id_viaggio, rows.len());
+    Ok(viaggio_da_righe(&id_viaggio, &rows))
+}
+
+// Toglie un servizio dal suo viaggio; le tappe rimaste si rinumerano
+#[tauri::command]
+async fn scollega_servizio_viaggio(servizio_id: u32) -> Result<(), ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziModifica).await?;
+    println!("=== scollega_servizio_viaggio {} ===", servizio_id);
+    ensure_supabase_client().await?;
+    let row = fetch_servizio_row_template(servizio_id).await?;
+    verifica_servizio_proprio(&perm, servizio_id, Some(&row)).await?;
+    let id_viaggio = get_field_any(&row, &["IdViaggio", "id_viaggio"]);
+    if id_viaggio.is_empty() {
+        return Ok(());
+    }
+
+    let client = supabase_client_clone().await?;
+    imposta_tappa_viaggio(&client, &row, None).await?;
+    let rimaste = righe_viaggio(&client, &id_viaggio).await?;
+    if !rimaste.is_empty() {
+        rinumera_tappe_viaggio(&client, &id_viaggio, rimaste).await?;
+    }
+    println!("✓ Servizio {} tolto dal viaggio {}", servizio_id, id_viaggio);
+    Ok(())
+}
+
+// Tappe di un viaggio, nell'ordine
+#[tauri::command]
+async fn get_viaggio_servizi(id_viaggio: String) -> Result<ViaggioServizi, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziLeggi).await?;
+    let propri = visibilita_propria(&perm).await?;
+    let id_viaggio = id_viaggio.trim().to_string();
+    println!("=== get_viaggio_servizi {} ===", id_viaggio);
+
+    let client = supabase_client_clone().await?;
+    let mut rows = righe_viaggio(&client, &id_viaggio).await?;
+    if let Some(vis) = propri {
+        let nominativi = fetch_idsocio_nominativo_map().await;
+        rows.retain(|r| vis.servizio_proprio(r, &nominativi));
+    }
+    Ok(viaggio_da_righe(&id_viaggio, &rows))
+}
+
+/// Applica alle altre tappe del viaggio non ancora chiuse la modifica che
+/// `modifica` ricava da (tappa appena salvata, altra tappa)
+async fn aggiorna_altre_tappe(
+    servizio_id: u32,
+    modifica: impl Fn(&serde_json::Value, &serde_json::Value) -> Option<UpdateServizioPayload>,
+    modificato_da: Option<&str>,
+) -> Result<EsitoModificaSerie, ApiError> {
+    let perm = richiedi_permesso(Capacita::ServiziModifica).await?;
+    let propri = visibilita_propria(&perm).await?;
+    ensure_supabase_client().await?;
+    let row = fetch_servizio_row_template(servizio_id).await?;
+    let id_viaggio = get_field_any(&row, &["IdViaggio", "id_viaggio"]);
+    if id_viaggio.is_empty() {
+        return Err(format!("Il servizio {} non fa parte di un viaggio", servizio_id).into());
+    }
+
+    let client = supabase_client_clone().await?;
+    let nominativi = fetch_idsocio_nominativo_map().await;
+    let mut esito = EsitoModificaSerie {
+        aggiornati: Vec::new(),
+        saltati: Vec::new(),
+    };
+    for r in righe_viaggio(&client, &id_viaggio).await? {
+        let rid = servizio_id_from_row(&r);
+        if rid == servizio_id {
+            continue;
+        }
+        let salta = |motivo: String| OccorrenzaSaltata {
+            id: rid,
+            data: servizio_data_italiana(&r),
+            motivo,
+        };
+        if propri.as_ref().is_some_and(|vis| !vis.servizio_proprio(&r, &nominativi)) {
+            esito.saltati.push(salta("servizio non assegnato a te".to_string()));
+            continue;
+        }
+        if let Some(stato) = stato_servizio_riga(&r).filter(|s| s.finale()) {
+            esito.saltati.push(salta(format!("servizio {}", stato.etichetta())));
+            continue;
+        }
+        let Some(payload) = modifica(&row, &r) else {
+            continue;
+        };
+        match aggiorna_servizio_collegato(&client, &r, &payload, modificato_da, &perm).await {
+            Ok(rid) => esito.aggiornati.push(rid),
+            Err(e) => esito.saltati.push(salta(e.to_string())),
+        }
+    }
+    Ok(esito)
+}
+
+// Dopo aver spostato una tappa: sposta le altre dello stesso intervallo (giorni
+// e, se la tappa aveva un'ora di partenza, anche ore e minuti). Una tappa senza
+// ora si sposta solo di giorni.
+#[tauri::command]
+async fn sposta_tappe_viaggio(
+    servizio_id: u32,
+    data_precedente: String,
+    ora_precedente: Option<String>,
+    modificato_da: Option<String>,
+) -> Result<EsitoModificaSerie, ApiError> {
+    println!(
+        "=== sposta_tappe_viaggio {} (era il {} ore {:?}) ===",
+        servizio_id, data_precedente, ora_precedente
+    );
+    let prima = tariffe::data_servizio(&data_precedente)
+        .ok_or_else(|| ApiError::from(format!("Data non valida: '{}'", data_precedente.trim())))?;
+    let ora = |testo: &str| chrono::NaiveTime::parse_from_str(&format_time_iso(testo), "%H:%M").ok();
+    let ora_prima = ora_precedente.as_deref().and_then(ora);
+
+    let esito = aggiorna_altre_tappe(
+        servizio_id,
+        |spostata, altra| {
+            let giorni = tariffe::data_servizio(&servizio_data_raw(spostata))? - prima;
+            let minuti = match (ora_prima, ora(&servizio_ora_prelievo(spostata))) {
+                (Some(prima), Some(dopo)) => dopo - prima,
+                _ => chrono::Duration::zero(),
+            };
+            let data = tariffe::data_servizio(&servizio_data_raw(altra))?;
+            match ora(&servizio_ora_prelievo(altra)) {
+                _ if giorni.is_zero() && minuti.is_zero() => None,
+                Some(ora_altra) => {
+                    let nuova = data.and_time(ora_altra) + giorni + minuti;
+                    Some(UpdateServizioPayload {
+                        data_prelievo: Some(nuova.format("%d/%m/%Y").to_string()),
+                        ora_inizio: Some(nuova.format("%H:%M").to_string()),
+                        ..UpdateServizioPayload::default()
+                    })
+                }
+                None if giorni.is_zero() => None,
+                None => Some(UpdateServizioPayload {
+                    data_prelievo: Some((data + giorni).format("%d/%m/%Y").to_string()),
+                    ..UpdateServizioPayload::default()
+                }),
+            }
+        },
+        modificato_da.as_deref(),
+    )
+    .await?;
+    println!("✓ Viaggio del servizio {}: {} tappe spostate", servizio_id, esito.aggiornati.len());
+    Ok(esito)
+}
+
+// Dopo aver annullato una tappa: annulla le altre con lo stesso motivo
+#[tauri::command]
+async fn annulla_tappe_viaggio(
+    servizio_id: u32,
+    motivo: String,
+    modificato_da: Option<String>,
+) -> Result<EsitoModificaSerie, ApiError> {
+    let motivo = motivo.trim().to_string();
+    if motivo.is_empty() {
+        return Err("Indica il motivo dell'annullamento".into());
+    }
+    println!("=== annulla_tappe_viaggio {} ===", servizio_id);
+    let esito = aggiorna_altre_tappe(
+        servizio_id,
+        |_, _| {
+            Some(UpdateServizioPayload {
+                stato_servizio: Some(StatoServizio::Annullato.etichetta().to_string()),
+                motivo_annullamento: Some(motivo.clone()),
+                ..UpdateServizioPayload::default()
+            })
+        },
+        modificato_da.as_deref(),
+    )
+    .await?;
+    println!("✓ Viaggio del servizio {}: {} tappe annullate", servizio_id, esito.aggiornati.len());
+    Ok(esito)
+}
+
+// Storico delle modifiche di un record (servizi, tesserati, automezzi, tratte,
+// impostazioni), dal più recente. Serve il permesso di lettura della tabella.
+#[tauri::command]
+async fn get_storico_record(entita: String, record_id: String) -> Result<Vec<VoceAudit>, ApiError> {
+    let entita = entita.trim().to_lowercase();
+    let record_id = record_id.trim().to_string();
+    let capacita = match entita.as_str() {
+        "servizi" => Capacita::ServiziLeggi,
+        "tesserati" => Capacita::SociLeggi,
+        "automezzi" => Capacita::MezziLeggi,
+        "tratte" => Capacita::TratteLeggi,
+        "impostazioni" | "tariffe" | "regole_tariffa" => Capacita::ImpostazioniModifica,
+        other => return Err(format!("Storico non disponibile per {}", other).into()),
+    };
+    let perm = richiedi_permesso(capacita).await?;
+    println!("=== get_storico_record {} {} ===", entita, record_id);
+
+    // Chi vede solo i propri servizi vede solo il loro storico
+    if entita == "servizi" {
+        if let Some(vis) = visibilita_propria(&perm).await? {
+            let id = record_id
+                .parse::<u32>()
+                .map_err(|_| ApiError::from(format!("ID servizio non valido: {}", record_id)))?;
+            leggi_servizio_completo(id, Some(&vis)).await?;
+        }
+    }
+
+    let client = supabase_client_clone().await?;
+    let mut voci: Vec<VoceAudit> = client
+        .fetch_audit(&entita, &record_id)
+        .await?
+        .iter()
+        .filter_map(VoceAudit::da_riga)
+        .collect();
+    voci.maschera(Mascheramento::per_utente(&perm));
+    println!("✓ {} voci di storico", voci.len());
+    Ok(voci)
+}
+
+// Stato della copia locale e della coda di scritture fatte offline
+#[tauri::command]
+async fn get_offline_status() -> Result<OfflineStatus, ApiError> {
+    get_offline_store()
+        .ok_or_else(|| ApiError::config("Copia locale offline non disponibile"))?
+        .status()
+}
+
+// Rinvia subito a Supabase le scritture fatte offline, nell'ordine in cui sono state fatte
+#[tauri::command]
+async fn sync_offline_outbox() -> Result<OutboxSyncReport, ApiError> {
+    richiedi_permesso(Capacita::ServiziLeggi).await?;
+    ensure_supabase_client().await?;
+    let client = {
+        let guard = get_supabase_client().lock().await;
+        guard
+            .as_ref()
+            .cloned()
+            .ok_or_else(|| ApiError::config("Client Supabase non disponibile"))?
+    };
+    client.replay_outbox().await
+}
+
+// Scarta una voce della outbox: la modifica offline va persa
+#[tauri::command]
+async fn discard_outbox_entry(id: i64) -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::ServiziModifica).await?;
+    get_offline_store()
+        .ok_or_else(|| ApiError::config("Copia locale offline non disponibile"))?
+        .discard(id)
+}
+
+// Voce in conflitto: la rinvia comunque, sovrascrivendo le modifiche altrui
+#[tauri::command]
+async fn force_outbox_entry(id: i64) -> Result<OutboxSyncReport, ApiError> {
+    richiedi_permesso(Capacita::ServiziModifica).await?;
+    get_offline_store()
+        .ok_or_else(|| ApiError::config("Copia locale offline non disponibile"))?
+        .force(id)?;
+    sync_offline_outbox().await
+}
+
+/// Rilegge le tabelle usate offline (servizi da inizio anno scorso) per
+/// tenere aggiornata la copia locale
+async fn refresh_offline_mirror(client: &SupabaseClient) -> Result<(), ApiError> {
+    let anno_scorso = Local::now().year() - 1;
+    client
+        .fetch_servizi(Query::new().gte("Prelievo_Data", format!("{}-01-01", anno_scorso)))
+        .await?;
+    client.fetch_tesserati(Query::new()).await?;
+    client.fetch_automezzi(Query::new()).await?;
+    client.fetch_tratte(Query::new()).await?;
+    client.fetch_impostazioni(Query::new()).await?;
+    client.fetch_dotazioni_mezzi(Query::new()).await?;
+    client.fetch_richiedenti(Query::new()).await?;
+    client.fetch_tipi_pagamento(Query::new()).await?;
+    client.fetch_motivazioni_trasporto(Query::new()).await?;
+    client.fetch_stati_del_servizio(Query::new()).await?;
+    client.fetch_tipologie_socio(Query::new()).await?;
+    println!("✓ Copia locale offline aggiornata");
+    Ok(())
+}
+
+/// In background: ogni 30 secondi controlla la connessione e rinvia la outbox;
+/// la copia locale viene riletta all'avvio e poi ogni 30 minuti.
+async fn offline_sync_loop() {
+    const INTERVALLO: std::time::Duration = std::time::Duration::from_secs(30);
+    const AGGIORNAMENTO_COPIA: std::time::Duration = std::time::Duration::from_secs(30 * 60);
+    let mut prossimo_aggiornamento = std::time::Instant::now();
+
+    loop {
+        let client = match ensure_supabase_client().await {
+            Ok(()) => get_supabase_client().lock().await.as_ref().cloned(),
+            Err(_) => None,
+        };
+
+        if let Some(client) = client.filter(|c| c.offline_store().is_some()) {
+            if client.probe_online().await {
+                let in_attesa = client
+                    .offline_store()
+                    .map(|s| s.pending_count())
+                    .unwrap_or(0);
+                if in_attesa > 0 {
+                    match client.replay_outbox().await {
+                        Ok(report) => println!(
+                            "🔄 Outbox: {} inviate, {} conflitti, {} errori, {} in attesa",
+                            report.inviate,
+                            report.conflitti.len(),
+                            report.errori.len(),
+                            report.in_attesa
+                        ),
+                        Err(e) => println!("⚠️ Rinvio outbox fallito: {}", e),
+                    }
+                }
+                if std::time::Instant::now() >= prossimo_aggiornamento {
+                    match refresh_offline_mirror(&client).await {
+                        Ok(()) => {
+                            prossimo_aggiornamento =
+                                std::time::Instant::now() + AGGIORNAMENTO_COPIA
+                        }
+                        Err(e) => println!("⚠️ Copia locale non aggiornata: {}", e),
+                    }
+                }
+            }
+        }
+
+        tokio::time::sleep(INTERVALLO).await;
+    }
+}
+
+/// Ascolta Supabase Realtime e rilancia le modifiche alle finestre.
+/// Si riconnette con attesa crescente; offline aspetta che la sonda ritrovi il server.
+async fn realtime_loop() {
+    const ATTESA_MINIMA: std::time::Duration = std::time::Duration::from_secs(2);
+    const ATTESA_MASSIMA: std::time::Duration = std::time::Duration::from_secs(60);
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<ModificaTabella>();
+    tauri::async_runtime::spawn(async move {
+        while let Some(modifica) = rx.recv().await {
+            aggiorna_cache_da_realtime(&modifica).await;
+            emetti_modifica(modifica);
+        }
+    });
+
+    let mut attesa = ATTESA_MINIMA;
+    let mut ripresa = false;
+    loop {
+        let client = match ensure_supabase_client().await {
+            Ok(()) => get_supabase_client().lock().await.as_ref().cloned(),
+            Err(_) => None,
+        };
+        let client = match client.filter(|c| !c.is_offline()) {
+            Some(c) => c,
+            None => {
+                tokio::time::sleep(ATTESA_MASSIMA).await;
+                continue;
+            }
+        };
+
+        let inizio = std::time::Instant::now();
+        match realtime::ascolta(&client, &tx, ripresa).await {
+            Ok(()) => {}
+            Err(e) => println!("⚠️ Realtime interrotto: {}", e),
+        }
+        ripresa = true;
+        // Una connessione rimasta su a lungo non conta come errore ripetuto
+        attesa = if inizio.elapsed() > ATTESA_MASSIMA {
+            ATTESA_MINIMA
+        } else {
+            (attesa * 2).min(ATTESA_MASSIMA)
+        };
+        tokio::time::sleep(attesa).await;
+    }
+}
+
+/// Tiene allineata la cache delta con le modifiche ricevute in tempo reale
+async fn aggiorna_cache_da_realtime(modifica: &ModificaTabella) {
+    let mut cache = delta_cache().lock().await;
+    let tabella = match cache.get_mut(&modifica.tabella) {
+        Some(t) => t,
+        None => return,
+    };
+    match (&modifica.riga, &modifica.chiave) {
+        (Some(riga), _) => tabella.merge_row(&modifica.tabella, riga.clone()),
+        (None, Some(chiave)) if modifica.tipo == "delete" => {
+            tabella.remove(chiave);
+        }
+        _ => {}
+    }
 }
 
 // Comando per caricare configurazione da file
 #[tauri::command]
-async fn load_config_file() -> Result<serde_json::Value, String> {
+async fn load_config_file() -> Result<serde_json::Value, ApiError> {
+    richiedi_permesso(Capacita::ImpostazioniModifica).await?;
     let config = load_app_config_from_file().await?;
+    // Le chiavi segrete restano nel backend: alla finestra arriva solo se ci sono
+    let mut supabase = serde_json::to_value(&config.supabase).unwrap_or_default();
+    if let Some(obj) = supabase.as_object_mut() {
+        obj.remove("secret_key");
+    }
+    let configurata = |v: Option<&String>| v.is_some_and(|s| !s.trim().is_empty());
     Ok(serde_json::json!({
         "sharepoint": {
             "site_url": config.sharepoint.site_url,
             "client_id": config.sharepoint.client_id,
             "tenant_id": config.sharepoint.tenant_id,
-            "client_secret": config.sharepoint.client_secret
+            "client_secret_configurato": configurata(config.sharepoint.client_secret.as_ref())
         },
-        "supabase": config.supabase,
+        "supabase": supabase,
+        "secret_key_configurata": configurata(config.supabase.as_ref().and_then(|s| s.secret_key.as_ref())),
         "github": config.github,
         "lists": config.lists
     }))
@@ -6175,7 +9620,8 @@ async fn load_config_file() -> Result<serde_json::Value, String> {
 
 // Comando per inizializzare client SharePoint da configurazione
 #[tauri::command]
-async fn init_sharepoint_from_config() -> Result<(), String> {
+async fn init_sharepoint_from_config() -> Result<(), ApiError> {
+    richiedi_permesso(Capacita::ImpostazioniModifica).await?;
     println!(
         "Tentativo di inizializzare SharePoint da config.json. Directory corrente: {:?}",
         std::env::current_dir()
@@ -6240,7 +9686,7 @@ async fn init_sharepoint_from_config() -> Result<(), String> {
 
 // Comando per inizializzare solo il client Supabase da config.json
 #[tauri::command]
-async fn init_supabase_from_config() -> Result<(), String> {
+async fn init_supabase_from_config() -> Result<(), ApiError> {
     let config = load_app_config_from_file().await?;
     setup_supabase_from_config(&config).await;
     Ok(())
@@ -6253,15 +9699,11 @@ async fn get_oauth_authorization_url(
     client_id: String,
     sharepoint_url: String,
     redirect_uri: String,
-) -> Result<String, String> {
-    use rand::Rng;
-    
+) -> Result<String, ApiError> {
+    richiedi_permesso(Capacita::ImpostazioniModifica).await?;
+
     // Genera uno state random per sicurezza
-    let state: String = rand::thread_rng()
-        .sample_iter(&rand::distributions::Alphanumeric)
-        .take(32)
-        .map(char::from)
-        .collect();
+    let state = supabase::codice_casuale(32);
     
     // Salva lo state
     {
@@ -6292,10 +9734,21 @@ async fn complete_oauth_authentication(
     code: String,
     tenant_id: String,
     client_id: String,
-    client_secret: String,
+    client_secret: Option<String>,
     sharepoint_url: String,
     redirect_uri: String,
-) -> Result<serde_json::Value, String> {
+) -> Result<serde_json::Value, ApiError> {
+    richiedi_permesso(Capacita::ImpostazioniModifica).await?;
+    // Senza secret dalla finestra vale quello di config.json (non inviato al frontend)
+    let client_secret = match client_secret.filter(|s| !s.trim().is_empty()) {
+        Some(s) => s,
+        None => load_app_config_from_file()
+            .await?
+            .sharepoint
+            .client_secret
+            .filter(|s| !s.trim().is_empty())
+            .ok_or_else(|| ApiError::config("Client Secret SharePoint non configurato"))?,
+    };
     let mut config = SharePointConfig {
         site_url: sharepoint_url.clone(),
         access_token: None,
@@ -6337,7 +9790,8 @@ async fn complete_oauth_authentication(
 
 fn main() {
     tauri::Builder::default()
-        .setup(|_| {
+        .setup(|app| {
+            let _ = APP_HANDLE.set(app.handle());
             tauri::async_runtime::block_on(async {
                 if let Ok(config) = load_app_config_from_file().await {
                     setup_supabase_from_config(&config).await;
@@ -6345,18 +9799,27 @@ fn main() {
                     println!("⚠️ Supabase: config.json non trovato all'avvio");
                 }
             });
+            tauri::async_runtime::spawn(offline_sync_loop());
+            tauri::async_runtime::spawn(realtime_loop());
+            tauri::async_runtime::spawn(auth_refresh_loop());
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
+            supabase_login,
+            supabase_logout,
+            get_auth_session,
             get_servizi_giorno,
             get_prossimi_servizi,
             get_servizi_inseriti_oggi,
             get_servizi_mezzo_nella_data,
             get_tessere_da_fare,
             get_all_tesserati,
+            get_elenco_operatori,
             get_socio_anagrafica,
             save_socio_anagrafica,
             get_next_idsocio,
+            reserve_idsocio,
+            release_idsocio,
             create_socio_anagrafica,
             save_tesseramento,
             save_socio_telefono,
@@ -6385,10 +9848,30 @@ fn main() {
             update_user_permissions,
             create_app_user,
             delete_app_user,
+            get_ruoli,
+            save_ruolo,
+            delete_ruolo,
+            get_storico_record,
+            get_ciclo_stati_servizio,
+            calcola_preventivo,
+            get_parametri_tariffa,
+            get_storico_tariffe,
+            crea_versione_tariffa,
+            get_regole_tariffa,
+            crea_serie_servizi,
+            get_serie_servizi,
+            update_servizio_serie,
+            annulla_serie_servizi,
+            save_regola_tariffa,
+            delete_regola_tariffa,
+            get_cestino_servizi,
+            ripristina_servizio,
+            svuota_cestino_servizi,
             get_all_stati_servizio,
             add_tipologia_socio,
             get_servizio_completo,
             get_all_servizi_completi,
+            get_servizi_completi_pagina,
             get_idsocio_con_servizi,
             get_operatori_con_servizi,
             get_motivazioni_servizi,
@@ -6406,13 +9889,25 @@ fn main() {
             create_servizio,
             delete_servizio,
             duplicate_servizio,
+            duplicate_servizio_multiplo,
+            crea_ritorno_servizio,
+            collega_servizi_viaggio,
+            scollega_servizio_viaggio,
+            get_viaggio_servizi,
+            sposta_tappe_viaggio,
+            annulla_tappe_viaggio,
             get_oauth_authorization_url,
             complete_oauth_authentication,
             load_config_file,
             init_sharepoint_from_config,
             init_supabase_from_config,
             test_supabase_connection,
-            check_authentication
+            check_authentication,
+            get_offline_status,
+            sync_offline_outbox,
+            discard_outbox_entry,
+            force_outbox_entry,
+            get_changes_since
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,206 @@
+// Oscuramento dei dati personali (GDPR) in base alle capacità di chi legge.
+// Senza "soci.dati_sensibili" il codice fiscale resta riconoscibile solo dalle
+// prime lettere (RSS***), della nascita resta l'anno, indirizzo e note spariscono;
+// senza "servizi.dati_sanitari" motivazione e note dei servizi non escono dal
+// backend; senza "incassi.vedi" restano nascosti pagamento, stato dell'incasso
+// e dati di bonifico e ricevuta. Si applica prima di restituire i dati alle finestre; al salvataggio i
+// campi oscurati vengono ripresi dal database, così "RSS***" non finisce mai su
+// Supabase.
+use crate::audit::VoceAudit;
+use crate::permessi::Capacita;
+use crate::{
+    ServizioCompleto, SocioAnagrafica, SocioAnagraficaCompleta, Tesserato, UserPermissionsRecord,
+};
+
+/// Segnaposto per i campi nascosti (vuoto resta vuoto)
+pub const OSCURATO: &str = "***";
+
+/// Cosa può vedere l'utente collegato
+#[derive(Debug, Clone, Copy)]
+pub struct Mascheramento {
+    pub dati_anagrafici: bool,
+    pub dati_sanitari: bool,
+    pub incassi: bool,
+}
+
+impl Mascheramento {
+    pub fn per_utente(perm: &UserPermissionsRecord) -> Self {
+        Self {
+            dati_anagrafici: Capacita::SociDatiSensibili.consentita(perm),
+            dati_sanitari: Capacita::ServiziDatiSanitari.consentita(perm),
+            incassi: Capacita::IncassiVedi.consentita(perm),
+        }
+    }
+
+    /// Nessun campo da oscurare
+    pub fn trasparente(self) -> bool {
+        self.dati_anagrafici && self.dati_sanitari && self.incassi
+    }
+}
+
+/// Struct restituite alle finestre che contengono dati personali
+pub trait Mascherabile {
+    fn maschera(&mut self, m: Mascheramento);
+}
+
+impl<T: Mascherabile> Mascherabile for Vec<T> {
+    fn maschera(&mut self, m: Mascheramento) {
+        if m.trasparente() {
+            return;
+        }
+        for item in self.iter_mut() {
+            item.maschera(m);
+        }
+    }
+}
+
+/// "RSSMRA80A01H501U" → "RSS***"
+pub fn parziale(valore: &str) -> String {
+    let valore = valore.trim();
+    if valore.is_empty() {
+        return String::new();
+    }
+    let inizio: String = valore.chars().take(3).collect();
+    format!("{}{}", inizio, OSCURATO)
+}
+
+/// Solo l'anno: "12/05/1948" → "**/**/1948", "1948-05-12" → "1948"
+fn solo_anno(data: &str) -> String {
+    let data = data.trim();
+    if data.is_empty() {
+        return String::new();
+    }
+    if let Some((_, anno)) = data.rsplit_once('/') {
+        if anno.len() == 4 {
+            return format!("**/**/{}", anno);
+        }
+    }
+    match data.get(..4) {
+        Some(anno) if anno.chars().all(|c| c.is_ascii_digit()) => anno.to_string(),
+        _ => OSCURATO.to_string(),
+    }
+}
+
+fn nascondi(campo: &mut String) {
+    if !campo.trim().is_empty() {
+        *campo = OSCURATO.to_string();
+    }
+}
+
+impl Mascherabile for SocioAnagrafica {
+    fn maschera(&mut self, m: Mascheramento) {
+        if m.dati_anagrafici {
+            return;
+        }
+        self.codicefiscale = parziale(&self.codicefiscale);
+        self.nascita_data = solo_anno(&self.nascita_data);
+        nascondi(&mut self.nascita_comune);
+        nascondi(&mut self.residenza_indirizzo);
+        nascondi(&mut self.residenza_civico);
+        nascondi(&mut self.residenza_cap);
+        nascondi(&mut self.notaaggiuntiva);
+    }
+}
+
+impl Mascherabile for SocioAnagraficaCompleta {
+    fn maschera(&mut self, m: Mascheramento) {
+        self.anagrafica.maschera(m);
+        if !m.dati_anagrafici {
+            for t in self.tesseramenti.iter_mut() {
+                nascondi(&mut t.note);
+            }
+        }
+    }
+}
+
+impl Mascherabile for Tesserato {
+    fn maschera(&mut self, m: Mascheramento) {
+        if m.dati_anagrafici {
+            return;
+        }
+        self.codicefiscale = parziale(&self.codicefiscale);
+        self.nascita_data = solo_anno(&self.nascita_data);
+        nascondi(&mut self.nascita_comune);
+        nascondi(&mut self.residenza_indirizzo);
+        nascondi(&mut self.residenza_civico);
+        nascondi(&mut self.residenza_cap);
+        nascondi(&mut self.notaaggiuntiva);
+    }
+}
+
+impl Mascherabile for ServizioCompleto {
+    fn maschera(&mut self, m: Mascheramento) {
+        if !m.dati_sanitari {
+            nascondi(&mut self.motivazione);
+            nascondi(&mut self.note_prelievo);
+            nascondi(&mut self.note_arrivo);
+            nascondi(&mut self.note_fine_servizio);
+        }
+        if !m.incassi {
+            nascondi(&mut self.pagamento);
+            nascondi(&mut self.stato_incasso);
+            nascondi(&mut self.tipo_pagamento);
+            nascondi(&mut self.data_bonifico);
+            nascondi(&mut self.data_ricevuta);
+            nascondi(&mut self.numero_ricevuta);
+        }
+    }
+}
+
+/// Colonne oscurate anche nello storico modifiche (nomi delle tabelle Supabase)
+fn colonna_riservata(entita: &str, colonna: &str, m: Mascheramento) -> bool {
+    let colonna = colonna.to_lowercase();
+    match entita {
+        "tesserati" if !m.dati_anagrafici => [
+            "codicefiscale",
+            "nascita",
+            "indirizzo",
+            "civico",
+            "cap",
+            "nota",
+        ]
+        .iter()
+        .any(|c| colonna.contains(c)),
+        "servizi" => {
+            (!m.dati_sanitari && (colonna.contains("motivazione") || colonna.contains("note")))
+                || (!m.incassi
+                    && ["pagamento", "incasso", "bonifico", "ricevuta", "donazione"]
+                        .iter()
+                        .any(|c| colonna.contains(c)))
+        }
+        _ => false,
+    }
+}
+
+impl Mascherabile for VoceAudit {
+    fn maschera(&mut self, m: Mascheramento) {
+        for campo in self.modifiche.iter_mut() {
+            if colonna_riservata(&self.entita, &campo.campo, m) {
+                for valore in [&mut campo.prima, &mut campo.dopo] {
+                    if !valore.is_null() {
+                        *valore = serde_json::json!(OSCURATO);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Prima di salvare: i campi che l'utente non può vedere tornano ai valori del
+/// database (altrimenti il segnaposto sovrascriverebbe il dato vero)
+pub fn ripristina_anagrafica(
+    anagrafica: &mut SocioAnagrafica,
+    originale: &SocioAnagrafica,
+    m: Mascheramento,
+) {
+    if m.dati_anagrafici {
+        return;
+    }
+    anagrafica.codicefiscale = originale.codicefiscale.clone();
+    anagrafica.nascita_data = originale.nascita_data.clone();
+    anagrafica.nascita_comune = originale.nascita_comune.clone();
+    anagrafica.residenza_indirizzo = originale.residenza_indirizzo.clone();
+    anagrafica.residenza_civico = originale.residenza_civico.clone();
+    anagrafica.residenza_cap = originale.residenza_cap.clone();
+    anagrafica.notaaggiuntiva = originale.notaaggiuntiva.clone();
+}
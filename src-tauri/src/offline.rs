@@ -0,0 +1,795 @@
+// Copia locale (SQLite) delle tabelle Supabase e coda delle scritture offline.
+// Quando Supabase non è raggiungibile le letture arrivano da `mirror` e le
+// modifiche finiscono in `outbox`, che viene rinviata in ordine al ritorno
+// della connessione (vedi SupabaseClient::replay_outbox).
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::error::ApiError;
+
+/// Tabelle copiate in locale. user_permissions resta solo online.
+const TABELLE_OFFLINE: &[&str] = &[
+    "servizi",
+    "tesserati",
+    "automezzi",
+    "tratte",
+    "tesseramenti",
+    "telefoni",
+    "email",
+    "impostazioni",
+    "dotazioni_mezzi",
+    "motivazioni_trasporto",
+    "motorizzazioni",
+    "richiedenti",
+    "stato_del_servizio",
+    "tipo_pagamenti",
+    "tipo_socio",
+    "tipologia_socio",
+];
+
+pub fn is_mirrored(table_type: &str) -> bool {
+    TABELLE_OFFLINE.contains(&table_type)
+}
+
+/// Colonne chiave candidate: vale la prima presente nella riga
+pub fn key_candidates(table_type: &str) -> &'static [&'static str] {
+    match table_type {
+        "servizi" => &["idservizio", "IdServizio", "Id_Servizio", "IDSERVIZIO", "id_servizio"],
+        "tesserati" => &["IdSocio"],
+        "automezzi" => &["IdAutomezzo", "ID_AUTOMEZZO", "id"],
+        "tratte" => &["IdTratta", "ID_TRATTA", "id"],
+        "impostazioni" => &["id", "Impostazione"],
+        "dotazioni_mezzi" => &["id", "Dotazione"],
+        _ => &["id"],
+    }
+}
+
+/// Colonna chiave presente nella riga (o la prima candidata se la riga non c'è)
+pub fn key_column(table_type: &str, row: Option<&Value>) -> &'static str {
+    let candidates = key_candidates(table_type);
+    row.and_then(|r| {
+        candidates
+            .iter()
+            .copied()
+            .find(|c| r.get(*c).map(|v| !v.is_null()).unwrap_or(false))
+    })
+    .unwrap_or(candidates[0])
+}
+
+/// Chiave della riga nella copia locale; senza colonna chiave si usa il JSON intero
+fn row_key(table_type: &str, row: &Value) -> String {
+    let column = key_column(table_type, Some(row));
+    match row.get(column) {
+        Some(v) if !v.is_null() => crate::supabase::json_to_string(v),
+        _ => row.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutboxOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl OutboxOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            OutboxOp::Insert => "insert",
+            OutboxOp::Update => "update",
+            OutboxOp::Delete => "delete",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "insert" => OutboxOp::Insert,
+            "delete" => OutboxOp::Delete,
+            _ => OutboxOp::Update,
+        }
+    }
+}
+
+/// Stato di una voce della outbox
+pub const STATO_IN_ATTESA: &str = "pending";
+pub const STATO_CONFLITTO: &str = "conflict";
+pub const STATO_ERRORE: &str = "error";
+
+/// Scrittura fatta offline, da rinviare a Supabase
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub created_at: String,
+    pub op: OutboxOp,
+    pub table_type: String,
+    pub key_column: String,
+    pub key_value: String,
+    pub body: serde_json::Map<String, Value>,
+    /// Riga com'era sul server quando è stata fatta la modifica (per i conflitti)
+    pub base: Option<Value>,
+    pub status: String,
+    pub error: Option<String>,
+}
+
+/// Voce non inviata: conflitto con una modifica fatta da altri o errore del server
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxProblema {
+    pub voce: i64,
+    pub tabella: String,
+    pub chiave: String,
+    pub operazione: OutboxOp,
+    pub campi: Vec<String>,
+    pub messaggio: String,
+}
+
+/// Servizio creato offline con un ID provvisorio, registrato con un altro ID
+#[derive(Debug, Clone, Serialize)]
+pub struct IdRiassegnato {
+    pub tabella: String,
+    pub locale: String,
+    pub definitivo: String,
+}
+
+/// Esito di un rinvio della outbox
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OutboxSyncReport {
+    pub inviate: usize,
+    pub conflitti: Vec<OutboxProblema>,
+    pub errori: Vec<OutboxProblema>,
+    pub id_riassegnati: Vec<IdRiassegnato>,
+    pub in_attesa: usize,
+    /// true se il rinvio si è fermato perché Supabase è ancora irraggiungibile
+    pub offline: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OfflineStatus {
+    pub percorso: String,
+    pub in_attesa: usize,
+    pub conflitti: usize,
+    pub errori: usize,
+    pub voci: Vec<OutboxEntry>,
+}
+
+fn db_err(e: rusqlite::Error) -> ApiError {
+    ApiError::from(format!("Errore database locale: {}", e))
+}
+
+fn now_iso() -> String {
+    chrono::Local::now().format("%Y-%m-%dT%H:%M:%S").to_string()
+}
+
+pub struct OfflineStore {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+    pub replay_lock: tokio::sync::Mutex<()>,
+}
+
+impl OfflineStore {
+    pub fn open(path: &Path) -> Result<Self, ApiError> {
+        let conn = Connection::open(path).map_err(db_err)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS mirror (
+                 table_type TEXT NOT NULL,
+                 pk TEXT NOT NULL,
+                 data TEXT NOT NULL,
+                 synced_at TEXT NOT NULL,
+                 dirty INTEGER NOT NULL DEFAULT 0,
+                 deleted INTEGER NOT NULL DEFAULT 0,
+                 PRIMARY KEY (table_type, pk)
+             );
+             CREATE TABLE IF NOT EXISTS outbox (
+                 id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 created_at TEXT NOT NULL,
+                 op TEXT NOT NULL,
+                 table_type TEXT NOT NULL,
+                 key_column TEXT NOT NULL,
+                 key_value TEXT NOT NULL,
+                 body TEXT NOT NULL,
+                 base TEXT,
+                 status TEXT NOT NULL DEFAULT 'pending',
+                 error TEXT
+             );",
+        )
+        .map_err(db_err)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            conn: Mutex::new(conn),
+            replay_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Apre (o crea) offline.sqlite nella cartella dati dell'utente
+    pub fn open_default() -> Option<Self> {
+        let dir = tauri::api::path::data_dir()?.join("com.auser.gestione-operativa");
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            println!("⚠️ Cartella copia locale non creata {:?}: {}", dir, e);
+            return None;
+        }
+        let path = dir.join("offline.sqlite");
+        match Self::open(&path) {
+            Ok(store) => {
+                println!("✓ Copia locale offline: {:?}", path);
+                Some(store)
+            }
+            Err(e) => {
+                println!("⚠️ Copia locale offline non disponibile: {}", e);
+                None
+            }
+        }
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    // --- Copia delle tabelle ---
+
+    /// Aggiorna la copia con le righe appena lette dal server. Le righe locali
+    /// per cui `sostituisce` è vero e che il server non ha più restituito
+    /// vengono tolte; le righe con modifiche offline non ancora inviate restano.
+    pub fn store_rows(
+        &self,
+        table_type: &str,
+        sostituisce: &dyn Fn(&Value) -> bool,
+        rows: &[Value],
+    ) -> Result<(), ApiError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_err)?;
+        {
+            let mut stmt = tx
+                .prepare("SELECT pk, data FROM mirror WHERE table_type = ?1 AND dirty = 0")
+                .map_err(db_err)?;
+            let esistenti: Vec<(String, String)> = stmt
+                .query_map(params![table_type], |r| Ok((r.get(0)?, r.get(1)?)))
+                .map_err(db_err)?
+                .filter_map(Result::ok)
+                .collect();
+            for (pk, data) in esistenti {
+                let row: Value = serde_json::from_str(&data).unwrap_or(Value::Null);
+                if sostituisce(&row) {
+                    tx.execute(
+                        "DELETE FROM mirror WHERE table_type = ?1 AND pk = ?2",
+                        params![table_type, pk],
+                    )
+                    .map_err(db_err)?;
+                }
+            }
+
+            let synced_at = now_iso();
+            let mut insert = tx
+                .prepare(
+                    "INSERT INTO mirror (table_type, pk, data, synced_at) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT(table_type, pk) DO UPDATE
+                     SET data = excluded.data, synced_at = excluded.synced_at
+                     WHERE mirror.dirty = 0",
+                )
+                .map_err(db_err)?;
+            for row in rows {
+                insert
+                    .execute(params![
+                        table_type,
+                        row_key(table_type, row),
+                        row.to_string(),
+                        synced_at
+                    ])
+                    .map_err(db_err)?;
+            }
+        }
+        tx.commit().map_err(db_err)
+    }
+
+    /// Tutte le righe visibili di una tabella (escluse quelle eliminate offline)
+    pub fn read_rows(&self, table_type: &str) -> Result<Vec<Value>, ApiError> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare("SELECT data FROM mirror WHERE table_type = ?1 AND deleted = 0 ORDER BY rowid")
+            .map_err(db_err)?;
+        let rows = stmt
+            .query_map(params![table_type], |r| r.get::<_, String>(0))
+            .map_err(db_err)?
+            .filter_map(Result::ok)
+            .filter_map(|data| serde_json::from_str(&data).ok())
+            .collect();
+        Ok(rows)
+    }
+
+    pub fn get_row(&self, table_type: &str, pk: &str) -> Result<Option<Value>, ApiError> {
+        let conn = self.conn();
+        let data: Option<String> = conn
+            .query_row(
+                "SELECT data FROM mirror WHERE table_type = ?1 AND pk = ?2 AND deleted = 0",
+                params![table_type, pk],
+                |r| r.get(0),
+            )
+            .optional()
+            .map_err(db_err)?;
+        Ok(data.and_then(|d| serde_json::from_str(&d).ok()))
+    }
+
+    /// Massima chiave numerica in copia (anche righe create offline)
+    pub fn max_numeric_key(&self, table_type: &str) -> Result<u64, ApiError> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare("SELECT pk FROM mirror WHERE table_type = ?1")
+            .map_err(db_err)?;
+        let max = stmt
+            .query_map(params![table_type], |r| r.get::<_, String>(0))
+            .map_err(db_err)?
+            .filter_map(Result::ok)
+            .filter_map(|pk| pk.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0);
+        Ok(max)
+    }
+
+    /// Applica in copia una modifica fatta offline (la riga resta "sporca"
+    /// finché la outbox non è stata inviata)
+    fn apply_local(
+        conn: &Connection,
+        op: OutboxOp,
+        table_type: &str,
+        key_column: &str,
+        key_value: &str,
+        body: &serde_json::Map<String, Value>,
+    ) -> Result<(), rusqlite::Error> {
+        if op == OutboxOp::Delete {
+            conn.execute(
+                "UPDATE mirror SET dirty = 1, deleted = 1 WHERE table_type = ?1 AND pk = ?2",
+                params![table_type, key_value],
+            )?;
+            return Ok(());
+        }
+
+        let attuale: Option<String> = conn
+            .query_row(
+                "SELECT data FROM mirror WHERE table_type = ?1 AND pk = ?2",
+                params![table_type, key_value],
+                |r| r.get(0),
+            )
+            .optional()?;
+        let mut row = match (op, attuale) {
+            (OutboxOp::Update, Some(data)) => serde_json::from_str::<Value>(&data)
+                .ok()
+                .and_then(|v| v.as_object().cloned())
+                .unwrap_or_default(),
+            _ => serde_json::Map::new(),
+        };
+        row.entry(key_column.to_string())
+            .or_insert_with(|| Value::String(key_value.to_string()));
+        for (k, v) in body {
+            row.insert(k.clone(), v.clone());
+        }
+
+        conn.execute(
+            "INSERT INTO mirror (table_type, pk, data, synced_at, dirty, deleted)
+             VALUES (?1, ?2, ?3, ?4, 1, 0)
+             ON CONFLICT(table_type, pk) DO UPDATE
+             SET data = excluded.data, dirty = 1, deleted = 0",
+            params![table_type, key_value, Value::Object(row).to_string(), now_iso()],
+        )?;
+        Ok(())
+    }
+
+    // --- Outbox ---
+
+    /// Accoda una scrittura e la applica subito alla copia locale.
+    /// Restituisce il numero della voce.
+    pub fn enqueue(
+        &self,
+        op: OutboxOp,
+        table_type: &str,
+        key_column: &str,
+        key_value: &str,
+        body: &serde_json::Map<String, Value>,
+    ) -> Result<i64, ApiError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_err)?;
+
+        // La base per i conflitti è la riga com'era sul server: se ci sono già
+        // voci aperte sulla stessa riga vale la loro, altrimenti la copia attuale.
+        let base_precedente: Option<Option<String>> = tx
+            .query_row(
+                "SELECT base FROM outbox WHERE table_type = ?1 AND key_value = ?2
+                 ORDER BY id LIMIT 1",
+                params![table_type, key_value],
+                |r| r.get(0),
+            )
+            .optional()
+            .map_err(db_err)?;
+        let base: Option<String> = match (op, base_precedente) {
+            (OutboxOp::Insert, _) => None,
+            (_, Some(base)) => base,
+            (_, None) => tx
+                .query_row(
+                    "SELECT data FROM mirror WHERE table_type = ?1 AND pk = ?2 AND dirty = 0",
+                    params![table_type, key_value],
+                    |r| r.get(0),
+                )
+                .optional()
+                .map_err(db_err)?,
+        };
+
+        tx.execute(
+            "INSERT INTO outbox (created_at, op, table_type, key_column, key_value, body, base)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                now_iso(),
+                op.as_str(),
+                table_type,
+                key_column,
+                key_value,
+                Value::Object(body.clone()).to_string(),
+                base
+            ],
+        )
+        .map_err(db_err)?;
+        let id = tx.last_insert_rowid();
+        Self::apply_local(&tx, op, table_type, key_column, key_value, body)
+            .map_err(db_err)?;
+        tx.commit().map_err(db_err)?;
+        Ok(id)
+    }
+
+    fn entries_where(&self, condizione: &str) -> Result<Vec<OutboxEntry>, ApiError> {
+        let conn = self.conn();
+        let sql = format!(
+            "SELECT id, created_at, op, table_type, key_column, key_value, body, base, status, error
+             FROM outbox {} ORDER BY id",
+            condizione
+        );
+        let mut stmt = conn.prepare(&sql).map_err(db_err)?;
+        let entries = stmt
+            .query_map([], |r| {
+                let body: String = r.get(6)?;
+                let base: Option<String> = r.get(7)?;
+                let op: String = r.get(2)?;
+                Ok(OutboxEntry {
+                    id: r.get(0)?,
+                    created_at: r.get(1)?,
+                    op: OutboxOp::parse(&op),
+                    table_type: r.get(3)?,
+                    key_column: r.get(4)?,
+                    key_value: r.get(5)?,
+                    body: serde_json::from_str::<Value>(&body)
+                        .ok()
+                        .and_then(|v| v.as_object().cloned())
+                        .unwrap_or_default(),
+                    base: base.and_then(|b| serde_json::from_str(&b).ok()),
+                    status: r.get(8)?,
+                    error: r.get(9)?,
+                })
+            })
+            .map_err(db_err)?
+            .filter_map(Result::ok)
+            .collect();
+        Ok(entries)
+    }
+
+    /// Tutte le voci della outbox, nell'ordine in cui sono state fatte
+    pub fn entries(&self) -> Result<Vec<OutboxEntry>, ApiError> {
+        self.entries_where("")
+    }
+
+    pub fn entry(&self, id: i64) -> Result<Option<OutboxEntry>, ApiError> {
+        Ok(self
+            .entries_where(&format!("WHERE id = {}", id))?
+            .into_iter()
+            .next())
+    }
+
+    pub fn pending(&self) -> Result<Vec<OutboxEntry>, ApiError> {
+        self.entries_where("WHERE status = 'pending'")
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.conn()
+            .query_row(
+                "SELECT COUNT(*) FROM outbox WHERE status = 'pending'",
+                [],
+                |r| r.get::<_, i64>(0),
+            )
+            .map(|n| n as usize)
+            .unwrap_or(0)
+    }
+
+    /// Voce precedente ancora bloccata (conflitto/errore) sulla stessa riga
+    pub fn blocked_by(&self, entry: &OutboxEntry) -> Result<Option<i64>, ApiError> {
+        self.conn()
+            .query_row(
+                "SELECT id FROM outbox WHERE table_type = ?1 AND key_value = ?2
+                 AND id < ?3 AND status <> 'pending' ORDER BY id LIMIT 1",
+                params![entry.table_type, entry.key_value, entry.id],
+                |r| r.get(0),
+            )
+            .optional()
+            .map_err(db_err)
+    }
+
+    pub fn set_status(&self, id: i64, status: &str, error: Option<&str>) -> Result<(), ApiError> {
+        self.conn()
+            .execute(
+                "UPDATE outbox SET status = ?2, error = ?3 WHERE id = ?1",
+                params![id, status, error],
+            )
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    /// Voce inviata: la toglie dalla coda, aggiorna la base delle voci successive
+    /// sulla stessa riga e, se non ne restano, riallinea la copia locale.
+    pub fn complete(&self, entry: &OutboxEntry) -> Result<(), ApiError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_err)?;
+        tx.execute("DELETE FROM outbox WHERE id = ?1", params![entry.id])
+            .map_err(db_err)?;
+
+        let successive: Vec<(i64, Option<String>)> = {
+            let mut stmt = tx
+                .prepare("SELECT id, base FROM outbox WHERE table_type = ?1 AND key_value = ?2")
+                .map_err(db_err)?;
+            let rows = stmt
+                .query_map(params![entry.table_type, entry.key_value], |r| {
+                    Ok((r.get(0)?, r.get(1)?))
+                })
+                .map_err(db_err)?
+                .filter_map(Result::ok)
+                .collect();
+            rows
+        };
+
+        if successive.is_empty() {
+            if entry.op == OutboxOp::Delete {
+                tx.execute(
+                    "DELETE FROM mirror WHERE table_type = ?1 AND pk = ?2",
+                    params![entry.table_type, entry.key_value],
+                )
+                .map_err(db_err)?;
+            } else {
+                tx.execute(
+                    "UPDATE mirror SET dirty = 0, synced_at = ?3 WHERE table_type = ?1 AND pk = ?2",
+                    params![entry.table_type, entry.key_value, now_iso()],
+                )
+                .map_err(db_err)?;
+            }
+        } else {
+            // Ora sul server ci sono anche i campi appena inviati
+            for (id, base) in successive {
+                let mut nuova = base
+                    .and_then(|b| serde_json::from_str::<Value>(&b).ok())
+                    .and_then(|v| v.as_object().cloned())
+                    .unwrap_or_default();
+                for (k, v) in &entry.body {
+                    nuova.insert(k.clone(), v.clone());
+                }
+                tx.execute(
+                    "UPDATE outbox SET base = ?2 WHERE id = ?1",
+                    params![id, Value::Object(nuova).to_string()],
+                )
+                .map_err(db_err)?;
+            }
+        }
+        tx.commit().map_err(db_err)
+    }
+
+    /// Cambia la chiave di una riga creata offline (ID provvisorio → definitivo)
+    /// nella copia locale e nelle voci successive della coda.
+    pub fn rewrite_key(
+        &self,
+        table_type: &str,
+        key_column: &str,
+        old: &str,
+        new: &str,
+    ) -> Result<(), ApiError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_err)?;
+        let data: Option<String> = tx
+            .query_row(
+                "SELECT data FROM mirror WHERE table_type = ?1 AND pk = ?2",
+                params![table_type, old],
+                |r| r.get(0),
+            )
+            .optional()
+            .map_err(db_err)?;
+        if let Some(data) = data {
+            let mut row: Value = serde_json::from_str(&data).unwrap_or(Value::Null);
+            if let Some(obj) = row.as_object_mut() {
+                let valore = new
+                    .parse::<u64>()
+                    .map(Value::from)
+                    .unwrap_or_else(|_| Value::String(new.to_string()));
+                obj.insert(key_column.to_string(), valore);
+            }
+            tx.execute(
+                "DELETE FROM mirror WHERE table_type = ?1 AND pk = ?2",
+                params![table_type, new],
+            )
+            .map_err(db_err)?;
+            tx.execute(
+                "UPDATE mirror SET pk = ?3, data = ?4 WHERE table_type = ?1 AND pk = ?2",
+                params![table_type, old, new, row.to_string()],
+            )
+            .map_err(db_err)?;
+        }
+        tx.execute(
+            "UPDATE outbox SET key_value = ?3 WHERE table_type = ?1 AND key_value = ?2",
+            params![table_type, old, new],
+        )
+        .map_err(db_err)?;
+
+        // Voci del registro modifiche in coda che puntano ancora all'ID provvisorio
+        let voci_audit: Vec<(i64, String)> = {
+            let mut stmt = tx
+                .prepare("SELECT id, body FROM outbox WHERE table_type = 'audit'")
+                .map_err(db_err)?;
+            let voci = stmt
+                .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+                .map_err(db_err)?
+                .filter_map(Result::ok)
+                .collect();
+            voci
+        };
+        for (id, body) in voci_audit {
+            let mut body: Value = match serde_json::from_str(&body) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            let testo = |k: &str| body.get(k).and_then(Value::as_str).unwrap_or_default();
+            if testo("entita") != table_type || testo("record_id") != old {
+                continue;
+            }
+            body["record_id"] = Value::String(new.to_string());
+            tx.execute(
+                "UPDATE outbox SET body = ?2 WHERE id = ?1",
+                params![id, body.to_string()],
+            )
+            .map_err(db_err)?;
+        }
+        tx.commit().map_err(db_err)
+    }
+
+    /// Scarta una voce: la modifica offline va persa e la riga torna com'era sul server
+    pub fn discard(&self, id: i64) -> Result<(), ApiError> {
+        let entry = self
+            .entry(id)?
+            .ok_or_else(|| ApiError::not_found(format!("Voce outbox {} non trovata", id)))?;
+
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_err)?;
+        tx.execute("DELETE FROM outbox WHERE id = ?1", params![id])
+            .map_err(db_err)?;
+        let restanti: i64 = tx
+            .query_row(
+                "SELECT COUNT(*) FROM outbox WHERE table_type = ?1 AND key_value = ?2",
+                params![entry.table_type, entry.key_value],
+                |r| r.get(0),
+            )
+            .map_err(db_err)?;
+        if restanti == 0 {
+            match (entry.op, &entry.base) {
+                (OutboxOp::Insert, _) => {
+                    tx.execute(
+                        "DELETE FROM mirror WHERE table_type = ?1 AND pk = ?2",
+                        params![entry.table_type, entry.key_value],
+                    )
+                    .map_err(db_err)?;
+                }
+                (_, Some(base)) => {
+                    tx.execute(
+                        "UPDATE mirror SET data = ?3, dirty = 0, deleted = 0
+                         WHERE table_type = ?1 AND pk = ?2",
+                        params![entry.table_type, entry.key_value, base.to_string()],
+                    )
+                    .map_err(db_err)?;
+                }
+                (_, None) => {
+                    tx.execute(
+                        "UPDATE mirror SET dirty = 0, deleted = 0 WHERE table_type = ?1 AND pk = ?2",
+                        params![entry.table_type, entry.key_value],
+                    )
+                    .map_err(db_err)?;
+                }
+            }
+        }
+        tx.commit().map_err(db_err)
+    }
+
+    /// Rimette in coda una voce in conflitto ignorando le modifiche altrui
+    pub fn force(&self, id: i64) -> Result<(), ApiError> {
+        let n = self
+            .conn()
+            .execute(
+                "UPDATE outbox SET base = NULL, status = 'pending', error = NULL WHERE id = ?1",
+                params![id],
+            )
+            .map_err(db_err)?;
+        if n == 0 {
+            return Err(ApiError::not_found(format!("Voce outbox {} non trovata", id)));
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> Result<OfflineStatus, ApiError> {
+        let voci = self.entries()?;
+        let conta = |s: &str| voci.iter().filter(|v| v.status == s).count();
+        Ok(OfflineStatus {
+            percorso: self.path.display().to_string(),
+            in_attesa: conta(STATO_IN_ATTESA),
+            conflitti: conta(STATO_CONFLITTO),
+            errori: conta(STATO_ERRORE),
+            voci,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_temporaneo(nome: &str) -> OfflineStore {
+        let path = std::env::temp_dir().join(format!(
+            "auser-offline-{}-{}.sqlite",
+            nome,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        OfflineStore::open(&path).unwrap()
+    }
+
+    fn corpo(valore: Value) -> serde_json::Map<String, Value> {
+        valore.as_object().cloned().unwrap()
+    }
+
+    #[test]
+    fn rewrite_key_aggiorna_le_voci_audit_del_servizio() {
+        let store = store_temporaneo("rewrite-audit");
+        store
+            .enqueue(
+                OutboxOp::Insert,
+                "servizi",
+                "idservizio",
+                "1001",
+                &corpo(serde_json::json!({ "idservizio": 1001 })),
+            )
+            .unwrap();
+        let audit = |chiave: &str, entita: &str, record_id: &str| {
+            store
+                .enqueue(
+                    OutboxOp::Insert,
+                    "audit",
+                    "id",
+                    chiave,
+                    &corpo(serde_json::json!({ "chiave": chiave, "entita": entita, "record_id": record_id })),
+                )
+                .unwrap();
+        };
+        audit("a1", "servizi", "1001");
+        audit("a2", "tesserati", "1001");
+        audit("a3", "servizi", "1002");
+
+        store
+            .rewrite_key("servizi", "idservizio", "1001", "1050")
+            .unwrap();
+
+        let voci = store.entries().unwrap();
+        let servizio = voci.iter().find(|v| v.table_type == "servizi").unwrap();
+        assert_eq!(servizio.key_value, "1050");
+        let record_id = |chiave: &str| {
+            voci.iter()
+                .find(|v| v.key_value == chiave)
+                .and_then(|v| v.body.get("record_id"))
+                .and_then(Value::as_str)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(record_id("a1"), "1050");
+        // Stesso ID ma altra entità, o altro servizio: invariati
+        assert_eq!(record_id("a2"), "1001");
+        assert_eq!(record_id("a3"), "1002");
+    }
+}
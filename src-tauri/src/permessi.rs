@@ -0,0 +1,415 @@
+// Autorizzazione lato backend: ogni comando Tauri dichiara la capacità che gli
+// serve e viene verificato contro i permessi (user_permissions) dell'utente della
+// sessione Supabase Auth, prima di toccare il database. Nascondere le voci della
+// sidebar (sidebar-permessi.js) resta solo una comodità dell'interfaccia.
+//
+// Le capacità arrivano dal ruolo assegnato all'utente (colonna Ruolo), con
+// eventuali eccezioni per utente (colonna CapacitaExtra: {"incassi.vedi": true}).
+// I ruoli predefiniti sono modelli: una riga con lo stesso codice in Ruoli_supa
+// li sostituisce, e se ne possono creare di nuovi. Gli utenti senza ruolo
+// (record precedenti) ricavano le capacità da Programma + voci sidebar.
+//
+// "servizi.solo_propri" è una restrizione, non un permesso: l'utente collegato a
+// un operatore (colonna IdSocio) vede solo i servizi in cui è operatore o
+// secondo operatore, e i contatti dei soci solo per i propri trasporti.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::UserPermissionsRecord;
+
+/// Ruolo con tutte le capacità, sempre (non modificabile)
+pub const RUOLO_AMMINISTRATORE: &str = "amministratore";
+
+/// Voci che per default sono spente ai non admin (come defaultSidebarMenu in JS)
+const VOCI_SPENTE_DI_DEFAULT: [&str; 3] =
+    ["riepilogo_incassi", "elenco_operatori", "riepilogo_pagamenti"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capacita {
+    ServiziLeggi,
+    ServiziCrea,
+    ServiziModifica,
+    ServiziElimina,
+    ServiziDatiSanitari,
+    SociLeggi,
+    SociCrea,
+    SociModifica,
+    SociElimina,
+    SociDatiSensibili,
+    MezziLeggi,
+    MezziCrea,
+    MezziModifica,
+    TratteLeggi,
+    TratteModifica,
+    IncassiVedi,
+    OperatoriVedi,
+    Esporta,
+    ImpostazioniModifica,
+    UtentiGestisci,
+    ServiziSoloPropri,
+}
+
+pub const TUTTE_LE_CAPACITA: [Capacita; 21] = [
+    Capacita::ServiziLeggi,
+    Capacita::ServiziCrea,
+    Capacita::ServiziModifica,
+    Capacita::ServiziElimina,
+    Capacita::ServiziDatiSanitari,
+    Capacita::SociLeggi,
+    Capacita::SociCrea,
+    Capacita::SociModifica,
+    Capacita::SociElimina,
+    Capacita::SociDatiSensibili,
+    Capacita::MezziLeggi,
+    Capacita::MezziCrea,
+    Capacita::MezziModifica,
+    Capacita::TratteLeggi,
+    Capacita::TratteModifica,
+    Capacita::IncassiVedi,
+    Capacita::OperatoriVedi,
+    Capacita::Esporta,
+    Capacita::ImpostazioniModifica,
+    Capacita::UtentiGestisci,
+    Capacita::ServiziSoloPropri,
+];
+
+impl Capacita {
+    /// Codice salvato su Supabase e usato dal frontend
+    pub fn codice(self) -> &'static str {
+        match self {
+            Capacita::ServiziLeggi => "servizi.leggi",
+            Capacita::ServiziCrea => "servizi.crea",
+            Capacita::ServiziModifica => "servizi.modifica",
+            Capacita::ServiziElimina => "servizi.elimina",
+            Capacita::ServiziDatiSanitari => "servizi.dati_sanitari",
+            Capacita::SociLeggi => "soci.leggi",
+            Capacita::SociCrea => "soci.crea",
+            Capacita::SociModifica => "soci.modifica",
+            Capacita::SociElimina => "soci.elimina",
+            Capacita::SociDatiSensibili => "soci.dati_sensibili",
+            Capacita::MezziLeggi => "mezzi.leggi",
+            Capacita::MezziCrea => "mezzi.crea",
+            Capacita::MezziModifica => "mezzi.modifica",
+            Capacita::TratteLeggi => "tratte.leggi",
+            Capacita::TratteModifica => "tratte.modifica",
+            Capacita::IncassiVedi => "incassi.vedi",
+            Capacita::OperatoriVedi => "operatori.vedi",
+            Capacita::Esporta => "esporta",
+            Capacita::ImpostazioniModifica => "impostazioni.modifica",
+            Capacita::UtentiGestisci => "utenti.gestisci",
+            Capacita::ServiziSoloPropri => "servizi.solo_propri",
+        }
+    }
+
+    pub fn descrizione(self) -> &'static str {
+        match self {
+            Capacita::ServiziLeggi => "Vedere i servizi",
+            Capacita::ServiziCrea => "Creare servizi",
+            Capacita::ServiziModifica => "Modificare e completare servizi",
+            Capacita::ServiziElimina => "Eliminare servizi",
+            Capacita::ServiziDatiSanitari => "Vedere motivazioni e note dei servizi",
+            Capacita::SociLeggi => "Vedere i soci",
+            Capacita::SociCrea => "Creare soci",
+            Capacita::SociModifica => "Modificare soci e tesseramenti",
+            Capacita::SociElimina => "Eliminare soci",
+            Capacita::SociDatiSensibili => "Vedere codice fiscale, nascita, indirizzo e note dei soci",
+            Capacita::MezziLeggi => "Vedere gli automezzi",
+            Capacita::MezziCrea => "Creare automezzi",
+            Capacita::MezziModifica => "Modificare automezzi",
+            Capacita::TratteLeggi => "Vedere le tratte",
+            Capacita::TratteModifica => "Modificare le tratte",
+            Capacita::IncassiVedi => "Vedere incassi e pagamenti",
+            Capacita::OperatoriVedi => "Vedere l'elenco operatori",
+            Capacita::Esporta => "Esportare e stampare elenchi",
+            Capacita::ImpostazioniModifica => "Modificare impostazioni e tabelle",
+            Capacita::UtentiGestisci => "Gestire utenti e ruoli",
+            Capacita::ServiziSoloPropri => "Solo i propri servizi (come operatore)",
+        }
+    }
+
+    pub fn da_codice(codice: &str) -> Option<Capacita> {
+        TUTTE_LE_CAPACITA
+            .iter()
+            .copied()
+            .find(|c| c.codice() == codice.trim())
+    }
+
+    pub fn consentita(self, perm: &UserPermissionsRecord) -> bool {
+        perm.is_admin || perm.capacita.iter().any(|c| c == self.codice())
+    }
+
+    /// Restrizioni: tolgono visibilità invece di darla, quindi mai agli admin
+    pub fn restrittiva(self) -> bool {
+        matches!(self, Capacita::ServiziSoloPropri)
+    }
+}
+
+/// L'utente vede solo i propri servizi (restrizione attiva, non admin)
+pub fn solo_propri(perm: &UserPermissionsRecord) -> bool {
+    !perm.is_admin
+        && perm
+            .capacita
+            .iter()
+            .any(|c| c == Capacita::ServiziSoloPropri.codice())
+}
+
+/// Tutte le capacità che danno accesso (quelle del ruolo amministratore)
+fn capacita_complete() -> Vec<Capacita> {
+    TUTTE_LE_CAPACITA
+        .iter()
+        .copied()
+        .filter(|c| !c.restrittiva())
+        .collect()
+}
+
+/// Capacità come la vede la pagina Gestione utenti
+#[derive(Debug, Clone, Serialize)]
+pub struct CapacitaInfo {
+    pub codice: &'static str,
+    pub descrizione: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ruolo {
+    pub codice: String,
+    pub nome: String,
+    #[serde(default)]
+    pub capacita: Vec<String>,
+    /// true se è un modello predefinito (anche se ridefinito in Ruoli_supa)
+    #[serde(default)]
+    pub predefinito: bool,
+}
+
+fn codici(capacita: &[Capacita]) -> Vec<String> {
+    capacita.iter().map(|c| c.codice().to_string()).collect()
+}
+
+/// Modelli di ruolo forniti con l'app
+pub fn ruoli_predefiniti() -> Vec<Ruolo> {
+    use Capacita::*;
+    let ruolo = |codice: &str, nome: &str, capacita: &[Capacita]| Ruolo {
+        codice: codice.to_string(),
+        nome: nome.to_string(),
+        capacita: codici(capacita),
+        predefinito: true,
+    };
+    vec![
+        ruolo(
+            "operatore",
+            "Operatore / autista",
+            &[
+                ServiziLeggi,
+                ServiziModifica,
+                ServiziDatiSanitari,
+                ServiziSoloPropri,
+                SociLeggi,
+                MezziLeggi,
+                TratteLeggi,
+            ],
+        ),
+        ruolo(
+            "centralinista",
+            "Centralinista",
+            &[
+                ServiziLeggi,
+                ServiziCrea,
+                ServiziModifica,
+                ServiziDatiSanitari,
+                SociLeggi,
+                SociCrea,
+                SociModifica,
+                MezziLeggi,
+                TratteLeggi,
+            ],
+        ),
+        ruolo(
+            "segreteria",
+            "Segreteria",
+            &[
+                ServiziLeggi,
+                ServiziCrea,
+                ServiziModifica,
+                ServiziElimina,
+                ServiziDatiSanitari,
+                SociLeggi,
+                SociCrea,
+                SociModifica,
+                SociElimina,
+                SociDatiSensibili,
+                MezziLeggi,
+                MezziCrea,
+                MezziModifica,
+                TratteLeggi,
+                TratteModifica,
+                OperatoriVedi,
+                Esporta,
+            ],
+        ),
+        ruolo(
+            "tesoriere",
+            "Tesoriere",
+            &[
+                ServiziLeggi,
+                ServiziModifica,
+                SociLeggi,
+                SociModifica,
+                SociDatiSensibili,
+                MezziLeggi,
+                TratteLeggi,
+                IncassiVedi,
+                OperatoriVedi,
+                Esporta,
+            ],
+        ),
+        ruolo(RUOLO_AMMINISTRATORE, "Amministratore", &capacita_complete()),
+    ]
+}
+
+/// Ruoli predefiniti più le righe di Ruoli_supa (stesso codice = sostituisce il modello)
+pub fn unisci_ruoli(righe: &[Value]) -> Vec<Ruolo> {
+    let mut ruoli = ruoli_predefiniti();
+    for riga in righe {
+        let codice = riga
+            .get("codice")
+            .and_then(|v| v.as_str())
+            .map(|s| s.trim().to_lowercase())
+            .unwrap_or_default();
+        if codice.is_empty() || codice == RUOLO_AMMINISTRATORE {
+            continue;
+        }
+        let nome = riga
+            .get("nome")
+            .and_then(|v| v.as_str())
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| codice.clone());
+        let capacita = normalizza_capacita(riga.get("capacita").unwrap_or(&Value::Null));
+        match ruoli.iter_mut().find(|r| r.codice == codice) {
+            Some(r) => {
+                r.nome = nome;
+                r.capacita = capacita;
+            }
+            None => ruoli.push(Ruolo {
+                codice,
+                nome,
+                capacita,
+                predefinito: false,
+            }),
+        }
+    }
+    ruoli
+}
+
+/// Lista di codici validi da un array JSON (o dal suo testo), in ordine di catalogo
+pub fn normalizza_capacita(valore: &Value) -> Vec<String> {
+    let lista = match valore {
+        Value::String(s) => serde_json::from_str::<Value>(s).unwrap_or(Value::Null),
+        v => v.clone(),
+    };
+    let presenti: Vec<&str> = lista
+        .as_array()
+        .map(|a| a.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+    TUTTE_LE_CAPACITA
+        .iter()
+        .filter(|c| presenti.iter().any(|p| p.trim() == c.codice()))
+        .map(|c| c.codice().to_string())
+        .collect()
+}
+
+/// Eccezioni per utente: oggetto {codice: bool}, solo codici validi
+pub fn normalizza_eccezioni(valore: &Value) -> Value {
+    let oggetto = match valore {
+        Value::String(s) => serde_json::from_str::<Value>(s).unwrap_or(Value::Null),
+        v => v.clone(),
+    };
+    let mut out = serde_json::Map::new();
+    if let Some(map) = oggetto.as_object() {
+        for c in TUTTE_LE_CAPACITA {
+            if let Some(v) = map.get(c.codice()) {
+                let attiva = v.as_bool().unwrap_or(v.as_str() == Some("true"));
+                out.insert(c.codice().to_string(), Value::Bool(attiva));
+            }
+        }
+    }
+    Value::Object(out)
+}
+
+/// Capacità effettive: ruolo (o voci sidebar per i record senza ruolo) più eccezioni
+pub fn capacita_effettive(perm: &UserPermissionsRecord, ruoli: &[Ruolo]) -> Vec<String> {
+    if perm.is_admin {
+        return codici(&capacita_complete());
+    }
+    let base: Vec<String> = if perm.ruolo.is_empty() {
+        capacita_da_sidebar(perm)
+    } else {
+        ruoli
+            .iter()
+            .find(|r| r.codice == perm.ruolo)
+            .map(|r| r.capacita.clone())
+            .unwrap_or_default()
+    };
+    let eccezioni = normalizza_eccezioni(&perm.capacita_extra);
+    TUTTE_LE_CAPACITA
+        .iter()
+        .map(|c| c.codice())
+        .filter(|codice| {
+            eccezioni
+                .get(*codice)
+                .and_then(|v| v.as_bool())
+                .unwrap_or_else(|| base.iter().any(|b| b == codice))
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Record precedenti ai ruoli: Programma dà le letture e la modifica dei servizi,
+/// ogni voce sidebar aggiunge le capacità della sua pagina
+fn capacita_da_sidebar(perm: &UserPermissionsRecord) -> Vec<String> {
+    use Capacita::*;
+    if !perm.programma {
+        return Vec::new();
+    }
+    // I record senza ruolo vedevano già tutto: niente oscuramento
+    let mut capacita = vec![
+        ServiziLeggi,
+        ServiziModifica,
+        ServiziDatiSanitari,
+        SociLeggi,
+        SociDatiSensibili,
+        MezziLeggi,
+        TratteLeggi,
+        Esporta,
+    ];
+    let per_voce: [(&str, &[Capacita]); 8] = [
+        ("nuovo_servizio", &[ServiziCrea, SociCrea, SociModifica]),
+        ("elenco_servizi", &[ServiziCrea, ServiziElimina]),
+        ("elenco_soci", &[SociCrea, SociModifica]),
+        ("elenco_mezzi", &[MezziCrea, MezziModifica]),
+        ("tratte_fuori_asti", &[TratteModifica]),
+        ("riepilogo_incassi", &[IncassiVedi]),
+        ("riepilogo_pagamenti", &[IncassiVedi]),
+        ("elenco_operatori", &[OperatoriVedi]),
+    ];
+    for (voce, aggiunte) in per_voce {
+        if voce_abilitata(perm, voce) {
+            capacita.extend_from_slice(aggiunte);
+        }
+    }
+    TUTTE_LE_CAPACITA
+        .iter()
+        .filter(|c| capacita.contains(c))
+        .map(|c| c.codice().to_string())
+        .collect()
+}
+
+/// Stessa lettura di normalizzaSidebarMenu: chiave assente → valore di default
+fn voce_abilitata(perm: &UserPermissionsRecord, voce: &str) -> bool {
+    match perm.sidebar_menu.get(voce) {
+        Some(Value::Bool(b)) => *b,
+        Some(Value::String(s)) => s == "true",
+        Some(Value::Number(n)) => n.as_i64() == Some(1),
+        Some(_) => false,
+        None => !VOCI_SPENTE_DI_DEFAULT.contains(&voce),
+    }
+}
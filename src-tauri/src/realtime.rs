@@ -0,0 +1,277 @@
+// Aggiornamenti in tempo reale da Supabase Realtime (postgres_changes su websocket,
+// protocollo Phoenix). Le modifiche di servizi, tesserati e automezzi fatte da altre
+// postazioni vengono rilanciate a tutte le finestre come evento Tauri `db-change`;
+// le scritture di questa app emettono lo stesso evento con origine "locale".
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::error::ApiError;
+use crate::offline;
+use crate::supabase::{json_to_string, SupabaseClient};
+
+/// Nome dell'evento Tauri ricevuto dalle finestre
+pub const EVENTO_MODIFICA: &str = "db-change";
+
+/// Tabelle seguite in tempo reale (tipi tabella dell'app, non nomi Supabase)
+pub const TABELLE_REALTIME: &[&str] = &["servizi", "tesserati", "automezzi"];
+
+/// Supabase chiude il canale se non riceve heartbeat per 60 secondi
+const HEARTBEAT: Duration = Duration::from_secs(25);
+
+const TOPIC: &str = "realtime:auser-gestione-operativa";
+
+/// Payload dell'evento `db-change`
+#[derive(Debug, Clone, Serialize)]
+pub struct ModificaTabella {
+    pub tabella: String,
+    /// insert, update, delete; "sync" = modifiche forse perse (riconnessione), ricaricare
+    pub tipo: String,
+    pub chiave: Option<String>,
+    /// Riga Supabase grezza (solo per gli eventi realtime di insert/update): serve
+    /// ad aggiornare la cache e non arriva alle finestre, che rileggono i dati dai
+    /// comandi con mascheramento e filtro dei servizi propri
+    #[serde(skip)]
+    pub riga: Option<Value>,
+    /// "realtime" (altra postazione o finestra) oppure "locale" (comando di questa app)
+    pub origine: &'static str,
+}
+
+impl ModificaTabella {
+    pub fn locale(tabella: &str, tipo: &str, chiave: impl ToString) -> Self {
+        Self {
+            tabella: tabella.to_string(),
+            tipo: tipo.to_string(),
+            chiave: Some(chiave.to_string()),
+            riga: None,
+            origine: "locale",
+        }
+    }
+
+    fn risincronizza(tabella: &str) -> Self {
+        Self {
+            tabella: tabella.to_string(),
+            tipo: "sync".to_string(),
+            chiave: None,
+            riga: None,
+            origine: "realtime",
+        }
+    }
+
+    /// Converte `payload.data` di un messaggio postgres_changes
+    fn da_postgres(table_type: &str, data: &Value) -> Option<Self> {
+        let tipo = data.get("type").and_then(|v| v.as_str())?.to_lowercase();
+        let riga = match tipo.as_str() {
+            "delete" => None,
+            _ => data.get("record").filter(|r| r.is_object()).cloned(),
+        };
+        // Nelle eliminazioni old_record contiene almeno la chiave primaria
+        let sorgente = riga.as_ref().or_else(|| data.get("old_record"));
+        let chiave = sorgente
+            .and_then(|r| r.get(offline::key_column(table_type, Some(r))))
+            .map(json_to_string)
+            .filter(|k| !k.is_empty());
+        Some(Self {
+            tabella: table_type.to_string(),
+            tipo,
+            chiave,
+            riga,
+            origine: "realtime",
+        })
+    }
+}
+
+fn errore_ws(contesto: &str, err: impl std::fmt::Display) -> ApiError {
+    ApiError::Network {
+        message: format!("{}: {}", contesto, err),
+        timeout: false,
+    }
+}
+
+/// URL websocket di Realtime ricavato dall'URL del progetto Supabase
+fn realtime_url(client: &SupabaseClient) -> String {
+    let base = client.config.url.trim_end_matches('/');
+    let base = if let Some(resto) = base.strip_prefix("https://") {
+        format!("wss://{}", resto)
+    } else if let Some(resto) = base.strip_prefix("http://") {
+        format!("ws://{}", resto)
+    } else {
+        base.to_string()
+    };
+    format!(
+        "{}/realtime/v1/websocket?apikey={}&vsn=1.0.0",
+        base,
+        urlencoding::encode(&client.config.anon_key)
+    )
+}
+
+/// Si collega a Realtime e inoltra le modifiche su `tx` finché la connessione resta
+/// aperta. Con `ripresa` (riconnessione) dopo l'iscrizione invia un evento "sync"
+/// per tabella, perché le modifiche avvenute durante l'interruzione non arrivano.
+pub async fn ascolta(
+    client: &SupabaseClient,
+    tx: &UnboundedSender<ModificaTabella>,
+    ripresa: bool,
+) -> Result<(), ApiError> {
+    let tabelle: Vec<(String, &str)> = TABELLE_REALTIME
+        .iter()
+        .filter_map(|t| client.config.tables.table_name(t).map(|n| (n.to_string(), *t)))
+        .collect();
+    if tabelle.is_empty() {
+        return Err(ApiError::config("Nessuna tabella configurata per Realtime"));
+    }
+
+    let attesa = Duration::from_secs(client.config.http.connect_timeout_secs);
+    let (mut ws, _) = tokio::time::timeout(attesa, tokio_tungstenite::connect_async(realtime_url(client)))
+        .await
+        .map_err(|_| ApiError::Network {
+            message: "Realtime: connessione websocket scaduta".to_string(),
+            timeout: true,
+        })?
+        .map_err(|e| errore_ws("Realtime: connessione websocket", e))?;
+
+    let mut payload = json!({
+        "config": {
+            "broadcast": { "self": false },
+            "presence": { "key": "" },
+            "postgres_changes": tabelle
+                .iter()
+                .map(|(nome, _)| json!({ "event": "*", "schema": "public", "table": nome }))
+                .collect::<Vec<_>>()
+        }
+    });
+    // Come per REST: il JWT dell'utente collegato, altrimenti solo le chiavi
+    // legacy JWT valgono anche come access token
+    let mut token_inviato = client.sessione().map(|s| s.access_token);
+    if let Some(token) = &token_inviato {
+        payload["access_token"] = json!(token);
+    } else if client.config.anon_key.starts_with("eyJ") {
+        payload["access_token"] = json!(client.config.anon_key);
+    }
+    let join = json!({
+        "topic": TOPIC,
+        "event": "phx_join",
+        "payload": payload,
+        "ref": "1",
+        "join_ref": "1"
+    });
+    ws.send(Message::Text(join.to_string()))
+        .await
+        .map_err(|e| errore_ws("Realtime: iscrizione", e))?;
+
+    let mut prossimo_ref: u64 = 2;
+    let mut heartbeat_in_attesa: Option<String> = None;
+    let mut battito = tokio::time::interval(HEARTBEAT);
+    battito.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = battito.tick() => {
+                if heartbeat_in_attesa.is_some() {
+                    return Err(ApiError::Network {
+                        message: "Realtime: nessuna risposta all'heartbeat".to_string(),
+                        timeout: true,
+                    });
+                }
+                let r = prossimo_ref.to_string();
+                prossimo_ref += 1;
+                let msg = json!({ "topic": "phoenix", "event": "heartbeat", "payload": {}, "ref": r });
+                ws.send(Message::Text(msg.to_string()))
+                    .await
+                    .map_err(|e| errore_ws("Realtime: heartbeat", e))?;
+                heartbeat_in_attesa = Some(r);
+                // Token rinnovato in background: il canale lo deve ricevere prima
+                // che il vecchio scada, altrimenti il server chiude l'iscrizione
+                let token = client.sessione().map(|s| s.access_token);
+                if token.is_some() && token != token_inviato {
+                    let msg = json!({
+                        "topic": TOPIC,
+                        "event": "access_token",
+                        "payload": { "access_token": token },
+                        "ref": prossimo_ref.to_string()
+                    });
+                    prossimo_ref += 1;
+                    ws.send(Message::Text(msg.to_string()))
+                        .await
+                        .map_err(|e| errore_ws("Realtime: rinnovo token", e))?;
+                    token_inviato = token;
+                }
+            }
+            msg = ws.next() => {
+                let testo = match msg {
+                    Some(Ok(Message::Text(t))) => t,
+                    Some(Ok(Message::Close(_))) | None => {
+                        println!("📡 Realtime: connessione chiusa dal server");
+                        return Ok(());
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(errore_ws("Realtime: lettura", e)),
+                };
+                let msg: Value = match serde_json::from_str(&testo) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                let evento = msg.get("event").and_then(|v| v.as_str()).unwrap_or("");
+                let payload = msg.get("payload").cloned().unwrap_or(Value::Null);
+                let rif = msg.get("ref").and_then(|v| v.as_str());
+
+                match evento {
+                    "phx_reply" if rif.is_some() && rif == heartbeat_in_attesa.as_deref() => {
+                        heartbeat_in_attesa = None;
+                    }
+                    "phx_reply" if rif == Some("1") => {
+                        if payload.get("status").and_then(|v| v.as_str()) != Some("ok") {
+                            return Err(ApiError::config(format!(
+                                "Realtime: iscrizione rifiutata: {}",
+                                payload.get("response").map(|r| r.to_string()).unwrap_or_default()
+                            )));
+                        }
+                        println!(
+                            "📡 Realtime: in ascolto su {}",
+                            tabelle.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>().join(", ")
+                        );
+                        if ripresa {
+                            for (_, table_type) in &tabelle {
+                                let _ = tx.send(ModificaTabella::risincronizza(table_type));
+                            }
+                        }
+                    }
+                    "postgres_changes" => {
+                        let data = match payload.get("data") {
+                            Some(d) => d,
+                            None => continue,
+                        };
+                        let nome = data.get("table").and_then(|v| v.as_str()).unwrap_or("");
+                        let table_type = match tabelle.iter().find(|(n, _)| n == nome) {
+                            Some((_, t)) => *t,
+                            None => continue,
+                        };
+                        if let Some(modifica) = ModificaTabella::da_postgres(table_type, data) {
+                            // Versione che le finestre possono aprire: serve ai salvataggi
+                            if let Some(riga) = &modifica.riga {
+                                client.ricorda_versioni(table_type, std::slice::from_ref(riga));
+                            }
+                            if tx.send(modifica).is_err() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                    // Errori di configurazione lato server (es. tabella non pubblicata)
+                    "system" if payload.get("status").and_then(|v| v.as_str()) == Some("error") => {
+                        println!(
+                            "⚠️ Realtime: {}",
+                            payload.get("message").map(json_to_string).unwrap_or_default()
+                        );
+                    }
+                    "phx_error" | "phx_close" => {
+                        return Err(errore_ws("Realtime: canale chiuso", evento));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
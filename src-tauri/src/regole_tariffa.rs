@@ -0,0 +1,496 @@
+// Regole della tariffa (RegoleTariffa_supa): condizioni dell'associazione
+// applicate al preventivo base di tariffe.rs. Ogni regola ha dei filtri sul
+// servizio (tipologia del socio, richiedente, tipo servizio, carrozzina: vuoto =
+// qualsiasi, "*" = valorizzato, più valori separati da virgola) e un effetto.
+//
+// Ordine: quota fissa (la prima che vale sostituisce il calcolo a km), poi i
+// supplementi (tutti), lo sconto (il primo), il minimo (il più alto) e infine
+// gratuito o rimborso. Ogni regola applicata compare fra le voci del preventivo.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::ApiError;
+use crate::tariffe::{arrotonda, formatta_importo, Preventivo, VocePreventivo};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TipoRegola {
+    /// Importo fisso al posto di km, pedaggio, extra e attesa (convenzione del richiedente)
+    QuotaFissa,
+    /// Importo aggiunto (es. carrozzina)
+    Supplemento,
+    /// Percentuale tolta dal totale
+    Sconto,
+    /// Totale minimo
+    Minimo,
+    /// Viaggio gratuito (AUSER GRATIS)
+    Gratuito,
+    /// Percentuale del totale rimborsata dal richiedente invece che pagata dal socio
+    /// (AUSER RIMBORSO); 0 = tutto
+    Rimborso,
+}
+
+impl TipoRegola {
+    fn fase(self) -> u8 {
+        match self {
+            TipoRegola::QuotaFissa => 0,
+            TipoRegola::Supplemento => 1,
+            TipoRegola::Sconto => 2,
+            TipoRegola::Minimo => 3,
+            TipoRegola::Gratuito | TipoRegola::Rimborso => 4,
+        }
+    }
+}
+
+/// Una riga di RegoleTariffa_supa
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegolaTariffa {
+    pub codice: String,
+    pub descrizione: String,
+    pub tipo: TipoRegola,
+    #[serde(default)]
+    pub valore: f64,
+    #[serde(default)]
+    pub tipologia_socio: String,
+    #[serde(default)]
+    pub richiedente: String,
+    #[serde(default)]
+    pub tipo_servizio: String,
+    #[serde(default)]
+    pub carrozzina: String,
+    /// A parità di fase vince la priorità più bassa
+    #[serde(default)]
+    pub priorita: i32,
+    #[serde(default = "attiva_predefinita")]
+    pub attiva: bool,
+}
+
+fn attiva_predefinita() -> bool {
+    true
+}
+
+/// Dati del servizio su cui si valutano i filtri
+#[derive(Debug, Clone, Default)]
+pub struct ContestoServizio {
+    pub tipologia_socio: String,
+    pub richiedente: String,
+    pub tipo_servizio: String,
+    pub carrozzina: String,
+}
+
+/// Regola applicata, con l'effetto sul totale
+#[derive(Debug, Clone, Serialize)]
+pub struct RegolaApplicata {
+    pub codice: String,
+    pub descrizione: String,
+    pub tipo: TipoRegola,
+    pub importo: f64,
+}
+
+fn normalizza(testo: &str) -> String {
+    testo
+        .trim()
+        .to_uppercase()
+        .replace(['_', '-'], " ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Filtro vuoto = qualsiasi valore, "*" = valore presente, altrimenti uno dei valori elencati
+fn corrisponde(filtro: &str, valore: &str) -> bool {
+    let filtro = filtro.trim();
+    if filtro.is_empty() {
+        return true;
+    }
+    let valore = normalizza(valore);
+    if filtro == "*" {
+        return !valore.is_empty();
+    }
+    filtro.split(',').any(|f| normalizza(f) == valore)
+}
+
+impl RegolaTariffa {
+    pub fn da_riga(row: &Value) -> Option<Self> {
+        match serde_json::from_value::<Self>(row.clone()) {
+            Ok(regola) => Some(regola),
+            Err(e) => {
+                println!("⚠️ Regola tariffa ignorata ({}): {}", e, row);
+                None
+            }
+        }
+    }
+
+    pub fn si_applica(&self, contesto: &ContestoServizio) -> bool {
+        self.attiva
+            && corrisponde(&self.tipologia_socio, &contesto.tipologia_socio)
+            && corrisponde(&self.richiedente, &contesto.richiedente)
+            && corrisponde(&self.tipo_servizio, &contesto.tipo_servizio)
+            && corrisponde(&self.carrozzina, &contesto.carrozzina)
+    }
+
+    pub fn valida(&self) -> Result<(), ApiError> {
+        if self.codice.trim().is_empty() {
+            return Err("Codice della regola obbligatorio".into());
+        }
+        if self.descrizione.trim().is_empty() {
+            return Err("Descrizione della regola obbligatoria".into());
+        }
+        if !self.valore.is_finite() || self.valore < 0.0 {
+            return Err("Il valore della regola non può essere negativo".into());
+        }
+        if matches!(self.tipo, TipoRegola::Sconto | TipoRegola::Rimborso) && self.valore > 100.0 {
+            return Err("Sconto e rimborso sono percentuali fra 0 e 100".into());
+        }
+        Ok(())
+    }
+
+    /// Riga da salvare in RegoleTariffa_supa
+    pub fn riga(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+fn aggiungi_voce(preventivo: &mut Preventivo, regola: &RegolaTariffa, descrizione: String, importo: f64) {
+    let importo = arrotonda(importo);
+    preventivo.voci.push(VocePreventivo {
+        codice: format!("regola:{}", regola.codice),
+        descrizione,
+        quantita: 1.0,
+        unitario: importo,
+        importo,
+    });
+    preventivo.regole.push(RegolaApplicata {
+        codice: regola.codice.clone(),
+        descrizione: regola.descrizione.clone(),
+        tipo: regola.tipo,
+        importo,
+    });
+    preventivo.totale = arrotonda(preventivo.voci.iter().map(|v| v.importo).sum());
+}
+
+/// Applica al preventivo base le regole valide per il servizio
+pub fn applica(regole: &[RegolaTariffa], contesto: &ContestoServizio, preventivo: &mut Preventivo) {
+    let mut valide: Vec<&RegolaTariffa> = regole.iter().filter(|r| r.si_applica(contesto)).collect();
+    valide.sort_by_key(|r| (r.tipo.fase(), r.priorita));
+
+    let prima = |tipo: TipoRegola| valide.iter().copied().find(|r| r.tipo == tipo);
+
+    if let Some(r) = prima(TipoRegola::QuotaFissa) {
+        let rettifica = r.valore - preventivo.totale;
+        aggiungi_voce(
+            preventivo,
+            r,
+            format!("{}: quota fissa {} €", r.descrizione, formatta_importo(r.valore)),
+            rettifica,
+        );
+    }
+    for r in valide.iter().filter(|r| r.tipo == TipoRegola::Supplemento) {
+        aggiungi_voce(preventivo, r, r.descrizione.clone(), r.valore);
+    }
+    if let Some(r) = prima(TipoRegola::Sconto) {
+        let sconto = preventivo.totale * r.valore / 100.0;
+        if sconto > 0.0 {
+            aggiungi_voce(
+                preventivo,
+                r,
+                format!("{}: sconto {}%", r.descrizione, r.valore),
+                -sconto,
+            );
+        }
+    }
+    if let Some(r) = valide
+        .iter()
+        .filter(|r| r.tipo == TipoRegola::Minimo)
+        .max_by(|a, b| a.valore.total_cmp(&b.valore))
+    {
+        if preventivo.totale < r.valore {
+            let rettifica = r.valore - preventivo.totale;
+            aggiungi_voce(
+                preventivo,
+                r,
+                format!("{}: minimo {} €", r.descrizione, formatta_importo(r.valore)),
+                rettifica,
+            );
+        }
+    }
+
+    // Gratuito prevale sul rimborso: non c'è niente da rimborsare
+    if let Some(r) = prima(TipoRegola::Gratuito) {
+        let totale = preventivo.totale;
+        aggiungi_voce(preventivo, r, r.descrizione.clone(), -totale);
+        preventivo.gratuito = true;
+    } else if let Some(r) = prima(TipoRegola::Rimborso) {
+        let percentuale = if r.valore > 0.0 { r.valore } else { 100.0 };
+        preventivo.rimborsato = arrotonda(preventivo.totale * percentuale / 100.0);
+        preventivo.regole.push(RegolaApplicata {
+            codice: r.codice.clone(),
+            descrizione: format!("{}: rimborso {}% dal richiedente", r.descrizione, percentuale),
+            tipo: r.tipo,
+            importo: 0.0,
+        });
+    }
+
+    preventivo.totale = preventivo.totale.max(0.0);
+    preventivo.a_carico_socio = arrotonda((preventivo.totale - preventivo.rimborsato).max(0.0));
+    preventivo.totale_testo = formatta_importo(preventivo.totale);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Preventivo base: 40 € di km
+    fn base() -> Preventivo {
+        Preventivo {
+            tratta: None,
+            andata_ritorno: true,
+            km: 80.0,
+            costo_km: 0.5,
+            voci: vec![VocePreventivo {
+                codice: "km".to_string(),
+                descrizione: "Km".to_string(),
+                quantita: 80.0,
+                unitario: 0.5,
+                importo: 40.0,
+            }],
+            totale: 40.0,
+            tariffa_valida_dal: None,
+            regole: Vec::new(),
+            gratuito: false,
+            rimborsato: 0.0,
+            a_carico_socio: 40.0,
+            totale_testo: "40,00".to_string(),
+        }
+    }
+
+    fn regola(codice: &str, tipo: TipoRegola, valore: f64) -> RegolaTariffa {
+        RegolaTariffa {
+            codice: codice.to_string(),
+            descrizione: codice.to_string(),
+            tipo,
+            valore,
+            tipologia_socio: String::new(),
+            richiedente: String::new(),
+            tipo_servizio: String::new(),
+            carrozzina: String::new(),
+            priorita: 0,
+            attiva: true,
+        }
+    }
+
+    fn con_priorita(mut r: RegolaTariffa, priorita: i32) -> RegolaTariffa {
+        r.priorita = priorita;
+        r
+    }
+
+    fn contesto(tipologia_socio: &str, richiedente: &str, carrozzina: &str) -> ContestoServizio {
+        ContestoServizio {
+            tipologia_socio: tipologia_socio.to_string(),
+            richiedente: richiedente.to_string(),
+            tipo_servizio: "ACCOMPAGNAMENTO".to_string(),
+            carrozzina: carrozzina.to_string(),
+        }
+    }
+
+    struct Caso {
+        nome: &'static str,
+        regole: Vec<RegolaTariffa>,
+        contesto: ContestoServizio,
+        totale: f64,
+        rimborsato: f64,
+        a_carico_socio: f64,
+        gratuito: bool,
+        applicate: &'static [&'static str],
+    }
+
+    #[test]
+    fn ordine_di_applicazione_delle_regole() {
+        use TipoRegola::*;
+        let qualsiasi = ContestoServizio::default();
+        let carrozzina = {
+            let mut r = regola("CARROZZINA", Supplemento, 5.0);
+            r.carrozzina = "*".to_string();
+            r
+        };
+        let solo_soci = {
+            let mut r = regola("SOCI", Sconto, 50.0);
+            r.tipologia_socio = "socio ordinario, socio_volontario".to_string();
+            r
+        };
+        let casi = vec![
+            Caso {
+                nome: "nessuna regola",
+                regole: Vec::new(),
+                contesto: qualsiasi.clone(),
+                totale: 40.0,
+                rimborsato: 0.0,
+                a_carico_socio: 40.0,
+                gratuito: false,
+                applicate: &[],
+            },
+            Caso {
+                // Elencate alla rovescia: conta la fase, non l'ordine della tabella
+                nome: "quota fissa, poi supplementi, poi sconto",
+                regole: vec![
+                    regola("SC10", Sconto, 10.0),
+                    regola("SUP5", Supplemento, 5.0),
+                    regola("QF20", QuotaFissa, 20.0),
+                ],
+                contesto: qualsiasi.clone(),
+                totale: 22.5,
+                rimborsato: 0.0,
+                a_carico_socio: 22.5,
+                gratuito: false,
+                applicate: &["QF20", "SUP5", "SC10"],
+            },
+            Caso {
+                nome: "vale la quota fissa e lo sconto con priorità più bassa",
+                regole: vec![
+                    con_priorita(regola("QF30", QuotaFissa, 30.0), 2),
+                    con_priorita(regola("QF10", QuotaFissa, 10.0), 1),
+                    con_priorita(regola("SC50", Sconto, 50.0), 5),
+                    con_priorita(regola("SC20", Sconto, 20.0), 3),
+                ],
+                contesto: qualsiasi.clone(),
+                totale: 8.0,
+                rimborsato: 0.0,
+                a_carico_socio: 8.0,
+                gratuito: false,
+                applicate: &["QF10", "SC20"],
+            },
+            Caso {
+                nome: "minimo dopo lo sconto, vale il più alto",
+                regole: vec![
+                    regola("MIN15", Minimo, 15.0),
+                    regola("SC50", Sconto, 50.0),
+                    regola("MIN25", Minimo, 25.0),
+                ],
+                contesto: qualsiasi.clone(),
+                totale: 25.0,
+                rimborsato: 0.0,
+                a_carico_socio: 25.0,
+                gratuito: false,
+                applicate: &["SC50", "MIN25"],
+            },
+            Caso {
+                nome: "minimo già superato",
+                regole: vec![regola("MIN15", Minimo, 15.0)],
+                contesto: qualsiasi.clone(),
+                totale: 40.0,
+                rimborsato: 0.0,
+                a_carico_socio: 40.0,
+                gratuito: false,
+                applicate: &[],
+            },
+            Caso {
+                nome: "gratuito per ultimo e prevale sul rimborso",
+                regole: vec![
+                    regola("RIMB", Rimborso, 0.0),
+                    regola("GRATIS", Gratuito, 0.0),
+                    regola("SUP5", Supplemento, 5.0),
+                    regola("MIN50", Minimo, 50.0),
+                ],
+                contesto: qualsiasi.clone(),
+                totale: 0.0,
+                rimborsato: 0.0,
+                a_carico_socio: 0.0,
+                gratuito: true,
+                applicate: &["SUP5", "MIN50", "GRATIS"],
+            },
+            Caso {
+                nome: "rimborso 0 = tutto a carico del richiedente",
+                regole: vec![regola("RIMB", Rimborso, 0.0)],
+                contesto: qualsiasi.clone(),
+                totale: 40.0,
+                rimborsato: 40.0,
+                a_carico_socio: 0.0,
+                gratuito: false,
+                applicate: &["RIMB"],
+            },
+            Caso {
+                nome: "rimborso parziale sul totale finale",
+                regole: vec![
+                    regola("RIMB30", Rimborso, 30.0),
+                    regola("SUP10", Supplemento, 10.0),
+                ],
+                contesto: qualsiasi.clone(),
+                totale: 50.0,
+                rimborsato: 15.0,
+                a_carico_socio: 35.0,
+                gratuito: false,
+                applicate: &["SUP10", "RIMB30"],
+            },
+            Caso {
+                nome: "filtri: carrozzina valorizzata e tipologia in elenco",
+                regole: vec![carrozzina.clone(), solo_soci.clone()],
+                contesto: contesto("SOCIO-VOLONTARIO", "", "SI"),
+                totale: 22.5,
+                rimborsato: 0.0,
+                a_carico_socio: 22.5,
+                gratuito: false,
+                applicate: &["CARROZZINA", "SOCI"],
+            },
+            Caso {
+                nome: "filtri non soddisfatti e regola disattivata",
+                regole: vec![carrozzina, solo_soci, {
+                    let mut r = regola("SPENTA", Gratuito, 0.0);
+                    r.attiva = false;
+                    r
+                }],
+                contesto: contesto("NON SOCIO", "ASL", ""),
+                totale: 40.0,
+                rimborsato: 0.0,
+                a_carico_socio: 40.0,
+                gratuito: false,
+                applicate: &[],
+            },
+        ];
+
+        for caso in casi {
+            let mut preventivo = base();
+            applica(&caso.regole, &caso.contesto, &mut preventivo);
+            assert_eq!(preventivo.totale, caso.totale, "{}: totale", caso.nome);
+            assert_eq!(
+                preventivo.rimborsato, caso.rimborsato,
+                "{}: rimborsato",
+                caso.nome
+            );
+            assert_eq!(
+                preventivo.a_carico_socio, caso.a_carico_socio,
+                "{}: a carico",
+                caso.nome
+            );
+            assert_eq!(
+                preventivo.gratuito, caso.gratuito,
+                "{}: gratuito",
+                caso.nome
+            );
+            assert_eq!(
+                preventivo.totale_testo,
+                formatta_importo(caso.totale),
+                "{}",
+                caso.nome
+            );
+            let applicate: Vec<&str> = preventivo
+                .regole
+                .iter()
+                .map(|r| r.codice.as_str())
+                .collect();
+            assert_eq!(applicate, caso.applicate, "{}: regole", caso.nome);
+            // Le voci sommano al totale (tranne il rimborso, che non è una voce)
+            let somma = arrotonda(preventivo.voci.iter().map(|v| v.importo).sum());
+            assert_eq!(somma, caso.totale, "{}: voci", caso.nome);
+        }
+    }
+
+    #[test]
+    fn regole_non_valide() {
+        assert!(regola("", TipoRegola::Supplemento, 5.0).valida().is_err());
+        assert!(regola("NEG", TipoRegola::Supplemento, -1.0)
+            .valida()
+            .is_err());
+        assert!(regola("SC", TipoRegola::Sconto, 120.0).valida().is_err());
+        assert!(regola("MIN", TipoRegola::Minimo, 120.0).valida().is_ok());
+    }
+}
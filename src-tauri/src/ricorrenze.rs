@@ -0,0 +1,321 @@
+// Serie di servizi ricorrenti (dialisi, cicli di terapia): da un servizio
+// modello e da una regola (ogni settimana in certi giorni, oppure ogni N giorni,
+// fino a una data o per N volte) si generano le date delle copie, saltando le
+// festività nazionali e le date escluse a mano. Le copie hanno lo stesso IdSerie
+// del modello; una modifica vale per la singola occorrenza, da questa in poi o
+// per tutta la serie.
+use chrono::{Datelike, Duration, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::error::ApiError;
+use crate::supabase::codice_casuale;
+use crate::tariffe::data_servizio;
+
+/// Oltre questo numero di copie una serie è quasi certamente un errore di regola
+pub const MAX_OCCORRENZE: usize = 200;
+/// Una serie senza data di fine si ferma dopo un anno
+pub const MAX_GIORNI_SERIE: i64 = 366;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "tipo", rename_all = "snake_case")]
+pub enum Frequenza {
+    /// Ogni settimana nei giorni indicati (1 = lunedì … 7 = domenica)
+    Settimanale { giorni: Vec<u32> },
+    /// Ogni N giorni dalla data di inizio
+    OgniNGiorni { intervallo: u32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegolaRicorrenza {
+    #[serde(flatten)]
+    pub frequenza: Frequenza,
+    /// Prima data possibile (ISO o gg/mm/aaaa)
+    pub dal: String,
+    pub fino_al: Option<String>,
+    /// Numero di servizi da creare (le date saltate non contano)
+    pub occorrenze: Option<u32>,
+    #[serde(default = "salta_festivi_predefinito")]
+    pub salta_festivi: bool,
+    #[serde(default)]
+    pub date_escluse: Vec<String>,
+}
+
+fn salta_festivi_predefinito() -> bool {
+    true
+}
+
+/// A quali occorrenze della serie si applica una modifica
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AmbitoSerie {
+    Singola,
+    DaQuestaInPoi,
+    Tutta,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DataSaltata {
+    pub data: String,
+    pub motivo: String,
+}
+
+/// Date generate dalla regola e date scartate
+#[derive(Debug, Clone, Serialize)]
+pub struct PianoSerie {
+    pub date: Vec<NaiveDate>,
+    pub saltate: Vec<DataSaltata>,
+}
+
+/// Codice della serie (colonna IdSerie dei servizi)
+pub fn nuovo_id_serie() -> String {
+    codice_casuale(12)
+}
+
+/// Domenica di Pasqua (calendario gregoriano, algoritmo di Meeus)
+fn pasqua(anno: i32) -> NaiveDate {
+    let a = anno % 19;
+    let b = anno / 100;
+    let c = anno % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let mese = (h + l - 7 * m + 114) / 31;
+    let giorno = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd_opt(anno, mese as u32, giorno as u32).unwrap_or_default()
+}
+
+/// Nome della festività nazionale, se la data lo è
+pub fn festivita(data: NaiveDate) -> Option<&'static str> {
+    let fissa = match (data.month(), data.day()) {
+        (1, 1) => Some("Capodanno"),
+        (1, 6) => Some("Epifania"),
+        (4, 25) => Some("Festa della Liberazione"),
+        (5, 1) => Some("Festa del Lavoro"),
+        (6, 2) => Some("Festa della Repubblica"),
+        (8, 15) => Some("Ferragosto"),
+        (11, 1) => Some("Ognissanti"),
+        (12, 8) => Some("Immacolata Concezione"),
+        (12, 25) => Some("Natale"),
+        (12, 26) => Some("Santo Stefano"),
+        _ => None,
+    };
+    if fissa.is_some() {
+        return fissa;
+    }
+    // San Francesco d'Assisi è di nuovo festa nazionale dal 2026
+    if (data.month(), data.day()) == (10, 4) && data.year() >= 2026 {
+        return Some("San Francesco d'Assisi");
+    }
+    let pasqua = pasqua(data.year());
+    if data == pasqua {
+        Some("Pasqua")
+    } else if data == pasqua + Duration::days(1) {
+        Some("Lunedì dell'Angelo")
+    } else {
+        None
+    }
+}
+
+fn data_obbligatoria(testo: &str, campo: &str) -> Result<NaiveDate, ApiError> {
+    data_servizio(testo).ok_or_else(|| ApiError::from(format!("{} non valida: '{}'", campo, testo.trim())))
+}
+
+impl RegolaRicorrenza {
+    fn cade_il(&self, inizio: NaiveDate, data: NaiveDate) -> bool {
+        match &self.frequenza {
+            Frequenza::Settimanale { giorni } => giorni.contains(&data.weekday().number_from_monday()),
+            Frequenza::OgniNGiorni { intervallo } => {
+                (data - inizio).num_days() % i64::from((*intervallo).max(1)) == 0
+            }
+        }
+    }
+
+    /// Date della serie, nell'ordine
+    pub fn piano(&self) -> Result<PianoSerie, ApiError> {
+        match &self.frequenza {
+            Frequenza::Settimanale { giorni } => {
+                if giorni.is_empty() || giorni.iter().any(|g| !(1..=7).contains(g)) {
+                    return Err("Indica i giorni della settimana (1 = lunedì … 7 = domenica)".into());
+                }
+            }
+            Frequenza::OgniNGiorni { intervallo } => {
+                if *intervallo == 0 {
+                    return Err("L'intervallo in giorni deve essere almeno 1".into());
+                }
+            }
+        }
+        let inizio = data_obbligatoria(&self.dal, "Data di inizio")?;
+        let fine = match self.fino_al.as_deref().filter(|d| !d.trim().is_empty()) {
+            Some(d) => Some(data_obbligatoria(d, "Data di fine")?),
+            None => None,
+        };
+        let occorrenze = self.occorrenze.filter(|n| *n > 0).map(|n| n as usize);
+        if fine.is_none() && occorrenze.is_none() {
+            return Err("Indica una data di fine o il numero di servizi".into());
+        }
+        let limite = inizio + Duration::days(MAX_GIORNI_SERIE);
+        let fine = match fine {
+            Some(f) if f < inizio => return Err("La data di fine è prima della data di inizio".into()),
+            Some(f) if f > limite => {
+                return Err(format!("Una serie dura al massimo {} giorni", MAX_GIORNI_SERIE).into())
+            }
+            Some(f) => f,
+            None => limite,
+        };
+        let escluse = self
+            .date_escluse
+            .iter()
+            .map(|d| data_obbligatoria(d, "Data esclusa"))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut piano = PianoSerie { date: Vec::new(), saltate: Vec::new() };
+        let mut data = inizio;
+        while data <= fine && occorrenze.map(|n| piano.date.len() < n).unwrap_or(true) {
+            if self.cade_il(inizio, data) {
+                let motivo = if escluse.contains(&data) {
+                    Some("Data esclusa".to_string())
+                } else if self.salta_festivi {
+                    festivita(data).map(str::to_string)
+                } else {
+                    None
+                };
+                match motivo {
+                    Some(motivo) => piano.saltate.push(DataSaltata {
+                        data: data.format("%d/%m/%Y").to_string(),
+                        motivo,
+                    }),
+                    None => piano.date.push(data),
+                }
+            }
+            data += Duration::days(1);
+        }
+
+        if piano.date.is_empty() {
+            return Err("La regola non genera nessuna data".into());
+        }
+        if piano.date.len() > MAX_OCCORRENZE {
+            return Err(format!(
+                "La regola genera {} servizi: il massimo per una serie è {}",
+                piano.date.len(),
+                MAX_OCCORRENZE
+            )
+            .into());
+        }
+        Ok(piano)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn d(anno: i32, mese: u32, giorno: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(anno, mese, giorno).unwrap()
+    }
+
+    fn settimanale(giorni: &[u32], dal: &str, fino_al: Option<&str>, occorrenze: Option<u32>) -> RegolaRicorrenza {
+        RegolaRicorrenza {
+            frequenza: Frequenza::Settimanale { giorni: giorni.to_vec() },
+            dal: dal.to_string(),
+            fino_al: fino_al.map(str::to_string),
+            occorrenze,
+            salta_festivi: true,
+            date_escluse: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn pasqua_meeus() {
+        assert_eq!(pasqua(2024), d(2024, 3, 31));
+        assert_eq!(pasqua(2025), d(2025, 4, 20));
+        assert_eq!(pasqua(2026), d(2026, 4, 5));
+    }
+
+    #[test]
+    fn festivita_nazionali() {
+        let casi = [
+            (d(2026, 1, 1), Some("Capodanno")),
+            (d(2025, 4, 20), Some("Pasqua")),
+            (d(2025, 4, 21), Some("Lunedì dell'Angelo")),
+            (d(2026, 4, 6), Some("Lunedì dell'Angelo")),
+            (d(2026, 4, 25), Some("Festa della Liberazione")),
+            (d(2026, 12, 26), Some("Santo Stefano")),
+            // San Francesco solo dal 2026
+            (d(2025, 10, 4), None),
+            (d(2026, 10, 4), Some("San Francesco d'Assisi")),
+            (d(2027, 10, 4), Some("San Francesco d'Assisi")),
+            (d(2026, 3, 3), None),
+            (d(2026, 4, 7), None),
+        ];
+        for (data, atteso) in casi {
+            assert_eq!(festivita(data), atteso, "{}", data);
+        }
+    }
+
+    #[test]
+    fn piano_salta_festivi_e_date_escluse() {
+        // Lunedì e giovedì dal 30/03/2026 al 09/04/2026: il 06/04 è Pasquetta
+        let mut regola = settimanale(&[1, 4], "2026-03-30", Some("09/04/2026"), None);
+        regola.date_escluse = vec!["02/04/2026".to_string()];
+        let piano = regola.piano().unwrap();
+        assert_eq!(piano.date, vec![d(2026, 3, 30), d(2026, 4, 9)]);
+        let saltate: Vec<_> = piano.saltate.iter().map(|s| (s.data.as_str(), s.motivo.as_str())).collect();
+        assert_eq!(
+            saltate,
+            vec![("02/04/2026", "Data esclusa"), ("06/04/2026", "Lunedì dell'Angelo")]
+        );
+
+        regola.salta_festivi = false;
+        let piano = regola.piano().unwrap();
+        assert_eq!(piano.date, vec![d(2026, 3, 30), d(2026, 4, 6), d(2026, 4, 9)]);
+    }
+
+    #[test]
+    fn piano_occorrenze_non_contano_le_date_saltate() {
+        // Ogni 8 giorni dal 26/09/2026: il 04/10 (San Francesco) è saltato e
+        // la terza occorrenza slitta al 20/10
+        let regola = RegolaRicorrenza {
+            frequenza: Frequenza::OgniNGiorni { intervallo: 8 },
+            ..settimanale(&[1], "2026-09-26", None, Some(3))
+        };
+        let piano = regola.piano().unwrap();
+        assert_eq!(piano.date, vec![d(2026, 9, 26), d(2026, 10, 12), d(2026, 10, 20)]);
+        assert_eq!(piano.saltate.len(), 1);
+        assert_eq!(piano.saltate[0].data, "04/10/2026");
+    }
+
+    #[test]
+    fn piano_limiti_e_regole_non_valide() {
+        let errore = |regola: RegolaRicorrenza| regola.piano().unwrap_err().to_string();
+
+        assert!(errore(settimanale(&[1], "2026-01-05", None, None)).contains("data di fine"));
+        assert!(errore(settimanale(&[], "2026-01-05", None, Some(3))).contains("giorni della settimana"));
+        assert!(errore(settimanale(&[8], "2026-01-05", None, Some(3))).contains("giorni della settimana"));
+        assert!(errore(settimanale(&[1], "2026-01-05", Some("2026-01-01"), None)).contains("prima della data di inizio"));
+        assert!(errore(settimanale(&[1], "2026-01-05", Some("2027-06-01"), None)).contains("al massimo"));
+        // Solo il 25/12 (venerdì) nell'intervallo, ed è festivo
+        assert!(errore(settimanale(&[5], "2026-12-25", Some("2026-12-25"), None)).contains("nessuna data"));
+
+        let ogni_giorno = RegolaRicorrenza {
+            frequenza: Frequenza::OgniNGiorni { intervallo: 1 },
+            ..settimanale(&[1], "2026-01-07", Some("2026-12-31"), None)
+        };
+        assert!(errore(ogni_giorno.clone()).contains(&format!("il massimo per una serie è {}", MAX_OCCORRENZE)));
+        let zero = RegolaRicorrenza {
+            frequenza: Frequenza::OgniNGiorni { intervallo: 0 },
+            ..ogni_giorno
+        };
+        assert!(errore(zero).contains("almeno 1"));
+
+        // Senza data di fine la serie si ferma comunque dopo MAX_GIORNI_SERIE
+        let piano = settimanale(&[1], "2026-01-05", None, Some(100)).piano().unwrap();
+        assert!(piano.date.len() < 100);
+        assert!(*piano.date.last().unwrap() <= d(2026, 1, 5) + Duration::days(MAX_GIORNI_SERIE));
+    }
+}
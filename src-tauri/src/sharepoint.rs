@@ -3,6 +3,8 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use chrono::{DateTime, Utc, NaiveDate, Local};
 
+use crate::error::ApiError;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SharePointConfig {
     pub site_url: String,
@@ -67,7 +69,7 @@ impl SharePointClient {
     }
 
     // Verifica se il token è scaduto e aggiornalo se necessario
-    pub async fn ensure_valid_token(&mut self) -> Result<(), String> {
+    pub async fn ensure_valid_token(&mut self) -> Result<(), ApiError> {
         let needs_refresh = if let Some(expires_at) = self.config.expires_at {
             expires_at < Utc::now()
         } else {
@@ -79,22 +81,22 @@ impl SharePointClient {
             if let Some(refresh_token) = self.config.refresh_token.clone() {
                 return self.refresh_access_token(&refresh_token).await;
             } else {
-                return Err("Token scaduto e refresh token non disponibile".to_string());
+                return Err("Token scaduto e refresh token non disponibile".into());
             }
         }
         Ok(())
     }
 
     // Refresh del token di accesso
-    pub async fn refresh_access_token(&mut self, refresh_token: &str) -> Result<(), String> {
+    pub async fn refresh_access_token(&mut self, refresh_token: &str) -> Result<(), ApiError> {
         let tenant_id = self.config.tenant_id.as_ref()
-            .ok_or("Tenant ID non configurato")?;
+            .ok_or_else(|| ApiError::config("Tenant ID non configurato"))?;
         
         let client_id = self.config.client_id.as_ref()
-            .ok_or("Client ID non configurato")?;
+            .ok_or_else(|| ApiError::config("Client ID non configurato"))?;
         
         let client_secret = self.config.client_secret.as_ref()
-            .ok_or("Client Secret non configurato")?;
+            .ok_or_else(|| ApiError::config("Client Secret non configurato"))?;
 
         let token_url = format!(
             "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
@@ -115,11 +117,16 @@ impl SharePointClient {
             .form(&params)
             .send()
             .await
-            .map_err(|e| format!("Errore nella richiesta refresh token: {}", e))?;
+            .map_err(|e| ApiError::network("Errore nella richiesta refresh token", e))?;
 
         if !response.status().is_success() {
+            let status = response.status();
             let error_text = response.text().await.unwrap_or_default();
-            return Err(format!("Errore nel refresh token: {}", error_text));
+            return Err(ApiError::from_response(
+                "Errore nel refresh token",
+                status.as_u16(),
+                &error_text,
+            ));
         }
 
         let token_data: TokenResponse = response
@@ -144,15 +151,15 @@ impl SharePointClient {
         &mut self,
         code: &str,
         redirect_uri: &str,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         let tenant_id = self.config.tenant_id.as_ref()
-            .ok_or("Tenant ID non configurato")?;
+            .ok_or_else(|| ApiError::config("Tenant ID non configurato"))?;
         
         let client_id = self.config.client_id.as_ref()
-            .ok_or("Client ID non configurato")?;
+            .ok_or_else(|| ApiError::config("Client ID non configurato"))?;
         
         let client_secret = self.config.client_secret.as_ref()
-            .ok_or("Client Secret non configurato")?;
+            .ok_or_else(|| ApiError::config("Client Secret non configurato"))?;
 
         let token_url = format!(
             "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
@@ -174,11 +181,16 @@ impl SharePointClient {
             .form(&params)
             .send()
             .await
-            .map_err(|e| format!("Errore nella richiesta token: {}", e))?;
+            .map_err(|e| ApiError::network("Errore nella richiesta token", e))?;
 
         if !response.status().is_success() {
+            let status = response.status();
             let error_text = response.text().await.unwrap_or_default();
-            return Err(format!("Errore nell'ottenimento token: {}", error_text));
+            return Err(ApiError::from_response(
+                "Errore nell'ottenimento token",
+                status.as_u16(),
+                &error_text,
+            ));
         }
 
         let token_data: TokenResponse = response
@@ -199,12 +211,12 @@ impl SharePointClient {
     }
 
     // Genera URL di autorizzazione OAuth2
-    pub fn get_authorization_url(&self, redirect_uri: &str, state: &str) -> Result<String, String> {
+    pub fn get_authorization_url(&self, redirect_uri: &str, state: &str) -> Result<String, ApiError> {
         let tenant_id = self.config.tenant_id.as_ref()
-            .ok_or("Tenant ID non configurato")?;
+            .ok_or_else(|| ApiError::config("Tenant ID non configurato"))?;
         
         let client_id = self.config.client_id.as_ref()
-            .ok_or("Client ID non configurato")?;
+            .ok_or_else(|| ApiError::config("Client ID non configurato"))?;
 
         let scope = urlencoding::encode("https://graph.microsoft.com/Sites.ReadWrite.All offline_access");
         let redirect = urlencoding::encode(redirect_uri);
@@ -231,7 +243,7 @@ impl SharePointClient {
         &mut self,
         list_name: &str,
         filter: Option<&str>,
-    ) -> Result<Vec<serde_json::Value>, String> {
+    ) -> Result<Vec<serde_json::Value>, ApiError> {
         println!("🔄 [SHAREPOINT] get_list_items CHIAMATO - lista: {}, filtro: {:?}", list_name, filter);
         // Verifica e aggiorna il token se necessario
         self.ensure_valid_token().await?;
@@ -241,7 +253,7 @@ impl SharePointClient {
             .config
             .access_token
             .as_ref()
-            .ok_or("Access token non disponibile. Esegui prima l'autenticazione.")?;
+            .ok_or_else(|| ApiError::config("Access token non disponibile. Esegui prima l'autenticazione."))?;
 
         // Estrai hostname e path dal site_url
         let site_url = self.config.site_url.trim_end_matches('/');
@@ -269,12 +281,16 @@ impl SharePointClient {
             .header("Expires", "0")
             .send()
             .await
-            .map_err(|e| format!("Errore nella richiesta ID sito: {}", e))?;
+            .map_err(|e| ApiError::network("Errore nella richiesta ID sito", e))?;
         
         if !site_response.status().is_success() {
             let status = site_response.status();
             let error_text = site_response.text().await.unwrap_or_default();
-            return Err(format!("Errore nell'ottenimento ID sito: {} - {}", status, error_text));
+            return Err(ApiError::from_response(
+                "Errore nell'ottenimento ID sito",
+                status.as_u16(),
+                &error_text,
+            ));
         }
         
         let site_json: serde_json::Value = site_response
@@ -302,12 +318,16 @@ impl SharePointClient {
             .header("Expires", "0")
             .send()
             .await
-            .map_err(|e| format!("Errore nella richiesta ID lista: {}", e))?;
+            .map_err(|e| ApiError::network("Errore nella richiesta ID lista", e))?;
 
         if !list_response.status().is_success() {
             let status = list_response.status();
             let error_text = list_response.text().await.unwrap_or_default();
-            return Err(format!("Errore nell'ottenimento ID lista: {} - {}", status, error_text));
+            return Err(ApiError::from_response(
+                "Errore nell'ottenimento ID lista",
+                status.as_u16(),
+                &error_text,
+            ));
         }
 
         let list_json: serde_json::Value = list_response
@@ -317,7 +337,7 @@ impl SharePointClient {
 
         let list_id = list_json["value"][0]["id"]
             .as_str()
-            .ok_or(format!("Lista '{}' non trovata", list_name))?;
+            .ok_or_else(|| ApiError::not_found(format!("Lista '{}' non trovata", list_name)))?;
         
         println!("✓ ID lista ottenuto: {}", list_id);
         
@@ -388,7 +408,7 @@ impl SharePointClient {
                 .header("Expires", "0")
                 .send()
                 .await
-                .map_err(|e| format!("Errore nella richiesta items (pagina {}): {}", page_count, e))?;
+                .map_err(|e| ApiError::network(format!("Errore nella richiesta items (pagina {})", page_count), e))?;
 
             println!("Status risposta Graph API (pagina {}): {}", page_count, items_response.status());
 
@@ -398,14 +418,16 @@ impl SharePointClient {
                 println!("✗ Errore Graph API (pagina {}): {} - {}", page_count, status, error_text);
                 
                 if status == 400 {
-                    return Err(format!(
-                        "Errore Graph API 400 (probabilmente filtro su campo non indicizzato): {}",
-                        error_text
+                    return Err(ApiError::from_response(
+                        "Errore Graph API 400 (probabilmente filtro su campo non indicizzato)",
+                        status.as_u16(),
+                        &error_text,
                     ));
                 }
-                return Err(format!(
-                    "Errore Graph API (pagina {}): {} - {}",
-                    page_count, status, error_text
+                return Err(ApiError::from_response(
+                    &format!("Errore Graph API (pagina {})", page_count),
+                    status.as_u16(),
+                    &error_text,
                 ));
             }
 
@@ -692,7 +714,7 @@ impl SharePointClient {
         &mut self,
         list_name: &str,
         filter: Option<&str>,
-    ) -> Result<Vec<serde_json::Value>, String> {
+    ) -> Result<Vec<serde_json::Value>, ApiError> {
         // Usa la stessa logica di get_list_items ma restituisci all_results invece di items
         // Chiama get_list_items internamente per ottenere all_results
         // Per semplicità, duplichiamo la logica di get_list_items ma restituiamo all_results
@@ -709,7 +731,7 @@ impl SharePointClient {
             .config
             .access_token
             .as_ref()
-            .ok_or("Access token non disponibile. Esegui prima l'autenticazione.")?;
+            .ok_or_else(|| ApiError::config("Access token non disponibile. Esegui prima l'autenticazione."))?;
 
         // Estrai hostname e path dal site_url
         let site_url = self.config.site_url.trim_end_matches('/');
@@ -737,12 +759,16 @@ impl SharePointClient {
             .header("Expires", "0")
             .send()
             .await
-            .map_err(|e| format!("Errore nella richiesta ID sito: {}", e))?;
+            .map_err(|e| ApiError::network("Errore nella richiesta ID sito", e))?;
 
         if !site_response.status().is_success() {
             let status = site_response.status();
             let error_text = site_response.text().await.unwrap_or_default();
-            return Err(format!("Errore nell'ottenimento ID sito: {} - {}", status, error_text));
+            return Err(ApiError::from_response(
+                "Errore nell'ottenimento ID sito",
+                status.as_u16(),
+                &error_text,
+            ));
         }
 
         let site_json: serde_json::Value = site_response
@@ -770,12 +796,16 @@ impl SharePointClient {
             .header("Expires", "0")
             .send()
             .await
-            .map_err(|e| format!("Errore nella richiesta ID lista: {}", e))?;
+            .map_err(|e| ApiError::network("Errore nella richiesta ID lista", e))?;
 
         if !list_response.status().is_success() {
             let status = list_response.status();
             let error_text = list_response.text().await.unwrap_or_default();
-            return Err(format!("Errore nell'ottenimento ID lista: {} - {}", status, error_text));
+            return Err(ApiError::from_response(
+                "Errore nell'ottenimento ID lista",
+                status.as_u16(),
+                &error_text,
+            ));
         }
 
         let list_json: serde_json::Value = list_response
@@ -785,7 +815,7 @@ impl SharePointClient {
 
         let list_id = list_json["value"][0]["id"]
             .as_str()
-            .ok_or(format!("Lista '{}' non trovata", list_name))?;
+            .ok_or_else(|| ApiError::not_found(format!("Lista '{}' non trovata", list_name)))?;
         
         println!("✓ ID lista ottenuto: {}", list_id);
         
@@ -825,12 +855,16 @@ impl SharePointClient {
                 .header("Authorization", format!("Bearer {}", access_token))
                 .send()
                 .await
-                .map_err(|e| format!("Errore nella richiesta items (pagina {}): {}", page_count, e))?;
+                .map_err(|e| ApiError::network(format!("Errore nella richiesta items (pagina {})", page_count), e))?;
 
             if !items_response.status().is_success() {
                 let status = items_response.status();
                 let error_text = items_response.text().await.unwrap_or_default();
-                return Err(format!("Errore Graph API (pagina {}): {} - {}", page_count, status, error_text));
+                return Err(ApiError::from_response(
+                    &format!("Errore Graph API (pagina {})", page_count),
+                    status.as_u16(),
+                    &error_text,
+                ));
             }
 
             let items_json: serde_json::Value = items_response
@@ -871,7 +905,7 @@ impl SharePointClient {
         list_name: &str,
         item_id: u32,
         data: &HashMap<String, serde_json::Value>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         // Verifica e aggiorna il token se necessario
         self.ensure_valid_token().await?;
         
@@ -879,7 +913,7 @@ impl SharePointClient {
             .config
             .access_token
             .as_ref()
-            .ok_or("Access token non disponibile")?;
+            .ok_or_else(|| ApiError::config("Access token non disponibile"))?;
 
         let site_url = self.config.site_url.trim_end_matches('/');
         let api_url = format!(
@@ -913,14 +947,15 @@ impl SharePointClient {
             .json(&sharepoint_data)
             .send()
             .await
-            .map_err(|e| format!("Errore nella richiesta HTTP: {}", e))?;
+            .map_err(|e| ApiError::network("Errore nella richiesta HTTP", e))?;
 
         if !response.status().is_success() {
             let status = response.status();
             let error_text = response.text().await.unwrap_or_default();
-            return Err(format!(
-                "Errore nell'aggiornamento: {} - {}",
-                status, error_text
+            return Err(ApiError::from_response(
+                "Errore nell'aggiornamento",
+                status.as_u16(),
+                &error_text,
             ));
         }
 
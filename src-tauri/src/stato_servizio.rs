@@ -0,0 +1,441 @@
+// Ciclo di vita del servizio: richiesto → confermato → assegnato → in corso →
+// completato, con annullato e non presentato come uscite. Lo stato resta testo
+// libero in StatoServizio (lookup StatoDelServizio_supa): qui si decide quali
+// valori sono ammessi, quali passaggi e con quali campi compilati.
+//
+// I valori storici restano validi: "DA ESEGUIRE" vale confermato (assegnato se
+// c'è già l'operatore) ed "ESEGUITO" vale completato. Si possono saltare passi in
+// avanti (un servizio registrato a cose fatte nasce già completato) purché i
+// campi di ogni passo saltato siano compilati; tornare indietro o riaprire un
+// servizio chiuso lo può fare solo un amministratore.
+use serde::Serialize;
+
+use crate::error::ApiError;
+use crate::permessi;
+use crate::UserPermissionsRecord;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StatoServizio {
+    Richiesto,
+    Confermato,
+    Assegnato,
+    InCorso,
+    Completato,
+    Annullato,
+    NonPresentato,
+}
+
+pub const TUTTI_GLI_STATI: [StatoServizio; 7] = [
+    StatoServizio::Richiesto,
+    StatoServizio::Confermato,
+    StatoServizio::Assegnato,
+    StatoServizio::InCorso,
+    StatoServizio::Completato,
+    StatoServizio::Annullato,
+    StatoServizio::NonPresentato,
+];
+
+/// Percorso normale, nell'ordine
+const PERCORSO: [StatoServizio; 5] = [
+    StatoServizio::Richiesto,
+    StatoServizio::Confermato,
+    StatoServizio::Assegnato,
+    StatoServizio::InCorso,
+    StatoServizio::Completato,
+];
+
+/// Campi del servizio che decidono se uno stato è raggiungibile (dopo il salvataggio)
+#[derive(Debug, Default)]
+pub struct DatiStato {
+    pub operatore: String,
+    pub mezzo: String,
+    pub km: String,
+    pub motivo_annullamento: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatoInfo {
+    pub etichetta: &'static str,
+    pub successivi: Vec<&'static str>,
+    pub campi_obbligatori: Vec<&'static str>,
+    pub finale: bool,
+}
+
+impl StatoServizio {
+    /// Valore scritto in StatoServizio
+    pub fn etichetta(self) -> &'static str {
+        match self {
+            StatoServizio::Richiesto => "RICHIESTO",
+            StatoServizio::Confermato => "CONFERMATO",
+            StatoServizio::Assegnato => "ASSEGNATO",
+            StatoServizio::InCorso => "IN CORSO",
+            StatoServizio::Completato => "COMPLETATO",
+            StatoServizio::Annullato => "ANNULLATO",
+            StatoServizio::NonPresentato => "NON PRESENTATO",
+        }
+    }
+
+    /// Riconosce etichette e valori storici; None se il testo non è uno stato
+    pub fn da_testo(testo: &str, dati: &DatiStato) -> Option<Self> {
+        let testo = testo.trim().to_uppercase().replace('_', " ");
+        match testo.as_str() {
+            "DA ESEGUIRE" if !dati.operatore.trim().is_empty() => Some(StatoServizio::Assegnato),
+            "DA ESEGUIRE" => Some(StatoServizio::Confermato),
+            "ESEGUITO" => Some(StatoServizio::Completato),
+            "NON PRESENTE" => Some(StatoServizio::NonPresentato),
+            t => TUTTI_GLI_STATI.iter().copied().find(|s| s.etichetta() == t),
+        }
+    }
+
+    pub fn finale(self) -> bool {
+        matches!(
+            self,
+            StatoServizio::Completato | StatoServizio::Annullato | StatoServizio::NonPresentato
+        )
+    }
+
+    fn posizione(self) -> Option<usize> {
+        PERCORSO.iter().position(|s| *s == self)
+    }
+
+    /// Passaggi diretti ammessi (i salti in avanti passano per questi)
+    pub fn successivi(self) -> Vec<StatoServizio> {
+        use StatoServizio::*;
+        match self {
+            Richiesto => vec![Confermato, Annullato],
+            Confermato => vec![Assegnato, Annullato],
+            Assegnato => vec![InCorso, Completato, Confermato, Annullato, NonPresentato],
+            InCorso => vec![Completato, Annullato, NonPresentato],
+            Completato | Annullato | NonPresentato => Vec::new(),
+        }
+    }
+
+    /// Campi da compilare per stare in questo stato
+    pub fn campi_obbligatori(self) -> Vec<&'static str> {
+        use StatoServizio::*;
+        match self {
+            Richiesto | Confermato => Vec::new(),
+            Assegnato | NonPresentato => vec!["operatore"],
+            InCorso => vec!["operatore", "mezzo"],
+            Completato => vec!["operatore", "km"],
+            Annullato => vec!["motivo_annullamento"],
+        }
+    }
+
+    pub fn info(self) -> StatoInfo {
+        StatoInfo {
+            etichetta: self.etichetta(),
+            successivi: self.successivi().iter().map(|s| s.etichetta()).collect(),
+            campi_obbligatori: self.campi_obbligatori(),
+            finale: self.finale(),
+        }
+    }
+}
+
+fn campi_mancanti(stato: StatoServizio, dati: &DatiStato) -> Vec<&'static str> {
+    stato
+        .campi_obbligatori()
+        .into_iter()
+        .filter(|campo| match *campo {
+            "operatore" => dati.operatore.trim().is_empty(),
+            "mezzo" => dati.mezzo.trim().is_empty(),
+            "km" => crate::parse_decimal_for_db(&dati.km) <= 0.0,
+            "motivo_annullamento" => dati.motivo_annullamento.trim().is_empty(),
+            _ => false,
+        })
+        .collect()
+}
+
+fn etichetta_campo(campo: &str) -> &'static str {
+    match campo {
+        "operatore" => "operatore",
+        "mezzo" => "mezzo",
+        "km" => "km percorsi",
+        "motivo_annullamento" => "motivo dell'annullamento",
+        _ => "campo",
+    }
+}
+
+fn non_ammessa(da: StatoServizio, a: StatoServizio) -> ApiError {
+    let ammessi: Vec<&str> = da.successivi().iter().map(|s| s.etichetta()).collect();
+    ApiError::Conflict {
+        code: Some("transizione_non_ammessa".to_string()),
+        message: format!(
+            "Da {} non si passa a {} (ammessi: {})",
+            da.etichetta(),
+            a.etichetta(),
+            if ammessi.is_empty() { "nessuno, servizio chiuso".to_string() } else { ammessi.join(", ") }
+        ),
+    }
+}
+
+/// Passaggio diretto, oppure salto in avanti lungo il percorso attraverso stati
+/// intermedi di cui i dati hanno già i campi obbligatori
+fn raggiungibile(da: StatoServizio, a: StatoServizio, dati: &DatiStato) -> bool {
+    let mut da_visitare = vec![da];
+    let mut visitati = Vec::new();
+    while let Some(stato) = da_visitare.pop() {
+        if stato.successivi().contains(&a) {
+            return true;
+        }
+        visitati.push(stato);
+        for successivo in stato.successivi() {
+            let in_avanti = matches!(
+                (stato.posizione(), successivo.posizione()),
+                (Some(i), Some(j)) if j > i
+            );
+            if in_avanti
+                && !visitati.contains(&successivo)
+                && campi_mancanti(successivo, dati).is_empty()
+            {
+                da_visitare.push(successivo);
+            }
+        }
+    }
+    false
+}
+
+/// Controlla il passaggio `da` → `a` (da None = servizio nuovo) con i dati che
+/// avrà il servizio dopo il salvataggio. Vale anche se lo stato non cambia: i
+/// campi obbligatori dello stato devono restare compilati.
+pub fn verifica_transizione(
+    da: Option<StatoServizio>,
+    a: StatoServizio,
+    dati: &DatiStato,
+    perm: &UserPermissionsRecord,
+) -> Result<(), ApiError> {
+    let nuovo = da.is_none();
+    let da = da.unwrap_or(StatoServizio::Richiesto);
+    // Stato invariato: i campi obbligatori valgono solo per chi ci arriva adesso,
+    // così le righe storiche (es. ESEGUITO senza km) restano modificabili
+    if !nuovo && da == a {
+        return Ok(());
+    }
+
+    let mancanti = campi_mancanti(a, dati);
+    if !mancanti.is_empty() {
+        let nomi: Vec<&str> = mancanti.iter().map(|c| etichetta_campo(c)).collect();
+        return Err(format!("Per lo stato {} serve: {}", a.etichetta(), nomi.join(", ")).into());
+    }
+    if da == a {
+        return Ok(());
+    }
+
+    if !raggiungibile(da, a, dati) {
+        if perm.is_admin {
+            println!("ℹ️ Stato {} → {} forzato da un amministratore", da.etichetta(), a.etichetta());
+            return Ok(());
+        }
+        return Err(non_ammessa(da, a));
+    }
+
+    // Chi vede solo i propri servizi li porta avanti, ma non conferma, assegna o
+    // annulla (la creazione la decide già la capacità servizi.crea)
+    if !nuovo && permessi::solo_propri(perm) {
+        let consentito = matches!(
+            a,
+            StatoServizio::InCorso | StatoServizio::Completato | StatoServizio::NonPresentato
+        ) && da >= StatoServizio::Assegnato;
+        if !consentito {
+            return Err(ApiError::permission_denied(format!(
+                "Un operatore non può portare il servizio da {} a {}",
+                da.etichetta(),
+                a.etichetta()
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StatoServizio::*;
+    use super::*;
+    use crate::permessi::Capacita;
+
+    fn utente(is_admin: bool, solo_propri: bool) -> UserPermissionsRecord {
+        UserPermissionsRecord {
+            user_id: "u1".to_string(),
+            username: "prova".to_string(),
+            is_admin,
+            programma: true,
+            calendario: true,
+            sidebar_menu: serde_json::Value::Null,
+            ruolo: String::new(),
+            capacita_extra: serde_json::Value::Null,
+            capacita: if solo_propri {
+                vec![Capacita::ServiziSoloPropri.codice().to_string()]
+            } else {
+                Vec::new()
+            },
+            idsocio: "12".to_string(),
+        }
+    }
+
+    fn dati(operatore: &str, mezzo: &str, km: &str, motivo: &str) -> DatiStato {
+        DatiStato {
+            operatore: operatore.to_string(),
+            mezzo: mezzo.to_string(),
+            km: km.to_string(),
+            motivo_annullamento: motivo.to_string(),
+        }
+    }
+
+    fn codice(esito: Result<(), ApiError>) -> Option<String> {
+        match esito {
+            Ok(()) => None,
+            Err(ApiError::Conflict { code, .. }) => code,
+            Err(ApiError::PermissionDenied { .. }) => Some("permesso".to_string()),
+            Err(_) => Some("campi".to_string()),
+        }
+    }
+
+    #[test]
+    fn valori_storici() {
+        let con_operatore = dati("Rossi", "", "", "");
+        assert_eq!(
+            StatoServizio::da_testo("da_eseguire", &con_operatore),
+            Some(Assegnato)
+        );
+        assert_eq!(
+            StatoServizio::da_testo("DA ESEGUIRE", &DatiStato::default()),
+            Some(Confermato)
+        );
+        assert_eq!(
+            StatoServizio::da_testo(" eseguito ", &DatiStato::default()),
+            Some(Completato)
+        );
+        assert_eq!(
+            StatoServizio::da_testo("NON PRESENTE", &DatiStato::default()),
+            Some(NonPresentato)
+        );
+        assert_eq!(
+            StatoServizio::da_testo("SOSPESO", &DatiStato::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn transizioni_ammesse_e_negate() {
+        let segreteria = utente(false, false);
+        let tutto = dati("Rossi", "Doblò", "42", "");
+        let casi: &[(Option<StatoServizio>, StatoServizio, Option<&str>)] = &[
+            (Some(Richiesto), Confermato, None),
+            (Some(Assegnato), InCorso, None),
+            (Some(Assegnato), Confermato, None),
+            (Some(InCorso), Completato, None),
+            (Some(InCorso), NonPresentato, None),
+            // Indietro o da uno stato chiuso: non ammesso
+            (Some(InCorso), Assegnato, Some("transizione_non_ammessa")),
+            (Some(Completato), InCorso, Some("transizione_non_ammessa")),
+            (Some(Annullato), Richiesto, Some("transizione_non_ammessa")),
+            (
+                Some(NonPresentato),
+                Completato,
+                Some("transizione_non_ammessa"),
+            ),
+        ];
+        for (da, a, atteso) in casi {
+            let esito = verifica_transizione(*da, *a, &tutto, &segreteria);
+            assert_eq!(codice(esito).as_deref(), *atteso, "{:?} → {:?}", da, a);
+        }
+    }
+
+    #[test]
+    fn salti_in_avanti_con_i_campi_compilati() {
+        let segreteria = utente(false, false);
+        // Richiesto → Completato passa da Confermato e Assegnato (operatore c'è)
+        assert!(verifica_transizione(
+            Some(Richiesto),
+            Completato,
+            &dati("Rossi", "", "42", ""),
+            &segreteria
+        )
+        .is_ok());
+        // Servizio registrato a cose fatte
+        assert!(
+            verifica_transizione(None, Completato, &dati("Rossi", "", "42", ""), &segreteria)
+                .is_ok()
+        );
+        // Campi dello stato di arrivo mancanti
+        assert_eq!(
+            codice(verifica_transizione(
+                Some(Richiesto),
+                Completato,
+                &dati("Rossi", "", "", ""),
+                &segreteria
+            ))
+            .as_deref(),
+            Some("campi")
+        );
+        assert_eq!(
+            codice(verifica_transizione(
+                Some(Assegnato),
+                InCorso,
+                &dati("Rossi", "", "", ""),
+                &segreteria
+            ))
+            .as_deref(),
+            Some("campi")
+        );
+        assert_eq!(
+            codice(verifica_transizione(
+                Some(Confermato),
+                Annullato,
+                &dati("", "", "", ""),
+                &segreteria
+            ))
+            .as_deref(),
+            Some("campi")
+        );
+        // Stato invariato: le righe storiche senza campi restano modificabili
+        assert!(verifica_transizione(
+            Some(Completato),
+            Completato,
+            &DatiStato::default(),
+            &segreteria
+        )
+        .is_ok());
+        // ...ma un servizio nuovo deve averli
+        assert!(verifica_transizione(None, Assegnato, &DatiStato::default(), &segreteria).is_err());
+    }
+
+    #[test]
+    fn amministratore_forza_i_passaggi() {
+        let admin = utente(true, false);
+        let tutto = dati("Rossi", "Doblò", "42", "");
+        assert!(verifica_transizione(Some(Completato), InCorso, &tutto, &admin).is_ok());
+        assert!(verifica_transizione(Some(Annullato), Richiesto, &tutto, &admin).is_ok());
+        // I campi obbligatori valgono anche per l'amministratore
+        assert!(verifica_transizione(
+            Some(Completato),
+            InCorso,
+            &dati("Rossi", "", "", ""),
+            &admin
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn operatore_solo_propri_porta_avanti_ma_non_conferma_ne_annulla() {
+        let operatore = utente(false, true);
+        let tutto = dati("Rossi", "Doblò", "42", "annullato dal socio");
+        let casi: &[(Option<StatoServizio>, StatoServizio, Option<&str>)] = &[
+            (Some(Assegnato), InCorso, None),
+            (Some(InCorso), Completato, None),
+            (Some(Assegnato), NonPresentato, None),
+            (Some(Richiesto), Confermato, Some("permesso")),
+            (Some(Confermato), Assegnato, Some("permesso")),
+            (Some(InCorso), Annullato, Some("permesso")),
+            (Some(Assegnato), Confermato, Some("permesso")),
+            // Indietro resta non ammesso prima ancora del permesso
+            (Some(Completato), InCorso, Some("transizione_non_ammessa")),
+            // La creazione la decide la capacità servizi.crea
+            (None, Confermato, None),
+        ];
+        for (da, a, atteso) in casi {
+            let esito = verifica_transizione(*da, *a, &tutto, &operatore);
+            assert_eq!(codice(esito).as_deref(), *atteso, "{:?} → {:?}", da, a);
+        }
+    }
+}
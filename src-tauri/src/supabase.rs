@@ -2,8 +2,17 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use chrono::NaiveTime;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::auth::{AuthStore, SessioneAuth};
+use crate::error::ApiError;
+use crate::offline::{self, IdRiassegnato, OfflineStore, OutboxEntry, OutboxOp, OutboxProblema, OutboxSyncReport};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct SupabaseTablesConfig {
     pub tesserati: String,
     pub servizi: String,
@@ -23,6 +32,20 @@ pub struct SupabaseTablesConfig {
     pub user_permissions: String,
     /// Storico annuale tesseramenti (più righe per IdSocio)
     pub tesseramenti: String,
+    /// Prenotazioni temporanee dei nuovi IdSocio
+    pub prenotazioni_idsocio: String,
+    /// Ruoli personalizzati (i predefiniti sono nel codice)
+    pub ruoli: String,
+    /// Registro delle modifiche (solo inserimenti)
+    pub audit: String,
+    /// Servizi eliminati, ripristinabili fino alla pulizia
+    pub cestino_servizi: String,
+    /// Versioni della tariffa, ciascuna valida da una data
+    pub tariffe: String,
+    /// Regole della tariffa (gratuità, sconti, quote fisse, minimi, supplementi)
+    pub regole_tariffa: String,
+    /// Serie di servizi ricorrenti (regola e modello)
+    pub serie_servizi: String,
 }
 
 impl SupabaseTablesConfig {
@@ -45,28 +68,967 @@ impl SupabaseTablesConfig {
             "tratte" => Some(&self.tratte),
             "user_permissions" => Some(&self.user_permissions),
             "tesseramenti" => Some(&self.tesseramenti),
+            "prenotazioni_idsocio" => Some(&self.prenotazioni_idsocio),
+            "ruoli" => Some(&self.ruoli),
+            "audit" => Some(&self.audit),
+            "cestino_servizi" => Some(&self.cestino_servizi),
+            "tariffe" => Some(&self.tariffe),
+            "regole_tariffa" => Some(&self.regole_tariffa),
+            "serie_servizi" => Some(&self.serie_servizi),
+            _ => None,
+        }
+    }
+
+    /// Chiave primaria usata per la paginazione keyset (cursore).
+    /// Solo tabelle con nome colonna certo: le altre paginano per offset.
+    pub fn primary_key(table_type: &str) -> Option<&'static str> {
+        match table_type {
+            "tesserati" => Some("IdSocio"),
+            "servizi" => Some("idservizio"),
+            "tratte" => Some("IdTratta"),
+            "stato_del_servizio" => Some("id"),
+            "telefoni" => Some("id"),
+            "email" => Some("id"),
+            "tesseramenti" => Some("id"),
+            "user_permissions" => Some("user_id"),
+            "prenotazioni_idsocio" => Some("IdSocio"),
+            "ruoli" => Some("codice"),
+            "audit" => Some("chiave"),
+            "cestino_servizi" => Some("idservizio"),
+            "tariffe" => Some("valido_dal"),
+            "regole_tariffa" => Some("codice"),
+            "serie_servizi" => Some("id_serie"),
             _ => None,
         }
     }
 }
 
+/// Timeout e ripetizioni automatiche delle richieste (sezione `supabase.http` di config.json)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SupabaseHttpConfig {
+    /// Tempo massimo per aprire la connessione (TCP + TLS); scaduto, la
+    /// richiesta si ripete come un errore di connessione
+    pub connect_timeout_secs: u64,
+    /// Tempo massimo per l'intera richiesta, dalla connessione all'ultimo byte
+    /// della risposta: reqwest 0.11 non ha un timeout di sola lettura, quindi
+    /// una pagina di 1000 righe su rete lenta deve stare in questo tempo
+    #[serde(alias = "read_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Tentativi aggiuntivi dopo il primo (solo GET e PATCH su chiave)
+    pub max_retries: u32,
+    /// Attesa prima del primo nuovo tentativo, raddoppiata a ogni giro
+    pub retry_base_delay_ms: u64,
+    /// Attesa massima fra due tentativi; anche un `Retry-After` più lungo si
+    /// ferma a questo valore
+    pub retry_max_delay_ms: u64,
+}
+
+impl Default for SupabaseHttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: 10,
+            request_timeout_secs: 30,
+            max_retries: 3,
+            retry_base_delay_ms: 500,
+            retry_max_delay_ms: 8000,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SupabaseConfig {
     pub url: String,
+    /// Chiave pubblica (publishable o anon): le richieste girano col JWT dell'utente collegato
     pub anon_key: String,
+    /// Chiave secret / service_role: solo per le operazioni di amministrazione
+    #[serde(default)]
+    pub service_key: Option<String>,
     pub tables: SupabaseTablesConfig,
+    #[serde(default)]
+    pub http: SupabaseHttpConfig,
 }
 
+/// Singola condizione PostgREST (colonna.operatore.valore) o gruppo logico or/and.
+#[derive(Debug, Clone)]
+pub enum Filter {
+    Condition {
+        column: String,
+        op: &'static str,
+        value: FilterValue,
+        negated: bool,
+    },
+    Or(Vec<Filter>),
+    #[allow(dead_code)]
+    And(Vec<Filter>),
+}
+
+/// Valore di un filtro: testo libero (da quotare/codificare), lista per `in`
+/// oppure letterale `null`/`true`/`false` per `is`.
+#[derive(Debug, Clone)]
+pub enum FilterValue {
+    Text(String),
+    List(Vec<String>),
+    Literal(&'static str),
+}
+
+// Builder completo: gli operatori non ancora usati dai comandi hanno
+// #[allow(dead_code)] uno per uno
+impl Filter {
+    fn condition(column: &str, op: &'static str, value: FilterValue) -> Self {
+        Filter::Condition {
+            column: column.to_string(),
+            op,
+            value,
+            negated: false,
+        }
+    }
+
+    pub fn eq(column: &str, value: impl ToString) -> Self {
+        Self::condition(column, "eq", FilterValue::Text(value.to_string()))
+    }
+
+    pub fn neq(column: &str, value: impl ToString) -> Self {
+        Self::condition(column, "neq", FilterValue::Text(value.to_string()))
+    }
+
+    pub fn gt(column: &str, value: impl ToString) -> Self {
+        Self::condition(column, "gt", FilterValue::Text(value.to_string()))
+    }
+
+    pub fn gte(column: &str, value: impl ToString) -> Self {
+        Self::condition(column, "gte", FilterValue::Text(value.to_string()))
+    }
+
+    pub fn lt(column: &str, value: impl ToString) -> Self {
+        Self::condition(column, "lt", FilterValue::Text(value.to_string()))
+    }
+
+    pub fn lte(column: &str, value: impl ToString) -> Self {
+        Self::condition(column, "lte", FilterValue::Text(value.to_string()))
+    }
+
+    /// Pattern PostgREST: `*` è il carattere jolly (es. `*rossi*`)
+    #[allow(dead_code)]
+    pub fn ilike(column: &str, pattern: impl ToString) -> Self {
+        Self::condition(column, "ilike", FilterValue::Text(pattern.to_string()))
+    }
+
+    pub fn in_list<I, S>(column: &str, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        Self::condition(
+            column,
+            "in",
+            FilterValue::List(values.into_iter().map(|v| v.to_string()).collect()),
+        )
+    }
+
+    pub fn is_null(column: &str) -> Self {
+        Self::condition(column, "is", FilterValue::Literal("null"))
+    }
+
+    pub fn is_true(column: &str) -> Self {
+        Self::condition(column, "is", FilterValue::Literal("true"))
+    }
+
+    #[allow(dead_code)]
+    pub fn is_false(column: &str) -> Self {
+        Self::condition(column, "is", FilterValue::Literal("false"))
+    }
+
+    pub fn not_null(column: &str) -> Self {
+        Self::is_null(column).not()
+    }
+
+    pub fn or(filters: Vec<Filter>) -> Self {
+        Filter::Or(filters)
+    }
+
+    #[allow(dead_code)]
+    pub fn and(filters: Vec<Filter>) -> Self {
+        Filter::And(filters)
+    }
+
+    /// Nega la condizione (`not.eq.`, `not.is.` …). Sui gruppi non ha effetto.
+    pub fn not(self) -> Self {
+        match self {
+            Filter::Condition {
+                column, op, value, ..
+            } => Filter::Condition {
+                column,
+                op,
+                value,
+                negated: true,
+            },
+            other => other,
+        }
+    }
+
+    /// Coppia chiave=valore per la query string (già codificata)
+    fn to_query_pair(&self) -> String {
+        match self {
+            Filter::Condition {
+                column,
+                op,
+                value,
+                negated,
+            } => {
+                let not = if *negated { "not." } else { "" };
+                let value = match value {
+                    FilterValue::Text(v) => v.clone(),
+                    FilterValue::List(list) => format!(
+                        "({})",
+                        list.iter()
+                            .map(|v| quote_filter_value(v))
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    ),
+                    FilterValue::Literal(l) => l.to_string(),
+                };
+                format!(
+                    "{}={}{}.{}",
+                    encode_query_value(column),
+                    not,
+                    op,
+                    encode_query_value(&value)
+                )
+            }
+            Filter::Or(list) => format!("or={}", encode_query_value(&render_group(list))),
+            Filter::And(list) => format!("and={}", encode_query_value(&render_group(list))),
+        }
+    }
+
+    /// Forma annidata usata dentro `or=(...)` / `and=(...)`: colonna.op.valore
+    fn to_nested(&self) -> String {
+        match self {
+            Filter::Condition {
+                column,
+                op,
+                value,
+                negated,
+            } => {
+                let not = if *negated { "not." } else { "" };
+                let value = match value {
+                    FilterValue::Text(v) => quote_filter_value(v),
+                    FilterValue::List(list) => format!(
+                        "({})",
+                        list.iter()
+                            .map(|v| quote_filter_value(v))
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    ),
+                    FilterValue::Literal(l) => l.to_string(),
+                };
+                format!("{}.{}{}.{}", column, not, op, value)
+            }
+            Filter::Or(list) => format!("or{}", render_group(list)),
+            Filter::And(list) => format!("and{}", render_group(list)),
+        }
+    }
+}
+
+fn render_group(list: &[Filter]) -> String {
+    format!(
+        "({})",
+        list.iter()
+            .map(Filter::to_nested)
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+/// Valori con caratteri riservati PostgREST (`,` `.` `:` `(` `)` `"` `\` o spazi)
+/// vanno racchiusi tra doppi apici, con `"` e `\` preceduti da backslash.
+fn quote_filter_value(value: &str) -> String {
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| matches!(c, ',' | '.' | ':' | '(' | ')' | '"' | '\\') || c.is_whitespace());
+    if !needs_quotes {
+        return value.to_string();
+    }
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{}\"", escaped)
+}
+
+#[derive(Debug, Clone)]
+struct OrderBy {
+    column: String,
+    ascending: bool,
+    nulls_last: bool,
+}
+
+/// Query PostgREST tipizzata: select, filtri, ordinamento e range.
+/// I valori vengono codificati qui, i chiamanti non costruiscono più stringhe a mano.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    select: Vec<String>,
+    filters: Vec<Filter>,
+    order: Vec<OrderBy>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn select(mut self, columns: &[&str]) -> Self {
+        self.select = columns.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    pub fn eq(self, column: &str, value: impl ToString) -> Self {
+        self.filter(Filter::eq(column, value))
+    }
+
+    pub fn neq(self, column: &str, value: impl ToString) -> Self {
+        self.filter(Filter::neq(column, value))
+    }
+
+    pub fn gt(self, column: &str, value: impl ToString) -> Self {
+        self.filter(Filter::gt(column, value))
+    }
+
+    pub fn gte(self, column: &str, value: impl ToString) -> Self {
+        self.filter(Filter::gte(column, value))
+    }
+
+    pub fn lt(self, column: &str, value: impl ToString) -> Self {
+        self.filter(Filter::lt(column, value))
+    }
+
+    pub fn lte(self, column: &str, value: impl ToString) -> Self {
+        self.filter(Filter::lte(column, value))
+    }
+
+    #[allow(dead_code)]
+    pub fn ilike(self, column: &str, pattern: impl ToString) -> Self {
+        self.filter(Filter::ilike(column, pattern))
+    }
+
+    pub fn in_list<I, S>(self, column: &str, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        self.filter(Filter::in_list(column, values))
+    }
+
+    pub fn is_null(self, column: &str) -> Self {
+        self.filter(Filter::is_null(column))
+    }
+
+    pub fn not_null(self, column: &str) -> Self {
+        self.filter(Filter::not_null(column))
+    }
+
+    pub fn or(self, filters: Vec<Filter>) -> Self {
+        self.filter(Filter::or(filters))
+    }
+
+    #[allow(dead_code)]
+    pub fn and(self, filters: Vec<Filter>) -> Self {
+        self.filter(Filter::and(filters))
+    }
+
+    pub fn order(mut self, column: &str, ascending: bool) -> Self {
+        self.order.push(OrderBy {
+            column: column.to_string(),
+            ascending,
+            nulls_last: false,
+        });
+        self
+    }
+
+    pub fn order_nulls_last(mut self, column: &str, ascending: bool) -> Self {
+        self.order.push(OrderBy {
+            column: column.to_string(),
+            ascending,
+            nulls_last: true,
+        });
+        self
+    }
+
+    /// Applica `order` solo se il chiamante non ne ha già indicato uno
+    pub fn default_order(self, column: &str, ascending: bool) -> Self {
+        if self.order.is_empty() {
+            self.order(column, ascending)
+        } else {
+            self
+        }
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn range(mut self, offset: usize, limit: usize) -> Self {
+        self.offset = Some(offset);
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn has_order(&self) -> bool {
+        !self.order.is_empty()
+    }
+
+    /// Query string completa (senza `?` iniziale), valori già codificati
+    pub fn to_query_string(&self) -> String {
+        let mut parts: Vec<String> = Vec::new();
+        if !self.select.is_empty() {
+            parts.push(format!(
+                "select={}",
+                self.select
+                    .iter()
+                    .map(|c| encode_query_value(c))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ));
+        }
+        parts.extend(self.filters.iter().map(Filter::to_query_pair));
+        if !self.order.is_empty() {
+            parts.push(format!(
+                "order={}",
+                self.order
+                    .iter()
+                    .map(|o| {
+                        format!(
+                            "{}.{}{}",
+                            encode_query_value(&o.column),
+                            if o.ascending { "asc" } else { "desc" },
+                            if o.nulls_last { ".nullslast" } else { "" }
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ));
+        }
+        if let Some(limit) = self.limit {
+            parts.push(format!("limit={}", limit));
+        }
+        if let Some(offset) = self.offset {
+            parts.push(format!("offset={}", offset));
+        }
+        parts.join("&")
+    }
+}
+
+impl std::fmt::Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_query_string())
+    }
+}
+
+// Valutazione locale delle query sulla copia offline: stessa semantica di
+// PostgREST per i filtri usati dai comandi (confronti, ilike, in, is, or/and).
+impl Filter {
+    fn matches(&self, row: &Value) -> bool {
+        match self {
+            Filter::Or(list) => list.iter().any(|f| f.matches(row)),
+            Filter::And(list) => list.iter().all(|f| f.matches(row)),
+            Filter::Condition {
+                column,
+                op,
+                value,
+                negated,
+            } => {
+                let cell = row.get(column.as_str()).unwrap_or(&Value::Null);
+                let esito = match (*op, value) {
+                    ("is", FilterValue::Literal(l)) => match *l {
+                        "null" => cell.is_null(),
+                        "true" => cell.as_bool() == Some(true),
+                        "false" => cell.as_bool() == Some(false),
+                        _ => false,
+                    },
+                    // In SQL un confronto con NULL non è mai vero, neanche negato
+                    _ if cell.is_null() => return false,
+                    ("in", FilterValue::List(list)) => list
+                        .iter()
+                        .any(|v| compare_cell(cell, v) == std::cmp::Ordering::Equal),
+                    ("ilike", FilterValue::Text(pattern)) => ilike_match(&json_to_string(cell), pattern),
+                    (op, FilterValue::Text(v)) => {
+                        let ord = compare_cell(cell, v);
+                        match op {
+                            "eq" => ord.is_eq(),
+                            "neq" => ord.is_ne(),
+                            "gt" => ord.is_gt(),
+                            "gte" => ord.is_ge(),
+                            "lt" => ord.is_lt(),
+                            "lte" => ord.is_le(),
+                            _ => false,
+                        }
+                    }
+                    _ => false,
+                };
+                esito != *negated
+            }
+        }
+    }
+}
+
+/// Confronto cella/valore: numerico se entrambi sono numeri, altrimenti sul testo
+/// (le date ISO si confrontano correttamente anche come stringhe)
+fn compare_cell(cell: &Value, value: &str) -> std::cmp::Ordering {
+    let testo = json_to_string(cell);
+    match (testo.parse::<f64>(), value.parse::<f64>()) {
+        (Ok(a), Ok(b)) if cell.is_number() => a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal),
+        _ => testo.as_str().cmp(value),
+    }
+}
+
+/// `ilike` senza distinzione maiuscole: `*` e `%` qualsiasi sequenza, `_` un carattere
+fn ilike_match(text: &str, pattern: &str) -> bool {
+    let t: Vec<char> = text.to_lowercase().chars().collect();
+    let p: Vec<char> = pattern.to_lowercase().chars().collect();
+    let (mut ti, mut pi) = (0usize, 0usize);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '_' || p[pi] == t[ti]) {
+            ti += 1;
+            pi += 1;
+        } else if pi < p.len() && matches!(p[pi], '*' | '%') {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| matches!(c, '*' | '%'))
+}
+
+fn compare_for_order(a: &Value, b: &Value) -> std::cmp::Ordering {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(std::cmp::Ordering::Equal),
+        _ => json_to_string(a).cmp(&json_to_string(b)),
+    }
+}
+
+impl Query {
+    pub fn matches(&self, row: &Value) -> bool {
+        self.filters.iter().all(|f| f.matches(row))
+    }
+
+    /// Esegue la query su righe già in memoria (copia offline):
+    /// filtri, ordinamento, offset/limit e colonne selezionate.
+    pub fn apply_local(&self, rows: Vec<Value>) -> Vec<Value> {
+        let mut rows: Vec<Value> = rows.into_iter().filter(|r| self.matches(r)).collect();
+
+        rows.sort_by(|a, b| {
+            for o in &self.order {
+                let va = a.get(o.column.as_str()).unwrap_or(&Value::Null);
+                let vb = b.get(o.column.as_str()).unwrap_or(&Value::Null);
+                // PostgREST: NULL in fondo in ordine crescente, in cima in decrescente
+                let nulls_last = o.nulls_last || o.ascending;
+                let ord = match (va.is_null(), vb.is_null()) {
+                    (true, true) => std::cmp::Ordering::Equal,
+                    (true, false) if nulls_last => std::cmp::Ordering::Greater,
+                    (true, false) => std::cmp::Ordering::Less,
+                    (false, true) if nulls_last => std::cmp::Ordering::Less,
+                    (false, true) => std::cmp::Ordering::Greater,
+                    (false, false) if o.ascending => compare_for_order(va, vb),
+                    (false, false) => compare_for_order(vb, va),
+                };
+                if ord.is_ne() {
+                    return ord;
+                }
+            }
+            std::cmp::Ordering::Equal
+        });
+
+        let rows = rows
+            .into_iter()
+            .skip(self.offset.unwrap_or(0))
+            .take(self.limit.unwrap_or(usize::MAX));
+
+        if self.select.is_empty() {
+            return rows.collect();
+        }
+        rows.map(|row| {
+            let mut out = serde_json::Map::new();
+            for col in &self.select {
+                if let Some(v) = row.get(col.as_str()) {
+                    out.insert(col.clone(), v.clone());
+                }
+            }
+            Value::Object(out)
+        })
+        .collect()
+    }
+}
+
+const PAGE_SIZE: usize = 1000;
+
+/// Attesa richiesta dal server con `Retry-After` (secondi o data HTTP)
+fn retry_after(response: &reqwest::Response) -> Option<std::time::Duration> {
+    let raw = response.headers().get("retry-after")?.to_str().ok()?.trim();
+    if let Ok(secs) = raw.parse::<u64>() {
+        return Some(std::time::Duration::from_secs(secs));
+    }
+    let when = chrono::DateTime::parse_from_rfc2822(raw).ok()?;
+    (when.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+/// base · 2^tentativo, più un jitter fino a metà della base
+fn backoff_delay(retry: &SupabaseHttpConfig, attempt: u32) -> std::time::Duration {
+    use rand::Rng;
+    let base = retry.retry_base_delay_ms.max(1);
+    let exp = base.saturating_mul(1u64 << attempt.min(16));
+    let jitter = rand::thread_rng().gen_range(0..=base / 2);
+    std::time::Duration::from_millis(exp.saturating_add(jitter))
+}
+
+/// Cursore di paginazione su una tabella.
+///
+/// Se la query è ordinata solo per chiave primaria (o non è ordinata) usa il
+/// keyset `pk=gt.<ultimo valore>`: nessuna riga persa o duplicata anche se la
+/// tabella cambia durante la lettura e nessun limite sul numero di pagine.
+/// Con un ordinamento diverso ricade su limit/offset, aggiungendo la chiave
+/// primaria come ultimo criterio per rendere l'ordine deterministico.
+pub struct TablePager<'a> {
+    client: &'a SupabaseClient,
+    table_type: String,
+    table_name: String,
+    query: Query,
+    keyset: Option<&'static str>,
+    last_key: Option<String>,
+    offset: usize,
+    remaining: Option<usize>,
+    page: usize,
+    done: bool,
+}
+
+impl<'a> TablePager<'a> {
+    fn new(client: &'a SupabaseClient, table_type: &str, table_name: String, query: Query) -> Self {
+        let pk = SupabaseTablesConfig::primary_key(table_type);
+        let mut query = query;
+        let remaining = query.limit.take();
+        let offset = query.offset.take().unwrap_or(0);
+
+        let solo_pk = query.order.is_empty()
+            || (query.order.len() == 1
+                && Some(query.order[0].column.as_str()) == pk
+                && query.order[0].ascending);
+        let keyset = if solo_pk && offset == 0 { pk } else { None };
+
+        if let Some(pk) = keyset {
+            query.order.clear();
+            query = query.order(pk, true);
+            if !query.select.is_empty() && !query.select.iter().any(|c| c == pk) {
+                query.select.push(pk.to_string());
+            }
+        } else if let Some(pk) = pk {
+            if !query.order.is_empty() && !query.order.iter().any(|o| o.column == pk) {
+                query = query.order(pk, true);
+            }
+        }
+
+        Self {
+            client,
+            table_type: table_type.to_string(),
+            table_name,
+            query,
+            keyset,
+            last_key: None,
+            offset,
+            remaining,
+            page: 0,
+            done: false,
+        }
+    }
+
+    /// Prossima pagina di righe, `None` quando la tabella è esaurita
+    pub async fn next_page(&mut self) -> Result<Option<Vec<Value>>, ApiError> {
+        if self.done || self.remaining == Some(0) {
+            return Ok(None);
+        }
+
+        let page_size = self.remaining.map_or(PAGE_SIZE, |r| r.min(PAGE_SIZE));
+        let page_query = match (self.keyset, &self.last_key) {
+            (Some(pk), Some(last)) => self.query.clone().gt(pk, last).limit(page_size),
+            (Some(_), None) => self.query.clone().limit(page_size),
+            (None, _) => self.query.clone().range(self.offset, page_size),
+        };
+        let url = self.client.rest_url(&self.table_name, &page_query);
+        self.page += 1;
+
+        match (self.keyset, &self.last_key) {
+            (Some(pk), Some(last)) => println!(
+                "📡 Supabase GET [{} → {}] pagina {} ({} > {}): {}",
+                self.table_type, self.table_name, self.page, pk, last, url
+            ),
+            (Some(_), None) => println!(
+                "📡 Supabase GET [{} → {}] pagina {}: {}",
+                self.table_type, self.table_name, self.page, url
+            ),
+            (None, _) => println!(
+                "📡 Supabase GET [{} → {}] pagina {} (offset {}): {}",
+                self.table_type, self.table_name, self.page, self.offset, url
+            ),
+        }
+
+        let request = self
+            .client
+            .http
+            .get(&url)
+            .header("Content-Type", "application/json");
+
+        let response = self
+            .client
+            .send_with_retry(
+                self.client.apply_auth_headers(request),
+                "Errore connessione Supabase",
+            )
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(ApiError::from_response(
+                "Errore Supabase",
+                status.as_u16(),
+                &body,
+            ));
+        }
+
+        let batch: Vec<Value> = response
+            .json()
+            .await
+            .map_err(|e| format!("Errore parsing risposta Supabase: {}", e))?;
+
+        if batch.len() < page_size {
+            self.done = true;
+        }
+        if let Some(r) = self.remaining.as_mut() {
+            *r = r.saturating_sub(batch.len());
+        }
+        self.offset += batch.len();
+
+        if let Some(pk) = self.keyset {
+            let last = batch
+                .last()
+                .map(|row| get_field(row, pk))
+                .unwrap_or_default();
+            if last.is_empty() {
+                if !self.done {
+                    return Err(format!(
+                        "Paginazione {}: colonna chiave {} assente nella risposta",
+                        self.table_type, pk
+                    ).into());
+                }
+            } else {
+                self.last_key = Some(last);
+            }
+        } else if !self.done && self.query.order.is_empty() {
+            println!(
+                "  ⚠️ Supabase [{}]: pagina piena senza order — rischio righe mancanti/duplicate",
+                self.table_type
+            );
+        }
+
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(batch))
+    }
+}
+
+#[derive(Clone)]
 pub struct SupabaseClient {
     pub config: SupabaseConfig,
     http: reqwest::Client,
+    /// Copia locale + outbox: senza, ogni errore di rete arriva al chiamante
+    offline: Option<Arc<OfflineStore>>,
+    /// Dopo un errore di rete si lavora sulla copia locale senza riprovare
+    /// il server a ogni comando, finché `probe_online` non lo ritrova
+    offline_mode: Arc<AtomicBool>,
+    /// La funzione RPC `next_servizio_id` non esiste nel database: si usa max+1
+    senza_rpc_id_servizio: Arc<AtomicBool>,
+    /// Sessione dell'utente collegato: il suo JWT va in tutte le richieste non admin
+    auth: Arc<AuthStore>,
+    /// Ultime versioni lette di ogni riga ("tabella:chiave" → [(versione, riga)]):
+    /// in caso di modifica concorrente dicono quali campi sono cambiati
+    versioni_lette: Arc<Mutex<VersioniLette>>,
+}
+
+/// Funzione Postgres che assegna il prossimo idservizio da una sequenza
+/// (vedi docs/supabase-servizi-sequence.sql)
+const RPC_ID_SERVIZIO: &str = "next_servizio_id";
+
+/// Tentativi di inserimento di un nuovo servizio se l'ID scelto è già stato usato
+const TENTATIVI_NUOVO_SERVIZIO: u32 = 6;
+
+/// Tentativi di prenotazione di un IdSocio se un'altra postazione prende lo stesso numero
+const TENTATIVI_PRENOTAZIONE_IDSOCIO: u32 = 6;
+
+/// Righe lette per "tabella:chiave", dalla versione più vecchia alla più recente
+type VersioniLette = HashMap<String, Vec<(String, Value)>>;
+
+/// Tabelle salvate con controllo della versione (modifiche concorrenti)
+const TABELLE_VERSIONATE: &[&str] = &["servizi", "tesserati", "automezzi"];
+
+/// Versioni ricordate per riga: bastano a coprire le modifiche arrivate
+/// mentre una scheda resta aperta
+const VERSIONI_PER_RIGA: usize = 3;
+
+/// Letture e PATCH condizionati ripetuti se la riga cambia proprio durante il
+/// salvataggio in campi diversi da quelli inviati
+const TENTATIVI_PATCH_VERSIONATO: u32 = 3;
+
+/// IdSocio tenuto da parte per un nuovo socio finché non viene salvato o rilasciato
+#[derive(Debug, Clone, Serialize)]
+pub struct PrenotazioneIdSocio {
+    pub idsocio: String,
+    /// Da passare a create_socio_anagrafica / release_idsocio.
+    /// Vuoto se la tabella prenotazioni non esiste (numero solo indicativo).
+    pub token: String,
+    pub scade_il: Option<String>,
 }
 
 impl SupabaseClient {
     pub fn new(config: SupabaseConfig) -> Self {
+        let http = reqwest::Client::builder()
+            .connect_timeout(std::time::Duration::from_secs(config.http.connect_timeout_secs))
+            // Timeout totale della richiesta (connessione compresa)
+            .timeout(std::time::Duration::from_secs(config.http.request_timeout_secs))
+            .build()
+            .unwrap_or_else(|e| {
+                println!("⚠️ Client HTTP con timeout non creato ({}), uso quello predefinito", e);
+                reqwest::Client::new()
+            });
         Self {
             config,
-            http: reqwest::Client::new(),
+            http,
+            offline: None,
+            offline_mode: Arc::new(AtomicBool::new(false)),
+            senza_rpc_id_servizio: Arc::new(AtomicBool::new(false)),
+            auth: Arc::new(AuthStore::default()),
+            versioni_lette: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_offline_store(mut self, store: Option<Arc<OfflineStore>>) -> Self {
+        self.offline = store;
+        self
+    }
+
+    pub fn with_auth_store(mut self, store: Arc<AuthStore>) -> Self {
+        self.auth = store;
+        self
+    }
+
+    pub fn offline_store(&self) -> Option<&Arc<OfflineStore>> {
+        self.offline.as_ref()
+    }
+
+    fn set_offline_mode(&self, offline: bool) {
+        if self.offline_mode.swap(offline, AtomicOrdering::SeqCst) != offline {
+            if offline {
+                println!("📴 Supabase non raggiungibile: modalità offline (copia locale)");
+            } else {
+                println!("📶 Supabase di nuovo raggiungibile");
+            }
+        }
+    }
+
+    /// true se l'ultima richiesta è fallita per rete e si legge dalla copia locale
+    pub fn is_offline(&self) -> bool {
+        self.offline.is_some() && self.offline_mode.load(AtomicOrdering::SeqCst)
+    }
+
+    /// In modalità offline (e con la copia locale) evita la richiesta al server
+    fn offline_shortcut(&self) -> Option<ApiError> {
+        if self.offline.is_some() && self.offline_mode.load(AtomicOrdering::SeqCst) {
+            Some(ApiError::Network {
+                message: "Modalità offline: Supabase non raggiungibile".to_string(),
+                timeout: false,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Richiesta leggera senza ripetizioni: true se il server risponde.
+    /// Aggiorna la modalità offline.
+    pub async fn probe_online(&self) -> bool {
+        let url = self.rest_url(&self.config.tables.stato_del_servizio, &Query::new().limit(1));
+        let online = self
+            .apply_auth_headers(self.http.get(&url))
+            .send()
+            .await
+            .is_ok();
+        self.set_offline_mode(!online);
+        online
+    }
+
+    /// Invia una richiesta idempotente (GET, PATCH filtrato per chiave) ripetendola
+    /// sugli errori transitori: connessione fallita, timeout, HTTP 429/502/503/504.
+    /// Attesa esponenziale con jitter; se il server indica `Retry-After` vale quello.
+    /// Le risposte non transitorie (anche di errore) tornano al chiamante così come sono.
+    async fn send_with_retry(
+        &self,
+        request: reqwest::RequestBuilder,
+        context: &str,
+    ) -> Result<reqwest::Response, ApiError> {
+        let retry = &self.config.http;
+        let max_delay = std::time::Duration::from_millis(retry.retry_max_delay_ms);
+        let mut attempt: u32 = 0;
+
+        loop {
+            let this_try = match request.try_clone() {
+                Some(r) => r,
+                // Corpo non clonabile (stream): un solo tentativo
+                None => return request.send().await.map_err(|e| ApiError::network(context, e)),
+            };
+
+            let result = this_try.send().await;
+            let wait = match &result {
+                Ok(resp) if matches!(resp.status().as_u16(), 429 | 502 | 503 | 504) => {
+                    Some(retry_after(resp))
+                }
+                Ok(_) => None,
+                Err(e) if e.is_connect() || e.is_timeout() => Some(None),
+                Err(_) => None,
+            };
+
+            let delay = match wait {
+                Some(_) if attempt >= retry.max_retries => None,
+                // Retry-After oltre il massimo: si riprova comunque dopo il massimo
+                Some(Some(server)) => Some(server.min(max_delay)),
+                Some(None) => Some(backoff_delay(retry, attempt).min(max_delay)),
+                None => None,
+            };
+
+            match delay {
+                Some(delay) => {
+                    attempt += 1;
+                    println!(
+                        "🔁 {}: errore transitorio, nuovo tentativo {}/{} fra {} ms",
+                        context,
+                        attempt,
+                        retry.max_retries,
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                None => return result.map_err(|e| ApiError::network(context, e)),
+            }
         }
     }
 
@@ -76,6 +1038,10 @@ impl SupabaseClient {
     ) -> reqwest::RequestBuilder {
         let key = &self.config.anon_key;
         let request = request.header("apikey", key);
+        // Utente collegato: il suo JWT, così le policy RLS valgono per utente
+        if let Some(token) = self.auth.access_token() {
+            return request.header("Authorization", format!("Bearer {}", token));
+        }
         // Chiavi legacy JWT (eyJ...): servono apikey + Authorization Bearer
         // Chiavi nuove publishable (sb_publishable_...): solo apikey
         if key.starts_with("eyJ") {
@@ -85,100 +1051,75 @@ impl SupabaseClient {
         }
     }
 
+    /// URL REST di una tabella con la query string generata dal builder
+    fn rest_url(&self, table_name: &str, query: &Query) -> String {
+        let base = self.config.url.trim_end_matches('/');
+        let qs = query.to_query_string();
+        if qs.is_empty() {
+            format!("{}/rest/v1/{}", base, table_name)
+        } else {
+            format!("{}/rest/v1/{}?{}", base, table_name, qs)
+        }
+    }
+
     /// Legge tutte le righe da una tabella Supabase, con paginazione automatica.
     /// PostgREST limita di default a 1000 righe per richiesta.
+    /// Le righe lette aggiornano la copia locale; se Supabase non è raggiungibile
+    /// la stessa query viene eseguita sulla copia.
     pub async fn fetch_table(
         &self,
         table_type: &str,
-        filter: Option<&str>,
-        columns: Option<&str>,
-        order: Option<&str>,
-    ) -> Result<Vec<Value>, String> {
-        const PAGE_SIZE: usize = 1000;
-        const MAX_PAGES: usize = 100;
-
-        let table_name = self
-            .config
-            .tables
-            .table_name(table_type)
-            .ok_or_else(|| format!("Tipo tabella Supabase sconosciuto: {}", table_type))?;
-
-        let base = self.config.url.trim_end_matches('/');
-        let select_cols = columns.unwrap_or("*");
-
-        let mut all_rows: Vec<Value> = Vec::new();
-        let mut offset: usize = 0;
-
-        for page in 1..=MAX_PAGES {
-            let mut url = format!("{}/rest/v1/{}?select={}", base, table_name, select_cols);
-
-            if let Some(f) = filter {
-                url.push('&');
-                url.push_str(f);
-            }
-            if let Some(o) = order {
-                url.push_str("&order=");
-                url.push_str(o);
-            }
-            url.push_str(&format!("&limit={}&offset={}", PAGE_SIZE, offset));
-
-            println!(
-                "📡 Supabase GET [{} → {}] pagina {} (offset {}): {}",
-                table_type, table_name, page, offset, url
-            );
-
-            let request = self
-                .http
-                .get(&url)
-                .header("Content-Type", "application/json")
-                .header("Prefer", "count=exact");
-
-            let response = self
-                .apply_auth_headers(request)
-                .send()
-                .await
-                .map_err(|e| format!("Errore connessione Supabase: {}", e))?;
-
-            if !response.status().is_success() {
-                let status = response.status();
-                let body = response.text().await.unwrap_or_default();
-                return Err(format!("Errore Supabase HTTP {}: {}", status, body));
-            }
+        query: &Query,
+    ) -> Result<Vec<Value>, ApiError> {
+        let store = match &self.offline {
+            Some(store) if offline::is_mirrored(table_type) => store,
+            _ => return self.fetch_table_remote(table_type, query).await,
+        };
 
-            if let Some(content_range) = response.headers().get("content-range") {
-                if let Ok(cr) = content_range.to_str() {
-                    println!("  Content-Range: {}", cr);
+        let result = match self.offline_shortcut() {
+            Some(e) => Err(e),
+            None => self.fetch_table_remote(table_type, query).await,
+        };
+        match result {
+            Ok(rows) => {
+                self.set_offline_mode(false);
+                // Solo righe complete: una select parziale non può sostituire la copia
+                if query.select.is_empty() {
+                    let completa = query.limit.is_none() && query.offset.is_none();
+                    let sostituisce = |row: &Value| completa && query.matches(row);
+                    if let Err(e) = store.store_rows(table_type, &sostituisce, &rows) {
+                        println!("⚠️ Copia locale [{}] non aggiornata: {}", table_type, e);
+                    }
                 }
+                Ok(rows)
             }
-
-            let batch: Vec<Value> = response
-                .json()
-                .await
-                .map_err(|e| format!("Errore parsing risposta Supabase: {}", e))?;
-
-            let batch_len = batch.len();
-            all_rows.extend(batch);
-
-            if batch_len < PAGE_SIZE {
-                break;
-            }
-
-            if order.is_none() {
+            Err(e @ ApiError::Network { .. }) => {
+                self.set_offline_mode(true);
+                let locali = store.read_rows(table_type)?;
+                if locali.is_empty() {
+                    return Err(e);
+                }
+                let rows = query.apply_local(locali);
                 println!(
-                    "  ⚠️ Supabase [{}]: pagina piena senza order — rischio righe mancanti/duplicate",
-                    table_type
+                    "📴 Supabase non raggiungibile: [{}] {} righe dalla copia locale",
+                    table_type,
+                    rows.len()
                 );
+                Ok(rows)
             }
-
-            offset += PAGE_SIZE;
+            Err(e) => Err(e),
         }
+    }
 
-        if all_rows.len() >= PAGE_SIZE * MAX_PAGES {
-            println!(
-                "⚠️ Supabase [{}]: raggiunto limite massimo {} righe",
-                table_type,
-                all_rows.len()
-            );
+    async fn fetch_table_remote(
+        &self,
+        table_type: &str,
+        query: &Query,
+    ) -> Result<Vec<Value>, ApiError> {
+        let mut pager = self.pages(table_type, query.clone())?;
+        let mut all_rows: Vec<Value> = Vec::new();
+        while let Some(batch) = pager.next_page().await? {
+            all_rows.extend(batch);
         }
 
         println!(
@@ -186,6 +1127,9 @@ impl SupabaseClient {
             table_type,
             all_rows.len()
         );
+        if query.select.is_empty() && TABELLE_VERSIONATE.contains(&table_type) {
+            self.ricorda_versioni(table_type, &all_rows);
+        }
 
         if all_rows.is_empty() {
             println!("  ⚠️ 0 righe — verifica: dati in tabella, RLS policy, o nome tabella");
@@ -204,34 +1148,187 @@ impl SupabaseClient {
         Ok(all_rows)
     }
 
-    pub async fn fetch_tesserati(
-        &self,
-        filter: Option<&str>,
-        columns: Option<&str>,
-    ) -> Result<Vec<Value>, String> {
-        self.fetch_table("tesserati", filter, columns, Some("IdSocio.asc"))
+    /// Lettura a pagine: ogni `next_page` restituisce il blocco successivo
+    /// (`None` a fine tabella), così il chiamante può elaborare e scartare
+    /// le righe senza tenere in memoria l'intero archivio.
+    pub fn pages(&self, table_type: &str, query: Query) -> Result<TablePager<'_>, ApiError> {
+        let table_name = self
+            .config
+            .tables
+            .table_name(table_type)
+            .ok_or_else(|| ApiError::config(format!("Tipo tabella Supabase sconosciuto: {}", table_type)))?
+            .to_string();
+        Ok(TablePager::new(self, table_type, table_name, query))
+    }
+
+    pub async fn fetch_tesserati(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("tesserati", &query.default_order("IdSocio", true))
             .await
     }
 
-    pub async fn fetch_automezzi(&self, filter: Option<&str>) -> Result<Vec<Value>, String> {
-        self.fetch_table("automezzi", filter, None, None).await
+    pub async fn fetch_automezzi(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("automezzi", &query).await
     }
 
-    pub async fn fetch_tratte(&self, filter: Option<&str>) -> Result<Vec<Value>, String> {
-        self.fetch_table("tratte", filter, None, Some("IdTratta.asc"))
+    pub async fn fetch_tratte(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("tratte", &query.default_order("IdTratta", true))
             .await
     }
 
-    pub async fn fetch_user_permissions(
-        &self,
-        filter: Option<&str>,
-    ) -> Result<Vec<Value>, String> {
-        self.fetch_table("user_permissions", filter, None, None)
+    pub async fn fetch_user_permissions(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("user_permissions", &query).await
+    }
+
+    /// Tutti i permessi utente con la chiave di servizio (pagina Utenti, solo admin):
+    /// col JWT dell'utente la policy mostra solo la propria riga
+    pub async fn fetch_all_user_permissions_admin(&self) -> Result<Vec<Value>, ApiError> {
+        let url = self.rest_url(&self.config.tables.user_permissions, &Query::new());
+        let response = self
+            .send_with_retry(
+                self.apply_service_headers(self.http.get(&url)),
+                "Errore connessione Supabase user_permissions",
+            )
+            .await?;
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            return Err(ApiError::from_response(
+                "Errore Supabase user_permissions",
+                status.as_u16(),
+                &text,
+            ));
+        }
+        serde_json::from_str(&text)
+            .map_err(|e| format!("Risposta user_permissions non valida: {}", e).into())
+    }
+
+    // --- Sessione utente (Supabase Auth) ---
+
+    pub fn sessione(&self) -> Option<SessioneAuth> {
+        self.auth.get()
+    }
+
+    /// POST /auth/v1/token con la sola chiave pubblica
+    async fn richiesta_token(&self, grant_type: &str, body: &Value) -> Result<SessioneAuth, ApiError> {
+        let base = self.config.url.trim_end_matches('/');
+        let url = format!("{}/auth/v1/token?grant_type={}", base, grant_type);
+        let key = &self.config.anon_key;
+        let mut request = self
+            .http
+            .post(&url)
+            .header("apikey", key)
+            .header("Content-Type", "application/json")
+            .json(body);
+        if key.starts_with("eyJ") {
+            request = request.header("Authorization", format!("Bearer {}", key));
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| ApiError::network("Errore connessione Supabase Auth", e))?;
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            return Err(ApiError::from_response(
+                "Errore Supabase Auth",
+                status.as_u16(),
+                &text,
+            ));
+        }
+        let parsed: Value = serde_json::from_str(&text)
+            .map_err(|e| format!("Risposta Auth non valida: {}", e))?;
+        SessioneAuth::da_risposta(&parsed)
+    }
+
+    /// Login email/password: la sessione vale per tutti i comandi successivi
+    pub async fn login(&self, email: &str, password: &str) -> Result<SessioneAuth, ApiError> {
+        println!("🔐 Supabase Auth login {}", email);
+        let sessione = self
+            .richiesta_token(
+                "password",
+                &serde_json::json!({ "email": email, "password": password }),
+            )
+            .await?;
+        self.auth.set(Some(sessione.clone()));
+        Ok(sessione)
+    }
+
+    /// Rinnova l'access token se scade entro `anticipo` secondi. `Ok(false)` se
+    /// non c'è nessuna sessione; un refresh token rifiutato chiude la sessione.
+    pub async fn rinnova_sessione(&self, anticipo: i64) -> Result<bool, ApiError> {
+        let _turno = self.auth.rinnovo.lock().await;
+        // Riletta dopo l'attesa: un altro comando può averla appena rinnovata
+        let attuale = match self.auth.get() {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+        if !attuale.scade_entro(anticipo) {
+            return Ok(true);
+        }
+        if attuale.refresh_token.is_empty() {
+            self.auth.set(None);
+            return Err(ApiError::permission_denied("Sessione scaduta: accedi di nuovo"));
+        }
+        match self
+            .richiesta_token(
+                "refresh_token",
+                &serde_json::json!({ "refresh_token": attuale.refresh_token }),
+            )
             .await
+        {
+            Ok(nuova) => {
+                println!("🔐 Sessione Supabase rinnovata per {}", nuova.email);
+                self.auth.set(Some(nuova));
+                Ok(true)
+            }
+            // Offline: si riprova più tardi con lo stesso refresh token
+            Err(e @ ApiError::Network { .. }) => Err(e),
+            Err(e) => {
+                println!("⚠️ Rinnovo sessione rifiutato, logout: {}", e);
+                self.auth.set(None);
+                Err(ApiError::permission_denied(format!(
+                    "Sessione scaduta: accedi di nuovo ({})",
+                    e.message()
+                )))
+            }
+        }
+    }
+
+    /// Chiude la sessione su Supabase (revoca il refresh token) e in locale
+    pub async fn logout(&self) -> Result<(), ApiError> {
+        let sessione = match self.auth.get() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        self.auth.set(None);
+        let base = self.config.url.trim_end_matches('/');
+        let request = self
+            .http
+            .post(format!("{}/auth/v1/logout", base))
+            .header("apikey", &self.config.anon_key)
+            .header("Authorization", format!("Bearer {}", sessione.access_token));
+        match request.send().await {
+            Ok(r) if r.status().is_success() || r.status().as_u16() == 401 => Ok(()),
+            Ok(r) => {
+                let status = r.status().as_u16();
+                let text = r.text().await.unwrap_or_default();
+                Err(ApiError::from_response(
+                    "Errore Supabase Auth logout",
+                    status,
+                    &text,
+                ))
+            }
+            Err(e) => Err(ApiError::network("Errore connessione Supabase Auth logout", e)),
+        }
     }
 
+    /// Chiave di servizio per le operazioni di amministrazione (utenti, permessi).
+    /// Senza secret_key in config.json si usa la chiave pubblica e decide la RLS.
     fn apply_service_headers(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
-        let key = &self.config.anon_key;
+        let key = match self.config.service_key.as_deref() {
+            Some(k) if !k.is_empty() => k,
+            _ => return self.apply_auth_headers(request),
+        };
         request
             .header("apikey", key)
             .header("Authorization", format!("Bearer {}", key))
@@ -242,13 +1339,12 @@ impl SupabaseClient {
         &self,
         user_id: &str,
         body: &serde_json::Map<String, Value>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         if body.is_empty() {
             return Ok(());
         }
         let table_name = &self.config.tables.user_permissions;
-        let base = self.config.url.trim_end_matches('/');
-        let url = format!("{}/rest/v1/{}?user_id=eq.{}", base, table_name, user_id);
+        let url = self.rest_url(table_name, &Query::new().eq("user_id", user_id));
 
         println!(
             "📡 Supabase PATCH [user_permissions → {}] user_id={} body={:?}",
@@ -263,27 +1359,29 @@ impl SupabaseClient {
             .json(body);
 
         let response = self
-            .apply_service_headers(request)
-            .send()
-            .await
-            .map_err(|e| format!("Errore connessione PATCH user_permissions: {}", e))?;
+            .send_with_retry(
+                self.apply_service_headers(request),
+                "Errore connessione PATCH user_permissions",
+            )
+            .await?;
 
         let status = response.status();
         let text = response.text().await.unwrap_or_default();
         if !status.is_success() {
-            return Err(format!(
-                "Errore PATCH user_permissions ({}): {}",
-                status, text
+            return Err(ApiError::from_response(
+                "Errore PATCH user_permissions",
+                status.as_u16(),
+                &text,
             ));
         }
 
         // return=representation: se 0 righe, il filtro non ha trovato l'utente
         let trimmed = text.trim();
         if trimmed.is_empty() || trimmed == "[]" || trimmed == "null" {
-            return Err(format!(
+            return Err(ApiError::not_found(format!(
                 "Nessuna riga aggiornata in user_permissions per user_id={}",
                 user_id
-            ));
+            )));
         }
 
         println!("✓ PATCH user_permissions ok: {}", trimmed);
@@ -291,15 +1389,9 @@ impl SupabaseClient {
     }
 
     /// Elimina una riga in user_permissions
-    pub async fn delete_user_permissions(&self, user_id: &str) -> Result<(), String> {
+    pub async fn delete_user_permissions(&self, user_id: &str) -> Result<(), ApiError> {
         let table_name = &self.config.tables.user_permissions;
-        let base = self.config.url.trim_end_matches('/');
-        let url = format!(
-            "{}/rest/v1/{}?user_id=eq.{}",
-            base,
-            table_name,
-            user_id.trim()
-        );
+        let url = self.rest_url(table_name, &Query::new().eq("user_id", user_id.trim()));
 
         println!(
             "📡 Supabase DELETE [user_permissions → {}] user_id={}",
@@ -311,24 +1403,24 @@ impl SupabaseClient {
             .apply_service_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione DELETE user_permissions: {}", e))?;
+            .map_err(|e| ApiError::network("Errore connessione DELETE user_permissions", e))?;
 
         if response.status().is_success() {
             return Ok(());
         }
         let status = response.status();
         let err_body = response.text().await.unwrap_or_default();
-        Err(format!(
-            "Errore DELETE user_permissions ({}): {}",
-            status, err_body
+        Err(ApiError::from_response(
+            "Errore DELETE user_permissions",
+            status.as_u16(),
+            &err_body,
         ))
     }
 
     /// Inserisce una riga in user_permissions
-    pub async fn insert_user_permissions(&self, body: &Value) -> Result<(), String> {
+    pub async fn insert_user_permissions(&self, body: &Value) -> Result<(), ApiError> {
         let table_name = &self.config.tables.user_permissions;
-        let base = self.config.url.trim_end_matches('/');
-        let url = format!("{}/rest/v1/{}", base, table_name);
+        let url = self.rest_url(table_name, &Query::new());
 
         println!("📡 Supabase POST [user_permissions → {}]", table_name);
 
@@ -343,16 +1435,17 @@ impl SupabaseClient {
             .apply_service_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione POST user_permissions: {}", e))?;
+            .map_err(|e| ApiError::network("Errore connessione POST user_permissions", e))?;
 
         if response.status().is_success() {
             return Ok(());
         }
         let status = response.status();
         let err_body = response.text().await.unwrap_or_default();
-        Err(format!(
-            "Errore POST user_permissions ({}): {}",
-            status, err_body
+        Err(ApiError::from_response(
+            "Errore POST user_permissions",
+            status.as_u16(),
+            &err_body,
         ))
     }
 
@@ -361,10 +1454,10 @@ impl SupabaseClient {
         &self,
         user_id: &str,
         body: &Value,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         match self.insert_user_permissions(body).await {
             Ok(()) => Ok(()),
-            Err(e) if e.contains("409") || e.contains("23505") || e.contains("duplicate") => {
+            Err(ApiError::Conflict { .. }) => {
                 println!(
                     "ℹ️ user_permissions già presente per {}: aggiorno (upsert)",
                     user_id
@@ -383,8 +1476,128 @@ impl SupabaseClient {
         }
     }
 
+    /// Ruoli personalizzati (Ruoli_supa), letti col JWT dell'utente
+    pub async fn fetch_ruoli(&self) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("ruoli", &Query::new()).await
+    }
+
+    /// Crea o sostituisce un ruolo (chiave: codice)
+    pub async fn upsert_ruolo(&self, body: &Value) -> Result<(), ApiError> {
+        let table_name = &self.config.tables.ruoli;
+        let url = format!("{}?on_conflict=codice", self.rest_url(table_name, &Query::new()));
+
+        println!("📡 Supabase UPSERT [ruoli → {}] body={}", table_name, body);
+
+        let request = self
+            .http
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .header("Prefer", "resolution=merge-duplicates,return=minimal")
+            .json(body);
+
+        let response = self
+            .send_with_retry(
+                self.apply_service_headers(request),
+                "Errore connessione UPSERT ruoli",
+            )
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let err_body = response.text().await.unwrap_or_default();
+        Err(ApiError::from_response(
+            "Errore UPSERT ruoli",
+            status.as_u16(),
+            &err_body,
+        ))
+    }
+
+    /// Elimina un ruolo personalizzato (o la ridefinizione di un predefinito)
+    pub async fn delete_ruolo(&self, codice: &str) -> Result<(), ApiError> {
+        let table_name = &self.config.tables.ruoli;
+        let url = self.rest_url(table_name, &Query::new().eq("codice", codice));
+
+        println!("📡 Supabase DELETE [ruoli → {}] codice={}", table_name, codice);
+
+        let response = self
+            .send_with_retry(
+                self.apply_service_headers(self.http.delete(&url)),
+                "Errore connessione DELETE ruoli",
+            )
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let err_body = response.text().await.unwrap_or_default();
+        Err(ApiError::from_response(
+            "Errore DELETE ruoli",
+            status.as_u16(),
+            &err_body,
+        ))
+    }
+
+    /// Aggiunge una voce al registro modifiche. Se l'invio non riesce, per
+    /// mancanza di rete o per un errore del server, la voce finisce nella
+    /// outbox e parte con il prossimo rinvio: il registro non perde righe.
+    pub async fn insert_audit(&self, riga: &serde_json::Map<String, Value>) -> Result<(), ApiError> {
+        let chiave = riga.get("chiave").map(json_to_string).unwrap_or_default();
+        let esito = self.insert_audit_remote(riga).await;
+        match (esito, &self.offline) {
+            (Err(e), Some(store)) if !matches!(e, ApiError::Network { .. }) => {
+                let voce = store.enqueue(
+                    OutboxOp::Insert,
+                    "audit",
+                    offline::key_column("audit", None),
+                    &chiave,
+                    riga,
+                )?;
+                println!("⚠️ {} → voce audit {} in coda, voce outbox #{}", e, chiave, voce);
+                Ok(())
+            }
+            (esito, _) => self.queue_if_offline(esito, OutboxOp::Insert, "audit", &chiave, riga),
+        }
+    }
+
+    /// POST su Audit_supa; una chiave già presente (rinvio) viene ignorata
+    async fn insert_audit_remote(&self, riga: &serde_json::Map<String, Value>) -> Result<(), ApiError> {
+        let table_name = &self.config.tables.audit;
+        let url = format!("{}?on_conflict=chiave", self.rest_url(table_name, &Query::new()));
+
+        let request = self
+            .http
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .header("Prefer", "resolution=ignore-duplicates,return=minimal")
+            .json(riga);
+
+        let response = self
+            .send_with_retry(self.apply_auth_headers(request), "Errore connessione INSERT audit")
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let err_body = response.text().await.unwrap_or_default();
+        Err(ApiError::from_response(
+            "Errore INSERT audit",
+            status.as_u16(),
+            &err_body,
+        ))
+    }
+
+    /// Storico di un record, dal più recente
+    pub async fn fetch_audit(&self, entita: &str, record_id: &str) -> Result<Vec<Value>, ApiError> {
+        let filter = Query::new()
+            .eq("entita", entita)
+            .eq("record_id", record_id)
+            .order("quando", false);
+        self.fetch_table("audit", &filter).await
+    }
+
     /// Cerca user_id Auth per email (Admin API)
-    pub async fn admin_find_user_id_by_email(&self, email: &str) -> Result<Option<String>, String> {
+    pub async fn admin_find_user_id_by_email(&self, email: &str) -> Result<Option<String>, ApiError> {
         let base = self.config.url.trim_end_matches('/');
         let url = format!(
             "{}/auth/v1/admin/users?page=1&per_page=200",
@@ -395,15 +1608,20 @@ impl SupabaseClient {
 
         let request = self.http.get(&url);
         let response = self
-            .apply_service_headers(request)
-            .send()
-            .await
-            .map_err(|e| format!("Errore connessione Auth Admin list: {}", e))?;
+            .send_with_retry(
+                self.apply_service_headers(request),
+                "Errore connessione Auth Admin list",
+            )
+            .await?;
 
         let status = response.status();
         let text = response.text().await.unwrap_or_default();
         if !status.is_success() {
-            return Err(format!("Errore lista utenti Auth ({}): {}", status, text));
+            return Err(ApiError::from_response(
+                "Errore lista utenti Auth",
+                status.as_u16(),
+                &text,
+            ));
         }
 
         let parsed: Value = serde_json::from_str(&text)
@@ -437,33 +1655,22 @@ impl SupabaseClient {
         &self,
         email: &str,
         password: &str,
-    ) -> Result<(String, bool), String> {
+    ) -> Result<(String, bool), ApiError> {
         match self.admin_create_auth_user(email, password).await {
             Ok(id) => Ok((id, true)),
-            Err(e) => {
-                let low = e.to_lowercase();
-                if low.contains("already")
-                    || low.contains("registered")
-                    || low.contains("exists")
-                    || low.contains("422")
-                    || low.contains("user_already")
-                {
-                    let existing = self
-                        .admin_find_user_id_by_email(email)
-                        .await?
-                        .ok_or_else(|| {
-                            format!(
-                                "Email già registrata in Auth, ma utente non trovato in elenco: {}",
-                                e
-                            )
-                        })?;
-                    self.admin_update_auth_password(&existing, password)
-                        .await?;
-                    Ok((existing, false))
-                } else {
-                    Err(e)
+            // email_exists (GoTrue recente) o 422 generico (versioni precedenti):
+            // se l'utente c'è davvero in elenco lo riuso, altrimenti l'errore resta
+            Err(e) if matches!(e, ApiError::Conflict { .. }) || e.status() == Some(422) => {
+                match self.admin_find_user_id_by_email(email).await? {
+                    Some(existing) => {
+                        self.admin_update_auth_password(&existing, password)
+                            .await?;
+                        Ok((existing, false))
+                    }
+                    None => Err(e),
                 }
             }
+            Err(e) => Err(e),
         }
     }
 
@@ -472,7 +1679,7 @@ impl SupabaseClient {
         &self,
         email: &str,
         password: &str,
-    ) -> Result<String, String> {
+    ) -> Result<String, ApiError> {
         let base = self.config.url.trim_end_matches('/');
         let url = format!("{}/auth/v1/admin/users", base);
         let body = serde_json::json!({
@@ -493,12 +1700,16 @@ impl SupabaseClient {
             .apply_service_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione Auth Admin create: {}", e))?;
+            .map_err(|e| ApiError::network("Errore connessione Auth Admin create", e))?;
 
         let status = response.status();
         let text = response.text().await.unwrap_or_default();
         if !status.is_success() {
-            return Err(format!("Errore creazione utente Auth ({}): {}", status, text));
+            return Err(ApiError::from_response(
+                "Errore creazione utente Auth",
+                status.as_u16(),
+                &text,
+            ));
         }
 
         let parsed: Value = serde_json::from_str(&text)
@@ -509,7 +1720,7 @@ impl SupabaseClient {
             .unwrap_or("")
             .to_string();
         if id.is_empty() {
-            return Err(format!("Auth non ha restituito id utente: {}", text));
+            return Err(format!("Auth non ha restituito id utente: {}", text).into());
         }
         Ok(id)
     }
@@ -520,7 +1731,7 @@ impl SupabaseClient {
         user_id: &str,
         password: Option<&str>,
         username: Option<&str>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         let base = self.config.url.trim_end_matches('/');
         let url = format!("{}/auth/v1/admin/users/{}", base, user_id);
 
@@ -560,24 +1771,25 @@ impl SupabaseClient {
             .apply_service_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione Auth Admin update: {}", e))?;
+            .map_err(|e| ApiError::network("Errore connessione Auth Admin update", e))?;
 
         if response.status().is_success() {
             return Ok(());
         }
         let status = response.status();
         let err_body = response.text().await.unwrap_or_default();
-        Err(format!(
-            "Errore aggiornamento utente Auth ({}): {}",
-            status, err_body
+        Err(ApiError::from_response(
+            "Errore aggiornamento utente Auth",
+            status.as_u16(),
+            &err_body,
         ))
     }
 
     /// Elimina utente Auth (Admin API)
-    pub async fn admin_delete_auth_user(&self, user_id: &str) -> Result<(), String> {
+    pub async fn admin_delete_auth_user(&self, user_id: &str) -> Result<(), ApiError> {
         let uid = user_id.trim();
         if uid.is_empty() {
-            return Err("user_id mancante per eliminazione Auth".to_string());
+            return Err("user_id mancante per eliminazione Auth".into());
         }
 
         let base = self.config.url.trim_end_matches('/');
@@ -590,16 +1802,17 @@ impl SupabaseClient {
             .apply_service_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione Auth Admin delete: {}", e))?;
+            .map_err(|e| ApiError::network("Errore connessione Auth Admin delete", e))?;
 
         if response.status().is_success() {
             return Ok(());
         }
         let status = response.status();
         let err_body = response.text().await.unwrap_or_default();
-        Err(format!(
-            "Errore eliminazione utente Auth ({}): {}",
-            status, err_body
+        Err(ApiError::from_response(
+            "Errore eliminazione utente Auth",
+            status.as_u16(),
+            &err_body,
         ))
     }
 
@@ -608,13 +1821,13 @@ impl SupabaseClient {
         &self,
         user_id: &str,
         password: &str,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         self.admin_update_auth_user(user_id, Some(password), None)
             .await
     }
 
-    pub async fn fetch_impostazioni(&self, filter: Option<&str>) -> Result<Vec<Value>, String> {
-        self.fetch_table("impostazioni", filter, None, Some("Impostazione.asc"))
+    pub async fn fetch_impostazioni(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("impostazioni", &query.default_order("Impostazione", true))
             .await
     }
 
@@ -623,25 +1836,21 @@ impl SupabaseClient {
         &self,
         id: &str,
         body: &serde_json::Map<String, Value>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         if body.is_empty() {
             return Ok(());
         }
 
         let id = id.trim();
         if id.is_empty() {
-            return Err("Id impostazione mancante".to_string());
+            return Err("Id impostazione mancante".into());
         }
 
         let table_name = &self.config.tables.impostazioni;
-        let base = self.config.url.trim_end_matches('/');
-        let mut last_error: Option<String> = None;
+        let mut last_error: Option<ApiError> = None;
 
         for id_col in ["id", "Id", "ID"] {
-            let url = format!(
-                "{}/rest/v1/{}?{}=eq.{}",
-                base, table_name, id_col, id
-            );
+            let url = self.rest_url(table_name, &Query::new().eq(id_col, id));
 
             println!(
                 "📡 Supabase PATCH [impostazioni → {}] {}={} body={:?}",
@@ -656,10 +1865,11 @@ impl SupabaseClient {
                 .json(body);
 
             let response = self
-                .apply_auth_headers(request)
-                .send()
-                .await
-                .map_err(|e| format!("Errore connessione Supabase PATCH impostazioni: {}", e))?;
+                .send_with_retry(
+                    self.apply_auth_headers(request),
+                    "Errore connessione Supabase PATCH impostazioni",
+                )
+                .await?;
 
             if response.status().is_success() {
                 return Ok(());
@@ -667,24 +1877,25 @@ impl SupabaseClient {
 
             let status = response.status();
             let err_body = response.text().await.unwrap_or_default();
-            last_error = Some(format!("HTTP {}: {}", status, err_body));
+            last_error = Some(ApiError::from_response(
+                "Errore Supabase",
+                status.as_u16(),
+                &err_body,
+            ));
         }
 
-        Err(last_error.unwrap_or_else(|| {
-            format!("Impossibile aggiornare impostazione id={}", id)
-        }))
+        Err(last_error.unwrap_or_else(|| format!("Impossibile aggiornare impostazione id={}", id).into()))
     }
 
-    pub async fn fetch_dotazioni_mezzi(&self, filter: Option<&str>) -> Result<Vec<Value>, String> {
-        self.fetch_table("dotazioni_mezzi", filter, None, Some("Dotazione.asc"))
+    pub async fn fetch_dotazioni_mezzi(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("dotazioni_mezzi", &query.default_order("Dotazione", true))
             .await
     }
 
     /// Inserisce una nuova dotazione nella tabella DotazioniMezzi_supa
-    pub async fn insert_dotazione_mezzo(&self, dotazione: &str) -> Result<(), String> {
+    pub async fn insert_dotazione_mezzo(&self, dotazione: &str) -> Result<(), ApiError> {
         let table_name = &self.config.tables.dotazioni_mezzi;
-        let base = self.config.url.trim_end_matches('/');
-        let url = format!("{}/rest/v1/{}", base, table_name);
+        let url = self.rest_url(table_name, &Query::new());
 
         let body = serde_json::json!({ "Dotazione": dotazione });
 
@@ -704,7 +1915,7 @@ impl SupabaseClient {
             .apply_auth_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione Supabase POST dotazioni_mezzi: {}", e))?;
+            .map_err(|e| ApiError::network("Errore connessione Supabase POST dotazioni_mezzi", e))?;
 
         if response.status().is_success() {
             return Ok(());
@@ -712,41 +1923,41 @@ impl SupabaseClient {
 
         let status = response.status();
         let err_body = response.text().await.unwrap_or_default();
-        Err(format!(
-            "Errore Supabase POST dotazioni_mezzi HTTP {}: {}",
-            status, err_body
+        Err(ApiError::from_response(
+            "Errore Supabase POST dotazioni_mezzi",
+            status.as_u16(),
+            &err_body,
         ))
     }
 
-    pub async fn fetch_tipologie_socio(&self, filter: Option<&str>) -> Result<Vec<Value>, String> {
-        self.fetch_table("tipo_socio", filter, None, None).await
+    pub async fn fetch_tipologie_socio(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("tipo_socio", &query).await
     }
 
-    pub async fn fetch_richiedenti(&self, filter: Option<&str>) -> Result<Vec<Value>, String> {
-        self.fetch_table("richiedenti", filter, None, None).await
+    pub async fn fetch_richiedenti(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("richiedenti", &query).await
     }
 
-    pub async fn fetch_tipi_pagamento(&self, filter: Option<&str>) -> Result<Vec<Value>, String> {
-        self.fetch_table("tipo_pagamenti", filter, None, None).await
+    pub async fn fetch_tipi_pagamento(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("tipo_pagamenti", &query).await
     }
 
-    pub async fn fetch_motivazioni_trasporto(
-        &self,
-        filter: Option<&str>,
-    ) -> Result<Vec<Value>, String> {
-        self.fetch_table("motivazioni_trasporto", filter, None, None)
-            .await
+    pub async fn fetch_motivazioni_trasporto(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("motivazioni_trasporto", &query).await
     }
 
-    pub async fn fetch_stati_del_servizio(&self, filter: Option<&str>) -> Result<Vec<Value>, String> {
-        self.fetch_table("stato_del_servizio", filter, None, Some("id.asc"))
+    pub async fn fetch_stati_del_servizio(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("stato_del_servizio", &query.default_order("id", true))
             .await
     }
 
     /// Inserisce una nuova tipologia in TipoSocio_supa
-    pub async fn insert_tipologia_socio(&self, tipologia: &str) -> Result<(), String> {
+    pub async fn insert_tipologia_socio(&self, tipologia: &str) -> Result<(), ApiError> {
         let table_name = &self.config.tables.tipo_socio;
-        let template_rows = self.fetch_tipologie_socio(None).await.unwrap_or_default();
+        let template_rows = self
+            .fetch_tipologie_socio(Query::new())
+            .await
+            .unwrap_or_default();
         let column_key = template_rows
             .first()
             .and_then(resolve_tipologia_lookup_column)
@@ -755,8 +1966,7 @@ impl SupabaseClient {
         let mut body = serde_json::Map::new();
         body.insert(column_key.clone(), serde_json::json!(tipologia));
 
-        let base = self.config.url.trim_end_matches('/');
-        let url = format!("{}/rest/v1/{}", base, table_name);
+        let url = self.rest_url(table_name, &Query::new());
 
         println!(
             "📡 Supabase POST [tipo_socio → {}] {}='{}'",
@@ -772,14 +1982,13 @@ impl SupabaseClient {
         table_type: &str,
         column_key: &str,
         value: &str,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         let table_name = self
             .config
             .tables
             .table_name(table_type)
-            .ok_or_else(|| format!("Tabella lookup sconosciuta: {}", table_type))?;
-        let base = self.config.url.trim_end_matches('/');
-        let url = format!("{}/rest/v1/{}", base, table_name);
+            .ok_or_else(|| ApiError::config(format!("Tabella lookup sconosciuta: {}", table_type)))?;
+        let url = self.rest_url(table_name, &Query::new());
 
         let mut body = serde_json::Map::new();
         body.insert(column_key.to_string(), serde_json::json!(value));
@@ -798,26 +2007,25 @@ impl SupabaseClient {
         table_type: &str,
         id: &str,
         body: &serde_json::Map<String, Value>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         if body.is_empty() {
             return Ok(());
         }
 
         let id = id.trim();
         if id.is_empty() {
-            return Err("Id riga lookup mancante".to_string());
+            return Err("Id riga lookup mancante".into());
         }
 
         let table_name = self
             .config
             .tables
             .table_name(table_type)
-            .ok_or_else(|| format!("Tabella lookup sconosciuta: {}", table_type))?;
-        let base = self.config.url.trim_end_matches('/');
-        let mut last_error: Option<String> = None;
+            .ok_or_else(|| ApiError::config(format!("Tabella lookup sconosciuta: {}", table_type)))?;
+        let mut last_error: Option<ApiError> = None;
 
         for id_col in ["id", "Id", "ID"] {
-            let url = format!("{}/rest/v1/{}?{}=eq.{}", base, table_name, id_col, id);
+            let url = self.rest_url(table_name, &Query::new().eq(id_col, id));
 
             println!(
                 "📡 Supabase PATCH [lookup {} → {}] {}={} body={:?}",
@@ -832,15 +2040,11 @@ impl SupabaseClient {
                 .json(body);
 
             let response = self
-                .apply_auth_headers(request)
-                .send()
-                .await
-                .map_err(|e| {
-                    format!(
-                        "Errore connessione Supabase PATCH {}: {}",
-                        table_type, e
-                    )
-                })?;
+                .send_with_retry(
+                    self.apply_auth_headers(request),
+                    &format!("Errore connessione Supabase PATCH {}", table_type),
+                )
+                .await?;
 
             if response.status().is_success() {
                 return Ok(());
@@ -848,31 +2052,32 @@ impl SupabaseClient {
 
             let status = response.status();
             let err_body = response.text().await.unwrap_or_default();
-            last_error = Some(format!("HTTP {}: {}", status, err_body));
+            last_error = Some(ApiError::from_response(
+                "Errore Supabase",
+                status.as_u16(),
+                &err_body,
+            ));
         }
 
-        Err(last_error.unwrap_or_else(|| {
-            format!("Impossibile aggiornare {} id={}", table_type, id)
-        }))
+        Err(last_error.unwrap_or_else(|| format!("Impossibile aggiornare {} id={}", table_type, id).into()))
     }
 
     /// Elimina una riga lookup per id
-    pub async fn delete_lookup_by_id(&self, table_type: &str, id: &str) -> Result<(), String> {
+    pub async fn delete_lookup_by_id(&self, table_type: &str, id: &str) -> Result<(), ApiError> {
         let id = id.trim();
         if id.is_empty() {
-            return Err("Id riga lookup mancante".to_string());
+            return Err("Id riga lookup mancante".into());
         }
 
         let table_name = self
             .config
             .tables
             .table_name(table_type)
-            .ok_or_else(|| format!("Tabella lookup sconosciuta: {}", table_type))?;
-        let base = self.config.url.trim_end_matches('/');
-        let mut last_error: Option<String> = None;
+            .ok_or_else(|| ApiError::config(format!("Tabella lookup sconosciuta: {}", table_type)))?;
+        let mut last_error: Option<ApiError> = None;
 
         for id_col in ["id", "Id", "ID"] {
-            let url = format!("{}/rest/v1/{}?{}=eq.{}", base, table_name, id_col, id);
+            let url = self.rest_url(table_name, &Query::new().eq(id_col, id));
 
             println!(
                 "📡 Supabase DELETE [lookup {} → {}] {}={}",
@@ -885,10 +2090,7 @@ impl SupabaseClient {
                 .send()
                 .await
                 .map_err(|e| {
-                    format!(
-                        "Errore connessione Supabase DELETE {}: {}",
-                        table_type, e
-                    )
+                    ApiError::network(format!("Errore connessione Supabase DELETE {}", table_type), e)
                 })?;
 
             if response.status().is_success() {
@@ -897,12 +2099,14 @@ impl SupabaseClient {
 
             let status = response.status();
             let err_body = response.text().await.unwrap_or_default();
-            last_error = Some(format!("HTTP {}: {}", status, err_body));
+            last_error = Some(ApiError::from_response(
+                "Errore Supabase",
+                status.as_u16(),
+                &err_body,
+            ));
         }
 
-        Err(last_error.unwrap_or_else(|| {
-            format!("Impossibile eliminare {} id={}", table_type, id)
-        }))
+        Err(last_error.unwrap_or_else(|| format!("Impossibile eliminare {} id={}", table_type, id).into()))
     }
 
     async fn post_lookup_row(
@@ -910,7 +2114,7 @@ impl SupabaseClient {
         url: &str,
         body: &serde_json::Map<String, Value>,
         label: &str,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         let request = self
             .http
             .post(url)
@@ -922,7 +2126,7 @@ impl SupabaseClient {
             .apply_auth_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione Supabase POST {}: {}", label, e))?;
+            .map_err(|e| ApiError::network(format!("Errore connessione Supabase POST {}", label), e))?;
 
         if response.status().is_success() {
             return Ok(());
@@ -930,39 +2134,54 @@ impl SupabaseClient {
 
         let status = response.status();
         let err_body = response.text().await.unwrap_or_default();
-        Err(format!(
-            "Errore Supabase POST {} HTTP {}: {}",
-            label, status, err_body
+        Err(ApiError::from_response(
+            &format!("Errore Supabase POST {}", label),
+            status.as_u16(),
+            &err_body,
         ))
     }
 
-    pub async fn fetch_servizi(&self, filter: Option<&str>) -> Result<Vec<Value>, String> {
-        self.fetch_table("servizi", filter, None, Some("idservizio.asc"))
+    pub async fn fetch_servizi(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("servizi", &query.default_order("idservizio", true))
             .await
     }
 
-    pub async fn fetch_servizi_motivazioni(&self) -> Result<Vec<Value>, String> {
+    pub async fn fetch_servizi_motivazioni(&self) -> Result<Vec<Value>, ApiError> {
         self.fetch_table(
             "servizi",
-            Some("Motivazione=not.is.null"),
-            Some("Motivazione"),
-            Some("Motivazione.asc"),
+            &Query::new()
+                .select(&["Motivazione"])
+                .not_null("Motivazione")
+                .order("Motivazione", true),
         )
         .await
     }
 
     /// Comuni/luoghi prelievo e destinazione dai servizi (autocomplete Nuovo Servizio).
-    pub async fn fetch_servizi_localita_autocomplete(&self) -> Result<Vec<Value>, String> {
-        let select_variants = [
-            "Prelievo_Comune,Prelievo_Indirizzo,Destinazione_Comune,Destinazione_Indirizzo",
-            "PRELIEVO_COMUNE,PRELIEVO_INDIRIZZO,DESTINAZIONE_COMUNE,DESTINAZIONE_INDIRIZZO",
+    pub async fn fetch_servizi_localita_autocomplete(&self) -> Result<Vec<Value>, ApiError> {
+        let select_variants: [&[&str]; 2] = [
+            &[
+                "Prelievo_Comune",
+                "Prelievo_Indirizzo",
+                "Destinazione_Comune",
+                "Destinazione_Indirizzo",
+            ],
+            &[
+                "PRELIEVO_COMUNE",
+                "PRELIEVO_INDIRIZZO",
+                "DESTINAZIONE_COMUNE",
+                "DESTINAZIONE_INDIRIZZO",
+            ],
         ];
         for select in select_variants {
-            match self.fetch_table("servizi", None, Some(select), None).await {
+            match self
+                .fetch_table("servizi", &Query::new().select(select))
+                .await
+            {
                 Ok(rows) if !rows.is_empty() => return Ok(rows),
                 Ok(_) => continue,
                 Err(e) => {
-                    println!("⚠️ fetch_servizi_localita_autocomplete select {}: {}", select, e);
+                    println!("⚠️ fetch_servizi_localita_autocomplete select {:?}: {}", select, e);
                     continue;
                 }
             }
@@ -972,14 +2191,12 @@ impl SupabaseClient {
     }
 
     /// Comuni di prelievo presenti nei servizi (solo colonna comune, leggero).
-    pub async fn fetch_servizi_comuni_prelievo(&self) -> Result<Vec<Value>, String> {
+    pub async fn fetch_servizi_comuni_prelievo(&self) -> Result<Vec<Value>, ApiError> {
         for col in ["Prelievo_Comune", "PRELIEVO_COMUNE", "Prelievo_comune"] {
             match self
                 .fetch_table(
                     "servizi",
-                    Some(&format!("{}=not.is.null", col)),
-                    Some(col),
-                    Some(&format!("{}.asc", col)),
+                    &Query::new().select(&[col]).not_null(col).order(col, true),
                 )
                 .await
             {
@@ -991,19 +2208,14 @@ impl SupabaseClient {
                 }
             }
         }
-        Err("Colonna Prelievo_Comune non trovata nella tabella servizi".to_string())
+        Err("Colonna Prelievo_Comune non trovata nella tabella servizi".into())
     }
 
     /// Elenco IdSocio presenti in almeno un servizio (solo colonna IdSocio, leggero).
-    pub async fn fetch_servizi_idsocio(&self) -> Result<Vec<Value>, String> {
+    pub async fn fetch_servizi_idsocio(&self) -> Result<Vec<Value>, ApiError> {
         for col in ["IdSocio", "IDSOCIO"] {
             match self
-                .fetch_table(
-                    "servizi",
-                    Some(&format!("{}=not.is.null", col)),
-                    Some(col),
-                    None,
-                )
+                .fetch_table("servizi", &Query::new().select(&[col]).not_null(col))
                 .await
             {
                 Ok(rows) if !rows.is_empty() => return Ok(rows),
@@ -1014,18 +2226,37 @@ impl SupabaseClient {
                 }
             }
         }
-        Err("Colonna IdSocio non trovata nella tabella servizi".to_string())
+        Err("Colonna IdSocio non trovata nella tabella servizi".into())
+    }
+
+    /// Massimo idservizio in tabella (0 se vuota o colonna non trovata).
+    /// Offline vale il massimo della copia locale: l'ID dato a un servizio creato
+    /// offline è provvisorio e viene riassegnato quando la outbox è inviata.
+    pub async fn fetch_max_servizio_id(&self) -> Result<u32, ApiError> {
+        let result = match self.offline_shortcut() {
+            Some(e) => Err(e),
+            None => self.fetch_max_servizio_id_remote().await,
+        };
+        match (result, &self.offline) {
+            (Err(ApiError::Network { .. }), Some(store)) => {
+                self.set_offline_mode(true);
+                let max = store
+                    .max_numeric_key("servizi")?
+                    .max(store.max_numeric_key("cestino_servizi")?);
+                println!("📴 Supabase non raggiungibile: max idservizio {} dalla copia locale", max);
+                Ok(max as u32)
+            }
+            (result, _) => result,
+        }
     }
 
-    /// Massimo idservizio in tabella (0 se vuota o colonna non trovata)
-    pub async fn fetch_max_servizio_id(&self) -> Result<u32, String> {
+    async fn fetch_max_servizio_id_remote(&self) -> Result<u32, ApiError> {
         let table_name = &self.config.tables.servizi;
-        let base = self.config.url.trim_end_matches('/');
 
         for id_col in ["idservizio", "IdServizio", "IDSERVIZIO"] {
-            let url = format!(
-                "{}/rest/v1/{}?select={}&order={}.desc&limit=1",
-                base, table_name, id_col, id_col
+            let url = self.rest_url(
+                table_name,
+                &Query::new().select(&[id_col]).order(id_col, false).limit(1),
             );
 
             println!("📡 Supabase GET max idservizio [{}]: {}", id_col, url);
@@ -1036,10 +2267,11 @@ impl SupabaseClient {
                 .header("Content-Type", "application/json");
 
             let response = self
-                .apply_auth_headers(request)
-                .send()
-                .await
-                .map_err(|e| format!("Errore connessione Supabase max idservizio: {}", e))?;
+                .send_with_retry(
+                    self.apply_auth_headers(request),
+                    "Errore connessione Supabase max idservizio",
+                )
+                .await?;
 
             if !response.status().is_success() {
                 continue;
@@ -1050,95 +2282,263 @@ impl SupabaseClient {
                 .await
                 .map_err(|e| format!("Errore parsing max idservizio: {}", e))?;
 
-            if let Some(row) = rows.first() {
-                let id_str = get_field(row, id_col);
-                if let Ok(id) = id_str.parse::<u32>() {
-                    return Ok(id);
-                }
-            }
+            let massimo = rows
+                .first()
+                .and_then(|row| get_field(row, id_col).parse::<u32>().ok())
+                .unwrap_or(0);
+            // Gli ID dei servizi nel cestino restano riservati: riusarli
+            // impedirebbe il ripristino (stesso idservizio già occupato)
+            let cestino = self.fetch_max_cestino_id_remote().await?;
+            return Ok(massimo.max(cestino));
+        }
+
+        Err("Impossibile determinare il massimo idservizio in Servizi_supa".into())
+    }
+
+    /// Massimo idservizio presente in CestinoServizi_supa (0 se vuoto o tabella assente).
+    async fn fetch_max_cestino_id_remote(&self) -> Result<u32, ApiError> {
+        let table_name = &self.config.tables.cestino_servizi;
+        let url = self.rest_url(
+            table_name,
+            &Query::new()
+                .select(&["idservizio"])
+                .order("idservizio", false)
+                .limit(1),
+        );
+
+        println!("📡 Supabase GET max idservizio cestino: {}", url);
+
+        let request = self
+            .http
+            .get(&url)
+            .header("Content-Type", "application/json");
 
+        let response = self
+            .send_with_retry(
+                self.apply_auth_headers(request),
+                "Errore connessione Supabase max idservizio cestino",
+            )
+            .await?;
+
+        if !response.status().is_success() {
+            // Cestino non ancora creato (supabase-cestino-servizi.sql non eseguito)
             return Ok(0);
         }
 
-        Err("Impossibile determinare il massimo idservizio in Servizi_supa".to_string())
+        let rows: Vec<Value> = response
+            .json()
+            .await
+            .map_err(|e| format!("Errore parsing max idservizio cestino: {}", e))?;
+
+        Ok(rows
+            .first()
+            .and_then(|row| get_field(row, "idservizio").parse::<u32>().ok())
+            .unwrap_or(0))
     }
 
-    /// Aggiorna un servizio per IdServizio (PATCH PostgREST)
+    /// Aggiorna un servizio per IdServizio (PATCH PostgREST); offline va in outbox
     pub async fn patch_servizio(
         &self,
         id_servizio: u32,
         body: &serde_json::Map<String, Value>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
+        let result = match self.offline_shortcut() {
+            Some(e) => Err(e),
+            None => self.patch_servizio_remote(id_servizio, body).await,
+        };
+        self.queue_if_offline(result, OutboxOp::Update, "servizi", &id_servizio.to_string(), body)
+    }
+
+    async fn patch_servizio_remote(
+        &self,
+        id_servizio: u32,
+        body: &serde_json::Map<String, Value>,
+    ) -> Result<(), ApiError> {
         if body.is_empty() {
             return Ok(());
         }
 
         let table_name = &self.config.tables.servizi;
-        let base = self.config.url.trim_end_matches('/');
-        let url = format!(
-            "{}/rest/v1/{}?idservizio=eq.{}",
-            base, table_name, id_servizio
-        );
+        let url = self.rest_url(table_name, &Query::new().eq("idservizio", id_servizio));
 
         println!("📡 Supabase PATCH [servizi → {}] idservizio={}", table_name, id_servizio);
 
         let request = self
             .http
-            .patch(&url)
+            .patch(&url)
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=minimal")
+            .json(body);
+
+        let response = self
+            .send_with_retry(
+                self.apply_auth_headers(request),
+                "Errore connessione Supabase PATCH",
+            )
+            .await?;
+
+        if response.status().is_success() {
+            return Ok(());
+        }
+
+        let status = response.status();
+        let err_body = response.text().await.unwrap_or_default();
+
+        // Fallback: prova altri nomi colonna ID
+        for id_col in ["IdServizio", "IDSERVIZIO"] {
+            let url_alt = self.rest_url(table_name, &Query::new().eq(id_col, id_servizio));
+            let request2 = self
+                .http
+                .patch(&url_alt)
+                .header("Content-Type", "application/json")
+                .header("Prefer", "return=minimal")
+                .json(body);
+            let response2 = self
+                .send_with_retry(
+                    self.apply_auth_headers(request2),
+                    "Errore connessione Supabase PATCH",
+                )
+                .await?;
+            if response2.status().is_success() {
+                return Ok(());
+            }
+        }
+
+        Err(ApiError::from_response(
+            "Errore Supabase PATCH",
+            status.as_u16(),
+            &err_body,
+        ))
+    }
+
+    /// Prossimo idservizio dalla sequenza del database; `None` se la funzione
+    /// RPC non è stata installata (allora si ricade su max+1)
+    async fn next_servizio_id_rpc(&self) -> Result<Option<u32>, ApiError> {
+        if self.senza_rpc_id_servizio.load(AtomicOrdering::SeqCst) {
+            return Ok(None);
+        }
+        let url = format!(
+            "{}/rest/v1/rpc/{}",
+            self.config.url.trim_end_matches('/'),
+            RPC_ID_SERVIZIO
+        );
+        let request = self
+            .http
+            .post(&url)
             .header("Content-Type", "application/json")
-            .header("Prefer", "return=minimal")
-            .json(body);
-
+            .json(&serde_json::json!({}));
         let response = self
             .apply_auth_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione Supabase PATCH: {}", e))?;
+            .map_err(|e| ApiError::network("Errore connessione Supabase RPC idservizio", e))?;
 
-        if response.status().is_success() {
-            return Ok(());
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            let err_body = response.text().await.unwrap_or_default();
+            return match ApiError::from_response(
+                "Errore Supabase RPC idservizio",
+                status,
+                &err_body,
+            ) {
+                // PGRST202: funzione inesistente
+                ApiError::NotFound { .. } => {
+                    println!(
+                        "ℹ️ Funzione {} non presente: idservizio assegnati con max+1 e nuovo tentativo sui duplicati",
+                        RPC_ID_SERVIZIO
+                    );
+                    self.senza_rpc_id_servizio.store(true, AtomicOrdering::SeqCst);
+                    Ok(None)
+                }
+                e => Err(e),
+            };
         }
 
-        let status = response.status();
-        let err_body = response.text().await.unwrap_or_default();
+        let valore: Value = response
+            .json()
+            .await
+            .map_err(|e| format!("Errore parsing risposta RPC idservizio: {}", e))?;
+        json_to_string(&valore)
+            .parse::<u32>()
+            .map(Some)
+            .map_err(|_| format!("RPC {}: risposta non numerica {}", RPC_ID_SERVIZIO, valore).into())
+    }
 
-        // Fallback: prova altri nomi colonna ID
-        for id_col in ["IdServizio", "IDSERVIZIO"] {
-            let url_alt = format!(
-                "{}/rest/v1/{}?{}=eq.{}",
-                base, table_name, id_col, id_servizio
-            );
-            let request2 = self
-                .http
-                .patch(&url_alt)
-                .header("Content-Type", "application/json")
-                .header("Prefer", "return=minimal")
-                .json(body);
-            let response2 = self
-                .apply_auth_headers(request2)
-                .send()
-                .await
-                .map_err(|e| format!("Errore connessione Supabase PATCH: {}", e))?;
-            if response2.status().is_success() {
-                return Ok(());
+    /// Inserisce un nuovo servizio assegnandogli un idservizio libero.
+    /// L'ID viene dalla sequenza del database se c'è, altrimenti è max+1; se
+    /// nel frattempo un'altra postazione ha usato lo stesso ID (violazione di
+    /// unicità 23505) si riprova con un ID nuovo, al massimo
+    /// `TENTATIVI_NUOVO_SERVIZIO` volte.
+    async fn insert_servizio_nuovo_id_remote(
+        &self,
+        body: &serde_json::Map<String, Value>,
+        id_column: &str,
+    ) -> Result<(u32, Value), ApiError> {
+        let mut body = body.clone();
+        let mut tentativo: u32 = 1;
+        loop {
+            let id = match self.next_servizio_id_rpc().await? {
+                Some(id) => id,
+                None => self.fetch_max_servizio_id_remote().await?.saturating_add(1),
+            };
+            body.insert(id_column.to_string(), serde_json::json!(id));
+
+            match self.insert_servizio_remote(&body).await {
+                Ok(row) => {
+                    let definitivo = get_field(&row, id_column).parse::<u32>().unwrap_or(id);
+                    return Ok((definitivo, row));
+                }
+                Err(ApiError::Conflict { code: Some(code), .. })
+                    if code == "23505" && tentativo < TENTATIVI_NUOVO_SERVIZIO =>
+                {
+                    use rand::Rng;
+                    println!(
+                        "⚠️ idservizio {} appena usato da un'altra postazione, nuovo tentativo ({}/{})",
+                        id, tentativo, TENTATIVI_NUOVO_SERVIZIO
+                    );
+                    // Attesa casuale: due postazioni in gara non riprovano all'unisono
+                    let attesa = rand::thread_rng().gen_range(50..=250u64 * tentativo as u64);
+                    tokio::time::sleep(std::time::Duration::from_millis(attesa)).await;
+                    tentativo += 1;
+                }
+                Err(e) => return Err(e),
             }
         }
+    }
 
-        Err(format!("Errore Supabase PATCH HTTP {}: {}", status, err_body))
+    /// Crea un nuovo servizio e restituisce l'idservizio assegnato e la riga.
+    /// Offline il servizio va in outbox con un ID provvisorio (max della copia
+    /// locale + 1), riassegnato quando la outbox viene inviata.
+    pub async fn insert_servizio_nuovo_id(
+        &self,
+        body: &serde_json::Map<String, Value>,
+        id_column: &str,
+    ) -> Result<(u32, Value), ApiError> {
+        let result = match self.offline_shortcut() {
+            Some(e) => Err(e),
+            None => self.insert_servizio_nuovo_id_remote(body, id_column).await,
+        };
+        let (err, store) = match (result, &self.offline) {
+            (Err(e @ ApiError::Network { .. }), Some(store)) => (e, store),
+            (result, _) => return result,
+        };
+        let provvisorio = (store.max_numeric_key("servizi")? as u32).saturating_add(1);
+        let mut body = body.clone();
+        body.insert(id_column.to_string(), serde_json::json!(provvisorio));
+        self.queue_if_offline(Err(err), OutboxOp::Insert, "servizi", &provvisorio.to_string(), &body)?;
+        Ok((provvisorio, Value::Object(body)))
     }
 
-    /// Inserisce un nuovo servizio (POST PostgREST)
-    pub async fn insert_servizio(
+    async fn insert_servizio_remote(
         &self,
         body: &serde_json::Map<String, Value>,
-    ) -> Result<Value, String> {
+    ) -> Result<Value, ApiError> {
         if body.is_empty() {
-            return Err("Nessun campo da inserire per il nuovo servizio".to_string());
+            return Err("Nessun campo da inserire per il nuovo servizio".into());
         }
 
         let table_name = &self.config.tables.servizi;
-        let base = self.config.url.trim_end_matches('/');
-        let url = format!("{}/rest/v1/{}", base, table_name);
+        let url = self.rest_url(table_name, &Query::new());
 
         println!("📡 Supabase POST [servizi → {}]", table_name);
 
@@ -1153,14 +2553,15 @@ impl SupabaseClient {
             .apply_auth_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione Supabase POST servizi: {}", e))?;
+            .map_err(|e| ApiError::network("Errore connessione Supabase POST servizi", e))?;
 
         if !response.status().is_success() {
             let status = response.status();
             let err_body = response.text().await.unwrap_or_default();
-            return Err(format!(
-                "Errore Supabase POST servizi HTTP {}: {}",
-                status, err_body
+            return Err(ApiError::from_response(
+                "Errore Supabase POST servizi",
+                status.as_u16(),
+                &err_body,
             ));
         }
 
@@ -1171,20 +2572,246 @@ impl SupabaseClient {
 
         rows.into_iter()
             .next()
-            .ok_or_else(|| "Nessuna riga restituita dopo insert servizio".to_string())
+            .ok_or_else(|| "Nessuna riga restituita dopo insert servizio".into())
+    }
+
+    /// Ripristina un servizio dal cestino con il suo idservizio originale
+    pub async fn insert_servizio_con_id(
+        &self,
+        body: &serde_json::Map<String, Value>,
+    ) -> Result<Value, ApiError> {
+        self.insert_servizio_remote(body).await
+    }
+
+    /// Mette nel cestino la riga di un servizio che sta per essere eliminato.
+    /// Offline va in outbox prima del DELETE, e viene rinviata nello stesso ordine.
+    pub async fn insert_cestino_servizio(
+        &self,
+        riga: &serde_json::Map<String, Value>,
+    ) -> Result<(), ApiError> {
+        let chiave = riga.get("idservizio").map(json_to_string).unwrap_or_default();
+        let result = match self.offline_shortcut() {
+            Some(e) => Err(e),
+            None => self.insert_cestino_servizio_remote(riga).await,
+        };
+        self.queue_if_offline(result, OutboxOp::Insert, "cestino_servizi", &chiave, riga)
+    }
+
+    /// Un servizio eliminato di nuovo dopo un ripristino sostituisce la voce precedente
+    async fn insert_cestino_servizio_remote(
+        &self,
+        riga: &serde_json::Map<String, Value>,
+    ) -> Result<(), ApiError> {
+        let table_name = &self.config.tables.cestino_servizi;
+        let url = format!("{}?on_conflict=idservizio", self.rest_url(table_name, &Query::new()));
+
+        println!("📡 Supabase UPSERT [cestino_servizi → {}]", table_name);
+
+        let request = self
+            .http
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .header("Prefer", "resolution=merge-duplicates,return=minimal")
+            .json(riga);
+
+        let response = self
+            .send_with_retry(
+                self.apply_auth_headers(request),
+                "Errore connessione UPSERT cestino servizi",
+            )
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let err_body = response.text().await.unwrap_or_default();
+        Err(ApiError::from_response(
+            "Errore UPSERT cestino servizi",
+            status.as_u16(),
+            &err_body,
+        ))
+    }
+
+    pub async fn fetch_tariffe(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("tariffe", &query.default_order("valido_dal", true))
+            .await
+    }
+
+    /// Nuova versione della tariffa; la stessa data di inizio sostituisce la versione
+    /// (correzione in giornata), le precedenti restano com'erano
+    pub async fn upsert_tariffa(&self, body: &serde_json::Map<String, Value>) -> Result<(), ApiError> {
+        let table_name = &self.config.tables.tariffe;
+        let url = format!("{}?on_conflict=valido_dal", self.rest_url(table_name, &Query::new()));
+
+        println!("📡 Supabase UPSERT [tariffe → {}]", table_name);
+
+        let request = self
+            .http
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .header("Prefer", "resolution=merge-duplicates,return=minimal")
+            .json(body);
+
+        let response = self
+            .send_with_retry(
+                self.apply_auth_headers(request),
+                "Errore connessione UPSERT tariffe",
+            )
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let err_body = response.text().await.unwrap_or_default();
+        Err(ApiError::from_response(
+            "Errore UPSERT tariffe",
+            status.as_u16(),
+            &err_body,
+        ))
+    }
+
+    pub async fn fetch_regole_tariffa(&self) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("regole_tariffa", &Query::new().default_order("priorita", true))
+            .await
+    }
+
+    /// Crea o sostituisce una regola della tariffa (chiave: codice)
+    pub async fn upsert_regola_tariffa(&self, body: &Value) -> Result<(), ApiError> {
+        let table_name = &self.config.tables.regole_tariffa;
+        let url = format!("{}?on_conflict=codice", self.rest_url(table_name, &Query::new()));
+
+        println!("📡 Supabase UPSERT [regole_tariffa → {}] body={}", table_name, body);
+
+        let request = self
+            .http
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .header("Prefer", "resolution=merge-duplicates,return=minimal")
+            .json(body);
+
+        let response = self
+            .send_with_retry(
+                self.apply_auth_headers(request),
+                "Errore connessione UPSERT regole tariffa",
+            )
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let err_body = response.text().await.unwrap_or_default();
+        Err(ApiError::from_response(
+            "Errore UPSERT regole tariffa",
+            status.as_u16(),
+            &err_body,
+        ))
+    }
+
+    pub async fn delete_regola_tariffa(&self, codice: &str) -> Result<(), ApiError> {
+        let table_name = &self.config.tables.regole_tariffa;
+        let url = self.rest_url(table_name, &Query::new().eq("codice", codice));
+
+        println!("📡 Supabase DELETE [regole_tariffa → {}] codice={}", table_name, codice);
+
+        let response = self
+            .send_with_retry(
+                self.apply_auth_headers(self.http.delete(&url)),
+                "Errore connessione DELETE regole tariffa",
+            )
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let err_body = response.text().await.unwrap_or_default();
+        Err(ApiError::from_response(
+            "Errore DELETE regole tariffa",
+            status.as_u16(),
+            &err_body,
+        ))
+    }
+
+    pub async fn fetch_serie_servizi(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("serie_servizi", &query).await
+    }
+
+    /// Crea una serie o ne aggiorna i campi passati (chiave: id_serie)
+    pub async fn upsert_serie_servizi(&self, body: &Value) -> Result<(), ApiError> {
+        let table_name = &self.config.tables.serie_servizi;
+        let url = format!("{}?on_conflict=id_serie", self.rest_url(table_name, &Query::new()));
+
+        println!("📡 Supabase UPSERT [serie_servizi → {}]", table_name);
+
+        let request = self
+            .http
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .header("Prefer", "resolution=merge-duplicates,return=minimal")
+            .json(body);
+
+        let response = self
+            .send_with_retry(
+                self.apply_auth_headers(request),
+                "Errore connessione UPSERT serie servizi",
+            )
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let err_body = response.text().await.unwrap_or_default();
+        Err(ApiError::from_response(
+            "Errore UPSERT serie servizi",
+            status.as_u16(),
+            &err_body,
+        ))
+    }
+
+    pub async fn fetch_cestino_servizi(&self, query: Query) -> Result<Vec<Value>, ApiError> {
+        self.fetch_table("cestino_servizi", &query).await
+    }
+
+    /// Toglie voci dal cestino (ripristino o pulizia definitiva)
+    pub async fn delete_cestino_servizi(&self, query: &Query) -> Result<(), ApiError> {
+        let table_name = &self.config.tables.cestino_servizi;
+        let url = self.rest_url(table_name, query);
+
+        println!("📡 Supabase DELETE [cestino_servizi → {}] {}", table_name, query);
+
+        let response = self
+            .send_with_retry(
+                self.apply_auth_headers(self.http.delete(&url)),
+                "Errore connessione DELETE cestino servizi",
+            )
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let err_body = response.text().await.unwrap_or_default();
+        Err(ApiError::from_response(
+            "Errore DELETE cestino servizi",
+            status.as_u16(),
+            &err_body,
+        ))
+    }
+
+    /// Elimina un servizio per idservizio (DELETE PostgREST); offline va in outbox
+    pub async fn delete_servizio(&self, id_servizio: u32) -> Result<(), ApiError> {
+        let result = match self.offline_shortcut() {
+            Some(e) => Err(e),
+            None => self.delete_servizio_remote(id_servizio).await,
+        };
+        let empty = serde_json::Map::new();
+        self.queue_if_offline(result, OutboxOp::Delete, "servizi", &id_servizio.to_string(), &empty)
     }
 
-    /// Elimina un servizio per idservizio (DELETE PostgREST)
-    pub async fn delete_servizio(&self, id_servizio: u32) -> Result<(), String> {
+    async fn delete_servizio_remote(&self, id_servizio: u32) -> Result<(), ApiError> {
         let table_name = &self.config.tables.servizi;
-        let base = self.config.url.trim_end_matches('/');
 
         let mut last_error = None;
         for id_col in ["idservizio", "IdServizio", "IDSERVIZIO"] {
-            let url = format!(
-                "{}/rest/v1/{}?{}=eq.{}",
-                base, table_name, id_col, id_servizio
-            );
+            let url = self.rest_url(table_name, &Query::new().eq(id_col, id_servizio));
 
             println!(
                 "📡 Supabase DELETE [servizi → {}] {}={}",
@@ -1196,7 +2823,7 @@ impl SupabaseClient {
                 .apply_auth_headers(request)
                 .send()
                 .await
-                .map_err(|e| format!("Errore connessione Supabase DELETE servizi: {}", e))?;
+                .map_err(|e| ApiError::network("Errore connessione Supabase DELETE servizi", e))?;
 
             if response.status().is_success() {
                 return Ok(());
@@ -1204,53 +2831,61 @@ impl SupabaseClient {
 
             let status = response.status();
             let err_body = response.text().await.unwrap_or_default();
-            last_error = Some(format!("HTTP {}: {}", status, err_body));
+            last_error = Some(ApiError::from_response(
+                "Errore Supabase",
+                status.as_u16(),
+                &err_body,
+            ));
         }
 
-        Err(last_error.unwrap_or_else(|| {
-            format!("Impossibile eliminare servizio idservizio={}", id_servizio)
-        }))
+        Err(last_error.unwrap_or_else(|| format!("Impossibile eliminare servizio idservizio={}", id_servizio).into()))
     }
 
     /// Storico tesseramenti: tabella dedicata (più anni per IdSocio).
     pub async fn fetch_tesseramenti(
         &self,
-        filter: Option<&str>,
-    ) -> Result<Vec<Value>, String> {
-        self.fetch_table(
-            "tesseramenti",
-            filter,
-            None,
-            Some("Anno.desc.nullslast,DataTesseramento.desc.nullslast"),
-        )
+        query: Query,
+    ) -> Result<Vec<Value>, ApiError> {
+        let query = if query.has_order() {
+            query
+        } else {
+            query
+                .order_nulls_last("Anno", false)
+                .order_nulls_last("DataTesseramento", false)
+        };
+        self.fetch_table("tesseramenti", &query)
             .await
     }
 
     /// Telefoni del socio (più numeri per IdSocio).
     pub async fn fetch_telefoni(
         &self,
-        filter: Option<&str>,
-    ) -> Result<Vec<Value>, String> {
-        self.fetch_table(
-            "telefoni",
-            filter,
-            None,
-            Some("OrdineUtilizzo.asc.nullslast,Telefono.asc.nullslast"),
-        )
+        query: Query,
+    ) -> Result<Vec<Value>, ApiError> {
+        let query = if query.has_order() {
+            query
+        } else {
+            query
+                .order_nulls_last("OrdineUtilizzo", true)
+                .order_nulls_last("Telefono", true)
+        };
+        self.fetch_table("telefoni", &query)
         .await
     }
 
     /// Email del socio (più indirizzi per IdSocio).
     pub async fn fetch_email_socio(
         &self,
-        filter: Option<&str>,
-    ) -> Result<Vec<Value>, String> {
-        self.fetch_table(
-            "email",
-            filter,
-            None,
-            Some("OrdineUtilizzo.asc.nullslast,Email.asc.nullslast"),
-        )
+        query: Query,
+    ) -> Result<Vec<Value>, ApiError> {
+        let query = if query.has_order() {
+            query
+        } else {
+            query
+                .order_nulls_last("OrdineUtilizzo", true)
+                .order_nulls_last("Email", true)
+        };
+        self.fetch_table("email", &query)
         .await
     }
 
@@ -1259,7 +2894,7 @@ impl SupabaseClient {
         &self,
         body: &serde_json::Map<String, Value>,
         row_id: Option<&str>,
-    ) -> Result<Value, String> {
+    ) -> Result<Value, ApiError> {
         self.upsert_named_table("telefoni", body, row_id).await
     }
 
@@ -1268,7 +2903,7 @@ impl SupabaseClient {
         &self,
         body: &serde_json::Map<String, Value>,
         row_id: Option<&str>,
-    ) -> Result<Value, String> {
+    ) -> Result<Value, ApiError> {
         self.upsert_named_table("email", body, row_id).await
     }
 
@@ -1277,16 +2912,14 @@ impl SupabaseClient {
         table_type: &str,
         body: &serde_json::Map<String, Value>,
         row_id: Option<&str>,
-    ) -> Result<Value, String> {
+    ) -> Result<Value, ApiError> {
         let table_name = self
             .config
             .tables
             .table_name(table_type)
-            .ok_or_else(|| format!("Tabella sconosciuta: {}", table_type))?;
-        let base = self.config.url.trim_end_matches('/');
-
+            .ok_or_else(|| ApiError::config(format!("Tabella sconosciuta: {}", table_type)))?;
         let response = if let Some(id) = row_id.map(str::trim).filter(|s| !s.is_empty()) {
-            let url = format!("{}/rest/v1/{}?id=eq.{}", base, table_name, id);
+            let url = self.rest_url(table_name, &Query::new().eq("id", id));
             println!(
                 "📡 Supabase PATCH [{} → {}] id={}",
                 table_type, table_name, id
@@ -1299,12 +2932,13 @@ impl SupabaseClient {
                 .header("Prefer", "return=representation")
                 .json(body);
 
-            self.apply_auth_headers(request)
-                .send()
-                .await
-                .map_err(|e| format!("Errore connessione Supabase PATCH {}: {}", table_type, e))?
+            self.send_with_retry(
+                self.apply_auth_headers(request),
+                &format!("Errore connessione Supabase PATCH {}", table_type),
+            )
+            .await?
         } else {
-            let url = format!("{}/rest/v1/{}", base, table_name);
+            let url = self.rest_url(table_name, &Query::new());
             println!("📡 Supabase POST [{} → {}]", table_type, table_name);
 
             let request = self
@@ -1317,15 +2951,16 @@ impl SupabaseClient {
             self.apply_auth_headers(request)
                 .send()
                 .await
-                .map_err(|e| format!("Errore connessione Supabase POST {}: {}", table_type, e))?
+                .map_err(|e| ApiError::network(format!("Errore connessione Supabase POST {}", table_type), e))?
         };
 
         if !response.status().is_success() {
             let status = response.status();
             let err_body = response.text().await.unwrap_or_default();
-            return Err(format!(
-                "Errore Supabase salvataggio {} HTTP {}: {}",
-                table_type, status, err_body
+            return Err(ApiError::from_response(
+                &format!("Errore Supabase salvataggio {}", table_type),
+                status.as_u16(),
+                &err_body,
             ));
         }
 
@@ -1336,7 +2971,7 @@ impl SupabaseClient {
 
         rows.into_iter()
             .next()
-            .ok_or_else(|| format!("Nessuna riga restituita dopo salvataggio {}", table_type))
+            .ok_or_else(|| format!("Nessuna riga restituita dopo salvataggio {}", table_type).into())
     }
 
     /// Elimina telefono per id (o IdSocio+Telefono come fallback).
@@ -1345,7 +2980,7 @@ impl SupabaseClient {
         row_id: Option<&str>,
         idsocio: &str,
         telefono: &str,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         self.delete_named_contato("telefoni", row_id, idsocio, "Telefono", telefono)
             .await
     }
@@ -1356,7 +2991,7 @@ impl SupabaseClient {
         row_id: Option<&str>,
         idsocio: &str,
         email: &str,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         self.delete_named_contato("email", row_id, idsocio, "Email", email)
             .await
     }
@@ -1368,16 +3003,14 @@ impl SupabaseClient {
         idsocio: &str,
         value_col: &str,
         value: &str,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         let table_name = self
             .config
             .tables
             .table_name(table_type)
-            .ok_or_else(|| format!("Tabella sconosciuta: {}", table_type))?;
-        let base = self.config.url.trim_end_matches('/');
-
+            .ok_or_else(|| ApiError::config(format!("Tabella sconosciuta: {}", table_type)))?;
         let url = if let Some(id) = row_id.map(str::trim).filter(|s| !s.is_empty()) {
-            format!("{}/rest/v1/{}?id=eq.{}", base, table_name, id)
+            self.rest_url(table_name, &Query::new().eq("id", id))
         } else {
             let idsocio = idsocio.trim();
             let value = value.trim();
@@ -1385,15 +3018,11 @@ impl SupabaseClient {
                 return Err(format!(
                     "Per eliminare {} servono id riga oppure IdSocio + {}",
                     table_type, value_col
-                ));
+                ).into());
             }
-                format!(
-                "{}/rest/v1/{}?IdSocio=eq.{}&{}=eq.{}",
-                base,
+            self.rest_url(
                 table_name,
-                encode_query_value(idsocio),
-                value_col,
-                encode_query_value(value)
+                &Query::new().eq("IdSocio", idsocio).eq(value_col, value),
             )
         };
 
@@ -1404,7 +3033,7 @@ impl SupabaseClient {
             .apply_auth_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione Supabase DELETE {}: {}", table_type, e))?;
+            .map_err(|e| ApiError::network(format!("Errore connessione Supabase DELETE {}", table_type), e))?;
 
         if response.status().is_success() {
             return Ok(());
@@ -1412,9 +3041,10 @@ impl SupabaseClient {
 
         let status = response.status();
         let err_body = response.text().await.unwrap_or_default();
-        Err(format!(
-            "Errore Supabase DELETE {} HTTP {}: {}",
-            table_type, status, err_body
+        Err(ApiError::from_response(
+            &format!("Errore Supabase DELETE {}", table_type),
+            status.as_u16(),
+            &err_body,
         ))
     }
 
@@ -1423,7 +3053,7 @@ impl SupabaseClient {
         &self,
         idsocio: &str,
         except_id: Option<&str>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         self.clear_principale_contatti("telefoni", idsocio, except_id)
             .await
     }
@@ -1433,7 +3063,7 @@ impl SupabaseClient {
         &self,
         idsocio: &str,
         except_id: Option<&str>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         self.clear_principale_contatti("email", idsocio, except_id)
             .await
     }
@@ -1443,7 +3073,7 @@ impl SupabaseClient {
         table_type: &str,
         idsocio: &str,
         except_id: Option<&str>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         let idsocio = idsocio.trim();
         if idsocio.is_empty() {
             return Ok(());
@@ -1453,16 +3083,14 @@ impl SupabaseClient {
             .config
             .tables
             .table_name(table_type)
-            .ok_or_else(|| format!("Tabella sconosciuta: {}", table_type))?;
-        let base = self.config.url.trim_end_matches('/');
-
-        let mut url = format!(
-            "{}/rest/v1/{}?IdSocio=eq.{}&Principale=eq.true",
-            base, table_name, idsocio
-        );
+            .ok_or_else(|| ApiError::config(format!("Tabella sconosciuta: {}", table_type)))?;
+        let mut query = Query::new()
+            .eq("IdSocio", idsocio)
+            .filter(Filter::is_true("Principale"));
         if let Some(id) = except_id.map(str::trim).filter(|s| !s.is_empty()) {
-            url.push_str(&format!("&id=neq.{}", id));
+            query = query.neq("id", id);
         }
+        let url = self.rest_url(table_name, &query);
 
         let body = serde_json::json!({ "Principale": false });
         println!(
@@ -1478,15 +3106,11 @@ impl SupabaseClient {
             .json(&body);
 
         let response = self
-            .apply_auth_headers(request)
-            .send()
-            .await
-            .map_err(|e| {
-                format!(
-                    "Errore connessione Supabase clear Principale {}: {}",
-                    table_type, e
-                )
-            })?;
+            .send_with_retry(
+                self.apply_auth_headers(request),
+                &format!("Errore connessione Supabase clear Principale {}", table_type),
+            )
+            .await?;
 
         if response.status().is_success() {
             return Ok(());
@@ -1494,28 +3118,37 @@ impl SupabaseClient {
 
         let status = response.status();
         let err_body = response.text().await.unwrap_or_default();
-        Err(format!(
-            "Errore clear Principale {} HTTP {}: {}",
-            table_type, status, err_body
+        Err(ApiError::from_response(
+            &format!("Errore clear Principale {}", table_type),
+            status.as_u16(),
+            &err_body,
         ))
     }
 
-    /// Aggiorna un tesserato per IdSocio (PATCH PostgREST)
+    /// Aggiorna un tesserato per IdSocio (PATCH PostgREST); offline va in outbox
     pub async fn patch_tesserato(
         &self,
         idsocio: &str,
         body: &serde_json::Map<String, Value>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
+        let result = match self.offline_shortcut() {
+            Some(e) => Err(e),
+            None => self.patch_tesserato_remote(idsocio, body).await,
+        };
+        self.queue_if_offline(result, OutboxOp::Update, "tesserati", idsocio, body)
+    }
+
+    async fn patch_tesserato_remote(
+        &self,
+        idsocio: &str,
+        body: &serde_json::Map<String, Value>,
+    ) -> Result<(), ApiError> {
         if body.is_empty() {
             return Ok(());
         }
 
         let table_name = &self.config.tables.tesserati;
-        let base = self.config.url.trim_end_matches('/');
-        let url = format!(
-            "{}/rest/v1/{}?IdSocio=eq.{}",
-            base, table_name, idsocio
-        );
+        let url = self.rest_url(table_name, &Query::new().eq("IdSocio", idsocio));
 
         println!(
             "📡 Supabase PATCH [tesserati → {}] IdSocio={}",
@@ -1530,10 +3163,11 @@ impl SupabaseClient {
             .json(body);
 
         let response = self
-            .apply_auth_headers(request)
-            .send()
-            .await
-            .map_err(|e| format!("Errore connessione Supabase PATCH tesserati: {}", e))?;
+            .send_with_retry(
+                self.apply_auth_headers(request),
+                "Errore connessione Supabase PATCH tesserati",
+            )
+            .await?;
 
         if response.status().is_success() {
             return Ok(());
@@ -1541,17 +3175,20 @@ impl SupabaseClient {
 
         let status = response.status();
         let err_body = response.text().await.unwrap_or_default();
-        Err(format!("HTTP {}: {}", status, err_body))
+        Err(ApiError::from_response(
+            "Errore Supabase PATCH tesserati",
+            status.as_u16(),
+            &err_body,
+        ))
     }
 
     /// Inserisce un nuovo tesserato (POST PostgREST)
     pub async fn insert_tesserato(
         &self,
         body: &serde_json::Map<String, Value>,
-    ) -> Result<Value, String> {
+    ) -> Result<Value, ApiError> {
         let table_name = &self.config.tables.tesserati;
-        let base = self.config.url.trim_end_matches('/');
-        let url = format!("{}/rest/v1/{}", base, table_name);
+        let url = self.rest_url(table_name, &Query::new());
 
         println!("📡 Supabase POST [tesserati → {}]", table_name);
 
@@ -1566,14 +3203,15 @@ impl SupabaseClient {
             .apply_auth_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione Supabase POST tesserati: {}", e))?;
+            .map_err(|e| ApiError::network("Errore connessione Supabase POST tesserati", e))?;
 
         if !response.status().is_success() {
             let status = response.status();
             let err_body = response.text().await.unwrap_or_default();
-            return Err(format!(
-                "Errore Supabase POST tesserati HTTP {}: {}",
-                status, err_body
+            return Err(ApiError::from_response(
+                "Errore Supabase POST tesserati",
+                status.as_u16(),
+                &err_body,
             ));
         }
 
@@ -1584,28 +3222,169 @@ impl SupabaseClient {
 
         rows.into_iter()
             .next()
-            .ok_or_else(|| "Nessuna riga restituita dopo insert tesserato".to_string())
+            .ok_or_else(|| "Nessuna riga restituita dopo insert tesserato".into())
+    }
+
+    // --- Prenotazione IdSocio ---
+
+    /// Massimo IdSocio numerico fra i tesserati (la colonna può essere testo:
+    /// l'ordinamento del server non basta, si leggono tutti gli IdSocio)
+    pub async fn fetch_max_idsocio(&self) -> Result<u64, ApiError> {
+        let rows = self
+            .fetch_tesserati(Query::new().select(&["IdSocio"]))
+            .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|r| get_field(r, "IdSocio").trim().parse::<u64>().ok())
+            .max()
+            .unwrap_or(0))
+    }
+
+    /// Prenota il primo IdSocio oltre il massimo dei tesserati e delle prenotazioni
+    /// ancora valide. La chiave primaria della tabella prenotazioni garantisce che
+    /// due postazioni non ottengano lo stesso numero: chi arriva secondo riceve
+    /// 23505 e riprova con il successivo. Senza tabella prenotazioni il numero
+    /// restituito è solo indicativo (token vuoto).
+    pub async fn reserve_idsocio(
+        &self,
+        durata: chrono::Duration,
+        prenotato_da: &str,
+    ) -> Result<PrenotazioneIdSocio, ApiError> {
+        use rand::Rng;
+
+        let scadute = Query::new().lt("scade_il", chrono::Utc::now().to_rfc3339());
+        match self.delete_prenotazioni_idsocio(&scadute).await {
+            Ok(()) => {}
+            Err(ApiError::Config { message }) => {
+                println!(
+                    "⚠️ Tabella {} non disponibile ({}): IdSocio proposto senza prenotazione",
+                    self.config.tables.prenotazioni_idsocio, message
+                );
+                return Ok(PrenotazioneIdSocio {
+                    idsocio: (self.fetch_max_idsocio().await? + 1).to_string(),
+                    token: String::new(),
+                    scade_il: None,
+                });
+            }
+            Err(e) => return Err(e),
+        }
+
+        let token = codice_casuale(32);
+        let mut tentativo: u32 = 1;
+        loop {
+            let prenotati = self
+                .fetch_table("prenotazioni_idsocio", &Query::new().select(&["IdSocio"]))
+                .await?
+                .iter()
+                .filter_map(|r| get_field(r, "IdSocio").trim().parse::<u64>().ok())
+                .max()
+                .unwrap_or(0);
+            let idsocio = self.fetch_max_idsocio().await?.max(prenotati) + 1;
+            let scade_il = (chrono::Utc::now() + durata).to_rfc3339();
+
+            let mut body = serde_json::Map::new();
+            body.insert("IdSocio".to_string(), serde_json::json!(idsocio));
+            body.insert("token".to_string(), serde_json::json!(token));
+            body.insert("prenotato_da".to_string(), serde_json::json!(prenotato_da));
+            body.insert("scade_il".to_string(), serde_json::json!(scade_il));
+            let url = self.rest_url(&self.config.tables.prenotazioni_idsocio, &Query::new());
+
+            match self.post_lookup_row(&url, &body, "prenotazione IdSocio").await {
+                Ok(()) => {
+                    println!("✓ IdSocio {} prenotato fino a {}", idsocio, scade_il);
+                    return Ok(PrenotazioneIdSocio {
+                        idsocio: idsocio.to_string(),
+                        token,
+                        scade_il: Some(scade_il),
+                    });
+                }
+                Err(ApiError::Conflict { code: Some(code), .. })
+                    if code == "23505" && tentativo < TENTATIVI_PRENOTAZIONE_IDSOCIO =>
+                {
+                    println!(
+                        "⚠️ IdSocio {} appena prenotato da un'altra postazione, nuovo tentativo ({}/{})",
+                        idsocio, tentativo, TENTATIVI_PRENOTAZIONE_IDSOCIO
+                    );
+                    let attesa = rand::thread_rng().gen_range(50..=250u64 * tentativo as u64);
+                    tokio::time::sleep(std::time::Duration::from_millis(attesa)).await;
+                    tentativo += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Prenotazione di un IdSocio (anche scaduta); `None` se assente o se la
+    /// tabella prenotazioni non esiste
+    pub async fn fetch_prenotazione_idsocio(&self, idsocio: &str) -> Result<Option<Value>, ApiError> {
+        match self
+            .fetch_table("prenotazioni_idsocio", &Query::new().eq("IdSocio", idsocio).limit(1))
+            .await
+        {
+            Ok(rows) => Ok(rows.into_iter().next()),
+            Err(ApiError::Config { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Rilascia la prenotazione identificata dal token (nuovo socio annullato o salvato)
+    pub async fn release_idsocio(&self, token: &str) -> Result<(), ApiError> {
+        if token.trim().is_empty() {
+            return Ok(());
+        }
+        match self.delete_prenotazioni_idsocio(&Query::new().eq("token", token.trim())).await {
+            Ok(()) | Err(ApiError::Config { .. }) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete_prenotazioni_idsocio(&self, query: &Query) -> Result<(), ApiError> {
+        let url = self.rest_url(&self.config.tables.prenotazioni_idsocio, query);
+        let request = self.http.delete(&url).header("Prefer", "return=minimal");
+        let response = self
+            .apply_auth_headers(request)
+            .send()
+            .await
+            .map_err(|e| ApiError::network("Errore connessione Supabase DELETE prenotazioni IdSocio", e))?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let err_body = response.text().await.unwrap_or_default();
+        Err(ApiError::from_response(
+            "Errore Supabase DELETE prenotazioni IdSocio",
+            status.as_u16(),
+            &err_body,
+        ))
     }
 
-    /// Aggiorna un automezzo per IdAutomezzo (PATCH PostgREST)
+    /// Aggiorna un automezzo per IdAutomezzo (PATCH PostgREST); offline va in outbox
     pub async fn patch_automezzo(
         &self,
         id_automezzo: u32,
         body: &serde_json::Map<String, Value>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
+        let result = match self.offline_shortcut() {
+            Some(e) => Err(e),
+            None => self.patch_automezzo_remote(id_automezzo, body).await,
+        };
+        self.queue_if_offline(result, OutboxOp::Update, "automezzi", &id_automezzo.to_string(), body)
+    }
+
+    async fn patch_automezzo_remote(
+        &self,
+        id_automezzo: u32,
+        body: &serde_json::Map<String, Value>,
+    ) -> Result<(), ApiError> {
         if body.is_empty() {
             return Ok(());
         }
 
         let table_name = &self.config.tables.automezzi;
-        let base = self.config.url.trim_end_matches('/');
-        let mut last_error: Option<String> = None;
+        let mut last_error: Option<ApiError> = None;
 
         for id_col in ["IdAutomezzo", "ID_AUTOMEZZO", "id"] {
-            let url = format!(
-                "{}/rest/v1/{}?{}=eq.{}",
-                base, table_name, id_col, id_automezzo
-            );
+            let url = self.rest_url(table_name, &Query::new().eq(id_col, id_automezzo));
 
             println!(
                 "📡 Supabase PATCH [automezzi → {}] {}={}",
@@ -1619,11 +3398,12 @@ impl SupabaseClient {
                 .header("Prefer", "return=minimal")
                 .json(body);
 
-            let response = self
-                .apply_auth_headers(request)
-                .send()
-                .await
-                .map_err(|e| format!("Errore connessione Supabase PATCH automezzi: {}", e))?;
+            let response = self
+                .send_with_retry(
+                    self.apply_auth_headers(request),
+                    "Errore connessione Supabase PATCH automezzi",
+                )
+                .await?;
 
             if response.status().is_success() {
                 return Ok(());
@@ -1631,33 +3411,43 @@ impl SupabaseClient {
 
             let status = response.status();
             let err_body = response.text().await.unwrap_or_default();
-            last_error = Some(format!("HTTP {}: {}", status, err_body));
+            last_error = Some(ApiError::from_response(
+                "Errore Supabase",
+                status.as_u16(),
+                &err_body,
+            ));
         }
 
-        Err(last_error.unwrap_or_else(|| {
-            format!("Impossibile aggiornare automezzo IdAutomezzo={}", id_automezzo)
-        }))
+        Err(last_error.unwrap_or_else(|| format!("Impossibile aggiornare automezzo IdAutomezzo={}", id_automezzo).into()))
     }
 
-    /// Aggiorna una tratta per IdTratta (PATCH PostgREST)
+    /// Aggiorna una tratta per IdTratta (PATCH PostgREST); offline va in outbox
     pub async fn patch_tratta(
         &self,
         id_tratta: u32,
         body: &serde_json::Map<String, Value>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
+        let result = match self.offline_shortcut() {
+            Some(e) => Err(e),
+            None => self.patch_tratta_remote(id_tratta, body).await,
+        };
+        self.queue_if_offline(result, OutboxOp::Update, "tratte", &id_tratta.to_string(), body)
+    }
+
+    async fn patch_tratta_remote(
+        &self,
+        id_tratta: u32,
+        body: &serde_json::Map<String, Value>,
+    ) -> Result<(), ApiError> {
         if body.is_empty() {
             return Ok(());
         }
 
         let table_name = &self.config.tables.tratte;
-        let base = self.config.url.trim_end_matches('/');
-        let mut last_error: Option<String> = None;
+        let mut last_error: Option<ApiError> = None;
 
         for id_col in ["IdTratta", "ID_TRATTA", "id"] {
-            let url = format!(
-                "{}/rest/v1/{}?{}=eq.{}",
-                base, table_name, id_col, id_tratta
-            );
+            let url = self.rest_url(table_name, &Query::new().eq(id_col, id_tratta));
 
             println!(
                 "📡 Supabase PATCH [tratte → {}] {}={}",
@@ -1672,10 +3462,11 @@ impl SupabaseClient {
                 .json(body);
 
             let response = self
-                .apply_auth_headers(request)
-                .send()
-                .await
-                .map_err(|e| format!("Errore connessione Supabase PATCH tratte: {}", e))?;
+                .send_with_retry(
+                    self.apply_auth_headers(request),
+                    "Errore connessione Supabase PATCH tratte",
+                )
+                .await?;
 
             if response.status().is_success() {
                 return Ok(());
@@ -1683,22 +3474,23 @@ impl SupabaseClient {
 
             let status = response.status();
             let err_body = response.text().await.unwrap_or_default();
-            last_error = Some(format!("HTTP {}: {}", status, err_body));
+            last_error = Some(ApiError::from_response(
+                "Errore Supabase",
+                status.as_u16(),
+                &err_body,
+            ));
         }
 
-        Err(last_error.unwrap_or_else(|| {
-            format!("Impossibile aggiornare tratta IdTratta={}", id_tratta)
-        }))
+        Err(last_error.unwrap_or_else(|| format!("Impossibile aggiornare tratta IdTratta={}", id_tratta).into()))
     }
 
     /// Inserisce un nuovo automezzo (POST PostgREST)
     pub async fn insert_automezzo(
         &self,
         body: &serde_json::Map<String, Value>,
-    ) -> Result<Value, String> {
+    ) -> Result<Value, ApiError> {
         let table_name = &self.config.tables.automezzi;
-        let base = self.config.url.trim_end_matches('/');
-        let url = format!("{}/rest/v1/{}", base, table_name);
+        let url = self.rest_url(table_name, &Query::new());
 
         println!("📡 Supabase POST [automezzi → {}]", table_name);
 
@@ -1713,14 +3505,15 @@ impl SupabaseClient {
             .apply_auth_headers(request)
             .send()
             .await
-            .map_err(|e| format!("Errore connessione Supabase POST automezzi: {}", e))?;
+            .map_err(|e| ApiError::network("Errore connessione Supabase POST automezzi", e))?;
 
         if !response.status().is_success() {
             let status = response.status();
             let err_body = response.text().await.unwrap_or_default();
-            return Err(format!(
-                "Errore Supabase POST automezzi HTTP {}: {}",
-                status, err_body
+            return Err(ApiError::from_response(
+                "Errore Supabase POST automezzi",
+                status.as_u16(),
+                &err_body,
             ));
         }
 
@@ -1731,7 +3524,7 @@ impl SupabaseClient {
 
         rows.into_iter()
             .next()
-            .ok_or_else(|| "Nessuna riga restituita dopo insert automezzo".to_string())
+            .ok_or_else(|| "Nessuna riga restituita dopo insert automezzo".into())
     }
 
     /// Salva una riga in Tesseramenti_supa:
@@ -1742,7 +3535,7 @@ impl SupabaseClient {
         &self,
         body: &serde_json::Map<String, Value>,
         row_id: Option<&str>,
-    ) -> Result<Value, String> {
+    ) -> Result<Value, ApiError> {
         let idsocio = body
             .get("IdSocio")
             .map(json_to_string)
@@ -1750,14 +3543,12 @@ impl SupabaseClient {
         let anno = body.get("Anno").map(json_to_string).unwrap_or_default();
 
         if idsocio.is_empty() || anno.is_empty() {
-            return Err("IdSocio e Anno obbligatori per il tesseramento".to_string());
+            return Err("IdSocio e Anno obbligatori per il tesseramento".into());
         }
 
         let table_name = &self.config.tables.tesseramenti;
-        let base = self.config.url.trim_end_matches('/');
-
         let response = if let Some(id) = row_id.map(str::trim).filter(|s| !s.is_empty()) {
-            let url = format!("{}/rest/v1/{}?id=eq.{}", base, table_name, id);
+            let url = self.rest_url(table_name, &Query::new().eq("id", id));
             println!(
                 "📡 Supabase PATCH [tesseramenti → {}] id={} IdSocio={} Anno={}",
                 table_name, id, idsocio, anno
@@ -1770,12 +3561,13 @@ impl SupabaseClient {
                 .header("Prefer", "return=representation")
                 .json(body);
 
-            self.apply_auth_headers(request)
-                .send()
-                .await
-                .map_err(|e| format!("Errore connessione Supabase PATCH tesseramenti: {}", e))?
+            self.send_with_retry(
+                self.apply_auth_headers(request),
+                "Errore connessione Supabase PATCH tesseramenti",
+            )
+            .await?
         } else {
-            let url = format!("{}/rest/v1/{}", base, table_name);
+            let url = self.rest_url(table_name, &Query::new());
             println!(
                 "📡 Supabase POST [tesseramenti → {}] IdSocio={} Anno={}",
                 table_name, idsocio, anno
@@ -1791,15 +3583,16 @@ impl SupabaseClient {
             self.apply_auth_headers(request)
                 .send()
                 .await
-                .map_err(|e| format!("Errore connessione Supabase POST tesseramenti: {}", e))?
+                .map_err(|e| ApiError::network("Errore connessione Supabase POST tesseramenti", e))?
         };
 
         if !response.status().is_success() {
             let status = response.status();
             let err_body = response.text().await.unwrap_or_default();
-            return Err(format!(
-                "Errore Supabase salvataggio tesseramenti HTTP {}: {}",
-                status, err_body
+            return Err(ApiError::from_response(
+                "Errore Supabase salvataggio tesseramenti",
+                status.as_u16(),
+                &err_body,
             ));
         }
 
@@ -1813,6 +3606,7 @@ impl SupabaseClient {
                 "Nessuna riga restituita dopo salvataggio tesseramento IdSocio={} Anno={}",
                 idsocio, anno
             )
+            .into()
         })
     }
 
@@ -1824,7 +3618,7 @@ impl SupabaseClient {
         numero: Option<&Value>,
         data: Option<&Value>,
         tipologia: Option<&Value>,
-    ) -> Result<(), String> {
+    ) -> Result<(), ApiError> {
         if idsocio.is_empty() || anno.is_empty() {
             return Ok(());
         }
@@ -1850,6 +3644,542 @@ impl SupabaseClient {
         );
         self.patch_tesserato(idsocio, &patch).await
     }
+
+    // --- Modifiche concorrenti ---
+
+    /// Ricorda le righe lette, per sapere più tardi cosa è cambiato rispetto
+    /// alla versione aperta dall'utente
+    pub(crate) fn ricorda_versioni(&self, table_type: &str, rows: &[Value]) {
+        let mut lette = match self.versioni_lette.lock() {
+            Ok(lette) => lette,
+            Err(_) => return,
+        };
+        for row in rows {
+            let chiave = match row.get(offline::key_column(table_type, Some(row))) {
+                Some(v) if !v.is_null() => format!("{}:{}", table_type, json_to_string(v)),
+                _ => continue,
+            };
+            let versione = versione_riga(row);
+            let storico = lette.entry(chiave).or_default();
+            if storico.iter().any(|(v, _)| *v == versione) {
+                continue;
+            }
+            if storico.len() >= VERSIONI_PER_RIGA {
+                storico.remove(0);
+            }
+            storico.push((versione, row.clone()));
+        }
+    }
+
+    fn versione_letta(&self, table_type: &str, key: &str, versione: &str) -> Option<Value> {
+        self.versioni_lette
+            .lock()
+            .ok()?
+            .get(&format!("{}:{}", table_type, key))?
+            .iter()
+            .find(|(v, _)| v == versione)
+            .map(|(_, row)| row.clone())
+    }
+
+    async fn leggi_riga_remota(&self, table_type: &str, key: &str) -> Result<Option<Value>, ApiError> {
+        let query = Query::new().eq(offline::key_column(table_type, None), key).limit(1);
+        Ok(self.fetch_table_remote(table_type, &query).await?.into_iter().next())
+    }
+
+    /// PATCH senza controllo di versione; offline va in outbox
+    async fn patch_riga(
+        &self,
+        table_type: &str,
+        key: &str,
+        body: &serde_json::Map<String, Value>,
+    ) -> Result<(), ApiError> {
+        let id = || {
+            key.parse::<u32>().map_err(|_| {
+                ApiError::from(format!("Chiave non numerica per {}: {}", table_type, key))
+            })
+        };
+        match table_type {
+            "servizi" => self.patch_servizio(id()?, body).await,
+            "tesserati" => self.patch_tesserato(key, body).await,
+            "automezzi" => self.patch_automezzo(id()?, body).await,
+            other => Err(format!("Salvataggio con versione non gestito per {}", other).into()),
+        }
+    }
+
+    /// Salva una riga solo se nessun altro ha cambiato, dopo la `versione` aperta
+    /// dall'utente, i campi che si stanno scrivendo (controllo ottimistico, stessa
+    /// regola del rinvio outbox). Se la tabella ha una colonna di modifica il PATCH
+    /// è condizionato al suo valore, così nemmeno due salvataggi nello stesso
+    /// istante si sovrascrivono. Senza versione oppure offline è il salvataggio
+    /// normale (i conflitti offline li controlla il rinvio della outbox).
+    /// Restituisce la riga aggiornata quando il server la rimanda.
+    pub async fn patch_versionato(
+        &self,
+        table_type: &str,
+        key: &str,
+        versione: Option<&str>,
+        body: &serde_json::Map<String, Value>,
+    ) -> Result<Option<Value>, ApiError> {
+        let versione = match versione.map(str::trim).filter(|v| !v.is_empty()) {
+            Some(v) if self.offline_shortcut().is_none() => v,
+            _ => return self.patch_riga(table_type, key, body).await.map(|_| None),
+        };
+        if body.is_empty() {
+            return Ok(None);
+        }
+        let table_name = self
+            .config
+            .tables
+            .table_name(table_type)
+            .ok_or_else(|| ApiError::config(format!("Tabella non configurata: {}", table_type)))?;
+
+        let mut tentativo: u32 = 0;
+        loop {
+            tentativo += 1;
+            let attuale = match self.leggi_riga_remota(table_type, key).await {
+                Ok(Some(row)) => row,
+                Ok(None) => {
+                    return Err(ApiError::not_found(format!("{} {} non trovato", table_type, key)))
+                }
+                // Rete assente: il PATCH normale finisce in outbox
+                Err(ApiError::Network { .. }) => {
+                    return self.patch_riga(table_type, key, body).await.map(|_| None)
+                }
+                Err(e) => return Err(e),
+            };
+            if versione_riga(&attuale) != versione {
+                let campi = self.campi_in_conflitto(table_type, key, versione, &attuale, body);
+                if !campi.is_empty() {
+                    return Err(errore_concorrente(table_type, key, campi, &attuale));
+                }
+            }
+
+            let key_column = offline::key_column(table_type, Some(&attuale));
+            let mut query = Query::new().eq(key_column, key);
+            let mut body = body.clone();
+            let colonna = colonna_modifica(&attuale);
+            if let Some(colonna) = colonna {
+                query = match attuale.get(colonna) {
+                    Some(v) if !v.is_null() => query.eq(colonna, json_to_string(v)),
+                    _ => query.is_null(colonna),
+                };
+                // La versione deve cambiare anche se il database non ha un trigger
+                body.entry(colonna.to_string())
+                    .or_insert_with(|| serde_json::json!(chrono::Local::now().to_rfc3339()));
+            }
+
+            println!(
+                "📡 Supabase PATCH [{} → {}] {}={} versione {}",
+                table_type, table_name, key_column, key, versione
+            );
+
+            let request = self
+                .http
+                .patch(self.rest_url(table_name, &query))
+                .header("Content-Type", "application/json")
+                .header("Prefer", "return=representation")
+                .json(&body);
+            let contesto = format!("Errore connessione Supabase PATCH {}", table_type);
+            let response = match self
+                .send_with_retry(self.apply_auth_headers(request), &contesto)
+                .await
+            {
+                Ok(r) => r,
+                Err(e) => {
+                    return self
+                        .queue_if_offline(Err(e), OutboxOp::Update, table_type, key, &body)
+                        .map(|_| None)
+                }
+            };
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let err_body = response.text().await.unwrap_or_default();
+                return Err(ApiError::from_response(
+                    &format!("Errore Supabase PATCH {}", table_type),
+                    status.as_u16(),
+                    &err_body,
+                ));
+            }
+
+            let rows: Vec<Value> = response
+                .json()
+                .await
+                .map_err(|e| format!("Errore parsing risposta PATCH {}: {}", table_type, e))?;
+            if let Some(row) = rows.into_iter().next() {
+                self.ricorda_versioni(table_type, std::slice::from_ref(&row));
+                return Ok(Some(row));
+            }
+
+            // Nessuna riga aggiornata: qualcuno ha salvato fra la lettura e il PATCH
+            // (oppure un tentativo ripetuto dopo un timeout era già andato a buon fine)
+            let dopo = self
+                .leggi_riga_remota(table_type, key)
+                .await?
+                .ok_or_else(|| ApiError::not_found(format!("{} {} non trovato", table_type, key)))?;
+            if let Some(colonna) = colonna {
+                if dopo.get(colonna).map(json_to_string) == body.get(colonna).map(json_to_string) {
+                    return Ok(Some(dopo));
+                }
+            }
+            if tentativo >= TENTATIVI_PATCH_VERSIONATO {
+                let campi = self.campi_in_conflitto(table_type, key, versione, &dopo, &body);
+                return Err(errore_concorrente(table_type, key, campi, &dopo));
+            }
+            println!("  🔁 {} {} cambiato durante il salvataggio, nuovo controllo", table_type, key);
+        }
+    }
+
+    /// Campi inviati che un altro utente ha cambiato dopo la versione aperta,
+    /// con un valore diverso da quello che si sta scrivendo. Se quella versione
+    /// non è più in memoria vale ogni campo inviato diverso dal valore sul server.
+    fn campi_in_conflitto(
+        &self,
+        table_type: &str,
+        key: &str,
+        versione: &str,
+        attuale: &Value,
+        body: &serde_json::Map<String, Value>,
+    ) -> Vec<String> {
+        let stesso = |a: Option<&Value>, b: Option<&Value>| {
+            let a = a.map(json_to_string).unwrap_or_default();
+            let b = b.map(json_to_string).unwrap_or_default();
+            a.trim() == b.trim()
+        };
+        let base = self.versione_letta(table_type, key, versione);
+        body.keys()
+            .filter(|k| !CAMPI_AUDIT.contains(&k.as_str()))
+            .filter(|k| {
+                let sul_server = attuale.get(k.as_str());
+                let cambiato = base
+                    .as_ref()
+                    .map(|b| !stesso(sul_server, b.get(k.as_str())))
+                    .unwrap_or(true);
+                cambiato && !stesso(sul_server, body.get(k.as_str()))
+            })
+            .cloned()
+            .collect()
+    }
+
+    // --- Outbox offline ---
+
+    /// Se la scrittura è fallita per mancanza di rete e c'è la copia locale,
+    /// la accoda nella outbox e la considera riuscita
+    fn queue_if_offline(
+        &self,
+        result: Result<(), ApiError>,
+        op: OutboxOp,
+        table_type: &str,
+        key: &str,
+        body: &serde_json::Map<String, Value>,
+    ) -> Result<(), ApiError> {
+        let (err, store) = match (result, &self.offline) {
+            (Err(e @ ApiError::Network { .. }), Some(store)) => (e, store),
+            (result, _) => return result,
+        };
+        self.set_offline_mode(true);
+        let riferimento = store
+            .get_row(table_type, key)?
+            .unwrap_or_else(|| Value::Object(body.clone()));
+        let key_column = offline::key_column(table_type, Some(&riferimento));
+        let voce = store.enqueue(op, table_type, key_column, key, body)?;
+        println!(
+            "📴 {} → [{} {}={}] salvato in locale, voce outbox #{}",
+            err, table_type, key_column, key, voce
+        );
+        Ok(())
+    }
+
+    /// Rinvia in ordine le scritture fatte offline. Si ferma al primo errore di
+    /// rete; una voce in conflitto o in errore blocca le successive sulla stessa
+    /// riga, che restano in coda finché non viene forzata o scartata.
+    pub async fn replay_outbox(&self) -> Result<OutboxSyncReport, ApiError> {
+        let store = self
+            .offline
+            .clone()
+            .ok_or_else(|| ApiError::config("Copia locale offline non disponibile"))?;
+        // Un solo rinvio alla volta (comando manuale e sincronizzazione periodica)
+        let _turno = store.replay_lock.lock().await;
+        let mut report = OutboxSyncReport::default();
+
+        let ids: Vec<i64> = store.pending()?.iter().map(|e| e.id).collect();
+        for id in ids {
+            // Riletta ogni volta: un insert precedente può averne cambiato la chiave
+            let entry = match store.entry(id)? {
+                Some(e) if e.status == offline::STATO_IN_ATTESA => e,
+                _ => continue,
+            };
+
+            if let Some(prima) = store.blocked_by(&entry)? {
+                let messaggio = format!("In attesa della voce #{} sulla stessa riga", prima);
+                store.set_status(entry.id, offline::STATO_CONFLITTO, Some(&messaggio))?;
+                report.conflitti.push(problema(&entry, Vec::new(), messaggio));
+                continue;
+            }
+
+            println!(
+                "🔄 Outbox #{}: {:?} [{} {}]",
+                entry.id, entry.op, entry.table_type, entry.key_value
+            );
+            match self.replay_entry(&store, &entry, &mut report).await {
+                Ok(None) => {
+                    let entry = store.entry(id)?.unwrap_or(entry);
+                    store.complete(&entry)?;
+                    report.inviate += 1;
+                    self.set_offline_mode(false);
+                }
+                Ok(Some((campi, messaggio))) => {
+                    println!("  ⚠️ Conflitto: {}", messaggio);
+                    store.set_status(entry.id, offline::STATO_CONFLITTO, Some(&messaggio))?;
+                    report.conflitti.push(problema(&entry, campi, messaggio));
+                }
+                Err(e @ ApiError::Network { .. }) => {
+                    println!("  📴 Ancora offline, rinvio sospeso: {}", e);
+                    report.offline = true;
+                    self.set_offline_mode(true);
+                    break;
+                }
+                Err(e) => {
+                    let messaggio = e.to_string();
+                    println!("  ❌ Errore: {}", messaggio);
+                    store.set_status(entry.id, offline::STATO_ERRORE, Some(&messaggio))?;
+                    report.errori.push(problema(&entry, Vec::new(), messaggio));
+                }
+            }
+        }
+
+        report.in_attesa = store.pending_count();
+        Ok(report)
+    }
+
+    /// Invia una voce; `Some((campi, messaggio))` se c'è un conflitto
+    async fn replay_entry(
+        &self,
+        store: &OfflineStore,
+        entry: &OutboxEntry,
+        report: &mut OutboxSyncReport,
+    ) -> Result<Option<(Vec<String>, String)>, ApiError> {
+        let id_numerico = || {
+            entry.key_value.parse::<u32>().map_err(|_| {
+                ApiError::from(format!(
+                    "Chiave non numerica per {}: {}",
+                    entry.table_type, entry.key_value
+                ))
+            })
+        };
+
+        match (entry.op, entry.table_type.as_str()) {
+            (OutboxOp::Insert, "servizi") => {
+                // L'ID dato offline è provvisorio: nel frattempo altri possono averlo usato
+                let (definitivo, _) = self
+                    .insert_servizio_nuovo_id_remote(&entry.body, &entry.key_column)
+                    .await?;
+                let definitivo = definitivo.to_string();
+                if definitivo != entry.key_value {
+                    store.rewrite_key("servizi", &entry.key_column, &entry.key_value, &definitivo)?;
+                    println!(
+                        "  📋 Servizio creato offline {} registrato come {}",
+                        entry.key_value, definitivo
+                    );
+                    report.id_riassegnati.push(IdRiassegnato {
+                        tabella: entry.table_type.clone(),
+                        locale: entry.key_value.clone(),
+                        definitivo,
+                    });
+                }
+                return Ok(None);
+            }
+            (OutboxOp::Insert, "cestino_servizi") => {
+                self.insert_cestino_servizio_remote(&entry.body).await?;
+                return Ok(None);
+            }
+            (OutboxOp::Insert, "audit") => {
+                self.insert_audit_remote(&entry.body).await?;
+                return Ok(None);
+            }
+            (OutboxOp::Insert, other) => {
+                return Err(format!("Inserimento offline non gestito per {}", other).into());
+            }
+            _ => {}
+        }
+
+        if let Some(conflitto) = self.check_outbox_conflict(entry).await? {
+            return Ok(Some(conflitto));
+        }
+
+        match (entry.op, entry.table_type.as_str()) {
+            (OutboxOp::Delete, "servizi") => match self.delete_servizio_remote(id_numerico()?).await {
+                Ok(()) | Err(ApiError::NotFound { .. }) => {}
+                Err(e) => return Err(e),
+            },
+            (OutboxOp::Update, "servizi") => {
+                self.patch_servizio_remote(id_numerico()?, &entry.body).await?
+            }
+            (OutboxOp::Update, "tesserati") => {
+                self.patch_tesserato_remote(&entry.key_value, &entry.body).await?
+            }
+            (OutboxOp::Update, "automezzi") => {
+                self.patch_automezzo_remote(id_numerico()?, &entry.body).await?
+            }
+            (OutboxOp::Update, "tratte") => {
+                self.patch_tratta_remote(id_numerico()?, &entry.body).await?
+            }
+            (op, other) => {
+                return Err(format!("Operazione {:?} non gestita offline per {}", op, other).into());
+            }
+        }
+        Ok(None)
+    }
+
+    /// Confronta la riga attuale sul server con quella letta prima della modifica
+    /// offline: un campo è in conflitto se nel frattempo qualcun altro l'ha cambiato
+    /// con un valore diverso da quello che stiamo per scrivere.
+    async fn check_outbox_conflict(
+        &self,
+        entry: &OutboxEntry,
+    ) -> Result<Option<(Vec<String>, String)>, ApiError> {
+        let base = match &entry.base {
+            Some(base) => base,
+            None => return Ok(None),
+        };
+
+        let query = Query::new().eq(&entry.key_column, &entry.key_value).limit(1);
+        let server = match self
+            .fetch_table_remote(&entry.table_type, &query)
+            .await?
+            .into_iter()
+            .next()
+        {
+            Some(row) => row,
+            None if entry.op == OutboxOp::Delete => return Ok(None),
+            None => {
+                return Ok(Some((
+                    Vec::new(),
+                    format!(
+                        "{} {} eliminato sul server mentre era offline",
+                        entry.table_type, entry.key_value
+                    ),
+                )))
+            }
+        };
+
+        let stesso = |a: Option<&Value>, b: Option<&Value>| {
+            let a = a.map(json_to_string).unwrap_or_default();
+            let b = b.map(json_to_string).unwrap_or_default();
+            a.trim() == b.trim()
+        };
+        // Per un delete conta qualsiasi modifica, per un update solo i campi inviati
+        let colonne: Vec<&String> = match entry.op {
+            OutboxOp::Delete => base
+                .as_object()
+                .map(|o| o.keys().collect())
+                .unwrap_or_default(),
+            _ => entry.body.keys().collect(),
+        };
+        let campi: Vec<String> = colonne
+            .into_iter()
+            .filter(|k| !CAMPI_AUDIT.contains(&k.as_str()))
+            .filter(|k| {
+                let sul_server = server.get(k.as_str());
+                !stesso(sul_server, base.get(k.as_str()))
+                    && (entry.op == OutboxOp::Delete || !stesso(sul_server, entry.body.get(k.as_str())))
+            })
+            .cloned()
+            .collect();
+
+        if campi.is_empty() {
+            return Ok(None);
+        }
+        let autore = autore_modifica(&server);
+        let messaggio = format!(
+            "{} {} modificato da {} mentre era offline: {}",
+            entry.table_type,
+            entry.key_value,
+            autore.as_deref().unwrap_or("un altro utente"),
+            campi.join(", ")
+        );
+        Ok(Some((campi, messaggio)))
+    }
+}
+
+/// Campi di audit (chi/quando): cambiano a ogni salvataggio, non sono conflitti
+const CAMPI_AUDIT: &[&str] = &[
+    "ModificatoDa",
+    "modificato_da",
+    "Modificato_Da",
+    "MODIFICATODA",
+    "modificated",
+    "Modificated",
+    "MODIFICATED",
+    "modified",
+    "Modified",
+    "updated_at",
+    "Updated_At",
+];
+
+/// Errore di modifica concorrente con i campi in conflitto e l'autore dell'altra modifica
+fn errore_concorrente(table_type: &str, key: &str, fields: Vec<String>, attuale: &Value) -> ApiError {
+    let modified_by = autore_modifica(attuale);
+    let mut message = format!(
+        "{} {} modificato da {} dopo l'apertura",
+        table_type,
+        key,
+        modified_by.as_deref().unwrap_or("un altro utente")
+    );
+    if !fields.is_empty() {
+        message.push_str(&format!(" (campi: {})", fields.join(", ")));
+    }
+    println!("⚠️ {}", message);
+
+    ApiError::ConcurrentUpdate {
+        message,
+        fields,
+        modified_by,
+        current_version: versione_riga(attuale),
+    }
+}
+
+/// ModificatoDa della riga, se compilato
+fn autore_modifica(row: &Value) -> Option<String> {
+    ["ModificatoDa", "modificato_da", "Modificato_Da", "MODIFICATODA"]
+        .iter()
+        .map(|c| get_field(row, c))
+        .find(|v| !v.trim().is_empty())
+}
+
+/// Colonna timestamp di modifica presente nella riga (anche se ancora vuota)
+fn colonna_modifica(row: &Value) -> Option<&'static str> {
+    let obj = row.as_object()?;
+    crate::delta::COLONNE_MODIFICA
+        .iter()
+        .copied()
+        .find(|c| obj.contains_key(*c))
+}
+
+/// Versione di una riga per il controllo delle modifiche concorrenti: il timestamp
+/// di modifica se compilato, altrimenti un'impronta dell'intero contenuto
+pub fn versione_riga(row: &Value) -> String {
+    let ts = colonna_modifica(row)
+        .and_then(|c| row.get(c))
+        .map(json_to_string)
+        .filter(|v| !v.trim().is_empty());
+    if let Some(ts) = ts {
+        return ts;
+    }
+    let mut hasher = DefaultHasher::new();
+    row.to_string().hash(&mut hasher);
+    format!("h:{:016x}", hasher.finish())
+}
+
+fn problema(entry: &OutboxEntry, campi: Vec<String>, messaggio: String) -> OutboxProblema {
+    OutboxProblema {
+        voce: entry.id,
+        tabella: entry.table_type.clone(),
+        chiave: entry.key_value.clone(),
+        operazione: entry.op,
+        campi,
+        messaggio,
+    }
 }
 
 /// Codifica un valore per filtri query PostgREST.
@@ -1898,6 +4228,16 @@ fn resolve_tipologia_lookup_column(row: &Value) -> Option<String> {
     None
 }
 
+/// Codice alfanumerico casuale (IdSerie, IdViaggio, chiavi audit, token)
+pub fn codice_casuale(lunghezza: usize) -> String {
+    use rand::Rng;
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(lunghezza)
+        .map(char::from)
+        .collect()
+}
+
 /// Converte un valore JSON Supabase in stringa
 pub fn json_to_string(val: &Value) -> String {
     match val {
@@ -1985,3 +4325,345 @@ pub fn format_date_iso(date_str: &str) -> String {
     }
     trimmed.to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// PostgREST finto: RPC dell'idservizio (presente o no), max idservizio e
+    /// POST servizi che risponde 23505 ai primi `conflitti` inserimenti, come
+    /// se un'altra postazione avesse appena usato lo stesso ID
+    #[derive(Default)]
+    struct StatoMock {
+        rpc_presente: bool,
+        conflitti: usize,
+        prossimo_rpc: u32,
+        max_id: u32,
+        chiamate_rpc: usize,
+        id_inseriti: Vec<u32>,
+        /// GET a cui rispondere 503 con un Retry-After di un'ora
+        occupato: usize,
+        letture: usize,
+    }
+
+    async fn leggi_richiesta(stream: &mut tokio::net::TcpStream) -> (String, String) {
+        let mut dati = Vec::new();
+        let mut buf = [0u8; 4096];
+        let fine_header = loop {
+            let n = stream.read(&mut buf).await.unwrap_or(0);
+            if n == 0 {
+                return (String::new(), String::new());
+            }
+            dati.extend_from_slice(&buf[..n]);
+            if let Some(pos) = dati.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+        let header = String::from_utf8_lossy(&dati[..fine_header]).to_string();
+        let lunghezza = header
+            .lines()
+            .find_map(|l| {
+                let (nome, valore) = l.split_once(':')?;
+                nome.eq_ignore_ascii_case("content-length").then(|| valore.trim().parse().ok())?
+            })
+            .unwrap_or(0usize);
+        while dati.len() < fine_header + lunghezza {
+            let n = stream.read(&mut buf).await.unwrap_or(0);
+            if n == 0 {
+                break;
+            }
+            dati.extend_from_slice(&buf[..n]);
+        }
+        let riga = header.lines().next().unwrap_or("").to_string();
+        (riga, String::from_utf8_lossy(&dati[fine_header..]).to_string())
+    }
+
+    fn risposta(stato: &str, corpo: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            stato,
+            corpo.len(),
+            corpo
+        )
+    }
+
+    fn rispondi(stato: &Mutex<StatoMock>, riga: &str, corpo: &str) -> String {
+        let mut stato = stato.lock().unwrap();
+        if riga.starts_with("POST /rest/v1/rpc/next_servizio_id") {
+            stato.chiamate_rpc += 1;
+            if !stato.rpc_presente {
+                return risposta(
+                    "404 Not Found",
+                    r#"{"code":"PGRST202","message":"Could not find the function public.next_servizio_id"}"#,
+                );
+            }
+            stato.prossimo_rpc += 1;
+            return risposta("200 OK", &stato.prossimo_rpc.to_string());
+        }
+        if riga.starts_with("GET /rest/v1/Servizi_supa") {
+            stato.letture += 1;
+            if stato.letture <= stato.occupato {
+                return risposta("503 Service Unavailable", "{}")
+                    .replacen("\r\n", "\r\nRetry-After: 3600\r\n", 1);
+            }
+            return risposta("200 OK", &format!(r#"[{{"idservizio":{}}}]"#, stato.max_id));
+        }
+        if riga.starts_with("POST /rest/v1/Servizi_supa") {
+            let body: Value = serde_json::from_str(corpo).unwrap_or(Value::Null);
+            let id = get_field(&body, "idservizio").parse().unwrap_or(0);
+            stato.id_inseriti.push(id);
+            if stato.id_inseriti.len() <= stato.conflitti {
+                // L'altra postazione ha preso proprio questo ID
+                stato.max_id = stato.max_id.max(id);
+                return risposta(
+                    "409 Conflict",
+                    r#"{"code":"23505","message":"duplicate key value violates unique constraint"}"#,
+                );
+            }
+            stato.max_id = stato.max_id.max(id);
+            return risposta("201 Created", &format!("[{}]", body));
+        }
+        risposta("404 Not Found", r#"{"message":"percorso non previsto"}"#)
+    }
+
+    async fn avvia_mock(stato: StatoMock) -> (String, Arc<Mutex<StatoMock>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let stato = Arc::new(Mutex::new(stato));
+        let condiviso = stato.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let stato = condiviso.clone();
+                tokio::spawn(async move {
+                    let (riga, corpo) = leggi_richiesta(&mut stream).await;
+                    let testo = rispondi(&stato, &riga, &corpo);
+                    let _ = stream.write_all(testo.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        (url, stato)
+    }
+
+    fn client_mock(url: &str) -> SupabaseClient {
+        SupabaseClient::new(SupabaseConfig {
+            url: url.to_string(),
+            anon_key: "sb_publishable_test".to_string(),
+            service_key: None,
+            tables: SupabaseTablesConfig {
+                servizi: "Servizi_supa".to_string(),
+                ..SupabaseTablesConfig::default()
+            },
+            http: SupabaseHttpConfig {
+                max_retries: 0,
+                ..SupabaseHttpConfig::default()
+            },
+        })
+    }
+
+    fn corpo_servizio() -> serde_json::Map<String, Value> {
+        let mut body = serde_json::Map::new();
+        body.insert("Trasportato".to_string(), serde_json::json!("ROSSI MARIO"));
+        body
+    }
+
+    #[tokio::test]
+    async fn nuovo_servizio_riprova_con_id_nuovo_dalla_sequenza() {
+        let (url, stato) = avvia_mock(StatoMock {
+            rpc_presente: true,
+            conflitti: 2,
+            prossimo_rpc: 100,
+            ..StatoMock::default()
+        })
+        .await;
+
+        let (id, riga) = client_mock(&url)
+            .insert_servizio_nuovo_id_remote(&corpo_servizio(), "idservizio")
+            .await
+            .expect("il terzo inserimento deve riuscire");
+
+        let stato = stato.lock().unwrap();
+        assert_eq!(stato.id_inseriti, vec![101, 102, 103]);
+        assert_eq!(stato.chiamate_rpc, 3);
+        assert_eq!(id, 103);
+        assert_eq!(get_field(&riga, "Trasportato"), "ROSSI MARIO");
+    }
+
+    #[tokio::test]
+    async fn nuovo_servizio_si_ferma_dopo_i_tentativi_previsti() {
+        let (url, stato) = avvia_mock(StatoMock {
+            rpc_presente: true,
+            conflitti: usize::MAX,
+            prossimo_rpc: 500,
+            ..StatoMock::default()
+        })
+        .await;
+
+        let esito = client_mock(&url)
+            .insert_servizio_nuovo_id_remote(&corpo_servizio(), "idservizio")
+            .await;
+
+        match esito {
+            Err(ApiError::Conflict { code, .. }) => assert_eq!(code.as_deref(), Some("23505")),
+            altro => panic!("atteso il conflitto 23505, ottenuto {:?}", altro.map(|(id, _)| id)),
+        }
+        let stato = stato.lock().unwrap();
+        assert_eq!(stato.id_inseriti.len(), TENTATIVI_NUOVO_SERVIZIO as usize);
+        let distinti: std::collections::HashSet<_> = stato.id_inseriti.iter().collect();
+        assert_eq!(distinti.len(), stato.id_inseriti.len(), "ogni tentativo usa un ID nuovo");
+    }
+
+    #[tokio::test]
+    async fn nuovo_servizio_senza_rpc_usa_max_piu_uno() {
+        let (url, stato) = avvia_mock(StatoMock {
+            rpc_presente: false,
+            conflitti: 1,
+            max_id: 41,
+            ..StatoMock::default()
+        })
+        .await;
+        let client = client_mock(&url);
+
+        let (id, _) = client
+            .insert_servizio_nuovo_id_remote(&corpo_servizio(), "idservizio")
+            .await
+            .expect("il secondo inserimento deve riuscire");
+        assert_eq!(id, 43);
+        {
+            let stato = stato.lock().unwrap();
+            // 42 preso dall'altra postazione, poi max+1 riletto
+            assert_eq!(stato.id_inseriti, vec![42, 43]);
+            // La funzione mancante si scopre una volta sola
+            assert_eq!(stato.chiamate_rpc, 1);
+        }
+
+        let (id, _) = client
+            .insert_servizio_nuovo_id_remote(&corpo_servizio(), "idservizio")
+            .await
+            .expect("inserimento successivo");
+        assert_eq!(id, 44);
+        assert_eq!(stato.lock().unwrap().chiamate_rpc, 1);
+    }
+
+    #[tokio::test]
+    async fn retry_after_oltre_il_massimo_attende_solo_il_massimo() {
+        let (url, stato) = avvia_mock(StatoMock {
+            occupato: 1,
+            max_id: 7,
+            ..StatoMock::default()
+        })
+        .await;
+        let mut client = client_mock(&url);
+        client.config.http.max_retries = 1;
+        client.config.http.retry_max_delay_ms = 20;
+
+        let rows = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            client.fetch_servizi(Query::new()),
+        )
+        .await
+        .expect("il Retry-After di un'ora va limitato a retry_max_delay_ms")
+        .expect("il secondo tentativo deve riuscire");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(stato.lock().unwrap().letture, 2);
+    }
+
+    /// Decodifica %XX per confrontare la query string con la forma leggibile
+    fn decodifica(s: &str) -> String {
+        let b = s.as_bytes();
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < b.len() {
+            if b[i] == b'%' {
+                out.push(u8::from_str_radix(&s[i + 1..i + 3], 16).unwrap());
+                i += 3;
+            } else {
+                out.push(b[i]);
+                i += 1;
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn quote_filter_value_quota_solo_i_caratteri_riservati() {
+        let casi = [
+            ("Rossi", "Rossi"),
+            ("d'Alba", "d'Alba"),
+            ("", "\"\""),
+            ("Rossi, Mario", "\"Rossi, Mario\""),
+            ("v.le Roma", "\"v.le Roma\""),
+            ("08:30", "\"08:30\""),
+            ("(sede)", "\"(sede)\""),
+            ("detto \"Nino\"", "\"detto \\\"Nino\\\"\""),
+            ("C:\\dati", "\"C:\\\\dati\""),
+        ];
+        for (valore, atteso) in casi {
+            assert_eq!(quote_filter_value(valore), atteso, "valore {:?}", valore);
+        }
+    }
+
+    #[test]
+    fn encode_query_value_lascia_solo_i_caratteri_non_riservati() {
+        assert_eq!(encode_query_value("Az09-_.~"), "Az09-_.~");
+        assert_eq!(
+            encode_query_value(",()\":\\ &=*"),
+            "%2C%28%29%22%3A%5C%20%26%3D%2A"
+        );
+        assert_eq!(encode_query_value("è"), "%C3%A8");
+    }
+
+    #[test]
+    fn eq_codifica_il_valore_senza_spezzare_la_query() {
+        let valore = "a,(b)\".c:d & x=1";
+        let q = Query::new().eq("Nome", valore).to_query_string();
+        assert!(!q.contains(['&', ',', '(', '"', ' ']), "query {}", q);
+        assert_eq!(decodifica(&q), format!("Nome=eq.{}", valore));
+        assert!(Query::new()
+            .eq("Nome", valore)
+            .matches(&serde_json::json!({ "Nome": valore })));
+    }
+
+    #[test]
+    fn in_list_quota_gli_elementi_con_caratteri_riservati() {
+        let q = Query::new()
+            .in_list("Comune", ["Asti, centro", "Bra", "d'Alba", "detto \"x\""])
+            .to_query_string();
+        assert_eq!(
+            decodifica(&q),
+            "Comune=in.(\"Asti, centro\",Bra,d'Alba,\"detto \\\"x\\\"\")"
+        );
+    }
+
+    #[test]
+    fn gruppi_or_and_quotano_i_valori_annidati() {
+        let q = Query::new()
+            .or(vec![
+                Filter::eq("Nome", "Rossi, M."),
+                Filter::ilike("Cognome", "*de (la)*"),
+                Filter::is_null("Note"),
+            ])
+            .to_query_string();
+        assert_eq!(
+            decodifica(&q),
+            "or=(Nome.eq.\"Rossi, M.\",Cognome.ilike.\"*de (la)*\",Note.is.null)"
+        );
+
+        let q = Query::new()
+            .and(vec![
+                Filter::gte("Data", "2026-01-01"),
+                Filter::or(vec![
+                    Filter::eq("Ora", "08:30"),
+                    Filter::eq("Stato", "x.y").not(),
+                ]),
+            ])
+            .to_query_string();
+        assert!(q.starts_with("and=%28"), "query {}", q);
+        assert_eq!(
+            decodifica(&q),
+            "and=(Data.gte.2026-01-01,or(Ora.eq.\"08:30\",Stato.not.eq.\"x.y\"))"
+        );
+    }
+}
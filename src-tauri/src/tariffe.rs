@@ -0,0 +1,620 @@
+// Calcolo tariffe dei servizi: unico punto in cui si decide quanto costa un
+// viaggio. Lo usano l'elenco tratte (get_all_tratte), il modale Calcola tariffa
+// di nuovo / modifica servizio (calcola_preventivo) e la chiusura del servizio
+// (completa_servizio), così lo stesso viaggio ha lo stesso prezzo ovunque.
+//
+// Preventivo = km × costo al km + pedaggio + extra + attesa oltre la franchigia.
+// I km di una tratta sono andata e ritorno (Tratta_KmAr); quelli scritti a mano
+// sono quelli da fatturare, raddoppiati solo se si chiede andata e ritorno.
+//
+// Le tariffe sono versionate (Tariffe_supa): ogni versione vale dalla sua data
+// fino al giorno prima della successiva e un servizio si prezza con quella in
+// vigore alla data del prelievo. Cambiare tariffa aggiunge una versione, non
+// riscrive tratte né versioni passate. Senza versioni valgono le impostazioni.
+// Sconti, quote fisse, gratuità e supplementi li aggiunge regole_tariffa.rs.
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::ApiError;
+use crate::regole_tariffa::{ContestoServizio, RegolaApplicata};
+use crate::{get_field_any, get_numeric_any};
+
+/// Costo al km se in Impostazioni_supa non c'è CostoAlKm
+pub const COSTO_KM_PREDEFINITO: f64 = 0.70;
+
+const CT_INIZIO: &str = "[[CT]]";
+const CT_FINE: &str = "[[/CT]]";
+const TFA_INIZIO: &str = "[[TFA]]";
+const TFA_FINE: &str = "[[/TFA]]";
+
+// Nomi accettati in Impostazioni_supa per ciascun parametro
+const NOMI_COSTO_KM: &[&str] = &["CostoAlKm", "Costo al km", "CostoKm"];
+const NOMI_EXTRA: &[&str] = &["Extra", "ExtraTariffa", "CostoExtra", "TariffaExtra"];
+const NOMI_COSTO_ATTESA: &[&str] = &["CostoAttesaOra", "CostoAttesa"];
+const NOMI_ATTESA_GRATUITA: &[&str] = &["AttesaGratuitaMinuti", "AttesaGratuita"];
+
+/// Parametri di una tariffa (da Impostazioni_supa o da una versione di Tariffe_supa)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParametriTariffa {
+    pub costo_al_km: f64,
+    /// Extra fisso aggiunto a ogni preventivo (impostazione Extra)
+    pub extra: f64,
+    /// Costo di un'ora di attesa dell'autista (impostazione CostoAttesaOra)
+    pub costo_attesa_ora: f64,
+    /// Minuti di attesa non addebitati (impostazione AttesaGratuitaMinuti)
+    pub attesa_gratuita_minuti: u32,
+}
+
+impl ParametriTariffa {
+    pub fn da_impostazioni(rows: &[Value]) -> Self {
+        let valore = |nomi: &[&str]| valore_impostazione(rows, nomi);
+        Self {
+            costo_al_km: valore(NOMI_COSTO_KM)
+                .filter(|v| *v > 0.0)
+                .unwrap_or(COSTO_KM_PREDEFINITO),
+            extra: valore(NOMI_EXTRA).unwrap_or(0.0),
+            costo_attesa_ora: valore(NOMI_COSTO_ATTESA).unwrap_or(0.0),
+            attesa_gratuita_minuti: valore(NOMI_ATTESA_GRATUITA)
+                .map(|v| v.max(0.0) as u32)
+                .unwrap_or(0),
+        }
+    }
+
+    /// Parametri con l'impostazione `nome` cambiata; None se non fa parte della tariffa
+    pub fn con_impostazione(&self, nome: &str, valore: &str) -> Option<Self> {
+        let nome = nome.trim();
+        let tra = |nomi: &[&str]| nomi.iter().any(|n| n.eq_ignore_ascii_case(nome));
+        let valore = numero(Some(valore)).unwrap_or(0.0);
+        let mut parametri = self.clone();
+        if tra(NOMI_COSTO_KM) {
+            parametri.costo_al_km = valore;
+        } else if tra(NOMI_EXTRA) {
+            parametri.extra = valore;
+        } else if tra(NOMI_COSTO_ATTESA) {
+            parametri.costo_attesa_ora = valore;
+        } else if tra(NOMI_ATTESA_GRATUITA) {
+            parametri.attesa_gratuita_minuti = valore.max(0.0) as u32;
+        } else {
+            return None;
+        }
+        Some(parametri)
+    }
+
+    pub fn valida(&self) -> Result<(), ApiError> {
+        if self.costo_al_km <= 0.0 {
+            return Err("Il costo al km deve essere maggiore di zero".into());
+        }
+        if self.extra < 0.0 || self.costo_attesa_ora < 0.0 {
+            return Err("Extra e costo dell'attesa non possono essere negativi".into());
+        }
+        Ok(())
+    }
+}
+
+/// Data del servizio: ISO ("2025-03-01", anche con ora) o italiana ("01/03/2025")
+pub fn data_servizio(testo: &str) -> Option<NaiveDate> {
+    let testo = testo.trim();
+    testo
+        .get(..10)
+        .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+        .or_else(|| NaiveDate::parse_from_str(testo, "%d/%m/%Y").ok())
+}
+
+/// Una versione della tariffa
+#[derive(Debug, Clone, Serialize)]
+pub struct VersioneTariffa {
+    pub valido_dal: NaiveDate,
+    /// Ultimo giorno di validità (None = in vigore)
+    pub valido_al: Option<NaiveDate>,
+    #[serde(flatten)]
+    pub parametri: ParametriTariffa,
+    pub creato_da: String,
+    pub creato_il: String,
+}
+
+impl VersioneTariffa {
+    fn da_riga(row: &Value) -> Option<Self> {
+        let numero = |k: &str| get_numeric_any(row, &[k]);
+        Some(Self {
+            valido_dal: data_servizio(&get_field_any(row, &["valido_dal"]))?,
+            valido_al: None,
+            parametri: ParametriTariffa {
+                costo_al_km: numero("costo_al_km"),
+                extra: numero("extra"),
+                costo_attesa_ora: numero("costo_attesa_ora"),
+                attesa_gratuita_minuti: numero("attesa_gratuita_minuti").max(0.0) as u32,
+            },
+            creato_da: get_field_any(row, &["creato_da"]),
+            creato_il: get_field_any(row, &["creato_il"]),
+        })
+    }
+
+    /// Riga da inserire in Tariffe_supa (valido_al non si salva: lo decide la versione successiva)
+    pub fn riga(&self) -> serde_json::Map<String, Value> {
+        let mut body = serde_json::Map::new();
+        body.insert(
+            "valido_dal".to_string(),
+            serde_json::json!(self.valido_dal.format("%Y-%m-%d").to_string()),
+        );
+        body.insert("costo_al_km".to_string(), serde_json::json!(self.parametri.costo_al_km));
+        body.insert("extra".to_string(), serde_json::json!(self.parametri.extra));
+        body.insert(
+            "costo_attesa_ora".to_string(),
+            serde_json::json!(self.parametri.costo_attesa_ora),
+        );
+        body.insert(
+            "attesa_gratuita_minuti".to_string(),
+            serde_json::json!(self.parametri.attesa_gratuita_minuti),
+        );
+        body.insert("creato_da".to_string(), serde_json::json!(self.creato_da));
+        body.insert("creato_il".to_string(), serde_json::json!(self.creato_il));
+        body
+    }
+}
+
+/// Tutte le versioni, dalla più vecchia
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StoricoTariffe {
+    pub versioni: Vec<VersioneTariffa>,
+}
+
+impl StoricoTariffe {
+    pub fn da_righe(rows: &[Value]) -> Self {
+        let mut versioni: Vec<VersioneTariffa> = rows
+            .iter()
+            .filter_map(VersioneTariffa::da_riga)
+            .filter(|v| v.parametri.costo_al_km > 0.0)
+            .collect();
+        versioni.sort_by_key(|v| v.valido_dal);
+        for i in 1..versioni.len() {
+            versioni[i - 1].valido_al = versioni[i].valido_dal.pred_opt();
+        }
+        Self { versioni }
+    }
+
+    /// Versione in vigore alla data; None prima della prima versione (vale allora
+    /// il CostoAlKm delle impostazioni)
+    pub fn in_vigore(&self, data: NaiveDate) -> Option<&VersioneTariffa> {
+        self.versioni.iter().rev().find(|v| v.valido_dal <= data)
+    }
+}
+
+fn valore_impostazione(rows: &[Value], nomi: &[&str]) -> Option<f64> {
+    rows.iter()
+        .find(|row| {
+            let chiave = get_field_any(
+                row,
+                &["Impostazione", "IMPOSTAZIONE", "Nome", "Chiave", "Impostazione_Nome"],
+            );
+            nomi.iter().any(|n| chiave.trim().eq_ignore_ascii_case(n))
+        })
+        .map(|row| {
+            get_numeric_any(
+                row,
+                &[
+                    "Valore",
+                    "VALORE",
+                    "Valore_Impostazione",
+                    "Impostazione_Valore",
+                    "ValoreNumerico",
+                    "CostoAlKm",
+                ],
+            )
+        })
+}
+
+/// Importi di una riga di Tratte_supa (km A/R, tariffa, pedaggio)
+#[derive(Debug, Clone, Copy)]
+pub struct ComponentiTratta {
+    pub km_ar: f64,
+    pub tariffa_km: f64,
+    pub costo: f64,
+    pub pedaggio: f64,
+    pub totale: f64,
+}
+
+/// Costo al km proprio della tratta (Tratta_TariffaKmSpeciale), se impostato.
+/// Tratta_TariffaKm non conta: era la copia del CostoAlKm globale scritta a ogni
+/// cambio di tariffa, e prevarrebbe sulle versioni.
+pub fn tariffa_speciale_tratta(row: &Value) -> Option<f64> {
+    Some(get_numeric_any(row, &["Tratta_TariffaKmSpeciale", "TRATTA_TARIFFAKMSPECIALE"]))
+        .filter(|v| *v > 0.0)
+}
+
+impl ComponentiTratta {
+    /// Tariffa propria della tratta, poi costo_al_km (> 0) della tariffa in vigore,
+    /// infine la vecchia tariffa copiata sulla tratta
+    pub fn da_riga(row: &Value, costo_al_km: f64) -> Self {
+        let km_ar = get_numeric_any(row, &["Tratta_KmAr", "TRATTA_KMAR"]);
+        let pedaggio = arrotonda(get_numeric_any(row, &["Tratta_Pedaggio", "TRATTA_PEDAGGIO"]));
+        let tariffa_km = match tariffa_speciale_tratta(row) {
+            Some(speciale) => speciale,
+            None if costo_al_km > 0.0 => costo_al_km,
+            None => get_numeric_any(row, &["Tratta_TariffaKm", "TRATTA_TARIFFAKM"]),
+        };
+        let costo = arrotonda(km_ar * tariffa_km);
+        Self {
+            km_ar,
+            tariffa_km,
+            costo,
+            pedaggio,
+            totale: arrotonda(costo + pedaggio),
+        }
+    }
+}
+
+/// Cosa si vuole prezzare: una tratta (per id o comune/località) oppure km a mano
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RichiestaPreventivo {
+    pub id_tratta: Option<u32>,
+    pub comune: Option<String>,
+    pub localita: Option<String>,
+    /// Km da fatturare quando non c'è una tratta
+    pub km: Option<String>,
+    /// Costo al km diverso da quello delle impostazioni (modale Calcola tariffa)
+    pub costo_km: Option<String>,
+    pub pedaggio: Option<String>,
+    /// Vuoto = extra delle impostazioni
+    pub extra: Option<String>,
+    /// Tratta: predefinito sì (km A/R); km a mano: predefinito no
+    pub andata_ritorno: Option<bool>,
+    #[serde(default)]
+    pub minuti_attesa: u32,
+    /// Data del servizio: sceglie la versione della tariffa (vuota = oggi)
+    pub data_prelievo: Option<String>,
+    // Dati del servizio per le regole della tariffa; la tipologia, se manca, si
+    // ricava dal socio
+    pub idsocio: Option<String>,
+    pub tipologia_socio: Option<String>,
+    pub richiedente: Option<String>,
+    pub tipo_servizio: Option<String>,
+    pub carrozzina: Option<String>,
+}
+
+impl RichiestaPreventivo {
+    /// Dalle note fine servizio: tratta fuori Asti ([[TFA]]) o tariffa calcolata ([[CT]])
+    pub fn dalle_note(note: &str) -> Option<Self> {
+        if let Some(tratta) = blocco_json(note, TFA_INIZIO, TFA_FINE) {
+            let id = match tratta.get("id")? {
+                Value::Number(n) => n.as_u64().map(|n| n as u32),
+                Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            }
+            .filter(|id| *id > 0)?;
+            return Some(Self {
+                id_tratta: Some(id),
+                ..Self::default()
+            });
+        }
+        let ct = blocco_json(note, CT_INIZIO, CT_FINE)?;
+        let testo = |k: &str| {
+            ct.get(k)
+                .map(crate::supabase::json_to_string)
+                .map(|s| s.replace('€', "").trim().to_string())
+                .filter(|s| !s.is_empty())
+        };
+        // Nel blocco i chilometri sono quelli fatturati, già raddoppiati se andata
+        // e ritorno: la richiesta vuole quelli di sola andata, o calcola li
+        // raddoppierebbe una seconda volta
+        let andata_ritorno = ct.get("andata_ritorno").and_then(Value::as_bool);
+        let km = testo("chilometri").or_else(|| testo("km")).map(|km| {
+            if andata_ritorno == Some(true) {
+                let andata = numero(Some(&km)).unwrap_or(0.0) / 2.0;
+                andata.to_string().replace('.', ",")
+            } else {
+                km
+            }
+        });
+        Some(Self {
+            km,
+            costo_km: testo("costo_km"),
+            pedaggio: testo("pedaggio"),
+            extra: testo("extra"),
+            andata_ritorno,
+            minuti_attesa: testo("attesa").and_then(|s| s.parse().ok()).unwrap_or(0),
+            ..Self::default()
+        })
+    }
+
+    pub fn contesto(&self) -> ContestoServizio {
+        let testo = |v: &Option<String>| v.as_deref().unwrap_or("").trim().to_string();
+        ContestoServizio {
+            tipologia_socio: testo(&self.tipologia_socio),
+            richiedente: testo(&self.richiedente),
+            tipo_servizio: testo(&self.tipo_servizio),
+            carrozzina: testo(&self.carrozzina),
+        }
+    }
+
+    pub fn cerca_tratta(&self) -> bool {
+        self.id_tratta.is_some()
+            || self.comune.as_deref().map(|c| !c.trim().is_empty()).unwrap_or(false)
+    }
+}
+
+fn blocco_json(note: &str, apertura: &str, chiusura: &str) -> Option<Value> {
+    let inizio = note.find(apertura)? + apertura.len();
+    let fine = inizio + note[inizio..].find(chiusura)?;
+    serde_json::from_str(note[inizio..fine].trim()).ok()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VocePreventivo {
+    /// km / pedaggio / extra / attesa / regola:<codice>
+    pub codice: String,
+    pub descrizione: String,
+    pub quantita: f64,
+    pub unitario: f64,
+    pub importo: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrattaPreventivo {
+    pub id: u32,
+    pub comune: String,
+    pub localita: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Preventivo {
+    pub tratta: Option<TrattaPreventivo>,
+    pub andata_ritorno: bool,
+    pub km: f64,
+    pub costo_km: f64,
+    pub voci: Vec<VocePreventivo>,
+    pub totale: f64,
+    /// Inizio della versione di tariffa applicata (None = impostazioni)
+    pub tariffa_valida_dal: Option<NaiveDate>,
+    /// Regole della tariffa applicate, nell'ordine
+    pub regole: Vec<RegolaApplicata>,
+    pub gratuito: bool,
+    /// Parte del totale rimborsata dal richiedente
+    pub rimborsato: f64,
+    pub a_carico_socio: f64,
+    /// Totale per il campo donazione / pagamento ("12,50")
+    pub totale_testo: String,
+}
+
+/// Centesimi: ogni voce è arrotondata prima di sommarla
+pub fn arrotonda(valore: f64) -> f64 {
+    (valore * 100.0).round() / 100.0
+}
+
+fn numero(valore: Option<&str>) -> Option<f64> {
+    let valore = valore?.trim();
+    if valore.is_empty() {
+        return None;
+    }
+    Some(crate::parse_decimal_for_db(valore))
+}
+
+/// Importo in formato italiano senza simbolo: 1234.5 → "1234,50"
+pub fn formatta_importo(valore: f64) -> String {
+    format!("{:.2}", valore).replace('.', ",")
+}
+
+pub fn id_tratta(row: &Value) -> u32 {
+    get_field_any(row, &["IdTratta", "ID_TRATTA", "id_tratta", "id"])
+        .parse()
+        .unwrap_or(0)
+}
+
+/// Tratta della richiesta: per id, altrimenti per comune e località (senza
+/// località vale la tratta del comune se ce n'è una sola)
+pub fn risolvi_tratta<'a>(tratte: &'a [Value], richiesta: &RichiestaPreventivo) -> Option<&'a Value> {
+    if let Some(id) = richiesta.id_tratta {
+        return tratte.iter().find(|row| id_tratta(row) == id);
+    }
+    let normalizza = |s: &str| s.trim().to_lowercase();
+    let comune = normalizza(richiesta.comune.as_deref()?);
+    if comune.is_empty() {
+        return None;
+    }
+    let localita = normalizza(richiesta.localita.as_deref().unwrap_or(""));
+    let del_comune: Vec<&Value> = tratte
+        .iter()
+        .filter(|row| normalizza(&get_field_any(row, &["Tratta_Comune", "TRATTA_COMUNE"])) == comune)
+        .collect();
+    let stessa_localita = del_comune.iter().find(|row| {
+        normalizza(&get_field_any(row, &["Tratta_Localita", "TRATTA_LOCALITA"])) == localita
+    });
+    match stessa_localita {
+        Some(row) => Some(*row),
+        None if localita.is_empty() && del_comune.len() == 1 => Some(del_comune[0]),
+        None => None,
+    }
+}
+
+/// Preventivo dettagliato. `tratta` è la riga già risolta (None = km a mano).
+pub fn calcola(
+    richiesta: &RichiestaPreventivo,
+    parametri: &ParametriTariffa,
+    tratta: Option<&Value>,
+) -> Result<Preventivo, ApiError> {
+    if tratta.is_none() && richiesta.cerca_tratta() {
+        return Err(ApiError::not_found(match richiesta.id_tratta {
+            Some(id) => format!("Tratta {} non trovata", id),
+            None => format!(
+                "Nessuna tratta per {} {}",
+                richiesta.comune.as_deref().unwrap_or("").trim(),
+                richiesta.localita.as_deref().unwrap_or("").trim()
+            ),
+        }));
+    }
+
+    // Costo al km: quello indicato nella richiesta, poi la tariffa propria della
+    // tratta, infine quello della tariffa in vigore alla data
+    let costo_km = numero(richiesta.costo_km.as_deref())
+        .filter(|v| *v > 0.0)
+        .or_else(|| tratta.and_then(tariffa_speciale_tratta))
+        .unwrap_or(parametri.costo_al_km);
+    let andata_ritorno = richiesta.andata_ritorno.unwrap_or(tratta.is_some());
+
+    let (km, pedaggio) = match tratta {
+        Some(row) => {
+            let c = ComponentiTratta::da_riga(row, costo_km);
+            // La tratta è A/R: solo andata vale metà km e metà pedaggio
+            let fattore = if andata_ritorno { 1.0 } else { 0.5 };
+            let pedaggio = numero(richiesta.pedaggio.as_deref()).unwrap_or(c.pedaggio * fattore);
+            (c.km_ar * fattore, pedaggio)
+        }
+        None => {
+            let km = numero(richiesta.km.as_deref()).unwrap_or(0.0);
+            let fattore = if andata_ritorno { 2.0 } else { 1.0 };
+            (km * fattore, numero(richiesta.pedaggio.as_deref()).unwrap_or(0.0))
+        }
+    };
+    if km < 0.0 || pedaggio < 0.0 {
+        return Err("Km e pedaggio non possono essere negativi".into());
+    }
+    let extra = numero(richiesta.extra.as_deref()).unwrap_or(parametri.extra);
+
+    let mut voci = vec![VocePreventivo {
+        codice: "km".to_string(),
+        descrizione: if andata_ritorno {
+            "Chilometri andata e ritorno".to_string()
+        } else {
+            "Chilometri".to_string()
+        },
+        quantita: km,
+        unitario: costo_km,
+        importo: arrotonda(km * costo_km),
+    }];
+    if pedaggio > 0.0 {
+        voci.push(VocePreventivo {
+            codice: "pedaggio".to_string(),
+            descrizione: "Pedaggio autostradale".to_string(),
+            quantita: 1.0,
+            unitario: pedaggio,
+            importo: arrotonda(pedaggio),
+        });
+    }
+    if extra != 0.0 {
+        voci.push(VocePreventivo {
+            codice: "extra".to_string(),
+            descrizione: "Extra".to_string(),
+            quantita: 1.0,
+            unitario: extra,
+            importo: arrotonda(extra),
+        });
+    }
+    let minuti_addebitati = richiesta
+        .minuti_attesa
+        .saturating_sub(parametri.attesa_gratuita_minuti);
+    if minuti_addebitati > 0 && parametri.costo_attesa_ora > 0.0 {
+        let ore = minuti_addebitati as f64 / 60.0;
+        voci.push(VocePreventivo {
+            codice: "attesa".to_string(),
+            descrizione: format!("Attesa ({} min oltre i {} gratuiti)", minuti_addebitati, parametri.attesa_gratuita_minuti),
+            quantita: ore,
+            unitario: parametri.costo_attesa_ora,
+            importo: arrotonda(ore * parametri.costo_attesa_ora),
+        });
+    }
+
+    let totale = arrotonda(voci.iter().map(|v| v.importo).sum());
+    Ok(Preventivo {
+        tratta: tratta.map(|row| TrattaPreventivo {
+            id: id_tratta(row),
+            comune: get_field_any(row, &["Tratta_Comune", "TRATTA_COMUNE"]),
+            localita: get_field_any(row, &["Tratta_Localita", "TRATTA_LOCALITA"]),
+        }),
+        andata_ritorno,
+        km,
+        costo_km,
+        voci,
+        totale,
+        tariffa_valida_dal: None,
+        regole: Vec::new(),
+        gratuito: false,
+        rimborsato: 0.0,
+        a_carico_socio: totale,
+        totale_testo: formatta_importo(totale),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parametri() -> ParametriTariffa {
+        ParametriTariffa {
+            costo_al_km: 0.5,
+            extra: 0.0,
+            costo_attesa_ora: 0.0,
+            attesa_gratuita_minuti: 0,
+        }
+    }
+
+    /// Blocco [[CT]] come lo scrive dettaglioDaPreventivo (calcola-tariffa.js)
+    fn nota_ct(richiesta: &RichiestaPreventivo, preventivo: &Preventivo) -> String {
+        let blocco = serde_json::json!({
+            "costo_km": formatta_importo(preventivo.costo_km),
+            "pedaggio": richiesta.pedaggio.clone().unwrap_or_default(),
+            "extra": "0,00",
+            "chilometri": preventivo.km.to_string().replace('.', ","),
+            "attesa": "",
+            "andata_ritorno": richiesta.andata_ritorno,
+            "totale": format!("€ {}", formatta_importo(preventivo.totale)),
+        });
+        format!("Paziente in carrozzina\n{}{}{}", CT_INIZIO, blocco, CT_FINE)
+    }
+
+    fn richiesta(km: &str, andata_ritorno: bool) -> RichiestaPreventivo {
+        RichiestaPreventivo {
+            km: Some(km.to_string()),
+            pedaggio: Some("2,00".to_string()),
+            andata_ritorno: Some(andata_ritorno),
+            ..RichiestaPreventivo::default()
+        }
+    }
+
+    #[test]
+    fn preventivo_riletto_dalle_note_non_raddoppia_i_km() {
+        for (km, andata_ritorno, km_fatturati, totale) in [
+            ("10", true, 20.0, 12.0),
+            ("7,5", true, 15.0, 9.5),
+            ("10", false, 10.0, 7.0),
+            ("12,5", false, 12.5, 8.25),
+        ] {
+            let prima = richiesta(km, andata_ritorno);
+            let preventivo = calcola(&prima, &parametri(), None).unwrap();
+            assert_eq!(preventivo.km, km_fatturati);
+            assert_eq!(preventivo.totale, totale);
+
+            let riletta = RichiestaPreventivo::dalle_note(&nota_ct(&prima, &preventivo)).unwrap();
+            let di_nuovo = calcola(&riletta, &parametri(), None).unwrap();
+            assert_eq!(di_nuovo.km, preventivo.km, "km {} a/r {}", km, andata_ritorno);
+            assert_eq!(di_nuovo.totale, preventivo.totale, "km {} a/r {}", km, andata_ritorno);
+        }
+    }
+
+    #[test]
+    fn tratta_prezzata_con_la_tariffa_in_vigore_salvo_tariffa_speciale() {
+        let copiata = serde_json::json!({
+            "IdTratta": 3, "Tratta_KmAr": 100, "Tratta_TariffaKm": 0.7, "Tratta_Pedaggio": 0
+        });
+        let richiesta = RichiestaPreventivo {
+            id_tratta: Some(3),
+            ..RichiestaPreventivo::default()
+        };
+        let preventivo = calcola(&richiesta, &parametri(), Some(&copiata)).unwrap();
+        assert_eq!(preventivo.costo_km, 0.5);
+        assert_eq!(preventivo.totale, 50.0);
+
+        let mut speciale = copiata.clone();
+        speciale["Tratta_TariffaKmSpeciale"] = serde_json::json!(0.4);
+        let preventivo = calcola(&richiesta, &parametri(), Some(&speciale)).unwrap();
+        assert_eq!(preventivo.costo_km, 0.4);
+        assert_eq!(ComponentiTratta::da_riga(&speciale, 0.5).totale, 40.0);
+        assert_eq!(ComponentiTratta::da_riga(&copiata, 0.5).totale, 50.0);
+    }
+
+    #[test]
+    fn blocco_tratta_fuori_asti_dalle_note() {
+        let richiesta = RichiestaPreventivo::dalle_note("[[TFA]]{\"id\": \"14\"}[[/TFA]]").unwrap();
+        assert_eq!(richiesta.id_tratta, Some(14));
+        assert!(RichiestaPreventivo::dalle_note("nessun blocco").is_none());
+    }
+}
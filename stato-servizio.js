@@ -0,0 +1,12 @@
+// Stato del servizio — stesse equivalenze del backend (stato_servizio.rs):
+// la chiusura dal modale Completa scrive COMPLETATO, i dati storici ESEGUITO
+
+export function normalizzaStatoServizio(stato) {
+    return String(stato || '').trim().toUpperCase();
+}
+
+/** Servizio svolto: COMPLETATO o il vecchio ESEGUITO */
+export function servizioSvolto(stato) {
+    const s = normalizzaStatoServizio(stato);
+    return s === 'COMPLETATO' || s === 'ESEGUITO';
+}
@@ -133,6 +133,7 @@ function normalizeTrattaFromApi(t) {
         costo: formatItalianNumber(t.costo, 1),
         pedaggio: formatItalianNumber(t.pedaggio, 2, true),
         totale: formatItalianNumber(t.totale, 2),
+        tariffa_speciale: t.tariffa_speciale === true,
         note_aggiuntive: t.note_aggiuntive || ''
     };
 }
@@ -175,7 +176,10 @@ function calcolaImportiTratta(km, costoKm, pedaggio) {
 function applicaTariffaECalcoli(tratta) {
     const km = parseItalianNumber(tratta.km);
     const pedaggio = parseItalianNumber(tratta.pedaggio);
-    const tariffa = costoAlKmGlobale > 0 ? costoAlKmGlobale : parseItalianNumber(tratta.costo_km);
+    // Una tratta con tariffa propria non segue il costo al km in vigore
+    const tariffa = costoAlKmGlobale > 0 && !tratta.tariffa_speciale
+        ? costoAlKmGlobale
+        : parseItalianNumber(tratta.costo_km);
     const { costo, totale } = calcolaImportiTratta(km, tariffa, pedaggio);
     return {
         ...tratta,
@@ -188,7 +192,7 @@ function applicaTariffaECalcoli(tratta) {
 function recalcRow(entryEl) {
     const km = parseItalianNumber(entryEl.querySelector('[data-field="km"]')?.value);
     const pedaggio = parseItalianNumber(entryEl.querySelector('[data-field="pedaggio"]')?.value);
-    const costoKm = costoAlKmGlobale > 0
+    const costoKm = costoAlKmGlobale > 0 && entryEl.dataset.tariffaSpeciale !== '1'
         ? costoAlKmGlobale
         : parseItalianNumber(entryEl.querySelector('[data-field="costo_km"]')?.value);
     const { costo, totale } = calcolaImportiTratta(km, costoKm, pedaggio);
@@ -221,6 +225,7 @@ function createTrattaEntry(tratta) {
     const div = document.createElement('div');
     div.className = 'tratta-entry';
     div.dataset.id = String(tratta.id);
+    if (tratta.tariffa_speciale) div.dataset.tariffaSpeciale = '1';
     if (selectedTrattaId === tratta.id) {
         div.classList.add('selected');
     }
@@ -312,7 +317,7 @@ async function loadTratte() {
         allTratte = rows.map((t) => applicaTariffaECalcoli(normalizeTrattaFromApi(t)));
         renderTratteList(allTratte);
         if (costoAlKmGlobale > 0) {
-            showStatus(`Tariffa km aggiornata da impostazioni: ${formatItalianNumber(costoAlKmGlobale, 2, true)}`);
+            showStatus(`Tariffa km in vigore: ${formatItalianNumber(costoAlKmGlobale, 2, true)}`);
         }
     } catch (error) {
         console.error('Errore caricamento tratte:', error);
@@ -1,4 +1,4 @@
-import { richiediSessione, isAdmin, leggiSessione } from './auth-session.js';
+import { richiediSessione, haCapacita, leggiSessione } from './auth-session.js';
 import {
     SIDEBAR_VOCI_UI,
     defaultSidebarMenu,
@@ -8,6 +8,9 @@ import {
 
 let invoke;
 let adminUserId = '';
+/** Ruoli e catalogo capacità da get_ruoli */
+let catalogo = { ruoli: [], capacita: [] };
+const RUOLO_AMMINISTRATORE = 'amministratore';
 
 async function initTauri() {
     try {
@@ -90,6 +93,176 @@ function setupToggleSidebarUi(prefix) {
     progEl?.addEventListener('change', sync);
 }
 
+// --- Ruoli e capacità ---
+
+function trovaRuolo(codice) {
+    return catalogo.ruoli.find((r) => r.codice === codice) || null;
+}
+
+function montaCheckboxCapacita(containerId, prefix) {
+    const box = document.getElementById(containerId);
+    if (!box) return;
+    box.innerHTML = catalogo.capacita.map((c) => `
+        <label title="${escapeHtml(c.codice)}">
+            <input type="checkbox" id="${prefix}-cap-${c.codice}" data-capacita="${escapeHtml(c.codice)}">
+            ${escapeHtml(c.descrizione)}
+        </label>
+    `).join('');
+}
+
+function leggiCapacitaDaCheckbox(prefix) {
+    return catalogo.capacita
+        .filter((c) => document.getElementById(`${prefix}-cap-${c.codice}`)?.checked)
+        .map((c) => c.codice);
+}
+
+function applicaCapacitaAiCheckbox(prefix, lista) {
+    const attive = new Set(lista || []);
+    catalogo.capacita.forEach((c) => {
+        const el = document.getElementById(`${prefix}-cap-${c.codice}`);
+        if (el) el.checked = attive.has(c.codice);
+    });
+}
+
+function riempiSelectRuoli(selectId, primaOpzione) {
+    const sel = document.getElementById(selectId);
+    if (!sel) return;
+    sel.innerHTML = `<option value="">${escapeHtml(primaOpzione)}</option>` +
+        catalogo.ruoli.map((r) => `<option value="${escapeHtml(r.codice)}">${escapeHtml(r.nome)}</option>`).join('');
+}
+
+/** Con un ruolo valgono le capacità; senza, le voci sidebar di prima */
+function aggiornaSezioniRuolo(prefix) {
+    const codice = document.getElementById(`${prefix}-ruolo`)?.value || '';
+    const capFieldset = document.getElementById(`${prefix}-capacita-fieldset`);
+    const sbFieldset = document.getElementById(`${prefix}-sidebar-fieldset`);
+    if (capFieldset) {
+        capFieldset.hidden = !codice;
+        capFieldset.disabled = codice === RUOLO_AMMINISTRATORE;
+    }
+    if (sbFieldset) sbFieldset.hidden = !!codice;
+    if (codice === RUOLO_AMMINISTRATORE) {
+        const adminEl = document.getElementById(`${prefix}-is-admin`);
+        if (adminEl) adminEl.checked = true;
+    }
+}
+
+function setupSelectRuolo(prefix) {
+    document.getElementById(`${prefix}-ruolo`)?.addEventListener('change', () => {
+        const ruolo = trovaRuolo(document.getElementById(`${prefix}-ruolo`)?.value || '');
+        applicaCapacitaAiCheckbox(prefix, ruolo?.capacita || []);
+        aggiornaSezioniRuolo(prefix);
+    });
+}
+
+/** Differenze fra le spunte e il ruolo: diventano eccezioni per l'utente */
+function eccezioniRuolo(prefix) {
+    const codice = document.getElementById(`${prefix}-ruolo`)?.value || '';
+    const ruolo = trovaRuolo(codice);
+    if (!ruolo || codice === RUOLO_AMMINISTRATORE) return {};
+    const scelte = new Set(leggiCapacitaDaCheckbox(prefix));
+    const base = new Set(ruolo.capacita || []);
+    const extra = {};
+    catalogo.capacita.forEach((c) => {
+        if (scelte.has(c.codice) !== base.has(c.codice)) extra[c.codice] = scelte.has(c.codice);
+    });
+    return extra;
+}
+
+async function caricaRuoli() {
+    catalogo = await invoke('get_ruoli', { adminUserId, admin_user_id: adminUserId });
+    montaCheckboxCapacita('edit-capacita-checks', 'edit');
+    montaCheckboxCapacita('nuovo-capacita-checks', 'nuovo');
+    montaCheckboxCapacita('ruolo-capacita-checks', 'ruolo');
+    riempiSelectRuoli('edit-ruolo', '— Nessun ruolo (voci sidebar) —');
+    riempiSelectRuoli('nuovo-ruolo', '— Nessun ruolo (voci sidebar) —');
+    riempiSelectRuoli('ruolo-scelta', '+ Nuovo ruolo');
+}
+
+function mostraRuoloInModale(codice) {
+    const ruolo = trovaRuolo(codice);
+    const codiceEl = document.getElementById('ruolo-codice');
+    const nomeEl = document.getElementById('ruolo-nome');
+    if (codiceEl) {
+        codiceEl.value = ruolo?.codice || '';
+        codiceEl.disabled = !!ruolo;
+    }
+    if (nomeEl) nomeEl.value = ruolo?.nome || '';
+    applicaCapacitaAiCheckbox('ruolo', ruolo?.capacita || []);
+
+    const bloccato = codice === RUOLO_AMMINISTRATORE;
+    const fieldset = document.getElementById('ruolo-capacita-fieldset');
+    if (fieldset) fieldset.disabled = bloccato;
+    if (nomeEl) nomeEl.disabled = bloccato;
+    const btnSalva = document.getElementById('btn-salva-ruolo');
+    if (btnSalva) btnSalva.disabled = bloccato;
+    const btnElimina = document.getElementById('btn-elimina-ruolo');
+    if (btnElimina) {
+        btnElimina.hidden = !ruolo || bloccato;
+        btnElimina.textContent = ruolo?.predefinito ? 'RIPRISTINA' : 'ELIMINA';
+    }
+}
+
+function apriRuoli() {
+    const sel = document.getElementById('ruolo-scelta');
+    if (sel) sel.value = '';
+    mostraRuoloInModale('');
+    document.getElementById('modal-ruoli').hidden = false;
+}
+
+function chiudiRuoli() {
+    document.getElementById('modal-ruoli').hidden = true;
+}
+
+async function salvaRuolo() {
+    const codice = document.getElementById('ruolo-codice')?.value.trim().toLowerCase() || '';
+    const nome = document.getElementById('ruolo-nome')?.value.trim() || '';
+    if (!codice || !nome) {
+        mostraMsg('errore', 'Codice e nome del ruolo sono obbligatori.');
+        return;
+    }
+    try {
+        await invoke('save_ruolo', {
+            payload: {
+                admin_user_id: adminUserId,
+                codice,
+                nome,
+                capacita: leggiCapacitaDaCheckbox('ruolo')
+            }
+        });
+        await caricaRuoli();
+        document.getElementById('ruolo-scelta').value = codice;
+        mostraRuoloInModale(codice);
+        mostraMsg('ok', `Ruolo ${nome} salvato.`);
+        await caricaUtenti();
+    } catch (error) {
+        console.error(error);
+        mostraMsg('errore', String(error?.message || error));
+    }
+}
+
+async function eliminaRuolo() {
+    const codice = document.getElementById('ruolo-scelta')?.value || '';
+    const ruolo = trovaRuolo(codice);
+    if (!ruolo) return;
+    const domanda = ruolo.predefinito
+        ? `Ripristinare il ruolo «${ruolo.nome}» com'era in origine?`
+        : `Eliminare il ruolo «${ruolo.nome}»?`;
+    if (!confirm(domanda)) return;
+    try {
+        await invoke('delete_ruolo', { payload: { admin_user_id: adminUserId, codice } });
+        await caricaRuoli();
+        const sel = document.getElementById('ruolo-scelta');
+        if (sel) sel.value = trovaRuolo(codice) ? codice : '';
+        mostraRuoloInModale(sel?.value || '');
+        mostraMsg('ok', ruolo.predefinito ? `Ruolo ${ruolo.nome} ripristinato.` : `Ruolo ${ruolo.nome} eliminato.`);
+        await caricaUtenti();
+    } catch (error) {
+        console.error(error);
+        mostraMsg('errore', String(error?.message || error));
+    }
+}
+
 function mostraMsg(tipo, testo) {
     const err = document.getElementById('ut-errore');
     const ok = document.getElementById('ut-ok');
@@ -126,6 +299,7 @@ function renderLista(lista) {
                 <div class="ut-riga-nome">${escapeHtml(u.username || '(senza nome)')}</div>
                 <div class="ut-riga-id">${escapeHtml(u.user_id)}</div>
             </div>
+            ${badge(escapeHtml(trovaRuolo(u.ruolo)?.nome?.toUpperCase() || 'NESSUN RUOLO'), !!u.ruolo)}
             ${badge('ADMIN', !!u.is_admin)}
             ${badge('PROGRAMMA', !!u.programma)}
             ${badge('CALENDARIO', !!u.calendario)}
@@ -173,8 +347,13 @@ function apriModifica(user) {
     document.getElementById('edit-programma').checked = !!user.programma;
     document.getElementById('edit-calendario').checked = !!user.calendario;
     document.getElementById('edit-password').value = '';
+    document.getElementById('edit-idsocio').value = user.idsocio || '';
     applicaSidebarAiCheckbox('edit', user.sidebar_menu);
     aggiornaAbilitazioneSidebarChecks('edit', !!user.is_admin, !!user.programma);
+    const ruoloEl = document.getElementById('edit-ruolo');
+    if (ruoloEl) ruoloEl.value = trovaRuolo(user.ruolo) ? user.ruolo : '';
+    applicaCapacitaAiCheckbox('edit', user.capacita);
+    aggiornaSezioniRuolo('edit');
 
     const btnElimina = document.getElementById('btn-elimina-edit');
     if (btnElimina) {
@@ -254,8 +433,13 @@ function apriNuovo() {
     document.getElementById('nuovo-is-admin').checked = false;
     document.getElementById('nuovo-programma').checked = true;
     document.getElementById('nuovo-calendario').checked = false;
+    document.getElementById('nuovo-idsocio').value = '';
     applicaSidebarAiCheckbox('nuovo', defaultSidebarMenu());
     aggiornaAbilitazioneSidebarChecks('nuovo', false, true);
+    const ruoloEl = document.getElementById('nuovo-ruolo');
+    if (ruoloEl) ruoloEl.value = trovaRuolo('operatore') ? 'operatore' : '';
+    applicaCapacitaAiCheckbox('nuovo', trovaRuolo(ruoloEl?.value || '')?.capacita || []);
+    aggiornaSezioniRuolo('nuovo');
     document.getElementById('modal-nuovo').hidden = false;
 }
 
@@ -287,6 +471,9 @@ async function salvaModifica() {
                 programma,
                 calendario,
                 sidebar_menu: sidebarMenu,
+                ruolo: document.getElementById('edit-ruolo')?.value || '',
+                capacita_extra: eccezioniRuolo('edit'),
+                idsocio: document.getElementById('edit-idsocio')?.value.trim() || '',
                 nuova_password: nuovaPassword.trim() ? nuovaPassword.trim() : null
             }
         });
@@ -323,7 +510,10 @@ async function salvaNuovo() {
                 is_admin: isAdminFlag,
                 programma,
                 calendario,
-                sidebar_menu: sidebarMenu
+                sidebar_menu: sidebarMenu,
+                ruolo: document.getElementById('nuovo-ruolo')?.value || '',
+                capacita_extra: eccezioniRuolo('nuovo'),
+                idsocio: document.getElementById('nuovo-idsocio')?.value.trim() || ''
             }
         });
         chiudiNuovo();
@@ -367,8 +557,8 @@ async function chiudiFinestra() {
 document.addEventListener('DOMContentLoaded', async () => {
     const sessione = richiediSessione();
     if (!sessione) return;
-    if (!isAdmin(sessione)) {
-        alert('Accesso riservato agli amministratori.');
+    if (!haCapacita('utenti.gestisci', sessione)) {
+        alert('Accesso riservato a chi gestisce gli utenti.');
         window.location.href = 'index.html';
         return;
     }
@@ -384,6 +574,8 @@ document.addEventListener('DOMContentLoaded', async () => {
     montaCheckboxSidebar('nuovo-sidebar-checks', 'nuovo');
     setupToggleSidebarUi('edit');
     setupToggleSidebarUi('nuovo');
+    setupSelectRuolo('edit');
+    setupSelectRuolo('nuovo');
 
     document.getElementById('btn-chiudi')?.addEventListener('click', chiudiFinestra);
     document.getElementById('btn-nuovo')?.addEventListener('click', apriNuovo);
@@ -394,6 +586,22 @@ document.addEventListener('DOMContentLoaded', async () => {
     document.getElementById('btn-elimina-edit')?.addEventListener('click', apriConfermaElimina);
     document.getElementById('btn-conferma-elimina-si')?.addEventListener('click', eliminaUtenteConfermato);
     document.getElementById('btn-conferma-elimina-no')?.addEventListener('click', chiudiConfermaElimina);
+    document.getElementById('btn-ruoli')?.addEventListener('click', apriRuoli);
+    document.getElementById('btn-chiudi-ruoli')?.addEventListener('click', chiudiRuoli);
+    document.getElementById('btn-salva-ruolo')?.addEventListener('click', salvaRuolo);
+    document.getElementById('btn-elimina-ruolo')?.addEventListener('click', eliminaRuolo);
+    document.getElementById('ruolo-scelta')?.addEventListener('change', (e) => {
+        mostraRuoloInModale(e.target.value);
+    });
 
+    if (!invoke) await initTauri();
+    if (invoke) {
+        try {
+            await caricaRuoli();
+        } catch (error) {
+            console.error(error);
+            mostraMsg('errore', String(error?.message || error));
+        }
+    }
     await caricaUtenti();
 });